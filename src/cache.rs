@@ -9,7 +9,7 @@ use crate::view::PixelRect;
 
 // NOTE: STYLES_BITS must be large enough to distinguish `FontStyle`s, that is:
 // assert!( FontStyle::all().len() < (1 << STYLES_BITS) )
-const STYLES_BITS: usize = 2;
+const STYLES_BITS: usize = 3;
 
 fn get_ascii_index(ch: char, style: FontStyle) -> usize {
     debug_assert!(ch.is_ascii());
@@ -21,6 +21,36 @@ fn get_ascii_index(ch: char, style: FontStyle) -> usize {
 
 pub type GlyphRegion = PixelRect;
 
+/// The number of slots for non-ASCII glyphs the cache tries to have,
+/// so that a screen full of CJK characters can be drawn from the cache.
+const OTHER_GLYPH_SLOTS: u32 = 2048;
+
+/// Returns the size of a slot for a non-ASCII glyph (wide characters take 2 cells)
+fn slot_size(cell_sz: CellSize) -> (u32, u32) {
+    let slot_width = (cell_sz.w as f32 * 2.5).round() as u32;
+    let slot_height = (cell_sz.h as f32 * 1.5).round() as u32;
+    (slot_width, slot_height)
+}
+
+/// Returns the size of the cache texture with the height of the region for ASCII glyphs.
+/// The texture is made as large as `OTHER_GLYPH_SLOTS` slots fit in it within `max_size`.
+fn texture_size(cell_sz: CellSize, styles: u32, max_size: u32) -> (u32, u32) {
+    let ascii_region_height = (6 * cell_sz.h) * styles;
+    let (slot_width, slot_height) = slot_size(cell_sz);
+
+    let texture_w = (16 * cell_sz.w + 1024).next_power_of_two().min(max_size);
+
+    let slots_per_row = (texture_w / slot_width).max(1);
+    let slot_rows = (OTHER_GLYPH_SLOTS + slots_per_row - 1) / slots_per_row;
+    let target = ascii_region_height + slot_rows * slot_height;
+    let texture_h = target.next_power_of_two().min(max_size);
+
+    // The ASCII region is required anyway (creating the texture fails if it exceeds `max_size`)
+    let texture_h = texture_h.max(ascii_region_height);
+
+    (texture_w, texture_h)
+}
+
 fn glyph_region_to_glium_rect(rect: GlyphRegion) -> glium::Rect {
     glium::Rect {
         left: rect.x as u32,
@@ -30,10 +60,123 @@ fn glyph_region_to_glium_rect(rect: GlyphRegion) -> glium::Rect {
     }
 }
 
+/// Slots for non-ASCII glyphs in the cache texture, which are managed in the LRU manner.
+/// Each slot is tagged with the frame where its glyph was used last.
+struct GlyphSlots {
+    lru: LruCache<(char, FontStyle), (GlyphRegion, GlyphMetrics, Option<u64>)>,
+    slot_size: (u32, u32),
+}
+
+impl GlyphSlots {
+    /// Splits the area of the texture below `y_origin` into slots for glyphs in the cell size
+    fn new(
+        texture_size: (u32, u32),
+        y_origin: u32,
+        cell_sz: CellSize,
+        dummy: GlyphMetrics,
+    ) -> Self {
+        let (width, height) = texture_size;
+        let height = height - y_origin;
+        let (slot_width, slot_height) = slot_size(cell_sz);
+
+        let rows = (height / slot_height) as usize;
+        let cols = (width / slot_width) as usize;
+        let capacity = rows * cols;
+
+        log::info!(
+            "{capacity} slots (rows:{rows}, cols:{cols}, each: {slot_width}x{slot_height} px)"
+        );
+
+        let mut lru = LruCache::new(capacity);
+
+        let mut dummy_next = 0_u32;
+
+        for row in 0..rows {
+            for col in 0..cols {
+                let y = (row as u32) * slot_height;
+                let x = (col as u32) * slot_width;
+
+                let region = GlyphRegion {
+                    x: x as i32,
+                    y: (y_origin + y) as i32,
+                    w: 0,
+                    h: 0,
+                };
+
+                let dummy_char = loop {
+                    dummy_next += 1;
+                    if let Some(ch) = char::from_u32(dummy_next) {
+                        break ch;
+                    }
+                };
+
+                let key = (dummy_char, FontStyle::Regular);
+                let val = (region, dummy, None);
+                lru.push(key, val);
+            }
+        }
+
+        Self {
+            lru,
+            slot_size: (slot_width, slot_height),
+        }
+    }
+
+    fn get(&mut self, ch: char, style: FontStyle, tag: u64) -> Option<(GlyphRegion, GlyphMetrics)> {
+        let (region, metrics, tag_mut) = self.lru.get_mut(&(ch, style))?;
+
+        // dummy slot
+        if tag_mut.is_none() {
+            return None;
+        }
+
+        // update tag
+        *tag_mut = Some(tag);
+
+        Some((*region, *metrics))
+    }
+
+    /// Returns false if the least recently used slot is used in the same frame
+    fn has_room(&self, tag: u64) -> bool {
+        // NOTE: This situation can be happen
+        //       when too many glyphs are drawn on a single same frame.
+        let (_, next) = self.lru.peek_lru().unwrap();
+        next.2 != Some(tag)
+    }
+
+    /// Evicts the least recently used slot for a glyph image of the size,
+    /// and returns the region where the image should be written
+    fn insert(
+        &mut self,
+        ch: char,
+        style: FontStyle,
+        image_size: (u32, u32),
+        metrics: GlyphMetrics,
+        tag: u64,
+    ) -> Result<GlyphRegion, ()> {
+        // A glyph larger than a slot would overwrite the adjacent ones
+        let (slot_width, slot_height) = self.slot_size;
+        let (w, h) = image_size;
+        if w > slot_width || h > slot_height {
+            return Err(());
+        }
+
+        let (_, (mut region, _, _)) = self.lru.pop_lru().unwrap();
+        region.w = w;
+        region.h = h;
+
+        let key = (ch, style);
+        let val = (region, metrics, Some(tag));
+        self.lru.push(key, val);
+
+        Ok(region)
+    }
+}
+
 pub struct GlyphCache {
     texture: Rc<texture::Texture2d>,
     ascii_glyph_region: Vec<Option<(GlyphRegion, GlyphMetrics)>>,
-    other_glyph_region: LruCache<(char, FontStyle), (GlyphRegion, GlyphMetrics, Option<u64>)>,
+    other_glyph_region: GlyphSlots,
 }
 
 impl GlyphCache {
@@ -73,30 +216,21 @@ impl GlyphCache {
 
         let styles = FontStyle::all().len() as u32;
 
-        let texture_w = {
-            let target = 16 * cell_sz.w + 1024;
-            let mut w = 1;
-            while w < target {
-                w <<= 1;
-            }
-            w
-        };
-        let texture_h = {
-            let target = (6 * cell_sz.h) * styles + 1024 /* space for other glyphs */;
-            let mut h = 1;
-            while h < target {
-                h <<= 1;
-            }
-            h
-        };
+        let (texture_w, texture_h) = texture_size(cell_sz, styles, max_texture_size as u32);
         log::debug!("cache texture: {}x{} (px)", texture_w, texture_h);
 
         let ascii_region_height = (6 * cell_sz.h) * styles;
 
         let zeros = vec![vec![0_u8; texture_w as usize]; texture_h as usize];
-        let texture =
-            texture::Texture2d::with_mipmaps(display, zeros, texture::MipmapsOption::NoMipmap)
-                .expect("Failed to create a texture");
+        let mipmaps = texture::MipmapsOption::NoMipmap;
+        let texture = texture::Texture2d::with_mipmaps(display, zeros, mipmaps);
+        let texture = texture.unwrap_or_else(|err| {
+            panic!(
+                "Failed to create the glyph cache texture \
+                 ({texture_w}x{texture_h}, max_texture_size = {max_texture_size}): {err}. \
+                 Try a smaller font_size."
+            )
+        });
 
         assert!(styles < (1 << STYLES_BITS));
         let mut ascii_glyph_region: Vec<Option<(GlyphRegion, GlyphMetrics)>> =
@@ -135,57 +269,13 @@ impl GlyphCache {
         }
 
         // Split the rest of texture into "slots" and store a non-ASCII glyph in a slot.
-        // These slots are managed in the LRU manner.
         let other_glyph_region = {
-            let height = texture.height() - ascii_region_height;
-            let width = texture.width();
-            let slot_height = (cell_sz.h as f32 * 1.5).round() as u32;
-            let slot_width = (cell_sz.w as f32 * 2.5).round() as u32;
-
-            let rows = (height / slot_height) as usize;
-            let cols = (width / slot_width) as usize;
-            let capacity = rows * cols;
-
-            log::info!(
-                "{capacity} slots (rows:{rows}, cols:{cols}, each: {slot_width}x{slot_height} px)"
-            );
-
-            let mut lru = LruCache::new(capacity);
-
-            let mut dummy_next = 0_u32;
-
             let dummy_metrics = {
                 let idx = get_ascii_index(' ', FontStyle::Regular);
                 ascii_glyph_region[idx].unwrap().1
             };
-
-            for row in 0..rows {
-                for col in 0..cols {
-                    let y_origin = ascii_region_height;
-                    let y = (row as u32) * slot_height;
-                    let x = (col as u32) * slot_width;
-
-                    let region = GlyphRegion {
-                        x: x as i32,
-                        y: (y_origin + y) as i32,
-                        w: 0,
-                        h: 0,
-                    };
-
-                    let dummy_char = loop {
-                        dummy_next += 1;
-                        if let Some(ch) = char::from_u32(dummy_next) {
-                            break ch;
-                        }
-                    };
-
-                    let key = (dummy_char, FontStyle::Regular);
-                    let val = (region, dummy_metrics, None);
-                    lru.push(key, val);
-                }
-            }
-
-            lru
+            let size = (texture.width(), texture.height());
+            GlyphSlots::new(size, ascii_region_height, cell_sz, dummy_metrics)
         };
 
         Self {
@@ -205,22 +295,12 @@ impl GlyphCache {
             let idx = get_ascii_index(ch, style);
             self.ascii_glyph_region[idx]
         } else {
-            let (region, metrics, tag_mut) = self.other_glyph_region.get_mut(&(ch, style))?;
-
-            // dummy slot
-            if *tag_mut == None {
-                return None;
-            }
-
-            // update tag
-            *tag_mut = Some(tag);
-
-            Some((*region, *metrics))
+            self.other_glyph_region.get(ch, style, tag)
         }
     }
 
-    pub fn get_or_insert<'a>(
-        &'_ mut self,
+    pub fn get_or_insert(
+        &mut self,
         ch: char,
         style: FontStyle,
         fonts: &FontSet,
@@ -229,11 +309,8 @@ impl GlyphCache {
         match self.get(ch, style, tag) {
             Some(found) => Ok(Some(found)),
             None => {
-                let (_, next) = self.other_glyph_region.peek_lru().unwrap();
-                if next.2 == Some(tag) {
-                    // Evicting a slot with the same tag is not desirable.
-                    // NOTE: This situation can be happen
-                    //       when too many glyphs are drawn on a single same frame.
+                // Evicting a slot with the same tag is not desirable.
+                if !self.other_glyph_region.has_room(tag) {
                     return Err(());
                 }
 
@@ -242,22 +319,15 @@ impl GlyphCache {
                     Some(got) => got,
                 };
 
-                // update
-                {
-                    let (_, (mut region, _, _)) = self.other_glyph_region.pop_lru().unwrap();
-
-                    region.w = image.width;
-                    region.h = image.height;
+                let size = (image.width, image.height);
+                let region = self
+                    .other_glyph_region
+                    .insert(ch, style, size, metrics, tag)?;
 
-                    let rect = glyph_region_to_glium_rect(region);
-                    self.texture.main_level().write(rect, image);
-
-                    let key = (ch, style);
-                    let val = (region, metrics, Some(tag));
-                    self.other_glyph_region.push(key, val);
-                }
+                let rect = glyph_region_to_glium_rect(region);
+                self.texture.main_level().write(rect, image);
 
-                Ok(Some(self.get(ch, style, tag).unwrap()))
+                Ok(Some((region, metrics)))
             }
         }
     }
@@ -266,3 +336,130 @@ impl GlyphCache {
         self.texture.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_texture_size() {
+        let cell_sz = CellSize { w: 17, h: 39 };
+        let styles = FontStyle::all().len() as u32;
+
+        let (w, h) = texture_size(cell_sz, styles, 16384);
+        assert!(w.is_power_of_two() && h.is_power_of_two());
+
+        let (slot_w, slot_h) = slot_size(cell_sz);
+        let ascii_region_height = (6 * cell_sz.h) * styles;
+        let slots = (w / slot_w) * ((h - ascii_region_height) / slot_h);
+        assert!(slots >= OTHER_GLYPH_SLOTS);
+
+        // limited by the maximum size
+        assert_eq!(texture_size(cell_sz, styles, 2048), (2048, 2048));
+    }
+
+    /// The embedded regular font in the size, with the cell size (padded as in the cache)
+    fn regular_fonts(font_size: u32) -> (FontSet, CellSize) {
+        let mut fonts = FontSet::new(font_size);
+        let font = crate::font::Font::new(include_bytes!("fonts/Mplus1Code-Regular.ttf"), 0);
+        fonts.add(FontStyle::Regular, font);
+
+        let advance = fonts.metrics('M', FontStyle::Regular).unwrap().horiAdvance >> 6;
+        let cell_sz = CellSize {
+            w: advance as u32 + 1,
+            h: font_size * 5 / 4 + 1,
+        };
+        (fonts, cell_sz)
+    }
+
+    #[test]
+    fn test_glyph_slots() {
+        let (fonts, cell_sz) = regular_fonts(20);
+        let (slot_w, slot_h) = slot_size(cell_sz);
+        let dummy = fonts.metrics(' ', FontStyle::Regular).unwrap();
+
+        // 2 slots
+        let mut slots = GlyphSlots::new((slot_w * 2, 10 + slot_h), 10, cell_sz, dummy);
+        assert!(slots.get('\u{3042}', FontStyle::Regular, 0).is_none());
+
+        let region = slots.insert('\u{3042}', FontStyle::Regular, (5, 5), dummy, 0);
+        let region = region.unwrap();
+        assert_eq!((region.y, region.w, region.h), (10, 5, 5));
+        assert!(slots.get('\u{3042}', FontStyle::Regular, 0).is_some());
+        assert!(slots
+            .insert('\u{3044}', FontStyle::Regular, (5, 5), dummy, 0)
+            .is_ok());
+
+        // the slots are full of the glyphs used in the frame
+        assert!(!slots.has_room(0));
+
+        // the least recently used one is evicted in the next frame
+        assert!(slots.get('\u{3042}', FontStyle::Regular, 1).is_some());
+        assert!(slots.has_room(1));
+        assert!(slots
+            .insert('\u{3046}', FontStyle::Regular, (5, 5), dummy, 1)
+            .is_ok());
+        assert!(slots.get('\u{3044}', FontStyle::Regular, 1).is_none());
+        assert!(slots.get('\u{3042}', FontStyle::Regular, 1).is_some());
+
+        // too large for a slot
+        let large = (slot_w + 1, 1);
+        assert!(slots
+            .insert('\u{3048}', FontStyle::Regular, large, dummy, 2)
+            .is_err());
+    }
+
+    /// Draws a screen full of Kanji from the cache, without OpenGL (the texture isn't written),
+    /// and checks that a cached frame takes a small fraction of the first (rasterized) one.
+    /// Run with `cargo test --release bench_kanji_screen -- --ignored`
+    #[test]
+    #[ignore]
+    fn bench_kanji_screen() {
+        use std::time::Instant;
+
+        let (fonts, cell_sz) = regular_fonts(24);
+        let styles = FontStyle::all().len() as u32;
+        let ascii_region_height = (6 * cell_sz.h) * styles;
+        let size = texture_size(cell_sz, styles, 16384);
+        let dummy = fonts.metrics(' ', FontStyle::Regular).unwrap();
+        let mut slots = GlyphSlots::new(size, ascii_region_height, cell_sz, dummy);
+
+        // 50x50 cells of distinct Kanji
+        let screen: Vec<char> = ('\u{4E00}'..)
+            .filter(|&ch| fonts.metrics(ch, FontStyle::Regular).is_some())
+            .take(50 * 50)
+            .collect();
+
+        let mut draw_frame = |tag: u64| -> usize {
+            let mut rendered = 0;
+            for &ch in screen.iter() {
+                if slots.get(ch, FontStyle::Regular, tag).is_none() {
+                    assert!(slots.has_room(tag));
+                    let (image, metrics) = fonts.render(ch, FontStyle::Regular).unwrap();
+                    let size = (image.width, image.height);
+                    slots
+                        .insert(ch, FontStyle::Regular, size, metrics, tag)
+                        .unwrap();
+                    rendered += 1;
+                }
+            }
+            rendered
+        };
+
+        let start = Instant::now();
+        assert_eq!(draw_frame(0), screen.len());
+        let rasterized = start.elapsed();
+
+        const FRAMES: u64 = 100;
+        let start = Instant::now();
+        for tag in 1..=FRAMES {
+            assert_eq!(draw_frame(tag), 0);
+        }
+        let cached = start.elapsed() / FRAMES as u32;
+
+        assert!(
+            cached * 10 < rasterized,
+            "cached frame: {cached:?}, first frame: {rasterized:?}"
+        );
+    }
+}
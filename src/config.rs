@@ -1,17 +1,338 @@
+use glium::glutin::event::{ModifiersState, VirtualKeyCode};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMode {
+    Windowed,
+    Maximized,
+    Fullscreen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bell {
+    None,
+    /// Flash the screen
+    Visual,
+    /// Ring the bell of the X server
+    Audible,
+    Both,
+}
+
+/// Commands which can be bound to keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    FontDec,
+    FontInc,
+    Copy,
+    Paste,
+    MatchCopy,
+    MatchCopyAll,
+    Search,
+    ClearHistory,
+    PrevPrompt,
+    NextPrompt,
+    ToggleFullscreen,
+
+    // multiplexer
+    Prefix,
+    NewTab,
+    NewWindow,
+    NextTab,
+    PrevTab,
+    SplitVertical,
+    SplitHorizontal,
+    ToggleMaximize,
+    SaveLayout,
+    RestoreLayout,
+    Close,
+    Detach,
+    FocusUp,
+    FocusDown,
+    FocusLeft,
+    FocusRight,
+    MovePartitionUp,
+    MovePartitionDown,
+    MovePartitionLeft,
+    MovePartitionRight,
+}
+
+impl Action {
+    /// Returns true if the action is performed by the multiplexer after the prefix key
+    fn is_multiplexer_command(self) -> bool {
+        use Action::*;
+        !matches!(
+            self,
+            FontDec
+                | FontInc
+                | Copy
+                | Paste
+                | MatchCopy
+                | MatchCopyAll
+                | Search
+                | ClearHistory
+                | PrevPrompt
+                | NextPrompt
+                | ToggleFullscreen
+        )
+    }
+}
+
+/// A key which an action is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// A key pressed with the exact modifiers (e.g. "ctrl+shift+c", "f11")
+    Code(ModifiersState, VirtualKeyCode),
+    /// A typed character (e.g. "%"), which doesn't depend on the keyboard layout
+    Char(char),
+}
+
+impl Key {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut chars = spec.chars();
+        if let (Some(ch), None) = (chars.next(), chars.next()) {
+            return Some(Key::Char(ch));
+        }
+
+        let spec = spec.to_ascii_lowercase();
+        let mut parts: Vec<&str> = spec.split('+').map(str::trim).collect();
+        let name = parts.pop()?;
+
+        let mut modifiers = ModifiersState::empty();
+        for modifier in parts {
+            modifiers |= match modifier {
+                "ctrl" | "control" => ModifiersState::CTRL,
+                "shift" => ModifiersState::SHIFT,
+                "alt" => ModifiersState::ALT,
+                "super" | "logo" => ModifiersState::LOGO,
+                _ => return None,
+            };
+        }
+
+        Some(Key::Code(modifiers, key_code(name)?))
+    }
+}
+
+fn key_code(name: &str) -> Option<VirtualKeyCode> {
+    use VirtualKeyCode::*;
+    const LETTERS: [VirtualKeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [VirtualKeyCode; 10] =
+        [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9];
+    const FUNCTIONS: [VirtualKeyCode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+
+    let code = match name.as_bytes() {
+        &[ch @ b'a'..=b'z'] => LETTERS[(ch - b'a') as usize],
+        &[ch @ b'0'..=b'9'] => DIGITS[(ch - b'0') as usize],
+        [b'f', n @ ..] if !n.is_empty() => {
+            let n: usize = std::str::from_utf8(n).ok()?.parse().ok()?;
+            *FUNCTIONS.get(n.checked_sub(1)?)?
+        }
+        _ => match name {
+            "up" => Up,
+            "down" => Down,
+            "left" => Left,
+            "right" => Right,
+            "pageup" => PageUp,
+            "pagedown" => PageDown,
+            "home" => Home,
+            "end" => End,
+            "insert" => Insert,
+            "delete" => Delete,
+            "backspace" => Back,
+            "enter" | "return" => Return,
+            "tab" => Tab,
+            "escape" => Escape,
+            "space" => Space,
+            "minus" => Minus,
+            "equals" => Equals,
+            "comma" => Comma,
+            "period" => Period,
+            "slash" => Slash,
+            "backslash" => Backslash,
+            "semicolon" => Semicolon,
+            "apostrophe" => Apostrophe,
+            "grave" => Grave,
+            "bracketleft" => LBracket,
+            "bracketright" => RBracket,
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+/// Keys bound to each action (see config.toml for the format)
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Keybindings {
+    pub font_dec: Vec<String>,
+    pub font_inc: Vec<String>,
+    pub copy: Vec<String>,
+    pub paste: Vec<String>,
+    pub match_copy: Vec<String>,
+    pub match_copy_all: Vec<String>,
+    pub search: Vec<String>,
+    pub clear_history: Vec<String>,
+    pub prev_prompt: Vec<String>,
+    pub next_prompt: Vec<String>,
+    pub toggle_fullscreen: Vec<String>,
+
+    // the multiplexer takes the following keys after the prefix key
+    pub prefix: Vec<String>,
+    pub new_tab: Vec<String>,
+    pub new_window: Vec<String>,
+    pub next_tab: Vec<String>,
+    pub prev_tab: Vec<String>,
+    pub split_vertical: Vec<String>,
+    pub split_horizontal: Vec<String>,
+    pub toggle_maximize: Vec<String>,
+    pub save_layout: Vec<String>,
+    pub restore_layout: Vec<String>,
+    pub close: Vec<String>,
+    pub detach: Vec<String>,
+    pub focus_up: Vec<String>,
+    pub focus_down: Vec<String>,
+    pub focus_left: Vec<String>,
+    pub focus_right: Vec<String>,
+    pub move_partition_up: Vec<String>,
+    pub move_partition_down: Vec<String>,
+    pub move_partition_left: Vec<String>,
+    pub move_partition_right: Vec<String>,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        let keys = |specs: &[&str]| specs.iter().map(|&s| s.to_owned()).collect();
+        Keybindings {
+            font_dec: keys(&["ctrl+minus"]),
+            font_inc: keys(&["ctrl+equals"]),
+            copy: keys(&["ctrl+shift+c"]),
+            paste: keys(&["ctrl+shift+v"]),
+            match_copy: keys(&["ctrl+shift+m"]),
+            match_copy_all: keys(&["ctrl+shift+k"]),
+            search: keys(&["ctrl+shift+f"]),
+            clear_history: keys(&["ctrl+shift+l"]),
+            prev_prompt: keys(&["ctrl+shift+up"]),
+            next_prompt: keys(&["ctrl+shift+down"]),
+            toggle_fullscreen: keys(&["f11"]),
+
+            prefix: keys(&["ctrl+a"]),
+            new_tab: keys(&["c"]),
+            new_window: keys(&["N"]),
+            next_tab: keys(&["n"]),
+            prev_tab: keys(&["p"]),
+            split_vertical: keys(&["%"]),
+            split_horizontal: keys(&["\""]),
+            toggle_maximize: keys(&["z"]),
+            save_layout: keys(&["s"]),
+            restore_layout: keys(&["r"]),
+            close: keys(&["x"]),
+            detach: keys(&["d"]),
+            focus_up: keys(&["up"]),
+            focus_down: keys(&["down"]),
+            focus_left: keys(&["left"]),
+            focus_right: keys(&["right"]),
+            move_partition_up: keys(&["ctrl+up"]),
+            move_partition_down: keys(&["ctrl+down"]),
+            move_partition_left: keys(&["ctrl+left"]),
+            move_partition_right: keys(&["ctrl+right"]),
+        }
+    }
+}
+
+impl Keybindings {
+    /// Returns the table to look up the action bound to a key
+    pub fn table(&self) -> HashMap<Key, Action> {
+        use Action::*;
+        let bindings = [
+            (FontDec, &self.font_dec),
+            (FontInc, &self.font_inc),
+            (Copy, &self.copy),
+            (Paste, &self.paste),
+            (MatchCopy, &self.match_copy),
+            (MatchCopyAll, &self.match_copy_all),
+            (Search, &self.search),
+            (ClearHistory, &self.clear_history),
+            (PrevPrompt, &self.prev_prompt),
+            (NextPrompt, &self.next_prompt),
+            (ToggleFullscreen, &self.toggle_fullscreen),
+            (Prefix, &self.prefix),
+            (NewTab, &self.new_tab),
+            (NewWindow, &self.new_window),
+            (NextTab, &self.next_tab),
+            (PrevTab, &self.prev_tab),
+            (SplitVertical, &self.split_vertical),
+            (SplitHorizontal, &self.split_horizontal),
+            (ToggleMaximize, &self.toggle_maximize),
+            (SaveLayout, &self.save_layout),
+            (RestoreLayout, &self.restore_layout),
+            (Close, &self.close),
+            (Detach, &self.detach),
+            (FocusUp, &self.focus_up),
+            (FocusDown, &self.focus_down),
+            (FocusLeft, &self.focus_left),
+            (FocusRight, &self.focus_right),
+            (MovePartitionUp, &self.move_partition_up),
+            (MovePartitionDown, &self.move_partition_down),
+            (MovePartitionLeft, &self.move_partition_left),
+            (MovePartitionRight, &self.move_partition_right),
+        ];
+
+        let mut table = HashMap::new();
+        for (action, specs) in bindings {
+            for spec in specs {
+                let key = match Key::parse(spec) {
+                    Some(key) => key,
+                    None => {
+                        log::warn!("invalid key for {:?}: {:?}", action, spec);
+                        continue;
+                    }
+                };
+                // Characters are typed to the terminal unless the prefix key is pressed before
+                if matches!(key, Key::Char(_)) && !action.is_multiplexer_command() {
+                    log::warn!("{:?} can't be bound to a character: {:?}", action, spec);
+                    continue;
+                }
+                if let Some(other) = table.insert(key, action) {
+                    log::warn!("{:?} is bound to both {:?} and {:?}", spec, other, action);
+                }
+            }
+        }
+        table
+    }
+}
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     pub shell: Vec<String>,
+    // environment variables given to the shell ($COLORTERM is not set if it's empty)
+    pub term: String,
+    pub colorterm: String,
+    // extra environment variables in the form of "NAME=VALUE"
+    pub env: Vec<String>,
 
     // paths to font files which FreeType supports (TTF, OTF, etc.)
     pub fonts_regular: Vec<PathBuf>,
     pub fonts_bold: Vec<PathBuf>,
     pub fonts_faint: Vec<PathBuf>,
+    pub fonts_italic: Vec<PathBuf>,
     pub font_size: u32,
 
     #[cfg(feature = "multiplex")]
     pub status_bar_font_size: u32,
+    // show the working directory of tabs in the status bar, truncated to the width
+    #[cfg(feature = "multiplex")]
+    pub status_bar_cwd: bool,
+    #[cfg(feature = "multiplex")]
+    pub status_bar_cwd_width: usize,
+    // keep the output of a pane whose shell has exited until a key is pressed
+    #[cfg(feature = "multiplex")]
+    pub hold_exited_panes: bool,
 
     // RRGGBBAA
     pub color_background: u32,
@@ -34,11 +355,100 @@ pub struct Config {
     pub color_bright_cyan: u32,
     pub color_bright_white: u32,
 
+    // the hue of the "gaming" color (SGR 70/80) goes around in this period (0 stops it at red)
+    pub gaming_color_period_ms: u32,
+
     pub scroll_bar_width: u32,
     pub scroll_bar_fg_color: u32,
     pub scroll_bar_bg_color: u32,
+    // draw the scroll bar over the contents only while scrolled back
+    pub scroll_bar_auto_hide: bool,
+
+    // render blinking text (SGR 5/6) as steady text
+    pub disable_blink: bool,
+
+    // what BEL does: "none", "visual", "audible" or "both"
+    pub bell: Bell,
+    // sound file played for audible bells
+    #[cfg(feature = "audio")]
+    pub bell_sound: PathBuf,
+    // request the attention (urgency hint) on BEL while the window is unfocused
+    pub bell_urgent: bool,
+
+    // blink the cursor unless an application selects a steady one (DECSCUSR)
+    pub cursor_blink: bool,
+    pub cursor_blink_ms: u32,
+    // follow the cursor blinking settings of the desktop (GTK) if any
+    pub cursor_blink_system: bool,
+    // the colors of the text under the block cursor and the cursor itself (RRGGBBAA)
+    pub cursor_fg: u32,
+    pub cursor_bg: u32,
+
+    // embolden the regular font for bold text if no bold font is given
+    pub synthetic_bold: bool,
+
+    // extra pixels between cells
+    pub letter_spacing: u32,
+    // center glyphs horizontally in the cell, and shift them by pixels
+    pub center_glyphs: bool,
+    pub horizontal_glyph_offset: i32,
+
+    // opacity of faint text (SGR 2) over the background
+    pub faint_alpha: f32,
+
+    // render bold text in the 8 base colors with the bright ones
+    pub bold_is_bright: bool,
+
+    // highlight the cell under the mouse pointer and show its details (for debugging)
+    pub debug_hover: bool,
+
+    // copy the decoded output of the PTY to stdout (for debugging)
+    pub debug_tee_output: bool,
+
+    // dim the window after the seconds of inactivity (0 disables it)
+    pub idle_dim_seconds: u32,
+    pub idle_dim_brightness: f32,
+
+    // draw a marker at the end of soft-wrapped lines
+    pub soft_wrap_indicator: bool,
 
     pub east_asian_width_ambiguous: u8,
+
+    // initial size of the (non-multiplexed) window:
+    // $LINES/$COLUMNS, or the size at the last exit
+    pub size_from_env: bool,
+    pub remember_size: bool,
+
+    // "windowed", "maximized" or "fullscreen" (overridden by --maximized/--fullscreen)
+    pub startup_mode: StartupMode,
+
+    // the maximum number of lines kept in the history (0 disables it)
+    pub scrollback_lines: usize,
+
+    // pass Ctrl+S/Ctrl+Q (XOFF/XON) to the PTY, or use them for the local scroll lock
+    pub flow_control_passthrough: bool,
+
+    // write DEL (0x7F) as a space instead of ignoring it
+    pub render_del_as_space: bool,
+
+    // the reply to ENQ (0x05), nothing is sent if it's empty
+    pub answerback: String,
+
+    // make Ctrl+Shift+L clear the screen too (and move the cursor to the home position)
+    pub clear_history_with_screen: bool,
+
+    // regular expression used by the match-and-copy commands
+    pub match_copy_pattern: String,
+
+    // command to open hyperlinks and URLs on Ctrl+Click (the URL is appended as the last argument)
+    pub url_opener: Vec<String>,
+
+    // show desktop notifications requested by OSC 9/777 while the window is unfocused,
+    // with the command (the title and the body are appended after `--` as the last arguments)
+    pub notifications: bool,
+    pub notification_command: Vec<String>,
+
+    pub keybindings: Keybindings,
 }
 
 impl Default for Config {
@@ -47,22 +457,60 @@ impl Default for Config {
 
         Config {
             shell,
+            term: "toyterm-256color".to_owned(),
+            colorterm: "truecolor".to_owned(),
+            // FIXME: an empty Vec cannot be serialized properly (see the fonts below)
+            env: vec![String::new()],
 
             east_asian_width_ambiguous: 1,
 
+            size_from_env: false,
+            remember_size: false,
+            startup_mode: StartupMode::Windowed,
+
             // FIXME: due to a bug on "config-rs", empty Vecs cannot be serialized properly.
             // https://github.com/mehcode/config-rs/issues/114
             fonts_regular: vec![PathBuf::new()],
             fonts_bold: vec![PathBuf::new()],
             fonts_faint: vec![PathBuf::new()],
+            fonts_italic: vec![PathBuf::new()],
             font_size: 32,
 
             #[cfg(feature = "multiplex")]
             status_bar_font_size: 32,
+            #[cfg(feature = "multiplex")]
+            status_bar_cwd: false,
+            #[cfg(feature = "multiplex")]
+            status_bar_cwd_width: 24,
+            #[cfg(feature = "multiplex")]
+            hold_exited_panes: false,
 
             scroll_bar_width: 5,
             scroll_bar_fg_color: 0x606060FF,
             scroll_bar_bg_color: 0x202020FF,
+            scroll_bar_auto_hide: false,
+
+            disable_blink: false,
+            bell: Bell::Visual,
+            #[cfg(feature = "audio")]
+            bell_sound: PathBuf::from("/usr/share/sounds/freedesktop/stereo/bell.oga"),
+            bell_urgent: false,
+            cursor_blink: false,
+            cursor_blink_ms: 500,
+            cursor_blink_system: false,
+            cursor_fg: 0xFFFFFFFF,
+            cursor_bg: 0x505050FF,
+            soft_wrap_indicator: false,
+            idle_dim_seconds: 0,
+            idle_dim_brightness: 0.5,
+            debug_hover: false,
+            debug_tee_output: false,
+            synthetic_bold: false,
+            bold_is_bright: false,
+            faint_alpha: 0.6,
+            letter_spacing: 0,
+            center_glyphs: false,
+            horizontal_glyph_offset: 0,
 
             color_background: 0x000000FF,
             color_foreground: 0xFFFFFFFF,
@@ -84,11 +532,30 @@ impl Default for Config {
             color_bright_magenta: 0xFF50FFFF,
             color_bright_cyan: 0x50FFFFFF,
             color_bright_white: 0xFFFFFFFF,
+            gaming_color_period_ms: 3000,
+
+            scrollback_lines: 10000,
+
+            flow_control_passthrough: true,
+            render_del_as_space: false,
+            answerback: String::new(),
+            clear_history_with_screen: false,
+
+            match_copy_pattern: r"https?://[^\s]+".to_owned(),
+            url_opener: vec!["xdg-open".to_owned()],
+            notifications: false,
+            notification_command: vec!["notify-send".to_owned()],
+
+            keybindings: Keybindings::default(),
         }
     }
 }
 
 pub fn build() -> Config {
+    try_build().expect("Failed to build config")
+}
+
+fn try_build() -> Result<Config, ::config::ConfigError> {
     let mut builder = ::config::Config::builder();
 
     // default config
@@ -101,15 +568,97 @@ pub fn build() -> Config {
         builder = builder.add_source(config::File::from(config_path).required(false));
     }
 
-    builder
-        .build()
-        .unwrap()
-        .try_deserialize()
-        .expect("Failed to build config")
+    builder.build()?.try_deserialize()
 }
 
-fn find_config_file() -> Option<PathBuf> {
-    let mut xdg_config_home = std::env::var_os("XDG_CONFIG_HOME")
+/// The configuration which is reloaded when the config file is modified
+pub struct SharedConfig {
+    current: RwLock<Arc<Config>>,
+    // incremented on every reload
+    generation: AtomicU64,
+    watcher: Mutex<FileWatcher>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+            generation: AtomicU64::new(0),
+            watcher: Mutex::new(FileWatcher::new(find_config_file())),
+        }
+    }
+
+    /// Returns the current configuration
+    pub fn load(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Reloads the config file if it has been modified.
+    /// Errors are reported, and the current configuration is kept then.
+    pub fn reload_if_modified(&self) {
+        if !self.watcher.lock().unwrap().modified() {
+            return;
+        }
+
+        match try_build() {
+            Ok(config) => {
+                log::info!("config reloaded");
+                self.replace(config);
+            }
+            Err(err) => log::error!("Failed to reload config: {}", err),
+        }
+    }
+
+    fn replace(&self, config: Config) {
+        *self.current.write().unwrap() = Arc::new(config);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Detects modifications of a file by its modification time
+struct FileWatcher {
+    path: Option<PathBuf>,
+    mtime: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+impl FileWatcher {
+    // the file is checked at most once in this interval
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(path: Option<PathBuf>) -> Self {
+        let mtime = path.as_deref().and_then(mtime);
+        Self {
+            path,
+            mtime,
+            last_checked: Instant::now(),
+        }
+    }
+
+    /// Returns true if the file has been modified (or created or removed) since the last check
+    fn modified(&mut self) -> bool {
+        if self.last_checked.elapsed() < Self::INTERVAL {
+            return false;
+        }
+        self.last_checked = Instant::now();
+
+        let mtime = self.path.as_deref().and_then(mtime);
+        let modified = mtime != self.mtime;
+        self.mtime = mtime;
+        modified
+    }
+}
+
+fn mtime(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn xdg_config_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
         .map(PathBuf::from)
         .or_else(|| {
             // fallback to "$HOME/.config"
@@ -117,9 +666,226 @@ fn find_config_file() -> Option<PathBuf> {
             let mut p = PathBuf::from(home);
             p.push(".config");
             Some(p)
-        })?;
+        })
+}
+
+fn find_config_file() -> Option<PathBuf> {
+    let mut path = xdg_config_home()?;
+    path.push("toyterm");
+    path.push("config.toml");
+    Some(path)
+}
+
+/// Cursor blinking settings of the desktop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SystemCursorBlink {
+    pub enabled: Option<bool>,
+    /// The length of a whole on/off cycle in milliseconds (as GTK defines)
+    pub cycle_ms: Option<u32>,
+}
 
-    xdg_config_home.push("toyterm");
-    xdg_config_home.push("config.toml");
-    Some(xdg_config_home)
+impl SystemCursorBlink {
+    /// Reads the settings from GSettings (GNOME) and GTK's settings.ini.
+    /// The former takes priority since GNOME doesn't write the latter.
+    pub fn probe() -> Self {
+        let ini = xdg_config_home()
+            .and_then(|dir| std::fs::read_to_string(dir.join("gtk-3.0/settings.ini")).ok())
+            .map_or_else(Self::default, |ini| Self::parse_gtk_settings(&ini));
+
+        let gsettings = |key: &str| -> Option<String> {
+            let output = std::process::Command::new("gsettings")
+                .args(["get", "org.gnome.desktop.interface", key])
+                .stderr(std::process::Stdio::null())
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        };
+
+        Self {
+            enabled: gsettings("cursor-blink")
+                .and_then(|v| v.parse().ok())
+                .or(ini.enabled),
+            cycle_ms: gsettings("cursor-blink-time")
+                .and_then(|v| v.trim_start_matches("uint32 ").parse().ok())
+                .or(ini.cycle_ms),
+        }
+    }
+
+    fn parse_gtk_settings(ini: &str) -> Self {
+        let mut settings = Self::default();
+        for line in ini.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "gtk-cursor-blink" => settings.enabled = value.trim().parse().ok(),
+                "gtk-cursor-blink-time" => settings.cycle_ms = value.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        settings
+    }
+}
+
+impl Config {
+    /// Returns whether the cursor blinks by default, and the duration of each on/off phase
+    pub fn cursor_blink_settings(&self, system: Option<SystemCursorBlink>) -> (bool, u32) {
+        let system = system.unwrap_or_default();
+        let enabled = system.enabled.unwrap_or(self.cursor_blink);
+        let phase_ms = system.cycle_ms.map_or(self.cursor_blink_ms, |ms| ms / 2);
+        (enabled, phase_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gtk_settings() {
+        let ini = "[Settings]\ngtk-theme-name=Adwaita\ngtk-cursor-blink-time = 1000\n";
+        let settings = SystemCursorBlink::parse_gtk_settings(ini);
+        assert_eq!(settings.enabled, None);
+        assert_eq!(settings.cycle_ms, Some(1000));
+
+        let settings = SystemCursorBlink::parse_gtk_settings("gtk-cursor-blink=false\n");
+        assert_eq!(settings.enabled, Some(false));
+        assert_eq!(settings.cycle_ms, None);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let ctrl_shift = ModifiersState::CTRL | ModifiersState::SHIFT;
+        assert_eq!(
+            Key::parse("ctrl+shift+c"),
+            Some(Key::Code(ctrl_shift, VirtualKeyCode::C))
+        );
+        assert_eq!(
+            Key::parse("Ctrl + Minus"),
+            Some(Key::Code(ModifiersState::CTRL, VirtualKeyCode::Minus))
+        );
+        assert_eq!(
+            Key::parse("f11"),
+            Some(Key::Code(ModifiersState::empty(), VirtualKeyCode::F11))
+        );
+        assert_eq!(Key::parse("N"), Some(Key::Char('N')));
+        assert_eq!(Key::parse("%"), Some(Key::Char('%')));
+
+        assert_eq!(Key::parse(""), None);
+        assert_eq!(Key::parse("f13"), None);
+        assert_eq!(Key::parse("f0"), None);
+        assert_eq!(Key::parse("hyper+a"), None);
+        assert_eq!(Key::parse("ctrl+"), None);
+    }
+
+    #[test]
+    fn test_keybindings_table() {
+        let table = Keybindings::default().table();
+        let ctrl_a = Key::Code(ModifiersState::CTRL, VirtualKeyCode::A);
+        assert_eq!(table.get(&ctrl_a), Some(&Action::Prefix));
+        assert_eq!(table.get(&Key::Char('"')), Some(&Action::SplitHorizontal));
+        assert_eq!(table.get(&Key::Char('N')), Some(&Action::NewWindow));
+        assert_eq!(table.get(&Key::Char('n')), Some(&Action::NextTab));
+
+        // invalid keys are skipped
+        let keybindings = Keybindings {
+            copy: vec!["ctrl+insert".to_owned(), "meta+c".to_owned()],
+            ..Default::default()
+        };
+        let table = keybindings.table();
+        let ctrl_insert = Key::Code(ModifiersState::CTRL, VirtualKeyCode::Insert);
+        assert_eq!(table.get(&ctrl_insert), Some(&Action::Copy));
+        assert_eq!(table.values().filter(|&&a| a == Action::Copy).count(), 1);
+
+        // only the multiplexer commands can be bound to characters
+        let keybindings = Keybindings {
+            search: vec!["/".to_owned()],
+            new_tab: vec!["t".to_owned()],
+            ..Default::default()
+        };
+        let table = keybindings.table();
+        assert_eq!(table.get(&Key::Char('/')), None);
+        assert_eq!(table.get(&Key::Char('t')), Some(&Action::NewTab));
+    }
+
+    #[test]
+    fn test_keybindings_config() {
+        let default_source = ::config::Config::try_from(&Config::default()).unwrap();
+        let user = "[keybindings]\ncopy = [\"ctrl+insert\"]\nnext_tab = [\"N\"]\n";
+        let config: Config = ::config::Config::builder()
+            .add_source(default_source)
+            .add_source(::config::File::from_str(user, ::config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        assert_eq!(config.keybindings.copy, ["ctrl+insert"]);
+        assert_eq!(config.keybindings.next_tab, ["N"]);
+        assert_eq!(config.keybindings.paste, ["ctrl+shift+v"]);
+    }
+
+    #[test]
+    fn test_file_watcher() {
+        let path = std::env::temp_dir().join(format!("toyterm-config-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut watcher = FileWatcher::new(Some(path.clone()));
+        let check = |watcher: &mut FileWatcher| {
+            watcher.last_checked -= FileWatcher::INTERVAL;
+            watcher.modified()
+        };
+        assert!(!check(&mut watcher));
+
+        std::fs::write(&path, "font_size = 20\n").unwrap();
+        // not checked within the interval
+        assert!(!watcher.modified());
+        assert!(check(&mut watcher));
+        assert!(!check(&mut watcher));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(check(&mut watcher));
+    }
+
+    #[test]
+    fn test_shared_config() {
+        let shared = SharedConfig::new(Config::default());
+        let old = shared.load();
+        assert_eq!(shared.generation(), 0);
+
+        shared.replace(Config {
+            font_size: 20,
+            ..Default::default()
+        });
+        assert_eq!(shared.generation(), 1);
+        assert_eq!(shared.load().font_size, 20);
+        // the old one is still available to its users
+        assert_eq!(old.font_size, 32);
+    }
+
+    #[test]
+    fn test_cursor_blink_settings() {
+        let config = Config {
+            cursor_blink: true,
+            cursor_blink_ms: 500,
+            ..Default::default()
+        };
+        assert_eq!(config.cursor_blink_settings(None), (true, 500));
+
+        // the probed settings override the config
+        let system = SystemCursorBlink {
+            enabled: None,
+            cycle_ms: Some(1200),
+        };
+        assert_eq!(config.cursor_blink_settings(Some(system)), (true, 600));
+
+        let system = SystemCursorBlink {
+            enabled: Some(false),
+            cycle_ms: None,
+        };
+        assert_eq!(config.cursor_blink_settings(Some(system)), (false, 500));
+    }
 }
@@ -1,7 +1,85 @@
 #![allow(dead_code)]
 
+use crate::iterm;
+use crate::kitty;
 use crate::sixel;
 
+/// Character sets which can be designated to G0 and G1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    DecSpecialGraphics,
+}
+
+/// The color parameter of OSC 4/10/11/12
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpec {
+    // RRGGBBAA
+    Rgb(u32),
+    Query,
+}
+
+impl ColorSpec {
+    /// Parses `?`, `rgb:R/G/B` (1 to 4 hex digits each) or `#RGB` (1 to 4 hex digits each)
+    pub fn parse(spec: &str) -> Option<Self> {
+        if spec == "?" {
+            return Some(ColorSpec::Query);
+        }
+
+        // Scales a component of 1 to 4 hex digits to 8 bits
+        let component = |hex: &str| -> Option<u32> {
+            if hex.is_empty() || hex.len() > 4 {
+                return None;
+            }
+            let value = u32::from_str_radix(hex, 16).ok()?;
+            let max = (1 << (4 * hex.len())) - 1;
+            Some((value * 0xFF + max / 2) / max)
+        };
+
+        let (r, g, b) = if let Some(rgb) = spec.strip_prefix("rgb:") {
+            let mut it = rgb.split('/');
+            let rgb = (it.next()?, it.next()?, it.next()?);
+            if it.next().is_some() {
+                return None;
+            }
+            (component(rgb.0)?, component(rgb.1)?, component(rgb.2)?)
+        } else if let Some(hex) = spec.strip_prefix('#') {
+            // (the high bits are significant in this form)
+            let n = hex.len() / 3;
+            if hex.len() % 3 != 0 || !(1..=4).contains(&n) || !hex.is_ascii() {
+                return None;
+            }
+            let high = |i: usize| -> Option<u32> {
+                let value = u32::from_str_radix(&hex[i * n..(i + 1) * n], 16).ok()?;
+                Some((value << (16 - 4 * n)) >> 8)
+            };
+            (high(0)?, high(1)?, high(2)?)
+        } else {
+            log::debug!("unsupported color: {:?}", spec);
+            return None;
+        };
+
+        Some(ColorSpec::Rgb((r << 24) | (g << 16) | (b << 8) | 0xFF))
+    }
+
+    /// Formats the color of RRGGBBAA in the form of replies (`rgb:RRRR/GGGG/BBBB`)
+    pub fn format(rgba: u32) -> String {
+        let [r, g, b, _] = rgba.to_be_bytes();
+        let c = |x: u8| x as u16 * 0x101;
+        format!("rgb:{:04x}/{:04x}/{:04x}", c(r), c(g), c(b))
+    }
+}
+
+/// Shell integration marks (OSC 133)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptMark {
+    PromptStart,
+    CommandStart,
+    OutputStart,
+    CommandFinished,
+}
+
 #[derive(Debug)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Function<'p> {
@@ -41,6 +119,8 @@ pub enum Function<'p> {
     IS3,
     IS2,
     IS1,
+    // DEL is not a control function, but it's passed to the terminal like C0 ones
+    DEL,
 
     // C1 set
     BPH,
@@ -116,7 +196,8 @@ pub enum Function<'p> {
     HPB,
     VPB,
     RM(u8, &'p [u16]),
-    SGR(&'p [u16]),
+    /// Parameters and whether each of them is a sub-parameter (separated by a colon)
+    SGR(&'p [u16], &'p [bool]),
     DSR(u16),
     DAQ,
 
@@ -163,17 +244,55 @@ pub enum Function<'p> {
     SLS,
     SCP,
 
+    // Independent control functions
+    RIS,
+
     // private
     SixelImage(sixel::Image),
+    KittyGraphics(kitty::Command),
     SelectCursorStyle(u16),
     SaveCursor,
     RestoreCursor,
     SetScrollRegion(u16, u16),
+    SoftReset,
+    FillRectangle(u16, [u16; 4]),
+    EraseRectangle([u16; 4]),
+    CopyRectangle([u16; 4], u16, u16),
+    HighlightMouseTracking([u16; 5]),
+    SetModifyKeys(u16, u16),
+    QueryModifyKeys(u16),
+    QueryVersion,
+    WindowOps(&'p [u16]),
+    DecStatusReport(u16),
+    RequestMode(u8, u16),
+    ScreenAlignmentTest,
+    DesignateCharset(u8, Charset),
+    SingleWidthLine,
+    DoubleWidthLine,
+    DoubleHeightLineTop,
+    DoubleHeightLineBottom,
+    OscSetTitle(String),
+    OscHyperlink {
+        id: Option<String>,
+        uri: String,
+    },
+    OscSetWorkingDirectory(std::path::PathBuf),
+    OscInlineImage(iterm::File),
+    OscSetPalette(Vec<(u8, ColorSpec)>),
+    OscResetPalette(Vec<u8>),
+    OscCursorColor(ColorSpec),
+    OscPromptMark(PromptMark),
+    OscNotify {
+        title: Option<String>,
+        body: String,
+    },
+    OscResetCursorColor,
 }
 
 enum State {
     Normal,
     EscapeSeq,
+    EscapeSeqIntermediate,
     ControlSeq,
 
     ApplicationProgramCommand,
@@ -186,6 +305,7 @@ enum State {
 struct Buffer {
     // for control seqence
     params: Vec<u16>,
+    sub_params: Vec<bool>,
     intermediate: u8,
     private: Option<u8>,
 
@@ -197,6 +317,7 @@ impl Default for Buffer {
     fn default() -> Self {
         let mut buf = Self {
             params: Vec::with_capacity(16),
+            sub_params: Vec::with_capacity(16),
             intermediate: 0,
             private: None,
             string: Vec::with_capacity(0x1000),
@@ -210,6 +331,8 @@ impl Buffer {
     fn clear(&mut self) {
         self.params.clear();
         self.params.push(0); // default value
+        self.sub_params.clear();
+        self.sub_params.push(false);
         self.intermediate = 0;
         self.private = None;
         self.string.clear();
@@ -223,7 +346,7 @@ fn parse_normal<'b>(state: &mut State, ch: char) -> Option<Function<'b>> {
         '\x02' => None,
         '\x03' => None,
         '\x04' => None,
-        '\x05' => None,
+        '\x05' => Some(Function::ENQ),
         '\x06' => None,
         '\x07' => Some(Function::BEL),
         '\x08' => Some(Function::BS),
@@ -249,17 +372,28 @@ fn parse_normal<'b>(state: &mut State, ch: char) -> Option<Function<'b>> {
             *state = State::EscapeSeq;
             None
         }
-        '\x7F' => None,
+        '\x7F' => Some(Function::DEL),
 
         _ => Some(Function::GraphicChar(ch)),
     }
 }
 
-fn parse_escape_sequence<'b>(state: &mut State, ch: char) -> Option<Function<'b>> {
+fn parse_escape_sequence<'b>(
+    state: &mut State,
+    buf: &mut Buffer,
+    ch: char,
+) -> Option<Function<'b>> {
     match ch {
         // Restart
         '\x1B' => None,
 
+        // intermediate bytes
+        '\x20'..='\x2F' => {
+            buf.intermediate = ch as u8;
+            *state = State::EscapeSeqIntermediate;
+            None
+        }
+
         '\x40' => Some(Function::Unsupported),
         '\x41' => Some(Function::Unsupported),
         '\x42' => Some(Function::BPH),
@@ -327,7 +461,11 @@ fn parse_escape_sequence<'b>(state: &mut State, ch: char) -> Option<Function<'b>
         }
 
         // Independent control functions (ECMA-48 5th-edition 5.5)
-        '\x60'..='\x7F' => Some(Function::Unsupported),
+        '\x63' => Some(Function::RIS),
+        '\x60'..='\x7E' => Some(Function::Unsupported),
+
+        // DEL is ignored in sequences
+        '\x7F' => None,
 
         // Private
         '\x37' => Some(Function::SaveCursor),
@@ -337,6 +475,52 @@ fn parse_escape_sequence<'b>(state: &mut State, ch: char) -> Option<Function<'b>
     }
 }
 
+fn parse_escape_sequence_intermediate<'b>(
+    state: &mut State,
+    buf: &mut Buffer,
+    ch: char,
+) -> Option<Function<'b>> {
+    match (buf.intermediate, ch) {
+        // Restart
+        (_, '\x1B') => {
+            buf.clear();
+            *state = State::EscapeSeq;
+            None
+        }
+
+        // DEL is ignored in sequences
+        (_, '\x7F') => None,
+
+        // DECALN - Screen Alignment Pattern
+        (b'#', '8') => Some(Function::ScreenAlignmentTest),
+
+        // SCS - Select Character Set (designate G0 or G1)
+        (b'(', 'B') => Some(Function::DesignateCharset(0, Charset::Ascii)),
+        (b'(', '0') => Some(Function::DesignateCharset(0, Charset::DecSpecialGraphics)),
+        (b')', 'B') => Some(Function::DesignateCharset(1, Charset::Ascii)),
+        (b')', '0') => Some(Function::DesignateCharset(1, Charset::DecSpecialGraphics)),
+
+        // DECDHL - Double-Height Line (top half, bottom half)
+        (b'#', '3') => Some(Function::DoubleHeightLineTop),
+        (b'#', '4') => Some(Function::DoubleHeightLineBottom),
+        // DECSWL - Single-Width Line
+        (b'#', '5') => Some(Function::SingleWidthLine),
+        // DECDWL - Double-Width Line
+        (b'#', '6') => Some(Function::DoubleWidthLine),
+
+        (i, '\x30'..='\x7E') => {
+            log::trace!(
+                "unsupported escape sequence: i=0x{:X}, final=0x{:X}",
+                i,
+                ch as u8
+            );
+            Some(Function::Unsupported)
+        }
+
+        _ => Some(Function::Invalid),
+    }
+}
+
 fn parse_control_sequence<'b>(
     state: &mut State,
     buf: &'b mut Buffer,
@@ -351,6 +535,9 @@ fn parse_control_sequence<'b>(
             None
         }
 
+        // DEL is ignored in sequences
+        '\x7F' => None,
+
         // parameter sub-string
         '0'..='9' => {
             let digit = ch.to_digit(10).unwrap() as u16;
@@ -358,14 +545,17 @@ fn parse_control_sequence<'b>(
             *last_param = last_param.saturating_mul(10).saturating_add(digit);
             None
         }
+        // sub-parameter separator
         ':' => {
-            log::warn!("a separator in a parameter sub-string is not supported");
-            Some(Unsupported)
+            buf.params.push(0);
+            buf.sub_params.push(true);
+            None
         }
 
         // parameter separator
         ';' => {
             buf.params.push(0);
+            buf.sub_params.push(false);
             None
         }
 
@@ -381,6 +571,15 @@ fn parse_control_sequence<'b>(
             None
         }
 
+        // sub-parameters are supported only by SGR
+        '\x40'..='\x7E'
+            if buf.sub_params.contains(&true)
+                && (buf.intermediate, ch, buf.private) != (0, '\x6D', None) =>
+        {
+            log::warn!("a separator in a parameter sub-string is not supported");
+            Some(Unsupported)
+        }
+
         '\x40'..='\x7E' => {
             match (buf.intermediate, ch, buf.params.as_slice()) {
                 // final bytes (w/o intermediate bytes)
@@ -397,8 +596,8 @@ fn parse_control_sequence<'b>(
                 (0, '\x48', &[pn]) => Some(CUP(pn, 1)),
 
                 (0, '\x49', _) => Some(CHT),
-                (0, '\x4A', &[ps @ (0 | 1 | 2)]) => Some(ED(ps)),
-                (0, '\x4B', &[ps @ (0 | 1 | 2)]) => Some(EL(ps)),
+                (0, '\x4A', &[ps @ 0..=3]) => Some(ED(ps)),
+                (0, '\x4B', &[ps @ 0..=2]) => Some(EL(ps)),
                 (0, '\x4C', &[pn]) => Some(IL(pn)),
                 (0, '\x4D', &[pn]) => Some(DL(pn)),
                 (0, '\x4E', _) => Some(EF),
@@ -407,6 +606,11 @@ fn parse_control_sequence<'b>(
                 (0, '\x51', _) => Some(SSE),
                 (0, '\x52', _) => Some(CPR),
                 (0, '\x53', _) => Some(SU),
+
+                // xterm: Initiate highlight mouse tracking (not SD)
+                (0, '\x54', &[func, x, y, first, last]) => {
+                    Some(HighlightMouseTracking([func, x, y, first, last]))
+                }
                 (0, '\x54', _) => Some(SD),
                 (0, '\x55', _) => Some(NP),
                 (0, '\x56', _) => Some(PP),
@@ -441,11 +645,30 @@ fn parse_control_sequence<'b>(
                     let private = buf.private.unwrap_or(0);
                     Some(RM(private, ps))
                 }
-                (0, '\x6D', ps) => Some(SGR(ps)),
-                (0, '\x6E', &[ps @ (5 | 6)]) => Some(DSR(ps)),
+                (0, '\x6D', ps) => match (buf.private, ps) {
+                    // XTMODKEYS - set/reset key modifier options (xterm)
+                    (Some(b'>'), &[pp, pv]) => Some(SetModifyKeys(pp, pv)),
+                    (Some(b'>'), &[pp]) => Some(SetModifyKeys(pp, 0)),
+                    // XTQMODKEYS - query key modifier options (xterm)
+                    (Some(b'?'), &[pp]) => Some(QueryModifyKeys(pp)),
+                    (Some(_), _) => Some(Unsupported),
+                    (None, ps) => Some(SGR(ps, &buf.sub_params)),
+                },
+                (0, '\x6E', &[ps]) => match buf.private {
+                    None => Some(DSR(ps)),
+                    // DECDSR - DEC-specific Device Status Report
+                    Some(b'?') => Some(DecStatusReport(ps)),
+                    Some(_) => Some(Unsupported),
+                },
                 (0, '\x6F', _) => Some(DAQ),
 
                 // private sequences
+                // XTVERSION - report the name and version (xterm)
+                (0, '\x71', &[0]) if buf.private == Some(b'>') => Some(QueryVersion),
+
+                // XTWINOPS - window manipulation (xterm)
+                (0, '\x74', ps) if buf.private.is_none() => Some(WindowOps(ps)),
+
                 (0, '\x72', &[pn1, pn2]) => Some(SetScrollRegion(pn1, pn2)),
                 (0, '\x72', &[pn1]) => Some(SetScrollRegion(pn1, 0)),
 
@@ -519,6 +742,26 @@ fn parse_control_sequence<'b>(
                     Some(Unsupported)
                 }
 
+                // DECSTR - Soft Terminal Reset
+                (b'!', '\x70', _) => Some(SoftReset),
+
+                // DECRQM - Request Mode
+                (b'$', '\x70', &[pd]) => Some(RequestMode(buf.private.unwrap_or(0), pd)),
+
+                // DECCRA - Copy Rectangular Area
+                (b'$', '\x76', params) => {
+                    let src = rectangle_params(params, 0);
+                    Some(CopyRectangle(src, param(params, 5), param(params, 6)))
+                }
+
+                // DECFRA - Fill Rectangular Area
+                (b'$', '\x78', params) => {
+                    Some(FillRectangle(param(params, 0), rectangle_params(params, 1)))
+                }
+
+                // DECERA - Erase Rectangular Area
+                (b'$', '\x7A', params) => Some(EraseRectangle(rectangle_params(params, 0))),
+
                 (i @ b'\x21'..=b'\x2F', '\x40'..='\x7E', params) => {
                     log::trace!(
                         "unsupported control sequence: i=0x{:X}, final=0x{:X}, params={:?}",
@@ -540,6 +783,21 @@ fn parse_control_sequence<'b>(
     }
 }
 
+/// Returns the i-th parameter, or 0 (default) if omitted
+fn param(params: &[u16], i: usize) -> u16 {
+    params.get(i).copied().unwrap_or(0)
+}
+
+/// Returns [top, left, bottom, right] of a rectangular area starting from the i-th parameter
+fn rectangle_params(params: &[u16], i: usize) -> [u16; 4] {
+    [
+        param(params, i),
+        param(params, i + 1),
+        param(params, i + 2),
+        param(params, i + 3),
+    ]
+}
+
 fn parse_control_string<'b>(
     state: &mut State,
     buf: &'b mut Buffer,
@@ -547,22 +805,46 @@ fn parse_control_string<'b>(
     ch: char,
 ) -> Option<Function<'b>> {
     // ST - STRING TERMINATOR
-    if let (Some('\x1B'), '\x5C') = (buf.string.last(), ch) {
-        buf.string.pop();
+    // (OSC can also be terminated by BEL as xterm does)
+    let terminated = match (buf.string.last(), ch) {
+        (Some('\x1B'), '\x5C') => {
+            buf.string.pop();
+            true
+        }
+        (_, '\x07') => matches!(state, State::OperatingSystemCommand),
+        _ => false,
+    };
 
+    if terminated {
         match state {
             State::ApplicationProgramCommand => {
                 log::trace!("application program command: {:?}", buf.string);
-                Some(Function::Unsupported)
+                match buf.string.split_first() {
+                    // Kitty graphics protocol (G <control data> ; <payload>)
+                    Some(('G', rest)) => {
+                        let string: String = rest.iter().collect();
+                        match kitty::Command::parse(&string) {
+                            Some(cmd) => Some(Function::KittyGraphics(cmd)),
+                            None => Some(Function::Invalid),
+                        }
+                    }
+                    _ => Some(Function::Unsupported),
+                }
             }
 
             State::DeviceControlString => {
                 log::trace!("device control string: {:?}", buf.string);
-                match buf.string.get(0) {
+                let params_len = (buf.string.iter())
+                    .take_while(|&&ch| ch.is_ascii_digit() || ch == ';')
+                    .count();
+                match buf.string.get(params_len) {
                     Some('q') => {
-                        // Sixel Sequence
-                        let mut chars = buf.string[1..].iter().copied();
-                        let image = sixel_parser.decode(&mut chars);
+                        // Sixel Sequence (P1;P2;P3 q)
+                        let params: String = buf.string[..params_len].iter().collect();
+                        // P2 = 1 leaves unpainted pixels transparent
+                        let transparent = params.split(';').nth(1) == Some("1");
+                        let mut chars = buf.string[params_len + 1..].iter().copied();
+                        let image = sixel_parser.decode(&mut chars, transparent);
                         Some(Function::SixelImage(image))
                     }
                     _ => Some(Function::Unsupported),
@@ -571,7 +853,8 @@ fn parse_control_string<'b>(
 
             State::OperatingSystemCommand => {
                 log::trace!("operating system command: {:?}", buf.string);
-                Some(Function::Unsupported)
+                let string: String = buf.string.iter().collect();
+                Some(parse_operating_system_command(&string))
             }
 
             State::PrivacyMessage => {
@@ -584,11 +867,162 @@ fn parse_control_string<'b>(
     } else if let '\x08'..='\x0D' | '\x1B' | '\x20'..='\x7E' = ch {
         buf.string.push(ch);
         None
+    } else if let (State::OperatingSystemCommand, '\u{A0}'..) = (&*state, ch) {
+        // OSC may contain non-ASCII text (e.g. window title)
+        buf.string.push(ch);
+        None
     } else {
         Some(Function::Invalid)
     }
 }
 
+fn parse_operating_system_command<'b>(string: &str) -> Function<'b> {
+    let (ps, pt) = string.split_once(';').unwrap_or((string, ""));
+    match ps {
+        // Change icon name and window title
+        "0" | "1" | "2" => Function::OscSetTitle(pt.to_owned()),
+
+        // Hyperlink: OSC 8 ; params ; URI ST
+        // (https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda)
+        "8" => match pt.split_once(';') {
+            Some((params, uri)) => {
+                let id = params
+                    .split(':')
+                    .find_map(|kv| kv.strip_prefix("id="))
+                    .map(str::to_owned);
+                Function::OscHyperlink {
+                    id,
+                    uri: uri.to_owned(),
+                }
+            }
+            None => Function::Invalid,
+        },
+
+        // Current working directory: OSC 7 ; file://host/path ST
+        "7" => match pt
+            .strip_prefix("file://")
+            .and_then(|s| s.find('/').map(|i| &s[i..]))
+        {
+            Some(path) => {
+                use std::os::unix::ffi::OsStringExt as _;
+                let path = std::ffi::OsString::from_vec(percent_decode(path));
+                Function::OscSetWorkingDirectory(path.into())
+            }
+            None => Function::Invalid,
+        },
+
+        // Palette: OSC 4 ; index ; color ; index ; color ... ST
+        "4" => {
+            let args: Vec<&str> = pt.split(';').collect();
+            let entries: Option<Vec<_>> = args
+                .chunks(2)
+                .map(|pair| match pair {
+                    [idx, spec] => Some((idx.parse().ok()?, ColorSpec::parse(spec)?)),
+                    _ => None,
+                })
+                .collect();
+            match entries {
+                Some(entries) if !entries.is_empty() => Function::OscSetPalette(entries),
+                _ => Function::Invalid,
+            }
+        }
+        // OSC 104 ; index ; index ... ST (all entries if no index is given)
+        "104" => {
+            let indices: Option<Vec<u8>> = pt
+                .split(';')
+                .filter(|idx| !idx.is_empty())
+                .map(|idx| idx.parse().ok())
+                .collect();
+            match indices {
+                Some(indices) => Function::OscResetPalette(indices),
+                None => Function::Invalid,
+            }
+        }
+
+        // Cursor color: OSC 12 ; color ST
+        "12" => match ColorSpec::parse(pt.split(';').next().unwrap_or_default()) {
+            Some(spec) => Function::OscCursorColor(spec),
+            None => Function::Invalid,
+        },
+        "112" => Function::OscResetCursorColor,
+
+        // Desktop notification: OSC 9 ; body ST (iTerm2)
+        // (`OSC 9 ; Ps ; ...` of ConEmu are not notifications)
+        "9" => match pt.split_once(';') {
+            Some((ps, _)) if !ps.is_empty() && ps.bytes().all(|b| b.is_ascii_digit()) => {
+                log::trace!("unsupported ConEmu command: {:?}", pt);
+                Function::Unsupported
+            }
+            _ => Function::OscNotify {
+                title: None,
+                body: pt.to_owned(),
+            },
+        },
+        // Desktop notification: OSC 777 ; notify ; title ; body ST (urxvt)
+        "777" => match pt.strip_prefix("notify;").map(|s| s.split_once(';')) {
+            Some(Some((title, body))) => Function::OscNotify {
+                title: Some(title.to_owned()),
+                body: body.to_owned(),
+            },
+            Some(None) => Function::Invalid,
+            None => {
+                log::trace!("unsupported OSC 777: {:?}", pt);
+                Function::Unsupported
+            }
+        },
+
+        // Shell integration: OSC 133 ; A/B/C/D [; options] ST
+        // (https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md)
+        "133" => match pt.split(';').next() {
+            Some("A") => Function::OscPromptMark(PromptMark::PromptStart),
+            Some("B") => Function::OscPromptMark(PromptMark::CommandStart),
+            Some("C") => Function::OscPromptMark(PromptMark::OutputStart),
+            Some("D") => Function::OscPromptMark(PromptMark::CommandFinished),
+            _ => Function::Invalid,
+        },
+
+        // iTerm2 inline image: OSC 1337 ; File = args : data ST
+        "1337" => match pt.strip_prefix("File=") {
+            Some(file) => match iterm::File::parse(file) {
+                Some(file) => Function::OscInlineImage(file),
+                None => Function::Invalid,
+            },
+            None => {
+                log::trace!("unsupported iTerm2 command: {:?}", pt);
+                Function::Unsupported
+            }
+        },
+
+        _ => {
+            log::trace!("unsupported OSC: ps={:?}, pt={:?}", ps, pt);
+            Function::Unsupported
+        }
+    }
+}
+
+/// Decodes `%XX` escapes in a URI
+fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
 fn parse_character_string<'b>(
     _: &mut State,
     buf: &'b mut Buffer,
@@ -612,13 +1046,21 @@ pub struct Parser {
 }
 
 impl Parser {
+    /// Resets the sixel color registers, which persist across images
+    pub fn reset_sixel_colors(&mut self) {
+        self.sixel_parser.reset_colors();
+    }
+
     pub fn feed(&mut self, ch: char) -> Option<Function> {
         let func = match self.state {
             State::Normal => {
                 self.buf.clear();
                 parse_normal(&mut self.state, ch)
             }
-            State::EscapeSeq => parse_escape_sequence(&mut self.state, ch),
+            State::EscapeSeq => parse_escape_sequence(&mut self.state, &mut self.buf, ch),
+            State::EscapeSeqIntermediate => {
+                parse_escape_sequence_intermediate(&mut self.state, &mut self.buf, ch)
+            }
             State::ControlSeq => parse_control_sequence(&mut self.state, &mut self.buf, ch),
 
             State::ApplicationProgramCommand
@@ -647,3 +1089,250 @@ impl Default for Parser {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sgr_sub_params() {
+        let mut parser = Parser::default();
+        let mut feed = |s: &str| {
+            let mut sgr = None;
+            for ch in s.chars() {
+                if let Some(Function::SGR(ps, subs)) = parser.feed(ch) {
+                    sgr = Some((ps.to_vec(), subs.to_vec()));
+                }
+            }
+            sgr
+        };
+
+        assert_eq!(
+            feed("\x1b[1;4:3;31m"),
+            Some((vec![1, 4, 3, 31], vec![false, false, true, false]))
+        );
+        assert_eq!(feed("\x1b[m"), Some((vec![0], vec![false])));
+
+        // other functions don't accept sub-parameters
+        let mut parser = Parser::default();
+        let unsupported = "\x1b[1:2H"
+            .chars()
+            .filter_map(|ch| parser.feed(ch).map(|f| matches!(f, Function::Unsupported)))
+            .collect::<Vec<_>>();
+        assert_eq!(unsupported, vec![true]);
+    }
+
+    #[test]
+    fn test_highlight_mouse_tracking() {
+        let mut parser = Parser::default();
+        let mut feed = |s: &str| {
+            let mut funcs = Vec::new();
+            for ch in s.chars() {
+                match parser.feed(ch) {
+                    Some(Function::HighlightMouseTracking(ps)) => funcs.push(Some(ps)),
+                    Some(Function::SD) => funcs.push(None),
+                    _ => {}
+                }
+            }
+            funcs
+        };
+
+        assert_eq!(feed("\x1b[1;2;3;4;5T"), vec![Some([1, 2, 3, 4, 5])]);
+        assert_eq!(feed("\x1b[T\x1b[3T\x1b[1;2;3;4T"), vec![None, None, None]);
+    }
+
+    #[test]
+    fn test_request_mode() {
+        let mut parser = Parser::default();
+        let mut feed = |s: &str| {
+            let mut funcs = Vec::new();
+            for ch in s.chars() {
+                match parser.feed(ch) {
+                    Some(Function::RequestMode(private, pd)) => funcs.push(Some((private, pd))),
+                    Some(Function::SoftReset) => funcs.push(None),
+                    _ => {}
+                }
+            }
+            funcs
+        };
+
+        assert_eq!(feed("\x1b[?2004$p"), vec![Some((b'?', 2004))]);
+        assert_eq!(feed("\x1b[4$p"), vec![Some((0, 4))]);
+        assert_eq!(feed("\x1b[!p\x1b[?1;2$p"), vec![None]);
+    }
+
+    #[test]
+    fn test_sixel_background_select() {
+        let mut parser = Parser::default();
+        let mut feed = |s: &str| {
+            let mut opaque = None;
+            for ch in s.chars() {
+                if let Some(Function::SixelImage(image)) = parser.feed(ch) {
+                    opaque = Some(image.is_opaque());
+                }
+            }
+            opaque
+        };
+
+        assert_eq!(feed("\x1bPq#0;2;0;0;100?~\x1b\\"), Some(true));
+        assert_eq!(feed("\x1bP0;0;0q#0;2;0;0;100?~\x1b\\"), Some(true));
+        assert_eq!(feed("\x1bP0;1;0q#0;2;0;0;100?~\x1b\\"), Some(false));
+        assert_eq!(feed("\x1bP;1q#0;2;0;0;100?~\x1b\\"), Some(false));
+    }
+
+    #[test]
+    fn test_kitty_graphics() {
+        let mut parser = Parser::default();
+        let mut feed = |s: &str| {
+            let mut cmd = None;
+            for ch in s.chars() {
+                match parser.feed(ch) {
+                    Some(Function::KittyGraphics(c)) => cmd = Some(Some(c)),
+                    Some(_) => cmd = Some(None),
+                    None => {}
+                }
+            }
+            cmd
+        };
+
+        let cmd = feed("\x1b_Ga=T,i=2;AAAA\x1b\\").unwrap().unwrap();
+        assert_eq!((cmd.action, cmd.id), ('T', 2));
+        assert_eq!(cmd.payload, "AAAA");
+
+        // other APCs are ignored
+        assert_eq!(feed("\x1b_Xa=T\x1b\\"), Some(None));
+        assert_eq!(feed("\x1b_Ga\x1b\\"), Some(None));
+    }
+
+    #[test]
+    fn test_osc_hyperlink() {
+        let link = |s| match parse_operating_system_command(s) {
+            Function::OscHyperlink { id, uri } => Some((id, uri)),
+            _ => None,
+        };
+
+        assert_eq!(link("8;;http://a/"), Some((None, "http://a/".to_owned())));
+        assert_eq!(
+            link("8;x=y:id=1;http://a/?b;c"),
+            Some((Some("1".to_owned()), "http://a/?b;c".to_owned()))
+        );
+
+        // closing the link
+        assert_eq!(link("8;;"), Some((None, String::new())));
+        assert_eq!(link("8"), None);
+    }
+
+    #[test]
+    fn test_osc_working_directory() {
+        let cwd = |s| match parse_operating_system_command(s) {
+            Function::OscSetWorkingDirectory(path) => Some(path),
+            _ => None,
+        };
+
+        assert_eq!(cwd("7;file://host/home/user"), Some("/home/user".into()));
+        assert_eq!(cwd("7;file:///tmp"), Some("/tmp".into()));
+        assert_eq!(cwd("7;file://host/a%20b/%E3%81%82"), Some("/a b/あ".into()));
+        assert_eq!(cwd("7;file://host/100%"), Some("/100%".into()));
+        assert_eq!(cwd("7;/home/user"), None);
+        assert_eq!(cwd("7;file://host"), None);
+    }
+
+    #[test]
+    fn test_color_spec() {
+        let rgb = |s| match ColorSpec::parse(s) {
+            Some(ColorSpec::Rgb(rgba)) => Some(rgba),
+            _ => None,
+        };
+
+        assert_eq!(rgb("rgb:ff/80/00"), Some(0xFF8000FF));
+        assert_eq!(rgb("rgb:f/8/0"), Some(0xFF8800FF));
+        assert_eq!(rgb("rgb:ffff/8080/0000"), Some(0xFF8000FF));
+        assert_eq!(rgb("#ff8000"), Some(0xFF8000FF));
+        assert_eq!(rgb("#f80"), Some(0xF08000FF));
+        assert_eq!(rgb("#ffff80800000"), Some(0xFF8000FF));
+        assert_eq!(ColorSpec::parse("?"), Some(ColorSpec::Query));
+
+        assert_eq!(rgb("rgb:ff/80"), None);
+        assert_eq!(rgb("rgb:ff/80/00/00"), None);
+        assert_eq!(rgb("rgb:fffff/0/0"), None);
+        assert_eq!(rgb("#ff80"), None);
+        assert_eq!(rgb("red"), None);
+
+        assert_eq!(ColorSpec::format(0xFF8000FF), "rgb:ffff/8080/0000");
+    }
+
+    #[test]
+    fn test_osc_cursor_color() {
+        let osc = parse_operating_system_command;
+        assert!(matches!(
+            osc("12;#102030"),
+            Function::OscCursorColor(ColorSpec::Rgb(0x102030FF))
+        ));
+        assert!(matches!(
+            osc("12;?"),
+            Function::OscCursorColor(ColorSpec::Query)
+        ));
+        assert!(matches!(osc("12;"), Function::Invalid));
+        assert!(matches!(osc("112"), Function::OscResetCursorColor));
+    }
+
+    #[test]
+    fn test_osc_palette() {
+        let set = |s| match parse_operating_system_command(s) {
+            Function::OscSetPalette(entries) => Some(entries),
+            _ => None,
+        };
+        assert_eq!(
+            set("4;1;#ff0000;255;?"),
+            Some(vec![
+                (1, ColorSpec::Rgb(0xFF0000FF)),
+                (255, ColorSpec::Query)
+            ])
+        );
+        assert_eq!(set("4;1"), None);
+        assert_eq!(set("4;256;#ff0000"), None);
+        assert_eq!(set("4;"), None);
+
+        let reset = |s| match parse_operating_system_command(s) {
+            Function::OscResetPalette(indices) => Some(indices),
+            _ => None,
+        };
+        assert_eq!(reset("104"), Some(vec![]));
+        assert_eq!(reset("104;1;200"), Some(vec![1, 200]));
+        assert_eq!(reset("104;x"), None);
+    }
+
+    #[test]
+    fn test_osc_prompt_mark() {
+        let mark = |s| match parse_operating_system_command(s) {
+            Function::OscPromptMark(mark) => Some(mark),
+            _ => None,
+        };
+        assert_eq!(mark("133;A"), Some(PromptMark::PromptStart));
+        assert_eq!(mark("133;B"), Some(PromptMark::CommandStart));
+        assert_eq!(mark("133;C"), Some(PromptMark::OutputStart));
+        assert_eq!(mark("133;D;0"), Some(PromptMark::CommandFinished));
+        assert_eq!(mark("133;A;aid=1"), Some(PromptMark::PromptStart));
+        assert_eq!(mark("133;E"), None);
+        assert_eq!(mark("133"), None);
+    }
+
+    #[test]
+    fn test_osc_notify() {
+        let notify = |s| match parse_operating_system_command(s) {
+            Function::OscNotify { title, body } => Some((title, body)),
+            _ => None,
+        };
+        assert_eq!(
+            notify("9;done; 0 errors"),
+            Some((None, "done; 0 errors".to_owned()))
+        );
+        assert_eq!(notify("9;4;1;50"), None);
+        assert_eq!(
+            notify("777;notify;make;done;ok"),
+            Some((Some("make".to_owned()), "done;ok".to_owned()))
+        );
+        assert_eq!(notify("777;notify;make"), None);
+        assert_eq!(notify("777;preexec"), None);
+    }
+}
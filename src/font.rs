@@ -63,11 +63,17 @@ pub enum FontStyle {
     Regular,
     Bold,
     Faint,
+    Italic,
 }
 
 impl FontStyle {
-    pub const fn all() -> [FontStyle; 3] {
-        [FontStyle::Regular, FontStyle::Bold, FontStyle::Faint]
+    pub const fn all() -> [FontStyle; 4] {
+        [
+            FontStyle::Regular,
+            FontStyle::Bold,
+            FontStyle::Faint,
+            FontStyle::Italic,
+        ]
     }
 }
 
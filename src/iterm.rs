@@ -0,0 +1,187 @@
+// iTerm2 inline image protocol
+// Reference: https://iterm2.com/documentation-images.html
+//
+// Images are transmitted by `OSC 1337 ; File = <arguments> : <base64 data> ST`.
+// Only PNG images can be decoded, and files which are not inline (downloads) are ignored.
+
+pub use crate::png::Image;
+use crate::utils::base64;
+
+/// The width or height of an image on the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Auto,
+    Cells(u64),
+    Pixels(u64),
+    Percent(u64),
+}
+
+impl Dimension {
+    fn parse(value: &str) -> Option<Self> {
+        if value == "auto" {
+            Some(Dimension::Auto)
+        } else if let Some(px) = value.strip_suffix("px") {
+            px.parse().ok().map(Dimension::Pixels)
+        } else if let Some(pct) = value.strip_suffix('%') {
+            pct.parse().ok().map(Dimension::Percent)
+        } else {
+            value.parse().ok().map(Dimension::Cells)
+        }
+    }
+
+    /// Returns the length in pixels (no longer than the screen), or None for `auto`
+    fn to_pixels(self, cell: u64, screen: u64) -> Option<u64> {
+        let len = match self {
+            Dimension::Auto => return None,
+            Dimension::Cells(n) => n.saturating_mul(cell),
+            Dimension::Pixels(n) => n,
+            Dimension::Percent(n) => screen.saturating_mul(n) / 100,
+        };
+        Some(len.min(screen))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    // the file name (base64 encoded)
+    pub name: Option<String>,
+    // the file size in bytes
+    pub size: Option<usize>,
+    pub width: Dimension,
+    pub height: Dimension,
+    // 0 stretches the image to fill the width and height
+    pub preserve_aspect_ratio: bool,
+    // 1 displays the file, otherwise it's downloaded
+    pub inline: bool,
+    // base64 encoded data
+    pub payload: String,
+}
+
+impl File {
+    /// Parses `<arguments> : <base64 data>` (following "File=")
+    pub fn parse(string: &str) -> Option<Self> {
+        let (args, payload) = string.split_once(':')?;
+
+        let mut file = File {
+            name: None,
+            size: None,
+            width: Dimension::Auto,
+            height: Dimension::Auto,
+            preserve_aspect_ratio: true,
+            inline: false,
+            payload: payload.to_owned(),
+        };
+
+        for kv in args.split(';').filter(|kv| !kv.is_empty()) {
+            let (key, value) = kv.split_once('=')?;
+            match key {
+                "name" => file.name = Some(value.to_owned()),
+                "size" => file.size = Some(value.parse().ok()?),
+                "width" => file.width = Dimension::parse(value)?,
+                "height" => file.height = Dimension::parse(value)?,
+                "preserveAspectRatio" => file.preserve_aspect_ratio = value != "0",
+                "inline" => file.inline = value == "1",
+                _ => log::debug!("inline image: ignored key: {key:?}"),
+            }
+        }
+
+        Some(file)
+    }
+
+    /// Decodes the image data
+    pub fn decode(&self) -> Result<Image, &'static str> {
+        let data = base64::decode(&self.payload).ok_or("invalid base64 data")?;
+        if self.size.map_or(false, |size| size != data.len()) {
+            log::debug!("inline image: the size doesn't match: {:?}", self.size);
+        }
+        crate::png::decode(&data)
+    }
+
+    /// Returns the size of the image on the screen in pixels, which fits in the screen
+    pub fn display_size(&self, image: &Image, cell: (u64, u64), screen: (u64, u64)) -> (u64, u64) {
+        let (img_w, img_h) = (image.width.max(1) as u64, image.height.max(1) as u64);
+        let width = self.width.to_pixels(cell.0, screen.0);
+        let height = self.height.to_pixels(cell.1, screen.1);
+
+        let (w, h) = match (width, height) {
+            (None, None) => (img_w, img_h),
+            (Some(w), None) => (w, img_h * w / img_w),
+            (None, Some(h)) => (img_w * h / img_h, h),
+            (Some(w), Some(h)) if self.preserve_aspect_ratio => fit((img_w, img_h), (w, h)),
+            (Some(w), Some(h)) => (w, h),
+        };
+        let (w, h) = if self.preserve_aspect_ratio && (w > screen.0 || h > screen.1) {
+            fit((w, h), screen)
+        } else {
+            (w.min(screen.0), h.min(screen.1))
+        };
+        (w.max(1), h.max(1))
+    }
+}
+
+/// Scales the size to fit in the box, preserving the aspect ratio
+fn fit((w, h): (u64, u64), (box_w, box_h): (u64, u64)) -> (u64, u64) {
+    if w * box_h > box_w * h {
+        (box_w, h * box_w / w)
+    } else {
+        (w * box_h / h.max(1), box_h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file() {
+        let file = File::parse("name=YS5wbmc=;size=4;width=50%;height=3px;inline=1:AAAA").unwrap();
+        assert_eq!(file.name.as_deref(), Some("YS5wbmc="));
+        assert_eq!(file.size, Some(4));
+        assert_eq!(file.width, Dimension::Percent(50));
+        assert_eq!(file.height, Dimension::Pixels(3));
+        assert!(file.preserve_aspect_ratio);
+        assert!(file.inline);
+        assert_eq!(file.payload, "AAAA");
+
+        let file = File::parse("width=2;height=auto;preserveAspectRatio=0:").unwrap();
+        assert_eq!(
+            (file.width, file.height),
+            (Dimension::Cells(2), Dimension::Auto)
+        );
+        assert!(!file.preserve_aspect_ratio);
+        assert!(!file.inline);
+
+        assert_eq!(File::parse("inline=1"), None);
+        assert_eq!(File::parse("width=x:AAAA"), None);
+    }
+
+    #[test]
+    fn test_display_size() {
+        // 40x20 image, 10x20 cells, 800x600 screen
+        let image = Image {
+            width: 40,
+            height: 20,
+            data: Vec::new(),
+        };
+        let size = |args: &str| {
+            let file = File::parse(&format!("{args}:")).unwrap();
+            file.display_size(&image, (10, 20), (800, 600))
+        };
+
+        assert_eq!(size(""), (40, 20));
+        assert_eq!(size("width=8"), (80, 40));
+        assert_eq!(size("height=50%"), (600, 300));
+        assert_eq!(size("width=100px;height=100px"), (100, 50));
+        assert_eq!(size("width=10px;height=100px"), (10, 5));
+        assert_eq!(
+            size("width=100px;height=100px;preserveAspectRatio=0"),
+            (100, 100)
+        );
+
+        // no larger than the screen
+        assert_eq!(size("width=1000"), (800, 400));
+        assert_eq!(size("width=18446744073709551615"), (800, 400));
+        assert_eq!(size("height=1000%;width=auto"), (800, 400));
+        assert_eq!(size("width=2000px;preserveAspectRatio=0"), (800, 400));
+    }
+}
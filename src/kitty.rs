@@ -0,0 +1,527 @@
+// Kitty graphics protocol
+// Reference: https://sw.kovidgoyal.net/kitty/graphics-protocol/
+//
+// Images are transmitted by `APC G <control data> ; <payload> ST`.
+// Direct (t=d), file (t=f) and temporary file (t=t) transmissions of RGB (f=24), RGBA (f=32)
+// and PNG (f=100) are supported. Shared memory objects and animations are not.
+
+use std::fmt;
+use std::io::Read as _;
+use std::path::Path;
+
+pub use crate::png::Image;
+use crate::png::MAX_PIXELS;
+use crate::utils::base64;
+
+// The total size of pixel data which can be stored
+const QUOTA: usize = 256 << 20;
+
+// The maximum length of a payload (including chunks)
+const MAX_PAYLOAD: usize = 128 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    // a: t (transmit), T (transmit and display), p (display), d (delete) or q (query)
+    pub action: char,
+    // f: 24 (RGB), 32 (RGBA) or 100 (PNG)
+    pub format: u32,
+    // t: d (direct), f (file), t (temporary file) or s (shared memory)
+    pub medium: char,
+    // o=z: the data is compressed with zlib
+    pub compressed: bool,
+    // s, v: the size of the image in pixels
+    pub width: u32,
+    pub height: u32,
+    // i: the image id (0 if omitted)
+    pub id: u32,
+    // m=1: more chunks follow
+    pub more: bool,
+    // q: 1 suppresses OK responses, 2 suppresses errors as well
+    pub quiet: u32,
+    // c, r: the number of cells to be occupied with the image (0 if omitted)
+    pub cols: u32,
+    pub rows: u32,
+    // C=1: the cursor doesn't move after the image is displayed
+    pub fixed_cursor: bool,
+    // d: what is to be deleted
+    pub delete: char,
+    // base64 encoded data
+    pub payload: String,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Self {
+            action: 't',
+            format: 32,
+            medium: 'd',
+            compressed: false,
+            width: 0,
+            height: 0,
+            id: 0,
+            more: false,
+            quiet: 0,
+            cols: 0,
+            rows: 0,
+            fixed_cursor: false,
+            delete: 'a',
+            payload: String::new(),
+        }
+    }
+}
+
+impl Command {
+    /// Parses `<control data> ; <payload>` (following 'G')
+    pub fn parse(string: &str) -> Option<Self> {
+        let (control, payload) = string.split_once(';').unwrap_or((string, ""));
+
+        let mut cmd = Command {
+            payload: payload.to_owned(),
+            ..Command::default()
+        };
+
+        for kv in control.split(',').filter(|kv| !kv.is_empty()) {
+            let (key, value) = kv.split_once('=')?;
+            let ch = || {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => Some(ch),
+                    _ => None,
+                }
+            };
+            let num = || value.parse::<u32>().ok();
+
+            match key {
+                "a" => cmd.action = ch()?,
+                "f" => cmd.format = num()?,
+                "t" => cmd.medium = ch()?,
+                "o" => cmd.compressed = ch()? == 'z',
+                "s" => cmd.width = num()?,
+                "v" => cmd.height = num()?,
+                "i" => cmd.id = num()?,
+                "m" => cmd.more = num()? == 1,
+                "q" => cmd.quiet = num()?,
+                "c" => cmd.cols = num()?,
+                "r" => cmd.rows = num()?,
+                "C" => cmd.fixed_cursor = num()? == 1,
+                "d" => cmd.delete = ch()?,
+                _ => log::debug!("kitty graphics: ignored key: {key:?}"),
+            }
+        }
+
+        Some(cmd)
+    }
+
+    /// Returns the response to this command, unless suppressed by `q`
+    pub fn response(&self, result: &Result<(), Error>) -> Option<String> {
+        // Commands without ids are not responded
+        if self.id == 0 {
+            return None;
+        }
+        let msg = match result {
+            Ok(()) if self.quiet == 0 => "OK".to_owned(),
+            Err(err) if self.quiet < 2 => err.to_string(),
+            _ => return None,
+        };
+        Some(format!("\x1b_Gi={};{}\x1b\\", self.id, msg))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    code: &'static str,
+    msg: &'static str,
+}
+
+impl Error {
+    pub fn new(code: &'static str, msg: &'static str) -> Self {
+        Self { code, msg }
+    }
+
+    fn invalid(msg: &'static str) -> Self {
+        Self::new("EINVAL", msg)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.code, self.msg)
+    }
+}
+
+/// Returns true if the file can be deleted after reading it (t=t).
+/// The path is resolved first, so that a symlink in the temporary directory doesn't count.
+fn is_temporary_file(path: &Path) -> bool {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let temp_dir = std::env::temp_dir();
+    let temp_dir = temp_dir.canonicalize().unwrap_or(temp_dir);
+
+    let in_temp_dir =
+        path.starts_with(temp_dir) || path.starts_with("/tmp") || path.starts_with("/dev/shm");
+    let name = path.file_name().and_then(|name| name.to_str());
+    in_temp_dir && name.map_or(false, |name| name.contains("tty-graphics-protocol"))
+}
+
+/// Reads a regular file transmitted by its path (t=f or t=t).
+/// Device files and the files under /proc, /sys and /dev (except /dev/shm) are refused.
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let read_error = |_| Error::new("EBADF", "failed to read the file");
+
+    let path = path.canonicalize().map_err(read_error)?;
+    let special = ["/proc", "/sys", "/dev"]
+        .iter()
+        .any(|dir| path.starts_with(dir));
+    if special && !path.starts_with("/dev/shm") {
+        return Err(Error::new("EPERM", "not allowed to read the file"));
+    }
+
+    // Opening a FIFO blocks until a writer appears, so the file type is checked beforehand,
+    // and again after opening it without blocking (in case the file is replaced meanwhile)
+    if !std::fs::metadata(&path).map_err(read_error)?.is_file() {
+        return Err(Error::invalid("not a regular file"));
+    }
+    use std::os::unix::fs::OpenOptionsExt as _;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_NONBLOCK)
+        .open(&path)
+        .map_err(read_error)?;
+    if !file.metadata().map_err(read_error)?.is_file() {
+        return Err(Error::invalid("not a regular file"));
+    }
+
+    let mut bytes = Vec::new();
+    file.take(MAX_PAYLOAD as u64 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(read_error)?;
+    if bytes.len() > MAX_PAYLOAD {
+        return Err(Error::invalid("too large file"));
+    }
+    Ok(bytes)
+}
+
+/// Decodes the image transmitted by the command
+pub fn load(cmd: &Command) -> Result<Image, Error> {
+    let data = base64::decode(&cmd.payload).ok_or(Error::invalid("invalid base64 data"))?;
+
+    let data = match cmd.medium {
+        'd' => data,
+        'f' | 't' => {
+            let path = String::from_utf8(data).map_err(|_| Error::invalid("invalid file path"))?;
+            let path = Path::new(&path);
+            let bytes = read_file(path)?;
+            if cmd.medium == 't' && is_temporary_file(path) {
+                let _ = std::fs::remove_file(path);
+            }
+            bytes
+        }
+        _ => return Err(Error::invalid("unsupported transmission medium")),
+    };
+
+    let data = if cmd.compressed {
+        miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&data, MAX_PIXELS as usize * 4)
+            .map_err(|_| Error::invalid("failed to decompress the data"))?
+    } else {
+        data
+    };
+
+    match cmd.format {
+        24 | 32 => {
+            let (width, height) = (cmd.width, cmd.height);
+            if width == 0 || height == 0 {
+                return Err(Error::invalid("the size of the image is not specified"));
+            }
+            if width as u64 * height as u64 > MAX_PIXELS {
+                return Err(Error::invalid("too large image"));
+            }
+
+            let channels = cmd.format as usize / 8;
+            let len = width as usize * height as usize * channels;
+            if data.len() < len {
+                return Err(Error::new("ENODATA", "insufficient image data"));
+            }
+
+            let data = if channels == 3 {
+                (data[..len].chunks_exact(3))
+                    .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xFF])
+                    .collect()
+            } else {
+                data[..len].to_vec()
+            };
+            Ok(Image {
+                width,
+                height,
+                data,
+            })
+        }
+        100 => crate::png::decode(&data).map_err(|msg| Error::new("EBADPNG", msg)),
+        _ => Err(Error::invalid("unknown format")),
+    }
+}
+
+/// Images transmitted with ids, and the command being transmitted in chunks
+#[derive(Debug, Default)]
+pub struct Store {
+    // in the order of use
+    images: Vec<(u32, Image)>,
+    pending: Option<Command>,
+}
+
+impl Store {
+    /// Gathers chunks, and returns the whole command when the last chunk arrives
+    pub fn feed(&mut self, cmd: Command) -> Option<Command> {
+        let first = match self.pending.take() {
+            Some(mut first) => {
+                first.payload += &cmd.payload;
+                first.more = cmd.more;
+                first
+            }
+            None => cmd,
+        };
+
+        if first.payload.len() > MAX_PAYLOAD {
+            log::warn!("kitty graphics: too large payload");
+            return None;
+        }
+
+        if first.more {
+            self.pending = Some(first);
+            None
+        } else {
+            Some(first)
+        }
+    }
+
+    pub fn insert(&mut self, id: u32, image: Image) {
+        self.remove(id);
+        self.images.push((id, image));
+
+        // Evict least recently used images
+        let mut total: usize = self.images.iter().map(|(_, img)| img.data.len()).sum();
+        while total > QUOTA && self.images.len() > 1 {
+            let (_, evicted) = self.images.remove(0);
+            total -= evicted.data.len();
+        }
+    }
+
+    pub fn get(&mut self, id: u32) -> Option<&Image> {
+        let i = self.images.iter().position(|(i, _)| *i == id)?;
+        let entry = self.images.remove(i);
+        self.images.push(entry);
+        self.images.last().map(|(_, img)| img)
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.images.retain(|(i, _)| *i != id);
+    }
+
+    pub fn clear(&mut self) {
+        self.images.clear();
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let cmd = Command::parse("a=T,f=24,s=2,v=1,i=7,c=3,C=1;AAAA").unwrap();
+        assert_eq!(cmd.action, 'T');
+        assert_eq!(cmd.format, 24);
+        assert_eq!((cmd.width, cmd.height), (2, 1));
+        assert_eq!(cmd.id, 7);
+        assert_eq!((cmd.cols, cmd.rows), (3, 0));
+        assert!(cmd.fixed_cursor);
+        assert_eq!(cmd.payload, "AAAA");
+
+        assert_eq!(Command::parse("").unwrap(), Command::default());
+        assert_eq!(Command::parse("a=d,d=I,i=1").unwrap().delete, 'I');
+        assert_eq!(Command::parse("s=x"), None);
+        assert_eq!(Command::parse("a"), None);
+    }
+
+    #[test]
+    fn test_load_raw() {
+        // 2x1 RGB
+        let cmd = Command::parse("f=24,s=2,v=1;/wAAAP8A").unwrap();
+        let img = load(&cmd).unwrap();
+        assert_eq!((img.width, img.height), (2, 1));
+        assert_eq!(img.data, [0xFF, 0, 0, 0xFF, 0, 0xFF, 0, 0xFF]);
+
+        let cmd = Command::parse("f=32,s=2,v=1;/wAAAP8A").unwrap();
+        assert_eq!(
+            load(&cmd).unwrap_err().to_string(),
+            "ENODATA:insufficient image data"
+        );
+        let cmd = Command::parse("f=32;/wAAAA").unwrap();
+        assert!(load(&cmd).unwrap_err().to_string().starts_with("EINVAL"));
+    }
+
+    #[test]
+    fn test_load_compressed() {
+        let rgba = [1, 2, 3, 4, 5, 6, 7, 8];
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&rgba, 6);
+        let cmd = Command {
+            format: 32,
+            width: 1,
+            height: 2,
+            compressed: true,
+            payload: base64::encode(&compressed),
+            ..Command::default()
+        };
+        assert_eq!(load(&cmd).unwrap().data, rgba);
+    }
+
+    #[test]
+    fn test_load_png() {
+        // 2x2 RGB image with the sub and up filters
+        let mut raw = Vec::new();
+        raw.extend([1, 10, 20, 30, 5, 5, 5]);
+        raw.extend([2, 1, 1, 1, 2, 2, 2]);
+        let png = crate::png::tests::encode(2, 2, 8, 2, &[], &raw);
+
+        let cmd = Command {
+            format: 100,
+            payload: base64::encode(&png),
+            ..Command::default()
+        };
+        let img = load(&cmd).unwrap();
+        assert_eq!((img.width, img.height), (2, 2));
+        #[rustfmt::skip]
+        assert_eq!(img.data, [
+            10, 20, 30, 0xFF,  15, 25, 35, 0xFF,
+            11, 21, 31, 0xFF,  17, 27, 37, 0xFF,
+        ]);
+
+        let cmd = Command {
+            format: 100,
+            payload: base64::encode(b"GIF89a"),
+            ..Command::default()
+        };
+        assert_eq!(
+            load(&cmd).unwrap_err().to_string(),
+            "EBADPNG:not a PNG image"
+        );
+    }
+
+    #[test]
+    fn test_load_temporary_file() {
+        let path = std::env::temp_dir().join(format!(
+            "toyterm-tty-graphics-protocol-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, [0, 0, 0, 0]).unwrap();
+
+        let cmd = Command {
+            medium: 't',
+            width: 1,
+            height: 1,
+            payload: base64::encode(path.to_str().unwrap().as_bytes()),
+            ..Command::default()
+        };
+        assert_eq!(load(&cmd).unwrap().data, [0, 0, 0, 0]);
+        assert!(!path.exists());
+        assert!(load(&cmd).unwrap_err().to_string().starts_with("EBADF"));
+    }
+
+    #[test]
+    fn test_load_special_files() {
+        let load_file = |path: &str| {
+            let cmd = Command {
+                medium: 'f',
+                width: 1,
+                height: 1,
+                payload: base64::encode(path.as_bytes()),
+                ..Command::default()
+            };
+            load(&cmd).unwrap_err().to_string()
+        };
+
+        assert!(load_file("/dev/zero").starts_with("EPERM"));
+        assert!(load_file("/proc/self/environ").starts_with("EPERM"));
+        assert!(load_file("/").starts_with("EINVAL"));
+        assert!(load_file("/nonexistent").starts_with("EBADF"));
+
+        // a symlink to a special file is resolved
+        let link = std::env::temp_dir().join(format!(
+            "toyterm-tty-graphics-protocol-link-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink("/dev/zero", &link).unwrap();
+        assert!(load_file(link.to_str().unwrap()).starts_with("EPERM"));
+        assert!(!is_temporary_file(&link));
+        std::fs::remove_file(&link).unwrap();
+
+        // a FIFO is refused without waiting for a writer
+        let fifo = std::env::temp_dir().join(format!("toyterm-fifo-{}", std::process::id()));
+        let _ = std::fs::remove_file(&fifo);
+        nix::unistd::mkfifo(&fifo, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        assert!(load_file(fifo.to_str().unwrap()).starts_with("EINVAL"));
+        std::fs::remove_file(&fifo).unwrap();
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut store = Store::default();
+        let first = Command::parse("a=T,f=24,s=1,v=1,m=1;AA").unwrap();
+        assert_eq!(store.feed(first), None);
+        assert_eq!(store.feed(Command::parse("m=1;A").unwrap()), None);
+        let cmd = store.feed(Command::parse("m=0;A").unwrap()).unwrap();
+        assert_eq!(cmd.action, 'T');
+        assert_eq!(cmd.payload, "AAAA");
+        assert!(!cmd.more);
+
+        // not chunked
+        let cmd = store.feed(Command::parse("a=q;AAAA").unwrap()).unwrap();
+        assert_eq!(cmd.action, 'q');
+    }
+
+    #[test]
+    fn test_store() {
+        let image = |w| Image {
+            width: w,
+            height: 1,
+            data: vec![0; QUOTA / 2],
+        };
+
+        let mut store = Store::default();
+        store.insert(1, image(1));
+        store.insert(2, image(2));
+        assert_eq!(store.get(1).map(|img| img.width), Some(1));
+
+        // the least recently used image (2) is evicted
+        store.insert(3, image(3));
+        assert!(store.get(2).is_none());
+        assert!(store.get(1).is_some());
+        assert!(store.get(3).is_some());
+
+        store.remove(1);
+        assert!(store.get(1).is_none());
+    }
+
+    #[test]
+    fn test_response() {
+        let cmd = Command::parse("i=3").unwrap();
+        assert_eq!(cmd.response(&Ok(())).unwrap(), "\x1b_Gi=3;OK\x1b\\");
+        let err = Err(Error::new("ENOENT", "not found"));
+        assert_eq!(
+            cmd.response(&err).unwrap(),
+            "\x1b_Gi=3;ENOENT:not found\x1b\\"
+        );
+
+        let cmd = Command::parse("i=3,q=1").unwrap();
+        assert_eq!(cmd.response(&Ok(())), None);
+        assert!(cmd.response(&err).is_some());
+        let cmd = Command::parse("i=3,q=2").unwrap();
+        assert_eq!(cmd.response(&err), None);
+
+        let cmd = Command::parse("").unwrap();
+        assert_eq!(cmd.response(&Ok(())), None);
+    }
+}
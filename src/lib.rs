@@ -2,9 +2,14 @@ mod cache;
 mod config;
 mod control_function;
 mod font;
+mod iterm;
+mod kitty;
 mod pipe_channel;
+mod png;
+mod search;
 mod sixel;
 mod terminal;
+pub mod terminfo;
 mod utils;
 mod view;
 pub mod window;
@@ -12,6 +17,10 @@ pub mod window;
 #[cfg(feature = "multiplex")]
 pub mod multiplexer;
 
+#[cfg(feature = "multiplex")]
+pub mod session;
+
 lazy_static::lazy_static! {
-    pub static ref TOYTERM_CONFIG: crate::config::Config = crate::config::build();
+    pub static ref TOYTERM_CONFIG: crate::config::SharedConfig =
+        crate::config::SharedConfig::new(crate::config::build());
 }
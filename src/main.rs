@@ -1,4 +1,11 @@
+use glium::glutin;
+
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("--dump-terminfo") {
+        print!("{}", toyterm::terminfo::source());
+        return;
+    }
+
     // Make sure that configuration errors are detected earlier
     lazy_static::initialize(&toyterm::TOYTERM_CONFIG);
 
@@ -9,25 +16,140 @@ fn main() {
         .format_timestamp(None)
         .init();
 
-    let event_loop = glium::glutin::event_loop::EventLoop::new();
+    #[cfg(feature = "multiplex")]
+    if std::env::args().nth(1).as_deref() == Some("--server") {
+        let server =
+            toyterm::session::socket_path().and_then(|path| toyterm::session::serve(&path));
+        match server {
+            Ok(server) => {
+                let _ = server.join();
+            }
+            Err(err) => log::error!("session server: {}", err),
+        }
+        return;
+    }
 
-    let title = "toyterm";
-    let display = {
-        use glium::glutin::{window::WindowBuilder, ContextBuilder};
-        let win_builder = WindowBuilder::new().with_title(title).with_resizable(true);
-        let ctx_builder = ContextBuilder::new().with_vsync(true).with_srgb(true);
-        glium::Display::new(win_builder, ctx_builder, &event_loop).expect("display new")
-    };
+    let event_loop = glutin::event_loop::EventLoop::new();
+
+    let mode = toyterm::window::startup_mode(std::env::args());
+    let mut launch = toyterm::window::launch_options(std::env::args().skip(1));
+    if let Some(dir) = launch
+        .working_directory
+        .as_ref()
+        .filter(|dir| !dir.is_dir())
+    {
+        log::error!("not a directory: {}", dir.display());
+        launch.working_directory = None;
+    }
+    let cwd = launch.working_directory.as_deref();
+    let command = launch.command.as_deref();
+
+    // Take over a detached session (a new shell is started if it fails)
+    #[cfg(feature = "multiplex")]
+    let attached = launch.attach.as_ref().and_then(|name| {
+        toyterm::session::attach(name.as_deref())
+            .map_err(|err| log::error!("Failed to attach a session: {}", err))
+            .ok()
+    });
+    #[cfg(not(feature = "multiplex"))]
+    if launch.attach.is_some() {
+        log::warn!("--attach requires the multiplexer (feature `multiplex`)");
+    }
+
+    let display = create_display(&event_loop, Some(mode));
 
     #[cfg(not(feature = "multiplex"))]
-    let mut term = toyterm::window::TerminalWindow::new(display, None);
+    {
+        let mut term = toyterm::window::TerminalWindow::new(display, cwd, command);
+
+        event_loop.run(move |event, _, control_flow| {
+            if let Some(event) = event.to_static() {
+                term.on_event(&event, control_flow);
+            }
+
+            if *control_flow == glutin::event_loop::ControlFlow::Exit {
+                term.save_terminal_size();
+            }
+        });
+    }
 
     #[cfg(feature = "multiplex")]
-    let mut term = toyterm::multiplexer::Multiplexer::new(display);
+    {
+        use toyterm::multiplexer::{Multiplexer, WindowRegistry};
 
-    event_loop.run(move |event, _, control_flow| {
-        if let Some(event) = event.to_static() {
-            term.on_event(&event, control_flow);
-        }
-    });
+        let mut windows = WindowRegistry::default();
+        let mux = match attached {
+            Some((pty, pid)) => Multiplexer::attached(display, pty, pid),
+            None => Multiplexer::new(display, cwd, command),
+        };
+        windows.insert(mux.window_id(), mux);
+
+        event_loop.run(move |event, target, control_flow| {
+            use glutin::event::Event;
+            use glutin::event_loop::ControlFlow;
+
+            let event = match event.to_static() {
+                Some(event) => event,
+                None => return,
+            };
+
+            // Events for a specific window are delivered only to it
+            let target_id = match &event {
+                Event::WindowEvent { window_id, .. } => Some(*window_id),
+                Event::RedrawRequested(window_id) => Some(*window_id),
+                _ => None,
+            };
+
+            let mut closed = Vec::new();
+            let mut requested = 0;
+            for (&id, mux) in windows.iter_mut() {
+                if target_id.map_or(true, |target| target == id) {
+                    let mut cf = ControlFlow::default();
+                    mux.on_event(&event, &mut cf);
+                    if cf == ControlFlow::Exit {
+                        closed.push(id);
+                    }
+                    if mux.take_new_window_request() {
+                        requested += 1;
+                    }
+                }
+            }
+
+            for id in closed {
+                log::debug!("window closed: {:?}", id);
+                windows.remove(&id);
+            }
+
+            for _ in 0..requested {
+                let mux = Multiplexer::new(create_display(target, None), None, None);
+                log::debug!("window opened: {:?}", mux.window_id());
+                windows.insert(mux.window_id(), mux);
+            }
+
+            if windows.is_empty() {
+                *control_flow = ControlFlow::Exit;
+            }
+        });
+    }
+}
+
+/// Creates a window (the startup mode is applied only to the first one)
+fn create_display(
+    event_loop: &glutin::event_loop::EventLoopWindowTarget<()>,
+    mode: Option<toyterm::window::StartupMode>,
+) -> glium::Display {
+    use glutin::{window::WindowBuilder, ContextBuilder};
+    let title = "toyterm";
+    let mut win_builder = WindowBuilder::new().with_title(title).with_resizable(true);
+    if let Some(mode) = mode {
+        win_builder = toyterm::window::with_startup_mode(win_builder, mode);
+    }
+    let ctx_builder = ContextBuilder::new().with_vsync(true).with_srgb(true);
+    let gl_window = ctx_builder
+        .build_windowed(win_builder, event_loop)
+        .unwrap_or_else(|err| {
+            let hint = toyterm::window::GL_HINT;
+            panic!("Failed to create an OpenGL context with an sRGB framebuffer: {err}. {hint}")
+        });
+    glium::Display::from_gl_window(gl_window).expect("display new")
 }
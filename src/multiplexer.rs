@@ -3,11 +3,13 @@ use glutin::{
     dpi::PhysicalPosition,
     event::{ElementState, ModifiersState, VirtualKeyCode, WindowEvent},
     event_loop::ControlFlow,
-    window::CursorIcon,
+    window::{CursorIcon, WindowId},
 };
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
+use crate::config::{Action, Key};
 use crate::terminal::{Cell, Color};
 use crate::view::{TerminalView, Viewport};
 use crate::window::TerminalWindow;
@@ -32,9 +34,11 @@ enum Command {
     ResizeIncreaseUp,
     ResizeDecreaseUp,
     AddNewTab,
+    NewWindow,
     SetMaximize,
     ResetMaximize,
     Close,
+    Detach,
 
     SaveLayout,
     RestoreLayout,
@@ -52,6 +56,65 @@ struct SingleLayout {
     #[serde(skip)]
     window: Option<Box<TerminalWindow>>,
     cwd: PathBuf,
+    #[serde(skip)]
+    pane: PaneState,
+}
+
+/// The lifecycle of a pane, which is kept as a "dead" pane after its shell exits
+/// if `hold_exited_panes` is set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum PaneState {
+    #[default]
+    Alive,
+    Dead,
+    Closed,
+}
+
+impl PaneState {
+    fn on_exit(self, hold: bool) -> Self {
+        match self {
+            Self::Alive if hold => Self::Dead,
+            Self::Alive => Self::Closed,
+            state => state,
+        }
+    }
+
+    fn on_dismiss(self) -> Self {
+        match self {
+            Self::Dead => Self::Closed,
+            state => state,
+        }
+    }
+}
+
+/// Returns true if the event is a key release which dismisses a dead pane (modifiers are ignored).
+/// The pane is closed on release, so the character typed by the key doesn't reach the next pane.
+fn is_dismiss_key(event: &Event) -> bool {
+    use VirtualKeyCode::*;
+    match event {
+        Event::WindowEvent {
+            event: WindowEvent::KeyboardInput { input, .. },
+            ..
+        } => {
+            input.state == ElementState::Released
+                && !matches!(
+                    input.virtual_keycode,
+                    None | Some(LControl | RControl | LShift | RShift | LAlt | RAlt | LWin | RWin)
+                )
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if the event is a keyboard input, which is not delivered to dead panes
+fn is_keyboard_input(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::WindowEvent {
+            event: WindowEvent::KeyboardInput { .. } | WindowEvent::ReceivedCharacter(_),
+            ..
+        }
+    )
 }
 
 impl SingleLayout {
@@ -63,6 +126,30 @@ impl SingleLayout {
         let cwd = self.get_mut().get_foreground_process_cwd();
         self.cwd = cwd;
     }
+
+    fn on_event(&mut self, event: &Event, control_flow: &mut ControlFlow) {
+        if self.pane == PaneState::Dead && is_keyboard_input(event) {
+            if is_dismiss_key(event) {
+                self.pane = self.pane.on_dismiss();
+            }
+        } else {
+            let mut cf = ControlFlow::default();
+            self.get_mut().on_event(event, &mut cf);
+            if cf == ControlFlow::Exit {
+                let next = self
+                    .pane
+                    .on_exit(crate::TOYTERM_CONFIG.load().hold_exited_panes);
+                if self.pane == PaneState::Alive && next == PaneState::Dead {
+                    self.get_mut().show_exit_banner();
+                }
+                self.pane = next;
+            }
+        }
+
+        if self.pane == PaneState::Closed {
+            *control_flow = ControlFlow::Exit;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -448,7 +535,8 @@ impl TabbedLayout {
             Command::AddNewTab => {
                 self.focused_mut().focused_window_mut().focus_changed(false);
 
-                let window = TerminalWindow::with_viewport(display.clone(), self.viewport, None);
+                let window =
+                    TerminalWindow::with_viewport(display.clone(), self.viewport, None, None);
                 let single = Layout::new_single(window.into());
 
                 self.tabs.push(Some(single.into()));
@@ -495,6 +583,7 @@ impl Layout {
         Self::Single(SingleLayout {
             window: Some(win),
             cwd,
+            pane: PaneState::Alive,
         })
     }
 
@@ -597,7 +686,7 @@ impl Layout {
 
     fn on_event(&mut self, display: &Display, event: &Event, control_flow: &mut ControlFlow) {
         match self {
-            Self::Single(layout) => layout.get_mut().on_event(event, control_flow),
+            Self::Single(layout) => layout.on_event(event, control_flow),
             Self::Binary(layout) => layout.on_event(display, event, control_flow),
             Self::Tabbed(layout) => layout.on_event(display, event, control_flow),
         }
@@ -677,7 +766,7 @@ impl Layout {
 
                     let new_window = {
                         let cwd = Some(old_cwd.as_ref()); // derive from current pane
-                        Box::new(TerminalWindow::new(display.clone(), cwd))
+                        Box::new(TerminalWindow::new(display.clone(), cwd, None))
                     };
 
                     let viewport = old_window.viewport();
@@ -698,8 +787,11 @@ impl Layout {
                 }
                 Command::RestoreLayout => {
                     debug_assert!(layout.window.is_none());
-                    let new_window =
-                        Box::new(TerminalWindow::new(display.clone(), Some(&layout.cwd)));
+                    let new_window = Box::new(TerminalWindow::new(
+                        display.clone(),
+                        Some(&layout.cwd),
+                        None,
+                    ));
                     layout.window = Some(new_window);
                     true
                 }
@@ -721,10 +813,30 @@ pub struct Multiplexer {
     main_layout: Layout,
     controller: Controller,
     finished: bool,
+    new_window_requested: bool,
 }
 
 impl Multiplexer {
-    pub fn new(display: Display) -> Self {
+    /// Opens a multiplexer whose first pane runs `command` (or the shell if None) in `cwd`
+    pub fn new(
+        display: Display,
+        cwd: Option<&std::path::Path>,
+        command: Option<&[String]>,
+    ) -> Self {
+        let size = display.gl_window().window().inner_size();
+        let viewport = Viewport {
+            x: 0,
+            y: 0,
+            w: size.width,
+            h: size.height,
+        };
+
+        let window = TerminalWindow::new(display.clone(), cwd, command);
+        Self::with_window(display, viewport, window)
+    }
+
+    /// Opens a multiplexer whose first pane takes over a detached session (see `session`)
+    pub fn attached(display: Display, pty: std::os::unix::io::OwnedFd, pid: i32) -> Self {
         let size = display.gl_window().window().inner_size();
         let viewport = Viewport {
             x: 0,
@@ -733,11 +845,15 @@ impl Multiplexer {
             h: size.height,
         };
 
-        let font_size = crate::TOYTERM_CONFIG.status_bar_font_size;
+        let window = TerminalWindow::attached(display.clone(), pty, pid);
+        Self::with_window(display, viewport, window)
+    }
+
+    fn with_window(display: Display, viewport: Viewport, window: TerminalWindow) -> Self {
+        let font_size = crate::TOYTERM_CONFIG.load().status_bar_font_size;
         let status_view = TerminalView::with_viewport(display.clone(), viewport, font_size, None);
 
         let main_layout = {
-            let window = TerminalWindow::new(display.clone(), None);
             let single = Layout::new_single(Box::new(window));
             Layout::new_tabbed(viewport, single.into())
         };
@@ -748,8 +864,9 @@ impl Multiplexer {
             status_view,
             last_updated: std::time::Instant::now(),
             main_layout,
-            controller: Controller::default(),
+            controller: Controller::new(),
             finished: false,
+            new_window_requested: false,
         };
 
         mux.refresh_layout();
@@ -757,6 +874,15 @@ impl Multiplexer {
         mux
     }
 
+    pub fn window_id(&self) -> WindowId {
+        self.display.gl_window().window().id()
+    }
+
+    /// Returns true once if a new top-level window has been requested
+    pub fn take_new_window_request(&mut self) -> bool {
+        std::mem::take(&mut self.new_window_requested)
+    }
+
     fn tab_layout(&mut self) -> &mut TabbedLayout {
         match &mut self.main_layout {
             Layout::Tabbed(layout) => layout,
@@ -795,11 +921,15 @@ impl Multiplexer {
             i: usize,
             focus: bool,
             name: String,
+            cwd: Option<String>,
         }
 
         impl Tab {
             fn display(&self) -> Vec<Cell> {
-                let text = format!("{}:{} ", self.i, self.name);
+                let text = match &self.cwd {
+                    Some(cwd) => format!("{}:{} {} ", self.i, self.name, cwd),
+                    None => format!("{}:{} ", self.i, self.name),
+                };
                 text.chars()
                     .map(|ch| {
                         let mut cell = default_cell();
@@ -816,6 +946,9 @@ impl Multiplexer {
         let cols = (self.viewport.w / self.status_view.cell_size().w) as usize;
         let mut cells = Vec::new();
 
+        let config = crate::TOYTERM_CONFIG.load();
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+
         let tab_layout = self.tab_layout();
         let focused_tab = tab_layout.focus;
         for (i, layout) in tab_layout.tabs.iter_mut().enumerate() {
@@ -824,10 +957,16 @@ impl Multiplexer {
                 let name = win.get_foreground_process_name();
                 let last_part = name.rsplit('/').next().unwrap().to_owned();
 
+                let cwd = config.status_bar_cwd.then(|| {
+                    let cwd = win.get_foreground_process_cwd();
+                    shorten_path(&cwd, home.as_deref(), config.status_bar_cwd_width)
+                });
+
                 let tab = Tab {
                     i,
                     focus: i == focused_tab,
                     name: last_part,
+                    cwd,
                 };
 
                 cells.extend(tab.display());
@@ -868,6 +1007,11 @@ impl Multiplexer {
             return;
         }
 
+        // Key bindings of the multiplexer are user inputs too
+        if crate::window::is_user_input(event) {
+            crate::view::notify_user_input();
+        }
+
         if let Some(cmd) = self.controller.on_event(event) {
             self.process_command(cmd);
             return;
@@ -934,7 +1078,7 @@ impl Multiplexer {
 
                 let path = find_layout_file();
                 let bytes = serde_json::to_vec(&self.main_layout).expect("serialize");
-                match std::fs::write(&path, &bytes) {
+                match std::fs::write(&path, bytes) {
                     Ok(_) => {
                         log::info!("layout saved in {}", path.display());
                     }
@@ -1007,9 +1151,35 @@ impl Multiplexer {
                 self.update_status_bar();
             }
 
+            Command::NewWindow => {
+                self.new_window_requested = true;
+            }
+
             Command::Close => {
                 self.close_focused_window();
             }
+
+            Command::Detach => {
+                let stream = match crate::session::connect() {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::error!("Failed to connect to the session server: {}", err);
+                        return;
+                    }
+                };
+
+                // The terminal is stopped only after the server has taken the PTY,
+                // so that the session is kept as it is if the detach fails
+                let (pty, pid) = self.main_layout.focused_window_mut().shell();
+                let name = pid.to_string();
+                if let Err(err) = crate::session::detach(&stream, &name, pid, pty) {
+                    log::error!("Failed to detach {:?}: {}", name, err);
+                    return;
+                }
+                log::info!("detached: {:?}", name);
+                self.main_layout.focused_window_mut().detach();
+                self.close_focused_window();
+            }
         }
     }
 
@@ -1031,6 +1201,50 @@ impl Multiplexer {
     }
 }
 
+/// Top-level windows sharing the event loop
+pub struct WindowRegistry<K, T> {
+    windows: Vec<(K, T)>,
+}
+
+impl<K: PartialEq, T> Default for WindowRegistry<K, T> {
+    fn default() -> Self {
+        WindowRegistry {
+            windows: Vec::new(),
+        }
+    }
+}
+
+impl<K: PartialEq, T> WindowRegistry<K, T> {
+    pub fn insert(&mut self, id: K, window: T) {
+        debug_assert!(self.get_mut(&id).is_none());
+        self.windows.push((id, window));
+    }
+
+    pub fn remove(&mut self, id: &K) -> Option<T> {
+        let i = self.windows.iter().position(|(k, _)| k == id)?;
+        Some(self.windows.remove(i).1)
+    }
+
+    pub fn get_mut(&mut self, id: &K) -> Option<&mut T> {
+        self.windows
+            .iter_mut()
+            .find(|(k, _)| k == id)
+            .map(|(_, w)| w)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut T)> {
+        self.windows.iter_mut().map(|(k, w)| (&*k, w))
+    }
+
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+}
+
 fn find_layout_file() -> PathBuf {
     let config_home = std::env::var_os("XDG_CONFIG_HOME")
         .map(PathBuf::from)
@@ -1052,15 +1266,36 @@ fn find_layout_file() -> PathBuf {
     layout_path
 }
 
-#[derive(Default)]
 struct Controller {
     modifiers: ModifiersState,
     consume: bool,
     maximized: bool,
+    keybindings: HashMap<Key, Action>,
+    // true after a key bound to an action is pressed, whose character is ignored
+    bound_key_pressed: bool,
+    config_generation: u64,
 }
 
 impl Controller {
+    fn new() -> Self {
+        Self {
+            modifiers: ModifiersState::empty(),
+            consume: false,
+            maximized: false,
+            keybindings: crate::TOYTERM_CONFIG.load().keybindings.table(),
+            bound_key_pressed: false,
+            config_generation: crate::TOYTERM_CONFIG.generation(),
+        }
+    }
+
     fn on_event(&mut self, event: &Event) -> Option<Command> {
+        // The keybindings may have been changed by reloading the config
+        let generation = crate::TOYTERM_CONFIG.generation();
+        if generation != self.config_generation {
+            self.config_generation = generation;
+            self.keybindings = crate::TOYTERM_CONFIG.load().keybindings.table();
+        }
+
         if let Event::WindowEvent { event: wev, .. } = event {
             match wev {
                 &WindowEvent::ModifiersChanged(new_states) => {
@@ -1086,65 +1321,256 @@ impl Controller {
     }
 
     fn on_character(&mut self, ch: char) -> Option<Command> {
-        if !self.consume {
-            if ch == '\x01' {
-                self.consume = true;
-                Some(Command::Nop)
-            } else {
-                None
+        if std::mem::take(&mut self.bound_key_pressed) {
+            return Some(Command::Nop);
+        }
+
+        if let Some(&action) = self.keybindings.get(&Key::Char(ch)) {
+            if let Some(cmd) = self.on_action(action) {
+                return Some(cmd);
             }
-        } else {
-            self.consume = false;
-            match ch {
-                '\x01' => None,
-                '\x1b' => Some(Command::Nop),
-                'c' => Some(Command::AddNewTab),
-                'n' => Some(Command::FocusNextTab),
-                'p' => Some(Command::FocusPrevTab),
-                digit @ ('0'..='9') => {
-                    let n = digit as u32 - '0' as u32;
-                    Some(Command::FocusTab(n as usize))
-                }
-                '%' => Some(Command::SplitVertical),
-                '"' => Some(Command::SplitHorizontal),
-                's' => Some(Command::SaveLayout),
-                'r' => Some(Command::RestoreLayout),
-                'z' => {
-                    self.maximized ^= true;
-                    if self.maximized {
-                        Some(Command::SetMaximize)
-                    } else {
-                        Some(Command::ResetMaximize)
-                    }
-                }
-                'x' => Some(Command::Close),
-                _ => Some(Command::Nop),
+        }
+
+        if !self.consume {
+            return None;
+        }
+        self.consume = false;
+        match ch {
+            digit @ ('0'..='9') => {
+                let n = digit as u32 - '0' as u32;
+                Some(Command::FocusTab(n as usize))
             }
+            _ => Some(Command::Nop),
         }
     }
 
     fn on_key_press(&mut self, keycode: VirtualKeyCode) -> Option<Command> {
-        use ModifiersState as Mod;
-        const EMPTY: u32 = Mod::empty().bits();
-        const CTRL: u32 = Mod::CTRL.bits();
-
-        if self.consume {
-            let cmd = match (self.modifiers.bits(), keycode) {
-                (EMPTY, VirtualKeyCode::Up) => Command::FocusUp,
-                (EMPTY, VirtualKeyCode::Down) => Command::FocusDown,
-                (EMPTY, VirtualKeyCode::Left) => Command::FocusLeft,
-                (EMPTY, VirtualKeyCode::Right) => Command::FocusRight,
-                (CTRL, VirtualKeyCode::Up) => Command::ResizeDecreaseUp,
-                (CTRL, VirtualKeyCode::Down) => Command::ResizeIncreaseUp,
-                (CTRL, VirtualKeyCode::Left) => Command::ResizeDecreaseLeft,
-                (CTRL, VirtualKeyCode::Right) => Command::ResizeIncreaseLeft,
-                _ => return None,
-            };
+        self.bound_key_pressed = false;
 
-            self.consume = false;
-            Some(cmd)
-        } else {
-            None
+        let action = *self.keybindings.get(&Key::Code(self.modifiers, keycode))?;
+        let cmd = self.on_action(action)?;
+        self.bound_key_pressed = true;
+        Some(cmd)
+    }
+
+    /// Returns the command for the action, or None if the multiplexer doesn't handle it
+    fn on_action(&mut self, action: Action) -> Option<Command> {
+        if !self.consume {
+            if action == Action::Prefix {
+                self.consume = true;
+                return Some(Command::Nop);
+            }
+            return None;
         }
+
+        let cmd = match action {
+            // The prefix key pressed twice is sent to the terminal
+            Action::Prefix => {
+                self.consume = false;
+                return None;
+            }
+
+            Action::NewTab => Command::AddNewTab,
+            Action::NewWindow => Command::NewWindow,
+            Action::NextTab => Command::FocusNextTab,
+            Action::PrevTab => Command::FocusPrevTab,
+            Action::SplitVertical => Command::SplitVertical,
+            Action::SplitHorizontal => Command::SplitHorizontal,
+            Action::SaveLayout => Command::SaveLayout,
+            Action::RestoreLayout => Command::RestoreLayout,
+            Action::ToggleMaximize => {
+                self.maximized ^= true;
+                if self.maximized {
+                    Command::SetMaximize
+                } else {
+                    Command::ResetMaximize
+                }
+            }
+            Action::Close => Command::Close,
+            Action::Detach => Command::Detach,
+            Action::FocusUp => Command::FocusUp,
+            Action::FocusDown => Command::FocusDown,
+            Action::FocusLeft => Command::FocusLeft,
+            Action::FocusRight => Command::FocusRight,
+            Action::MovePartitionUp => Command::ResizeDecreaseUp,
+            Action::MovePartitionDown => Command::ResizeIncreaseUp,
+            Action::MovePartitionLeft => Command::ResizeDecreaseLeft,
+            Action::MovePartitionRight => Command::ResizeIncreaseLeft,
+
+            // actions of the terminal window
+            _ => return None,
+        };
+
+        self.consume = false;
+        Some(cmd)
+    }
+}
+
+/// Shortens the path to `max_chars` characters at most:
+/// the home directory is replaced with `~`, and leading parts are omitted with `…`.
+fn shorten_path(path: &Path, home: Option<&Path>, max_chars: usize) -> String {
+    let path = match home.and_then(|home| path.strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_owned(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    };
+
+    let len = path.chars().count();
+    if len <= max_chars {
+        return path;
+    }
+    if max_chars == 0 {
+        return String::new();
+    }
+
+    // Keep the trailing components as many as possible
+    let tail: Vec<char> = path.chars().skip(len - (max_chars - 1)).collect();
+    let tail = match tail.iter().position(|&ch| ch == '/') {
+        Some(slash) if slash + 1 < tail.len() => &tail[slash..],
+        _ => &tail[..],
+    };
+    std::iter::once('…').chain(tail.iter().copied()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shorten_path() {
+        let home = Some(Path::new("/home/user"));
+        let shorten = |path: &str, max| shorten_path(Path::new(path), home, max);
+
+        assert_eq!(shorten("/home/user", 24), "~");
+        assert_eq!(shorten("/home/user/src/toyterm", 24), "~/src/toyterm");
+        assert_eq!(shorten("/home/username", 24), "/home/username");
+        assert_eq!(shorten("/tmp", 24), "/tmp");
+
+        // leading components are omitted
+        assert_eq!(
+            shorten("/usr/local/share/applications", 16),
+            "…/applications"
+        );
+        assert_eq!(shorten("/a/b/c/d/e", 6), "…/d/e");
+        // a long last component is cut
+        assert_eq!(shorten("/a/very_long_directory_name", 8), "…ry_name");
+        assert_eq!(shorten("/tmp", 0), "");
+        assert_eq!(
+            shorten_path(Path::new("/home/user/x"), None, 24),
+            "/home/user/x"
+        );
+    }
+
+    #[test]
+    fn test_dead_pane() {
+        // closed immediately by default
+        assert_eq!(PaneState::Alive.on_exit(false), PaneState::Closed);
+
+        // or kept until dismissed
+        let dead = PaneState::Alive.on_exit(true);
+        assert_eq!(dead, PaneState::Dead);
+        assert_eq!(dead.on_exit(true), PaneState::Dead);
+        assert_eq!(PaneState::Alive.on_dismiss(), PaneState::Alive);
+        assert_eq!(dead.on_dismiss(), PaneState::Closed);
+    }
+
+    #[test]
+    fn test_dismiss_key() {
+        #[allow(deprecated)]
+        let key = |state, keycode| Event::WindowEvent {
+            window_id: unsafe { WindowId::dummy() },
+            event: WindowEvent::KeyboardInput {
+                device_id: unsafe { glutin::event::DeviceId::dummy() },
+                input: glutin::event::KeyboardInput {
+                    scancode: 0,
+                    state,
+                    virtual_keycode: Some(keycode),
+                    modifiers: ModifiersState::empty(),
+                },
+                is_synthetic: false,
+            },
+        };
+
+        assert!(is_dismiss_key(&key(
+            ElementState::Released,
+            VirtualKeyCode::Q
+        )));
+        assert!(!is_dismiss_key(&key(
+            ElementState::Pressed,
+            VirtualKeyCode::Q
+        )));
+        assert!(!is_dismiss_key(&key(
+            ElementState::Released,
+            VirtualKeyCode::LControl
+        )));
+        assert!(is_keyboard_input(&key(
+            ElementState::Released,
+            VirtualKeyCode::Q
+        )));
+        assert!(!is_keyboard_input(&Event::MainEventsCleared));
+    }
+
+    #[test]
+    fn test_controller() {
+        let mut ctrl = Controller {
+            keybindings: crate::config::Keybindings::default().table(),
+            ..Controller::new()
+        };
+        let prefix = |ctrl: &mut Controller| {
+            ctrl.modifiers = ModifiersState::CTRL;
+            let cmd = ctrl.on_key_press(VirtualKeyCode::A);
+            // the character of the prefix key is ignored
+            let ch = ctrl.on_character('\x01');
+            ctrl.modifiers = ModifiersState::empty();
+            (cmd, ch)
+        };
+
+        assert_eq!(prefix(&mut ctrl), (Some(Command::Nop), Some(Command::Nop)));
+        assert_eq!(ctrl.on_key_press(VirtualKeyCode::LShift), None);
+        assert_eq!(ctrl.on_character('%'), Some(Command::SplitVertical));
+        assert_eq!(ctrl.on_character('%'), None);
+
+        prefix(&mut ctrl);
+        assert_eq!(
+            ctrl.on_key_press(VirtualKeyCode::Up),
+            Some(Command::FocusUp)
+        );
+        assert_eq!(ctrl.on_key_press(VirtualKeyCode::A), None);
+        assert_eq!(ctrl.on_character('a'), None);
+
+        prefix(&mut ctrl);
+        assert_eq!(ctrl.on_character('3'), Some(Command::FocusTab(3)));
+        prefix(&mut ctrl);
+        assert_eq!(ctrl.on_character('q'), Some(Command::Nop));
+        assert_eq!(ctrl.on_character('q'), None);
+
+        // the prefix key pressed twice is sent as is
+        prefix(&mut ctrl);
+        assert_eq!(prefix(&mut ctrl), (None, None));
+        assert!(!ctrl.consume);
+    }
+
+    #[test]
+    fn test_window_registry() {
+        let mut reg = WindowRegistry::default();
+        assert!(reg.is_empty());
+
+        reg.insert(1, 'a');
+        reg.insert(2, 'b');
+        reg.insert(3, 'c');
+        assert_eq!(reg.len(), 3);
+        assert_eq!(reg.get_mut(&2), Some(&mut 'b'));
+        assert_eq!(reg.get_mut(&4), None);
+
+        assert_eq!(reg.remove(&2), Some('b'));
+        assert_eq!(reg.remove(&2), None);
+        assert_eq!(reg.get_mut(&2), None);
+
+        let ids: Vec<i32> = reg.iter_mut().map(|(&k, _)| k).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        assert_eq!(reg.remove(&1), Some('a'));
+        assert_eq!(reg.remove(&3), Some('c'));
+        assert!(reg.is_empty());
     }
 }
@@ -0,0 +1,353 @@
+// A minimal PNG decoder for images sent by the kitty graphics protocol
+// Reference: https://www.w3.org/TR/png/
+//
+// Non-interlaced images of all the color types and bit depths are supported.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// The maximum number of pixels to be decoded
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    // RGBA
+    pub data: Vec<u8>,
+}
+
+struct Header {
+    width: u32,
+    height: u32,
+    depth: u8,
+    color_type: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color_type {
+            0 => 1, // grayscale
+            2 => 3, // RGB
+            3 => 1, // indexed
+            4 => 2, // grayscale + alpha
+            _ => 4, // RGBA
+        }
+    }
+
+    /// Returns the number of bytes in a scanline (without the filter type)
+    fn stride(&self) -> usize {
+        (self.width as usize * self.channels() * self.depth as usize + 7) / 8
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, &'static str> {
+    if data.len() != 13 {
+        return Err("invalid IHDR");
+    }
+
+    let header = Header {
+        width: be_u32(&data[0..4]),
+        height: be_u32(&data[4..8]),
+        depth: data[8],
+        color_type: data[9],
+    };
+
+    let valid_depth = match header.color_type {
+        0 => matches!(header.depth, 1 | 2 | 4 | 8 | 16),
+        3 => matches!(header.depth, 1 | 2 | 4 | 8),
+        2 | 4 | 6 => matches!(header.depth, 8 | 16),
+        _ => false,
+    };
+    if !valid_depth {
+        return Err("unsupported color type or bit depth");
+    }
+    if data[10] != 0 || data[11] != 0 {
+        return Err("unknown compression or filter method");
+    }
+    if data[12] != 0 {
+        return Err("interlaced images are not supported");
+    }
+    if header.width == 0 || header.height == 0 {
+        return Err("empty image");
+    }
+    if header.width as u64 * header.height as u64 > MAX_PIXELS {
+        return Err("too large image");
+    }
+    Ok(header)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Reverses the filters of scanlines, and returns them without the filter types
+fn unfilter(raw: &[u8], stride: usize, bpp: usize) -> Result<Vec<u8>, &'static str> {
+    let rows = raw.len() / (stride + 1);
+    let mut out = vec![0_u8; rows * stride];
+
+    for y in 0..rows {
+        let filter = raw[y * (stride + 1)];
+        let line = &raw[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+
+        let (prev, cur) = out.split_at_mut(y * stride);
+        let prev = if y > 0 {
+            &prev[(y - 1) * stride..]
+        } else {
+            &[][..]
+        };
+        let cur = &mut cur[..stride];
+
+        for x in 0..stride {
+            let a = if x >= bpp { cur[x - bpp] } else { 0 };
+            let b = prev.get(x).copied().unwrap_or(0);
+            let c = if x >= bpp {
+                prev.get(x - bpp).copied().unwrap_or(0)
+            } else {
+                0
+            };
+
+            let predictor = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err("unknown filter type"),
+            };
+            cur[x] = line[x].wrapping_add(predictor);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Returns the k-th sample in the scanline, scaled to 8 bits unless it's a palette index
+fn sample(line: &[u8], k: usize, header: &Header) -> u8 {
+    match header.depth {
+        8 => line[k],
+        16 => line[2 * k],
+        depth => {
+            let bit = k * depth as usize;
+            let mask = (1_u8 << depth) - 1;
+            let v = (line[bit / 8] >> (8 - depth as usize - bit % 8)) & mask;
+            if header.color_type == 3 {
+                v
+            } else {
+                (v as u16 * 255 / mask as u16) as u8
+            }
+        }
+    }
+}
+
+/// Decodes a PNG image into RGBA pixels
+pub fn decode(bytes: &[u8]) -> Result<Image, &'static str> {
+    let mut rest = bytes.strip_prefix(&SIGNATURE).ok_or("not a PNG image")?;
+
+    let mut header = None;
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut transparent: Option<Vec<u8>> = None;
+    let mut compressed = Vec::new();
+
+    loop {
+        if rest.len() < 12 {
+            return Err("unexpected end of image");
+        }
+        let len = be_u32(&rest[0..4]) as usize;
+        let kind = &rest[4..8];
+        let data = rest.get(8..8 + len).ok_or("unexpected end of image")?;
+        rest = rest.get(12 + len..).ok_or("unexpected end of image")?;
+
+        match kind {
+            b"IHDR" => header = Some(parse_header(data)?),
+            b"PLTE" => {
+                palette = data
+                    .chunks_exact(3)
+                    .map(|rgb| [rgb[0], rgb[1], rgb[2], 0xFF])
+                    .collect();
+            }
+            b"tRNS" => transparent = Some(data.to_vec()),
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or("IHDR is missing")?;
+    let stride = header.stride();
+    let height = header.height as usize;
+    let width = header.width as usize;
+
+    let limit = height * (stride + 1);
+    let raw = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&compressed, limit)
+        .map_err(|_| "invalid image data")?;
+    if raw.len() < limit {
+        return Err("insufficient image data");
+    }
+
+    let bpp = ((header.channels() * header.depth as usize + 7) / 8).max(1);
+    let lines = unfilter(&raw[..limit], stride, bpp)?;
+
+    // Alpha of palette entries, or the transparent color of grayscale/RGB images
+    if let (3, Some(alpha)) = (header.color_type, transparent.as_ref()) {
+        for (entry, &a) in palette.iter_mut().zip(alpha) {
+            entry[3] = a;
+        }
+    }
+    let key: Option<Vec<u8>> = match (header.color_type, transparent.as_ref()) {
+        // each sample is given in 2 bytes, whose lower byte is used for 8-bit images
+        (0 | 2, Some(t)) => Some(t.chunks_exact(2).map(|s| s[1]).collect()),
+        _ => None,
+    };
+
+    let mut data = Vec::with_capacity(width * height * 4);
+    for line in lines.chunks_exact(stride) {
+        for x in 0..width {
+            let pixel = match header.color_type {
+                0 => {
+                    let v = sample(line, x, &header);
+                    [v, v, v, 0xFF]
+                }
+                2 => {
+                    let k = 3 * x;
+                    let rgb = [
+                        sample(line, k, &header),
+                        sample(line, k + 1, &header),
+                        sample(line, k + 2, &header),
+                    ];
+                    [rgb[0], rgb[1], rgb[2], 0xFF]
+                }
+                3 => {
+                    let index = sample(line, x, &header) as usize;
+                    *palette.get(index).ok_or("invalid palette index")?
+                }
+                4 => {
+                    let v = sample(line, 2 * x, &header);
+                    [v, v, v, sample(line, 2 * x + 1, &header)]
+                }
+                _ => {
+                    let k = 4 * x;
+                    [
+                        sample(line, k, &header),
+                        sample(line, k + 1, &header),
+                        sample(line, k + 2, &header),
+                        sample(line, k + 3, &header),
+                    ]
+                }
+            };
+            data.extend_from_slice(&pixel);
+        }
+    }
+
+    // Pixels in the transparent color (only for 8-bit images)
+    if let (Some(key), 8) = (key, header.depth) {
+        for pixel in data.chunks_exact_mut(4) {
+            let color = if header.color_type == 0 {
+                &pixel[..1]
+            } else {
+                &pixel[..3]
+            };
+            if color == key.as_slice() {
+                pixel[3] = 0;
+            }
+        }
+    }
+
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        data,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Builds a PNG image from filtered scanlines
+    pub fn encode(
+        width: u32,
+        height: u32,
+        depth: u8,
+        color_type: u8,
+        chunks: &[(&[u8], &[u8])],
+        raw: &[u8],
+    ) -> Vec<u8> {
+        fn chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(kind);
+            out.extend(data);
+            out.extend([0; 4]); // CRC is not checked
+        }
+
+        let mut ihdr = Vec::new();
+        ihdr.extend(width.to_be_bytes());
+        ihdr.extend(height.to_be_bytes());
+        ihdr.extend([depth, color_type, 0, 0, 0]);
+
+        let mut out = SIGNATURE.to_vec();
+        chunk(&mut out, b"IHDR", &ihdr);
+        for (kind, data) in chunks {
+            chunk(&mut out, kind, data);
+        }
+        let compressed = miniz_oxide::deflate::compress_to_vec_zlib(raw, 6);
+        chunk(&mut out, b"IDAT", &compressed);
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn test_paeth_filter() {
+        // 2x2 grayscale + alpha
+        let raw = [0, 1, 2, 3, 4, 4, 10, 20, 5, 5];
+        let img = decode(&encode(2, 2, 8, 4, &[], &raw)).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(img.data, [
+            1, 1, 1, 2,  3, 3, 3, 4,
+            // predictors: a=0,b=1,c=0 -> b / a=11,b=3,c=1 -> a
+            11, 11, 11, 22,  16, 16, 16, 27,
+        ]);
+    }
+
+    #[test]
+    fn test_palette() {
+        // 4x1, 2-bit indices with transparency of the first entry
+        let plte = [0, 0, 0, 255, 0, 0, 0, 255, 0];
+        let chunks: [(&[u8], &[u8]); 2] = [(b"PLTE", &plte), (b"tRNS", &[0])];
+        let raw = [0, 0b00_01_10_01];
+        let img = decode(&encode(4, 1, 2, 3, &chunks, &raw)).unwrap();
+        #[rustfmt::skip]
+        assert_eq!(img.data, [
+            0, 0, 0, 0,  255, 0, 0, 255,  0, 255, 0, 255,  255, 0, 0, 255,
+        ]);
+
+        // out of the palette
+        let raw = [0, 0b11_00_00_00];
+        let err = decode(&encode(4, 1, 2, 3, &chunks, &raw)).unwrap_err();
+        assert_eq!(err, "invalid palette index");
+    }
+
+    #[test]
+    fn test_invalid_images() {
+        assert_eq!(decode(b"GIF89a").unwrap_err(), "not a PNG image");
+        // truncated scanlines
+        let err = decode(&encode(2, 2, 8, 0, &[], &[0, 1, 2])).unwrap_err();
+        assert_eq!(err, "insufficient image data");
+        // 16-bit indices
+        let err = decode(&encode(1, 1, 16, 3, &[], &[0, 0, 0])).unwrap_err();
+        assert_eq!(err, "unsupported color type or bit depth");
+    }
+}
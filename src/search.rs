@@ -0,0 +1,183 @@
+use crate::terminal::Line;
+
+/// Text dumped from lines, which remembers the cell position of each character
+pub struct Dump {
+    pub text: String,
+    // (byte offset in text, row, col) for each character
+    positions: Vec<(usize, usize, usize)>,
+}
+
+impl Dump {
+    pub fn new<'a>(lines: impl IntoIterator<Item = &'a Line>) -> Self {
+        let mut text = String::new();
+        let mut positions = Vec::new();
+
+        for (i, line) in lines.into_iter().enumerate() {
+            let mut last_col = 0;
+            for (j, cell) in line.iter().enumerate() {
+                if cell.width == 0 {
+                    continue;
+                }
+                if cell.ch == '\n' {
+                    break;
+                }
+                positions.push((text.len(), i, j));
+                text.push(cell.ch);
+                last_col = j + cell.width as usize;
+            }
+
+            if !line.linewrap() {
+                positions.push((text.len(), i, last_col));
+                text.push('\n');
+            }
+        }
+
+        Dump { text, positions }
+    }
+
+    /// Returns the cell position of the character at the byte offset
+    pub fn position(&self, offset: usize) -> Option<(usize, usize)> {
+        let i = match self.positions.binary_search_by_key(&offset, |&(o, _, _)| o) {
+            Ok(i) => i,
+            Err(i) => i.checked_sub(1)?,
+        };
+        let (_, row, col) = self.positions[i];
+        Some((row, col))
+    }
+}
+
+/// A match found in dumped text
+#[derive(Debug, PartialEq, Eq)]
+pub struct Match {
+    pub text: String,
+    // cell positions of the first and the last character
+    pub first: (usize, usize),
+    pub last: (usize, usize),
+}
+
+/// Finds non-overlapping matches of the pattern in the dumped text
+pub fn find_matches(dump: &Dump, re: &regex::Regex) -> Vec<Match> {
+    re.find_iter(&dump.text)
+        .filter(|m| !m.as_str().is_empty())
+        .filter_map(|m| {
+            let last_char = m.as_str().char_indices().last()?.0;
+            Some(Match {
+                text: m.as_str().to_owned(),
+                first: dump.position(m.start())?,
+                last: dump.position(m.start() + last_char)?,
+            })
+        })
+        .collect()
+}
+
+/// Finds URLs (`http://` or `https://`) in the dumped text.
+/// Punctuation at the end and unbalanced closing brackets are not regarded as a part of URLs.
+pub fn find_urls(dump: &Dump) -> Vec<Match> {
+    lazy_static::lazy_static! {
+        static ref URL: regex::Regex = regex::Regex::new(r#"https?://[^\s<>"'`]+"#).unwrap();
+    }
+
+    URL.find_iter(&dump.text)
+        .filter_map(|m| {
+            let url = trim_url(m.as_str());
+            let last_char = url.char_indices().last()?.0;
+            Some(Match {
+                text: url.to_owned(),
+                first: dump.position(m.start())?,
+                last: dump.position(m.start() + last_char)?,
+            })
+        })
+        .collect()
+}
+
+fn trim_url(mut url: &str) -> &str {
+    while let Some(last) = url.chars().last() {
+        let trimmed = match last {
+            '.' | ',' | ':' | ';' | '!' | '?' => true,
+            ')' => url.matches('(').count() < url.matches(')').count(),
+            ']' => url.matches('[').count() < url.matches(']').count(),
+            _ => false,
+        };
+        if !trimmed {
+            break;
+        }
+        url = &url[..url.len() - last.len_utf8()];
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Cell;
+
+    fn line(s: &str, cols: usize) -> Line {
+        let mut cells: Vec<Cell> = s.chars().map(Cell::new_ascii).collect();
+        cells.resize(cols, Cell::new_ascii('\n'));
+        cells.into_iter().collect()
+    }
+
+    #[test]
+    fn test_find_matches() {
+        let lines = vec![
+            line("$ ip addr", 20),
+            line("inet 192.168.0.10/24", 20),
+            line("inet 10.0.0.1/8", 20),
+        ];
+        let dump = Dump::new(&lines);
+
+        let re = regex::Regex::new(r"\d+\.\d+\.\d+\.\d+").unwrap();
+        let matches = find_matches(&dump, &re);
+        assert_eq!(
+            matches,
+            vec![
+                Match {
+                    text: "192.168.0.10".to_owned(),
+                    first: (1, 5),
+                    last: (1, 16),
+                },
+                Match {
+                    text: "10.0.0.1".to_owned(),
+                    first: (2, 5),
+                    last: (2, 12),
+                },
+            ]
+        );
+
+        // lines are separated by newlines
+        let re = regex::Regex::new(r"addr\ninet").unwrap();
+        let matches = find_matches(&dump, &re);
+        assert_eq!(matches[0].first, (0, 5));
+        assert_eq!(matches[0].last, (1, 3));
+
+        let re = regex::Regex::new(r"https?://\S+").unwrap();
+        assert!(find_matches(&dump, &re).is_empty());
+    }
+
+    #[test]
+    fn test_find_urls() {
+        let mut wrapped = line("see https://exa", 15);
+        wrapped.set_linewrap(true);
+        let lines = vec![
+            wrapped,
+            line("mple.com/a_(b). (http://x.org/y)", 40),
+            line("<https://[::1]:8080/>, ftp://no", 40),
+        ];
+        let dump = Dump::new(&lines);
+
+        let urls = find_urls(&dump);
+        let texts: Vec<&str> = urls.iter().map(|m| m.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "https://example.com/a_(b)",
+                "http://x.org/y",
+                "https://[::1]:8080/"
+            ]
+        );
+
+        // a URL wrapped across lines
+        assert_eq!((urls[0].first, urls[0].last), ((0, 4), (1, 13)));
+        assert_eq!((urls[1].first, urls[1].last), ((1, 17), (1, 30)));
+    }
+}
@@ -0,0 +1,548 @@
+//! Persistent sessions (detach/reattach)
+//!
+//! A session server (`toyterm --server`) keeps PTYs alive while no GUI is attached to them.
+//! The GUI remains the owner of the screen state; on detach (the `detach` key of the
+//! multiplexer) it hands the PTY master over a Unix socket (as `SCM_RIGHTS` ancillary data)
+//! together with the PID of the shell, and the server holds the fd so that the shell doesn't
+//! receive SIGHUP. The server is started on the first detach if it's not running.
+//! On attach (`toyterm --attach[=NAME]`), the server sends the fd back and forgets the session.
+//! The screen contents are not transferred: the shell (or the foreground application) redraws
+//! them after the window size is set again.
+//!
+//! Every message is a single JSON object sent by one `sendmsg`.
+//!
+//! The socket is placed in a directory accessible only by the user, and both sides check that
+//! the peer is run by the same user (`SO_PEERCRED`), since PTYs are passed through it.
+
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
+use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _, PermissionsExt as _};
+use std::os::unix::io::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MAX_MESSAGE_LEN: usize = 0x1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    /// Keeps the PTY attached to this message as a session
+    Detach {
+        name: String,
+        pid: i32,
+    },
+    /// Takes the PTY of the session back (the most recently detached one if `name` is None)
+    Attach {
+        name: Option<String>,
+    },
+    List,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    Detached,
+    /// The PTY is attached to this message
+    Attached {
+        pid: i32,
+    },
+    Sessions(Vec<SessionInfo>),
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub name: String,
+    pub pid: i32,
+}
+
+struct Session<T> {
+    info: SessionInfo,
+    pty: T,
+}
+
+/// Detached sessions held by the server
+pub struct SessionRegistry<T> {
+    sessions: Vec<Session<T>>,
+}
+
+impl<T> Default for SessionRegistry<T> {
+    fn default() -> Self {
+        SessionRegistry {
+            sessions: Vec::new(),
+        }
+    }
+}
+
+impl<T> SessionRegistry<T> {
+    /// Processes a request. `pty` is the fd received along with the request,
+    /// and the returned one will be sent back along with the response.
+    pub fn handle(&mut self, req: Request, pty: Option<T>) -> (Response, Option<T>) {
+        match req {
+            Request::Detach { name, pid } => {
+                let pty = match pty {
+                    Some(pty) => pty,
+                    None => return (Response::Error("no PTY is given".to_owned()), None),
+                };
+
+                if self.sessions.iter().any(|s| s.info.name == name) {
+                    let msg = format!("session {name:?} already exists");
+                    return (Response::Error(msg), None);
+                }
+
+                log::info!("detached: {:?} (pid={})", name, pid);
+                let info = SessionInfo { name, pid };
+                self.sessions.push(Session { info, pty });
+                (Response::Detached, None)
+            }
+
+            Request::Attach { name } => {
+                let found = match &name {
+                    Some(name) => self.sessions.iter().position(|s| &s.info.name == name),
+                    None => self.sessions.len().checked_sub(1),
+                };
+                match found {
+                    Some(i) => {
+                        let session = self.sessions.remove(i);
+                        log::info!("attached: {:?}", session.info.name);
+                        let pid = session.info.pid;
+                        (Response::Attached { pid }, Some(session.pty))
+                    }
+                    None => {
+                        let msg = match name {
+                            Some(name) => format!("session {name:?} not found"),
+                            None => "no session is detached".to_owned(),
+                        };
+                        (Response::Error(msg), None)
+                    }
+                }
+            }
+
+            Request::List => {
+                let list = self.sessions.iter().map(|s| s.info.clone()).collect();
+                (Response::Sessions(list), None)
+            }
+        }
+    }
+}
+
+/// Returns the path of the server socket, which is in `$XDG_RUNTIME_DIR` or in a private
+/// directory created in the temporary directory
+pub fn socket_path() -> Result<PathBuf> {
+    let uid = nix::unistd::getuid();
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let dir = std::env::temp_dir().join(format!("toyterm-{uid}"));
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+            dir
+        }
+    };
+    check_private_dir(&dir)?;
+    Ok(dir.join(format!("toyterm-{uid}.sock")))
+}
+
+/// Fails unless `dir` is a directory (not a symlink) owned by the user and closed to others
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let meta = std::fs::symlink_metadata(dir)?;
+    let private = meta.permissions().mode() & 0o077 == 0;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || !private {
+        let msg = format!("{} is not a private directory", dir.display());
+        return Err(Error::new(ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+/// Fails if the socket exists and is owned by another user
+fn check_socket_owner(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.uid() != nix::unistd::getuid().as_raw() => {
+            let msg = format!("{} is owned by another user", path.display());
+            Err(Error::new(ErrorKind::PermissionDenied, msg))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails unless the peer of the stream is run by the same user
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    let cred = getsockopt(stream.as_raw_fd(), PeerCredentials)?;
+    if cred.uid() != nix::unistd::getuid().as_raw() {
+        let msg = format!("the peer is run by another user (uid={})", cred.uid());
+        return Err(Error::new(ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+/// Connects to the server at `path` after checking the owner of the socket
+fn connect_to(path: &Path) -> Result<UnixStream> {
+    check_socket_owner(path)?;
+    UnixStream::connect(path)
+}
+
+/// Starts the session server, which serves each client on its own thread.
+/// The returned handle finishes only if the listener fails.
+pub fn serve(path: &Path) -> Result<std::thread::JoinHandle<()>> {
+    // Remove the socket left by the previous server
+    check_socket_owner(path)?;
+    if UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+
+    let listener = UnixListener::bind(path)?;
+    log::info!("listening on {}", path.display());
+
+    let registry = Arc::new(Mutex::new(SessionRegistry::<OwnedFd>::default()));
+    let handle = std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("accept: {}", err);
+                    continue;
+                }
+            };
+
+            // A client which doesn't send anything blocks only its own thread
+            let registry = registry.clone();
+            std::thread::spawn(move || serve_client(&stream, &registry));
+        }
+    });
+    Ok(handle)
+}
+
+fn serve_client(stream: &UnixStream, registry: &Mutex<SessionRegistry<OwnedFd>>) {
+    if let Err(err) = check_peer(stream) {
+        log::error!("Refused a client: {}", err);
+        return;
+    }
+
+    let (req, pty) = match recv_message(stream) {
+        Ok(received) => received,
+        Err(err) => {
+            log::error!("Failed to receive a request: {}", err);
+            return;
+        }
+    };
+
+    let (res, pty) = registry.lock().unwrap().handle(req, pty);
+    let fd = pty.as_ref().map(|fd| fd.as_raw_fd());
+    if let Err(err) = send_message(stream, &res, fd) {
+        log::error!("Failed to send a response: {}", err);
+    }
+}
+
+/// Connects to the server, starting it if it's not running
+pub fn connect() -> Result<UnixStream> {
+    let path = socket_path()?;
+    check_socket_owner(&path)?;
+    if let Ok(stream) = UnixStream::connect(&path) {
+        return Ok(stream);
+    }
+
+    log::info!("starting the session server");
+    spawn_server()?;
+
+    // Wait for the server to listen on the socket
+    let mut last_err = None;
+    for _ in 0..50 {
+        match connect_to(&path) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    Err(last_err.unwrap())
+}
+
+/// Runs `toyterm --server` in a new session, so that it outlives this process
+fn spawn_server() -> Result<()> {
+    use std::os::unix::process::CommandExt as _;
+    use std::process::{Command, Stdio};
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("--server")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    // Safety: setsid is async-signal-safe
+    unsafe {
+        command.pre_exec(|| nix::unistd::setsid().map(drop).map_err(Error::from));
+    }
+    let mut child = command.spawn()?;
+
+    // Reap the server if it exits while this process is running
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+/// Sends a request to the server and waits for the response
+pub fn request(
+    stream: &UnixStream,
+    req: &Request,
+    pty: Option<RawFd>,
+) -> Result<(Response, Option<OwnedFd>)> {
+    // The PTY must not be passed to (or taken from) a server run by someone else
+    check_peer(stream)?;
+    send_message(stream, req, pty)?;
+    recv_message(stream)
+}
+
+/// Hands the PTY of a shell over to the server
+pub fn detach(stream: &UnixStream, name: &str, pid: i32, pty: RawFd) -> Result<()> {
+    let req = Request::Detach {
+        name: name.to_owned(),
+        pid,
+    };
+    match request(stream, &req, Some(pty))? {
+        (Response::Detached, _) => Ok(()),
+        (Response::Error(msg), _) => Err(Error::new(ErrorKind::Other, msg)),
+        (res, _) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected response: {res:?}"),
+        )),
+    }
+}
+
+/// Takes the PTY and the PID of the shell of a detached session back from the server
+pub fn attach(name: Option<&str>) -> Result<(OwnedFd, i32)> {
+    let stream = connect_to(&socket_path()?)?;
+    let req = Request::Attach {
+        name: name.map(str::to_owned),
+    };
+    match request(&stream, &req, None)? {
+        (Response::Attached { pid }, Some(pty)) => Ok((pty, pid)),
+        (Response::Error(msg), _) => Err(Error::new(ErrorKind::Other, msg)),
+        (res, _) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected response: {res:?}"),
+        )),
+    }
+}
+
+fn send_message<M: Serialize>(stream: &UnixStream, msg: &M, fd: Option<RawFd>) -> Result<()> {
+    let bytes = serde_json::to_vec(msg)?;
+    if bytes.len() > MAX_MESSAGE_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, "message too long"));
+    }
+
+    let iov = [IoSlice::new(&bytes)];
+    let fds: Vec<RawFd> = fd.into_iter().collect();
+    let cmsgs: Vec<ControlMessage> = if fds.is_empty() {
+        Vec::new()
+    } else {
+        vec![ControlMessage::ScmRights(&fds)]
+    };
+
+    sendmsg::<()>(stream.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+fn recv_message<M>(stream: &UnixStream) -> Result<(M, Option<OwnedFd>)>
+where
+    M: for<'de> Deserialize<'de>,
+{
+    let mut buf = vec![0_u8; MAX_MESSAGE_LEN];
+    let mut cmsg_buf = nix::cmsg_space!(RawFd);
+
+    let (nb, fd) = {
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        let mut fd = None;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                for raw in fds {
+                    // Safety: the received fd is owned only here
+                    let owned = unsafe { OwnedFd::from_raw_fd(raw) };
+                    fd.get_or_insert(owned);
+                }
+            }
+        }
+        (msg.bytes, fd)
+    };
+
+    let msg = serde_json::from_slice(&buf[..nb])?;
+    Ok((msg, fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detach_request(name: &str, pid: i32) -> Request {
+        Request::Detach {
+            name: name.to_owned(),
+            pid,
+        }
+    }
+
+    fn attach_request(name: &str) -> Request {
+        Request::Attach {
+            name: Some(name.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut reg = SessionRegistry::default();
+
+        assert_eq!(
+            reg.handle(detach_request("a", 10), Some(100)),
+            (Response::Detached, None)
+        );
+        assert_eq!(
+            reg.handle(detach_request("b", 20), Some(200)),
+            (Response::Detached, None)
+        );
+
+        // names must be unique, and a PTY is required
+        assert!(matches!(
+            reg.handle(detach_request("a", 30), Some(300)),
+            (Response::Error(_), None)
+        ));
+        assert!(matches!(
+            reg.handle(detach_request("c", 30), None),
+            (Response::Error(_), None)
+        ));
+
+        let (res, _) = reg.handle(Request::List, None);
+        let expected = vec![
+            SessionInfo {
+                name: "a".to_owned(),
+                pid: 10,
+            },
+            SessionInfo {
+                name: "b".to_owned(),
+                pid: 20,
+            },
+        ];
+        assert_eq!(res, Response::Sessions(expected));
+
+        // attaching takes the PTY back and forgets the session
+        assert_eq!(
+            reg.handle(attach_request("a"), None),
+            (Response::Attached { pid: 10 }, Some(100))
+        );
+        assert!(matches!(
+            reg.handle(attach_request("a"), None),
+            (Response::Error(_), None)
+        ));
+
+        let (res, _) = reg.handle(Request::List, None);
+        let expected = vec![SessionInfo {
+            name: "b".to_owned(),
+            pid: 20,
+        }];
+        assert_eq!(res, Response::Sessions(expected));
+
+        // without a name, the most recently detached session is attached
+        reg.handle(detach_request("c", 30), Some(300));
+        let latest = Request::Attach { name: None };
+        assert_eq!(
+            reg.handle(latest.clone(), None),
+            (Response::Attached { pid: 30 }, Some(300))
+        );
+        assert_eq!(
+            reg.handle(latest.clone(), None),
+            (Response::Attached { pid: 20 }, Some(200))
+        );
+        assert!(matches!(
+            reg.handle(latest, None),
+            (Response::Error(_), None)
+        ));
+    }
+
+    #[test]
+    fn test_server() {
+        let dir = std::env::temp_dir().join(format!("toyterm-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        serve(&path).unwrap();
+
+        // a client which never sends a request doesn't block the others
+        let _idle = UnixStream::connect(&path).unwrap();
+
+        let (r, w) = nix::unistd::pipe().unwrap();
+        let stream = UnixStream::connect(&path).unwrap();
+        detach(&stream, "pipe", 42, w).unwrap();
+        nix::unistd::close(w).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let (res, fd) = request(&stream, &attach_request("pipe"), None).unwrap();
+        assert_eq!(res, Response::Attached { pid: 42 });
+
+        // the writer end of the pipe has been kept by the server
+        let fd = fd.expect("fd");
+        nix::unistd::write(fd.as_raw_fd(), b"kept").unwrap();
+        let mut buf = [0; 4];
+        nix::unistd::read(r, &mut buf).unwrap();
+        assert_eq!(&buf, b"kept");
+        nix::unistd::close(r).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_private_dir() {
+        let dir = std::env::temp_dir().join(format!("toyterm-private-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        check_private_dir(&dir).unwrap();
+
+        // directories open to others and symlinks are refused
+        let set_mode = |mode| {
+            let perm = std::fs::Permissions::from_mode(mode);
+            std::fs::set_permissions(&dir, perm).unwrap();
+        };
+        set_mode(0o755);
+        assert!(check_private_dir(&dir).is_err());
+        set_mode(0o700);
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(check_private_dir(&link).is_err());
+
+        // the peer of a stream created here is the same user
+        let (a, _b) = UnixStream::pair().unwrap();
+        check_peer(&a).unwrap();
+        check_socket_owner(&link).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_message_with_fd() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (r, w) = nix::unistd::pipe().unwrap();
+
+        send_message(&a, &detach_request("x", 1), Some(w)).unwrap();
+        nix::unistd::close(w).unwrap();
+
+        let (req, fd): (Request, _) = recv_message(&b).unwrap();
+        assert_eq!(req, detach_request("x", 1));
+
+        // the received fd refers to the same pipe
+        let fd = fd.expect("fd");
+        nix::unistd::write(fd.as_raw_fd(), b"hello").unwrap();
+        let mut buf = [0; 5];
+        nix::unistd::read(r, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        nix::unistd::close(r).unwrap();
+
+        // messages without fd
+        send_message(&a, &Response::Detached, None).unwrap();
+        let (res, fd): (Response, _) = recv_message(&b).unwrap();
+        assert_eq!(res, Response::Detached);
+        assert!(fd.is_none());
+    }
+}
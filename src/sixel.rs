@@ -2,13 +2,22 @@
 
 use std::iter::Peekable;
 
-const PIXEL_SIZE: usize = 3; // RGB
+const PIXEL_SIZE: usize = 4; // RGBA
+
+// the largest aspect ratio of a pixel accepted from the raster attributes
+const MAX_PIXEL_SCALE: u64 = 16;
+
+// the largest width and height of an image (pixels beyond them are dropped)
+const MAX_SIZE: u64 = 8192;
 
 #[derive(Debug, Clone, Default)]
 pub struct Image {
     pub width: u64,
     pub height: u64,
     pub data: Vec<u8>,
+    // the size of each pixel on the screen (given by the raster attributes as the aspect ratio)
+    pub pixel_width: u64,
+    pub pixel_height: u64,
 }
 
 impl Image {
@@ -20,14 +29,53 @@ impl Image {
             height: (height + 5) / 6 * 6,
 
             data: vec![0_u8; PIXEL_SIZE * (width * height) as usize],
+
+            pixel_width: 1,
+            pixel_height: 1,
         }
     }
 
+    /// Returns the width of the image on the screen, which is at most `max_width`
+    pub fn display_width(&self, max_width: u64) -> u64 {
+        let scale = self.pixel_width.min(MAX_PIXEL_SCALE);
+        self.width.saturating_mul(scale).min(max_width)
+    }
+
+    /// Returns the height of the image on the screen, which is at most `max_height`
+    pub fn display_height(&self, max_height: u64) -> u64 {
+        let scale = self.pixel_height.min(MAX_PIXEL_SCALE);
+        self.height.saturating_mul(scale).min(max_height)
+    }
+
+    /// Returns true if the image has no transparent pixel
+    #[cfg(test)]
+    pub fn is_opaque(&self) -> bool {
+        self.data.chunks(PIXEL_SIZE).all(|pixel| pixel[3] == 0xFF)
+    }
+
     fn resize(&mut self, new_width: u64, new_height: u64) {
+        let new_width = new_width.min(MAX_SIZE);
+        let new_height = (new_height.min(MAX_SIZE) + 5) / 6 * 6;
+
+        if new_width != self.width {
+            // Re-layout each line so that existing pixels keep their positions
+            let old_line = PIXEL_SIZE * self.width as usize;
+            let new_line = PIXEL_SIZE * new_width as usize;
+            let mut data = vec![0_u8; new_line * new_height as usize];
+            if old_line > 0 {
+                for (src, dst) in self.data.chunks(old_line).zip(data.chunks_mut(new_line)) {
+                    let len = src.len().min(dst.len());
+                    dst[..len].copy_from_slice(&src[..len]);
+                }
+            }
+            self.data = data;
+        } else {
+            let size = PIXEL_SIZE * (new_height * new_width) as usize;
+            self.data.resize(size, 0_u8);
+        }
+
         self.width = new_width;
-        self.height = (new_height + 5) / 6 * 6;
-        let size = PIXEL_SIZE * (self.height * self.width) as usize;
-        self.data.resize(size, 0_u8);
+        self.height = new_height;
     }
 }
 
@@ -60,6 +108,11 @@ impl Parser {
         }
     }
 
+    /// Resets the color registers, which are kept across images otherwise
+    pub fn reset_colors(&mut self) {
+        self.colors.fill(Color::default());
+    }
+
     fn parse_numeric<I: Iterator<Item = char>>(&mut self, iter: &mut Peekable<I>) -> u64 {
         let mut num = 0u64;
         while let Some(digit @ '0'..='9') = iter.peek().copied() {
@@ -125,7 +178,8 @@ impl Parser {
                         match pu {
                             1 => {
                                 // HLS
-                                todo!();
+                                let color = hls_to_rgb(px, py, pz);
+                                Some(Function::DefineColor(reg, color))
                             }
                             2 => {
                                 // RGB
@@ -173,8 +227,10 @@ impl Parser {
         }
     }
 
-    /// Decodes sixel string
-    pub fn decode<I>(&mut self, iter: &mut I) -> Image
+    /// Decodes sixel string.
+    /// Pixels which are not painted are left transparent if `transparent` is true (P2 = 1),
+    /// otherwise they are filled with the color of the register 0.
+    pub fn decode<I>(&mut self, iter: &mut I, transparent: bool) -> Image
     where
         I: Iterator<Item = char>,
     {
@@ -184,22 +240,21 @@ impl Parser {
         let mut color = Color::default();
         let mut x: u64 = 0;
         let mut y: u64 = 0;
-        let mut pixel_w: u64 = 1;
-        let mut pixel_h: u64 = 1;
 
         while let Some(func) = self.parse(&mut iter) {
             match func {
                 Function::RasterAttributes(pan, pad, ph, pv) => {
-                    pixel_h = pan;
-                    pixel_w = pad;
-                    img.resize(pixel_w * ph, pixel_h * pv);
+                    // The pixels are stored as they are, and scaled when the image is drawn
+                    img.pixel_height = pan.clamp(1, MAX_PIXEL_SCALE);
+                    img.pixel_width = pad.clamp(1, MAX_PIXEL_SCALE);
+                    img.resize(ph, pv);
                     log::debug!("buffer size changed: w={}, h={}", img.width, img.height);
                 }
                 Function::CarriageReturn => {
                     x = 0;
                 }
                 Function::NewLine => {
-                    y += pixel_h * 6;
+                    y += 6;
                     x = 0;
                 }
                 Function::SelectColor(reg) => {
@@ -209,33 +264,24 @@ impl Parser {
                     self.colors[reg as usize] = c;
                 }
                 Function::Sixel { bits, repeat } => {
-                    let total = PIXEL_SIZE * ((y + pixel_h * 6) * img.width) as usize;
+                    let end = x.saturating_add(repeat as u64).min(MAX_SIZE);
 
-                    if img.data.len() < total {
-                        let each_line = PIXEL_SIZE * img.width as usize;
-                        let new_height = ((total + each_line - 1) / each_line) as u64;
-                        img.resize(img.width, new_height);
-                        log::debug!("image height changed: h={}", new_height);
+                    if img.width < end || img.height < y + 6 {
+                        img.resize(img.width.max(end), img.height.max(y + 6));
+                        log::debug!("image size changed: w={}, h={}", img.width, img.height);
                     }
 
-                    for _ in 0..(pixel_w as usize) * repeat {
-                        // FIXME
-                        if x >= img.width {
-                            log::debug!("line overflow");
-                            break;
-                        }
-
+                    while x < end {
                         for i in 0..6 {
                             if ((bits >> i) & 1) == 0 {
                                 continue;
                             }
 
-                            for k in 0..pixel_h {
-                                let y = y + i * pixel_h + k;
-                                let offset = PIXEL_SIZE * (y * img.width + x) as usize;
-                                img.data[offset + 0] += color.r;
-                                img.data[offset + 1] += color.g;
-                                img.data[offset + 2] += color.b;
+                            let y = y + i;
+                            let offset = PIXEL_SIZE * (y * img.width + x) as usize;
+                            // the pixel gets the selected color, overwriting the previous one
+                            if let Some(pixel) = img.data.get_mut(offset..offset + PIXEL_SIZE) {
+                                pixel.copy_from_slice(&[color.r, color.g, color.b, 0xFF]);
                             }
                         }
 
@@ -245,10 +291,49 @@ impl Parser {
             }
         }
 
+        if !transparent {
+            let bg = self.colors[0];
+            for pixel in img.data.chunks_mut(PIXEL_SIZE) {
+                if pixel[3] == 0 {
+                    pixel.copy_from_slice(&[bg.r, bg.g, bg.b, 0xFF]);
+                }
+            }
+        }
+
         img
     }
 }
 
+/// Converts a color in the HLS color space of DEC terminals to RGB.
+/// The hue is an angle (0-360) where blue is at 0, red at 120 and green at 240,
+/// and the lightness and the saturation are percentages (0-100).
+fn hls_to_rgb(h: u64, l: u64, s: u64) -> Color {
+    // rotate the hue so that red is at 0 as usual
+    let h = ((h % 360 + 240) % 360) as f64;
+    let l = l.min(100) as f64 / 100.0;
+    let s = s.min(100) as f64 / 100.0;
+
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
+    let m = l - c / 2.0;
+
+    let (r, g, b) = match (h / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    let to_u8 = |v: f64| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    Color {
+        r: to_u8(r),
+        g: to_u8(g),
+        b: to_u8(b),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -259,50 +344,182 @@ mod tests {
         let mut itr = b.chars();
 
         let mut parser = Parser::new();
-        let image = parser.decode(&mut itr);
+        let image = parser.decode(&mut itr, false);
 
         assert_eq!(image.width, 6);
         assert_eq!(image.height, 6);
         assert_eq!(
             image.data,
             vec![
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, //
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, //
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, //
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, //
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, //
-                255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 0, 0, 255,
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255, //
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255, //
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255, //
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255, //
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255, //
+                255, 0, 0, 255, 255, 0, 0, 255, 0, 255, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 0,
+                0, 255, 255,
             ]
         );
 
         let b = "\"1;1;10;10\x1b\\";
         let mut itr = b.chars();
-        let image = parser.decode(&mut itr);
+        let image = parser.decode(&mut itr, false);
         assert_eq!(image.width, 10);
         assert_eq!(image.height, 12);
 
         let b = "~~~~~~-~~~~~~\x1b\\";
         let mut itr = b.chars();
-        let image = parser.decode(&mut itr);
+        let image = parser.decode(&mut itr, false);
         assert_eq!(image.width, 6);
         assert_eq!(image.height, 12);
 
         let b = "\"1;1;6;6~~~~~~-~~~~~~-???-!6~\x1b\\";
         let mut itr = b.chars();
-        let image = parser.decode(&mut itr);
+        let image = parser.decode(&mut itr, false);
         assert_eq!(image.width, 6);
         assert_eq!(image.height, 24);
 
         let b = "\"2;2;10;10\x1b\\";
         let mut itr = b.chars();
-        let image = parser.decode(&mut itr);
-        assert_eq!(image.width, 20);
-        assert_eq!(image.height, 24);
+        let image = parser.decode(&mut itr, false);
+        assert_eq!(image.display_width(u64::MAX), 20);
+        assert_eq!(image.display_height(u64::MAX), 24);
 
         let b = "\"2;3;6;6~~~~~~-~~~~~~-???-!6~\x1b\\";
         let mut itr = b.chars();
-        let image = parser.decode(&mut itr);
-        assert_eq!(image.width, 18);
-        assert_eq!(image.height, 48);
+        let image = parser.decode(&mut itr, false);
+        assert_eq!(image.display_width(u64::MAX), 18);
+        assert_eq!(image.display_height(u64::MAX), 48);
+    }
+
+    #[test]
+    fn test_aspect_ratio() {
+        let display_size = |image: &Image| {
+            let max = u64::MAX;
+            (image.display_width(max), image.display_height(max))
+        };
+
+        // pixels twice as tall as wide are stored once, and scaled on the screen
+        let b = "\"2;1;2;6#1;2;100;0;0#1~\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!((image.width, image.height), (2, 6));
+        assert_eq!((image.pixel_width, image.pixel_height), (1, 2));
+        assert_eq!(display_size(&image), (2, 12));
+        assert_eq!(image.data.len(), 2 * 6 * PIXEL_SIZE);
+        assert_eq!(&image.data[..8], &[255, 0, 0, 255, 0, 0, 0, 0]);
+
+        // a zero aspect is regarded as 1
+        let b = "\"0;0;1;6\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!(display_size(&image), (1, 6));
+
+        // the aspect ratio is limited, and the size on the screen as well
+        let b = "\"100000;1;1;6\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!(display_size(&image), (1, 6 * MAX_PIXEL_SCALE));
+        assert_eq!((image.display_width(0), image.display_height(50)), (0, 50));
+    }
+
+    #[test]
+    fn test_size_limit() {
+        // a huge repeat count or raster doesn't allocate beyond the limit
+        let b = "!4000000000?\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!((image.width, image.height), (MAX_SIZE, 6));
+
+        let b = "#1;2;100;0;0#1!99999999999999999999999~!9~\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!((image.width, image.height), (MAX_SIZE, 6));
+        assert_eq!(&image.data[..4], &[255, 0, 0, 255]);
+
+        let b = "\"1;1;99999999999;99999999999~\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert_eq!(image.width, MAX_SIZE);
+        assert_eq!(image.height, (MAX_SIZE + 5) / 6 * 6);
+        assert_eq!(
+            image.data.len(),
+            PIXEL_SIZE * (image.width * image.height) as usize
+        );
+    }
+
+    #[test]
+    fn test_persistent_colors() {
+        let mut parser = Parser::new();
+
+        // the second image uses the color defined in the first one
+        let b = "\"1;1;1;6#5;2;0;100;0#5~\x1b\\";
+        let image = parser.decode(&mut b.chars(), false);
+        assert_eq!(&image.data[..4], &[0, 255, 0, 255]);
+        let b = "\"1;1;1;6#5~\x1b\\";
+        let image = parser.decode(&mut b.chars(), false);
+        assert_eq!(&image.data[..4], &[0, 255, 0, 255]);
+
+        parser.reset_colors();
+        let image = parser.decode(&mut b.chars(), false);
+        assert_eq!(&image.data[..4], &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_overwrite_pixels() {
+        // the same cells are drawn in red, and then in blue
+        let b = "\"1;1;2;6#1;2;100;0;0#2;2;0;0;100#1~~$#2~~\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), false);
+        assert_eq!((image.width, image.height), (2, 6));
+        for pixel in image.data.chunks(PIXEL_SIZE) {
+            assert_eq!(pixel, [0, 0, 255, 255]);
+        }
+    }
+
+    #[test]
+    fn test_background_select() {
+        // only the upper half of the second column is painted
+        let b = "\"1;1;2;6#0;2;0;100;0#1;2;100;0;0#1?F\x1b\\";
+        let pixel = |image: &Image, x: u64, y: u64| {
+            let offset = PIXEL_SIZE * (y * image.width + x) as usize;
+            image.data[offset..offset + PIXEL_SIZE].to_vec()
+        };
+
+        // unpainted pixels are filled with the color 0
+        let image = Parser::new().decode(&mut b.chars(), false);
+        assert!(image.is_opaque());
+        assert_eq!(pixel(&image, 0, 0), [0, 255, 0, 255]);
+        assert_eq!(pixel(&image, 1, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(&image, 1, 5), [0, 255, 0, 255]);
+
+        // or left transparent
+        let image = Parser::new().decode(&mut b.chars(), true);
+        assert!(!image.is_opaque());
+        assert_eq!(pixel(&image, 0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&image, 1, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(&image, 1, 5), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_hls_colors() {
+        let rgb = |r, g, b| Color { r, g, b };
+
+        // blue, red and green are at 0, 120 and 240 degrees respectively
+        assert_eq!(hls_to_rgb(0, 50, 100), rgb(0, 0, 255));
+        assert_eq!(hls_to_rgb(120, 50, 100), rgb(255, 0, 0));
+        assert_eq!(hls_to_rgb(240, 50, 100), rgb(0, 255, 0));
+        assert_eq!(hls_to_rgb(360, 50, 100), rgb(0, 0, 255));
+        assert_eq!(hls_to_rgb(180, 50, 100), rgb(255, 255, 0));
+
+        // lightness and saturation
+        assert_eq!(hls_to_rgb(0, 0, 100), rgb(0, 0, 0));
+        assert_eq!(hls_to_rgb(0, 100, 100), rgb(255, 255, 255));
+        assert_eq!(hls_to_rgb(90, 50, 0), rgb(128, 128, 128));
+        assert_eq!(hls_to_rgb(60, 25, 50), rgb(96, 32, 96));
+
+        // a palette entry defined in HLS
+        let b = "\"1;1;2;6#1;1;120;50;100#2;1;240;25;100#1~#2~\x1b\\";
+        let image = Parser::new().decode(&mut b.chars(), false);
+        assert_eq!(image.width, 2);
+        assert_eq!(&image.data[..8], &[255, 0, 0, 255, 0, 128, 0, 255]);
     }
 }
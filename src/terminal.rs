@@ -1,24 +1,35 @@
 use nix::errno::Errno;
 use nix::unistd::Pid;
 use std::cmp::{max, min};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Result;
 use std::ops::{Range, RangeBounds};
 use std::os::unix::io::{AsRawFd as _, FromRawFd as _, OwnedFd};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::control_function;
+use crate::control_function::{self, Charset, ColorSpec, PromptMark};
+use crate::kitty;
 use crate::pipe_channel;
 use crate::utils::io::FdIo;
 use crate::utils::utf8;
 
+/// Hyperlinks are garbage-collected after this number of lines with them are evicted
+const LINK_GC_INTERVAL: usize = 64;
+
 #[derive(Debug, Clone)]
 pub struct PositionedImage {
     pub row: isize,
     pub col: isize,
+    // the size on the screen
     pub height: u64,
     pub width: u64,
+    // the size of `data` (RGBA), which is scaled to the size on the screen
+    pub data_height: u64,
+    pub data_width: u64,
     pub data: Vec<u8>,
+    // the image id given by the kitty graphics protocol (0 for sixel images)
+    pub id: u32,
 }
 
 fn overwrap(outer: &PositionedImage, inner: &PositionedImage) -> bool {
@@ -81,7 +92,7 @@ impl Cell {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Color {
     Black,
     Red,
@@ -99,21 +110,115 @@ pub enum Color {
     BrightMagenta,
     BrightCyan,
     BrightWhite,
-    Rgb { rgba: u32 },
+    Rgb {
+        rgba: u32,
+    },
+    /// An entry of the 256-color palette except the named ones (16-255)
+    Indexed(u8),
+    /// "Gaming" color (SGR 70/80), whose hue changes over time
     Special,
     Foreground,
     Background,
     Selection,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Color {
+    /// Returns the color of the 256-color palette.
+    /// The first 16 colors are the named ones, so they follow the configured palette.
+    pub fn indexed(idx: u8) -> Self {
+        const NAMED: [Color; 16] = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Yellow,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::White,
+            Color::BrightBlack,
+            Color::BrightRed,
+            Color::BrightGreen,
+            Color::BrightYellow,
+            Color::BrightBlue,
+            Color::BrightMagenta,
+            Color::BrightCyan,
+            Color::BrightWhite,
+        ];
+
+        match idx {
+            0..=15 => NAMED[idx as usize],
+            16..=255 => Color::Indexed(idx),
+        }
+    }
+
+    /// Returns the index in the 256-color palette, if the color is one of its entries
+    pub fn palette_index(self) -> Option<u8> {
+        match self {
+            Color::Black => Some(0),
+            Color::Red => Some(1),
+            Color::Green => Some(2),
+            Color::Yellow => Some(3),
+            Color::Blue => Some(4),
+            Color::Magenta => Some(5),
+            Color::Cyan => Some(6),
+            Color::White => Some(7),
+            Color::BrightBlack => Some(8),
+            Color::BrightRed => Some(9),
+            Color::BrightGreen => Some(10),
+            Color::BrightYellow => Some(11),
+            Color::BrightBlue => Some(12),
+            Color::BrightMagenta => Some(13),
+            Color::BrightCyan => Some(14),
+            Color::BrightWhite => Some(15),
+            Color::Indexed(idx) => Some(idx),
+            _ => None,
+        }
+    }
+
+    /// Returns the RGBA of an entry of the 256-color palette (16-255)
+    pub fn indexed_rgba(idx: u8) -> u32 {
+        debug_assert!(idx >= 16, "the named colors are configured");
+        match idx {
+            // the named colors are given by the config (see `Color::indexed`)
+            0..=15 => 0x000000FF,
+
+            // 6x6x6 colors
+            16..=231 => {
+                let mut x = (idx - 16) as u32;
+
+                let b = (x % 6) * 51;
+                x /= 6;
+                let g = (x % 6) * 51;
+                x /= 6;
+                let r = (x % 6) * 51;
+
+                (r << 24) | (g << 16) | (b << 8) | 0xFF
+            }
+
+            // grayscale colors
+            232..=255 => {
+                let x = (idx - 232) as u32;
+                let v = x * 11;
+                (v << 24) | (v << 16) | (v << 8) | 0xFF
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GraphicAttribute {
     pub fg: Color,
     pub bg: Color,
     pub bold: i8,
+    pub italic: bool,
+    pub underline: u8,
+    pub underline_color: Option<Color>,
     pub inversed: bool,
     pub blinking: u8,
     pub concealed: bool,
+    pub strike: bool,
+    pub overline: bool,
+    pub link: Option<u32>,
 }
 
 impl GraphicAttribute {
@@ -122,13 +227,38 @@ impl GraphicAttribute {
             fg: Color::Foreground,
             bg: Color::Background,
             bold: 0,
+            italic: false,
+            underline: 0,
+            underline_color: None,
             inversed: false,
             blinking: 0,
             concealed: false,
+            strike: false,
+            overline: false,
+            link: None,
         }
     }
 }
 
+/// A hyperlink given by OSC 8
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Hyperlink {
+    id: Option<String>,
+    uri: String,
+}
+
+/// The size of a line given by DECSWL, DECDWL or DECDHL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineSize {
+    #[default]
+    Normal,
+    DoubleWidth,
+    /// The top half of a double-height (and double-width) line
+    DoubleHeightTop,
+    /// The bottom half of a double-height (and double-width) line
+    DoubleHeightBottom,
+}
+
 /// A single line of terminal buffer
 ///
 /// A `Line` consists of multiple `Cell`s, which may have different width.
@@ -151,6 +281,9 @@ impl GraphicAttribute {
 pub struct Line {
     cells: Vec<Cell>,
     linewrap: bool,
+    size: LineSize,
+    // the start of a prompt or a command output reported by OSC 133
+    mark: Option<PromptMark>,
 }
 
 impl std::iter::FromIterator<Cell> for Line {
@@ -161,6 +294,8 @@ impl std::iter::FromIterator<Cell> for Line {
         Line {
             cells: iter.into_iter().collect(),
             linewrap: false,
+            size: LineSize::Normal,
+            mark: None,
         }
     }
 }
@@ -170,6 +305,8 @@ impl Line {
         Line {
             cells: vec![Cell::TERM; len],
             linewrap: false,
+            size: LineSize::Normal,
+            mark: None,
         }
     }
 
@@ -181,6 +318,8 @@ impl Line {
             self.cells.extend_from_slice(&src.cells);
         }
         self.linewrap = src.linewrap;
+        self.size = src.size;
+        self.mark = src.mark;
     }
 
     fn saturating_range<R: RangeBounds<usize>>(&self, range: R) -> Range<usize> {
@@ -214,9 +353,23 @@ impl Line {
 
         self.cells.copy_within(src.start..src.start + count, dst);
 
-        let (dst_start, dst_end) = (dst, dst + count);
-
         // Correct boundaries because the above `copy_within` may violates the invariant.
+        self.correct_boundaries(dst, dst + count);
+    }
+
+    /// Overwrites the cells from `at` with the given ones
+    fn write_cells(&mut self, at: usize, cells: &[Cell]) {
+        let count = min(cells.len(), self.cells.len().saturating_sub(at));
+        if count == 0 {
+            return;
+        }
+
+        self.cells[at..at + count].copy_from_slice(&cells[..count]);
+        self.correct_boundaries(at, at + count);
+    }
+
+    /// Replaces wide characters broken by overwriting [dst_start, dst_end) with spaces
+    fn correct_boundaries(&mut self, dst_start: usize, dst_end: usize) {
         {
             // correct ..dst_start)
             if dst_start > 0 {
@@ -240,7 +393,7 @@ impl Line {
             }
 
             // correct [dst_end..
-            let mut i = dst + count;
+            let mut i = dst_end;
             while i < self.cells.len() && self.cells[i].width == 0 {
                 self.cells[i] = Cell::SPACE;
                 i += 1;
@@ -257,6 +410,8 @@ impl Line {
     fn erase_all(&mut self) {
         self.cells.fill(Cell::TERM);
         self.linewrap = false;
+        self.size = LineSize::Normal;
+        self.mark = None;
     }
 
     fn erase_at(&mut self, at: usize) {
@@ -321,6 +476,168 @@ impl Line {
     pub fn linewrap(&self) -> bool {
         self.linewrap
     }
+
+    #[cfg(test)]
+    pub fn set_linewrap(&mut self, linewrap: bool) {
+        self.linewrap = linewrap;
+    }
+
+    pub fn size(&self) -> LineSize {
+        self.size
+    }
+
+    /// Returns true if the line is set by DECDWL or DECDHL,
+    /// where only the left half of the cells are used and each of them is drawn twice as wide.
+    pub fn double_width(&self) -> bool {
+        self.size != LineSize::Normal
+    }
+
+    /// Returns true if nothing is written in the line
+    fn is_empty(&self) -> bool {
+        !self.linewrap && self.cells.iter().all(|c| c.ch == '\n')
+    }
+
+    /// Returns the number of columns available in the line
+    fn visible_columns(&self) -> usize {
+        if self.double_width() {
+            max(self.cells.len() / 2, 1)
+        } else {
+            self.cells.len()
+        }
+    }
+}
+
+/// Joins soft-wrapped lines and wraps them again with `cols` columns.
+/// `cursor` is (row, col) in `lines`, and is moved along with the character under it
+/// (col can be `cols` if the cursor is at the end of a line).
+/// Lines set by DECDWL/DECDHL are just resized.
+fn reflow(
+    lines: Vec<Line>,
+    cols: usize,
+    cursor: Option<(usize, usize)>,
+) -> (Vec<Line>, Option<(usize, usize)>) {
+    let mut out: Vec<Line> = Vec::with_capacity(lines.len());
+    let mut new_cursor = None;
+
+    // cells of the current logical line, and the offset of the cursor in it
+    let mut logical: Vec<Cell> = Vec::new();
+    let mut cursor_offset = None;
+
+    // the mark of the first line of the logical line (OSC 133)
+    let mut logical_mark = None;
+
+    let flush = |logical: &mut Vec<Cell>,
+                 logical_mark: &mut Option<PromptMark>,
+                 cursor_offset: &mut Option<usize>,
+                 out: &mut Vec<Line>,
+                 new_cursor: &mut Option<(usize, usize)>| {
+        let mut line = Line::new(cols);
+        line.mark = logical_mark.take();
+        let mut col = 0;
+        for (i, &cell) in logical.iter().enumerate() {
+            let width = cell.width as usize;
+            if width == 0 {
+                continue;
+            }
+
+            if col + width > cols {
+                line.linewrap = true;
+                out.push(std::mem::replace(&mut line, Line::new(cols)));
+                col = 0;
+            }
+
+            if matches!(*cursor_offset, Some(offset) if i <= offset && offset < i + width) {
+                *new_cursor = Some((out.len(), col));
+            }
+
+            if width <= cols {
+                line.put(col, cell);
+            }
+            col += width;
+        }
+
+        // The cursor is after the last character.
+        // (`cols` means that it's at the end of the line, waiting for the next character to wrap)
+        if let Some(offset) = cursor_offset.take() {
+            if offset >= logical.len() {
+                let extra = offset - logical.len();
+                let col = if extra == 0 {
+                    min(col, cols)
+                } else {
+                    min(col + extra, cols - 1)
+                };
+                *new_cursor = Some((out.len(), col));
+            }
+        }
+
+        out.push(line);
+        logical.clear();
+    };
+
+    for (i, line) in lines.into_iter().enumerate() {
+        let cursor_col = cursor.and_then(|(row, col)| (row == i).then_some(col));
+
+        if line.size != LineSize::Normal {
+            if !logical.is_empty() || cursor_offset.is_some() {
+                flush(
+                    &mut logical,
+                    &mut logical_mark,
+                    &mut cursor_offset,
+                    &mut out,
+                    &mut new_cursor,
+                );
+            }
+
+            let mut line = line;
+            line.resize(cols);
+            if let Some(col) = cursor_col {
+                new_cursor = Some((out.len(), min(col, cols - 1)));
+            }
+            out.push(line);
+            continue;
+        }
+
+        if logical.is_empty() {
+            logical_mark = line.mark;
+        }
+        if let Some(col) = cursor_col {
+            cursor_offset = Some(logical.len() + col);
+        }
+
+        if line.linewrap {
+            logical.extend_from_slice(&line.cells);
+        } else {
+            // Trailing blanks are not a part of the line
+            let blank =
+                |c: &Cell| c.ch == '\n' || (c.ch == ' ' && c.attr == GraphicAttribute::default());
+            let len = line
+                .cells
+                .iter()
+                .rposition(|c| !blank(c))
+                .map_or(0, |i| i + 1);
+            logical.extend_from_slice(&line.cells[..len]);
+            flush(
+                &mut logical,
+                &mut logical_mark,
+                &mut cursor_offset,
+                &mut out,
+                &mut new_cursor,
+            );
+        }
+    }
+
+    // The last line is soft-wrapped
+    if !logical.is_empty() || cursor_offset.is_some() {
+        flush(
+            &mut logical,
+            &mut logical_mark,
+            &mut cursor_offset,
+            &mut out,
+            &mut new_cursor,
+        );
+    }
+
+    (out, new_cursor)
 }
 
 impl std::fmt::Debug for Line {
@@ -338,13 +655,33 @@ impl std::fmt::Debug for Line {
     }
 }
 
+/// A desktop notification requested by the application (OSC 9/777)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub title: Option<String>,
+    pub body: String,
+}
+
+/// The maximum number of notifications kept until the window takes them
+const MAX_PENDING_NOTIFICATIONS: usize = 8;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Mode {
     pub cursor_visible: bool,
     pub bracketed_paste: bool,
     pub mouse_track: bool,
+    pub mouse_track_drag: bool,
+    pub mouse_track_any: bool,
     pub sgr_ext_mouse_track: bool,
+    pub urxvt_ext_mouse_track: bool,
     pub sixel_scrolling: bool,
+    pub insert_mode: bool,
+    pub autowrap: bool,
+    pub modify_other_keys: u16,
+    pub origin_mode: bool,
+    pub reverse_video: bool,
+    pub alt_screen: bool,
+    pub focus_event: bool,
 }
 
 impl Default for Mode {
@@ -353,8 +690,18 @@ impl Default for Mode {
             cursor_visible: true,
             bracketed_paste: false,
             mouse_track: false,
+            mouse_track_drag: false,
+            mouse_track_any: false,
             sgr_ext_mouse_track: false,
+            urxvt_ext_mouse_track: false,
             sixel_scrolling: true,
+            insert_mode: false,
+            autowrap: true,
+            modify_other_keys: 0,
+            origin_mode: false,
+            reverse_video: false,
+            alt_screen: false,
+            focus_event: false,
         }
     }
 }
@@ -369,23 +716,40 @@ pub struct State {
     cursor: Cursor,
     attr: GraphicAttribute,
     size: TerminalSize,
-    history_size: usize,
+    history_capacity: usize,
     mode: Mode,
     scroll_region: (usize, usize),
+    title: Option<String>,
+    cwd: Option<std::path::PathBuf>,
+    links: HashMap<u32, Hyperlink>,
+    next_link_id: u32,
+    current_link: Option<u32>,
+    // the number of lines with hyperlinks evicted from the history since the last `gc_links`
+    evicted_link_lines: usize,
+    // G0 and G1
+    charsets: [Charset; 2],
+    // true while G1 is invoked by SO
+    shift_out: bool,
+    // when BEL is received last (cleared by `take_bell`)
+    bell: Option<Instant>,
+    // desktop notifications requested by OSC 9/777 (cleared by `take_notifications`)
+    notifications: Vec<Notification>,
+    // palette entries redefined by OSC 4 (RRGGBBAA)
+    palette: HashMap<u8, u32>,
+    // the color of the cursor set by OSC 12 (RRGGBBAA)
+    cursor_color: Option<u32>,
 
     pub updated: bool,
     pub exit_status: Option<i32>,
 }
 
 impl State {
-    const HISTORY_CAPACITY: usize = 10000;
-
-    pub fn new(sz: TerminalSize) -> Self {
+    /// `history_capacity` is the maximum number of lines kept in the history (0 disables it)
+    pub fn new(sz: TerminalSize, history_capacity: usize) -> Self {
         assert!(sz.rows > 0 && sz.cols > 0);
 
-        let history: VecDeque<_> = std::iter::repeat_with(|| Line::new(sz.cols))
-            .take(Self::HISTORY_CAPACITY)
-            .collect();
+        // Lines are pushed into the history as they scroll off
+        let history = VecDeque::new();
 
         let lines: VecDeque<_> = std::iter::repeat_with(|| Line::new(sz.cols))
             .take(sz.rows)
@@ -407,9 +771,21 @@ impl State {
             cursor,
             attr: GraphicAttribute::default(),
             size: sz,
-            history_size: 0,
+            history_capacity,
             mode: Mode::default(),
             scroll_region: (0, sz.rows - 1),
+            title: None,
+            cwd: None,
+            links: HashMap::new(),
+            next_link_id: 1,
+            current_link: None,
+            evicted_link_lines: 0,
+            charsets: [Charset::Ascii; 2],
+            shift_out: false,
+            bell: None,
+            notifications: Vec::new(),
+            palette: HashMap::new(),
+            cursor_color: None,
 
             updated: true,
             exit_status: None,
@@ -428,19 +804,157 @@ impl State {
     }
 
     pub fn history_size(&self) -> usize {
-        self.history_size
+        self.history.len()
     }
 
     pub fn mode(&self) -> Mode {
         self.mode
     }
 
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Returns when the bell is rung, if it is rung after the last call
+    pub fn take_bell(&mut self) -> Option<Instant> {
+        self.bell.take()
+    }
+
+    /// Returns the 1-origin cursor position reported by CPR and DECXCPR
+    /// (relative to the scroll region in the origin mode)
+    fn reported_position(&self) -> (usize, usize) {
+        let (mut row, col) = self.cursor.pos();
+        if self.mode.origin_mode {
+            row = row.saturating_sub(self.scroll_region.0);
+        }
+        (row + 1, col + 1)
+    }
+
+    /// Returns the palette entries redefined by the application
+    pub fn palette(&self) -> &HashMap<u8, u32> {
+        &self.palette
+    }
+
+    /// Returns the color of the cursor set by the application, if any
+    pub fn cursor_color(&self) -> Option<u32> {
+        self.cursor_color
+    }
+
+    /// Returns the working directory reported by OSC 7
+    #[cfg(feature = "multiplex")]
+    pub fn cwd(&self) -> Option<&std::path::Path> {
+        self.cwd.as_deref()
+    }
+
+    /// Returns the desktop notifications requested after the last call
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Returns the rows where prompts start (OSC 133 ; A), counted from the top of the history
+    pub fn prompt_rows(&self) -> Vec<usize> {
+        self.history
+            .iter()
+            .chain(&self.lines)
+            .enumerate()
+            .filter(|(_, line)| line.mark == Some(PromptMark::PromptStart))
+            .map(|(row, _)| row)
+            .collect()
+    }
+
     pub fn clear_history(&mut self) {
         self.updated = true;
-        self.history_size = 0;
-        for line in self.history.iter_mut() {
-            line.erase_all();
+        self.history.clear();
+        self.gc_links();
+    }
+
+    /// Writes a line of ASCII text below the output (used after the process has exited).
+    /// The cursor is hidden.
+    #[cfg(feature = "multiplex")]
+    pub fn put_banner(&mut self, text: &str) {
+        self.updated = true;
+
+        let (mut row, col) = self.cursor.pos();
+        if col > 0 || self.cursor.end {
+            if row == self.scroll_region.1 {
+                self.scroll_up();
+            } else {
+                row += 1;
+            }
+        }
+
+        let line = &mut self.lines[row];
+        line.erase_all();
+        let chars = text.chars().filter(char::is_ascii).take(self.size.cols);
+        let mut len = 0;
+        for (i, ch) in chars.enumerate() {
+            let mut cell = Cell::new_ascii(ch);
+            cell.attr.inversed = true;
+            line.put(i, cell);
+            len = i + 1;
+        }
+
+        self.cursor = self.cursor.exact(row, min(len, self.size.cols - 1));
+        self.mode.cursor_visible = false;
+    }
+
+    /// Clears both the screen and the history, and moves the cursor to the home position
+    pub fn clear_all(&mut self) {
+        self.updated = true;
+        self.history.clear();
+        self.clear_screen();
+        self.cursor = self.cursor.exact(0, 0);
+        self.gc_links();
+    }
+
+    /// Returns the URI of the hyperlink
+    pub fn link(&self, id: u32) -> Option<&str> {
+        self.links.get(&id).map(|link| link.uri.as_str())
+    }
+
+    /// Opens a hyperlink, which will be attached to the following characters
+    fn open_link(&mut self, id: Option<String>, uri: String) {
+        let link = Hyperlink { id, uri };
+
+        // Links with the same id and URI share the same entry
+        let found = self
+            .links
+            .iter()
+            .find(|(_, l)| l.id.is_some() && **l == link)
+            .map(|(&k, _)| k);
+
+        let k = found.unwrap_or_else(|| {
+            let k = self.next_link_id;
+            self.next_link_id = self.next_link_id.wrapping_add(1).max(1);
+            self.links.insert(k, link);
+            k
+        });
+        self.current_link = Some(k);
+    }
+
+    fn close_link(&mut self) {
+        self.current_link = None;
+    }
+
+    /// Removes hyperlinks which are no longer referred from any cell
+    fn gc_links(&mut self) {
+        self.evicted_link_lines = 0;
+        if self.links.is_empty() {
+            return;
+        }
+
+        let mut used: HashSet<u32> = self.current_link.into_iter().collect();
+        let lines = self
+            .history
+            .iter()
+            .chain(&self.lines)
+            .chain(&self.alt_lines);
+        for line in lines {
+            used.extend(line.cells.iter().filter_map(|c| c.attr.link));
         }
+
+        self.links.retain(|k, _| used.contains(k));
+        log::debug!("{} links retained", self.links.len());
     }
 
     pub fn range(&self, top: isize, bot: isize) -> impl Iterator<Item = &Line> + '_ {
@@ -452,8 +966,8 @@ impl State {
             let bot = min(bot, buff_len) as usize;
             self.history.range(0..0).chain(self.lines.range(top..bot))
         } else if bot < 0 {
-            let top = max(hist_len + top, 0) as usize;
-            let bot = (hist_len + bot) as usize;
+            let bot = max(hist_len + bot, 0) as usize;
+            let top = min(max(hist_len + top, 0) as usize, bot);
             self.history.range(top..bot).chain(self.lines.range(0..0))
         } else {
             let top = max(hist_len + top, 0) as usize;
@@ -467,11 +981,19 @@ impl State {
     }
 
     fn resize(&mut self, sz: TerminalSize) {
+        // Only the primary screen is reflowed.
+        // Applications on the alternate screen redraw it on their own.
+        if sz.cols != self.size.cols {
+            self.reflow_primary_screen(sz);
+        }
+
         self.size = sz;
 
         let (row, col) = self.cursor.pos();
+        let end = self.cursor.end;
         self.cursor.sz = sz;
         self.cursor = self.cursor.exact(row, col);
+        self.cursor.end = end && col == sz.cols - 1;
 
         for line in self.history.iter_mut() {
             line.resize(sz.cols);
@@ -490,16 +1012,98 @@ impl State {
         self.scroll_region = (0, sz.rows - 1);
     }
 
-    /// Scroll up the buffer by 1 line
+    /// Rewraps the history and the primary screen to the new width.
+    /// The cursor (if it's on the primary screen) follows the character under it.
+    fn reflow_primary_screen(&mut self, sz: TerminalSize) {
+        let on_primary = !self.mode.alt_screen;
+
+        let hist_len = self.history.len();
+        let mut lines: Vec<Line> = self.history.drain(..).collect();
+
+        let primary = if on_primary {
+            &mut self.lines
+        } else {
+            &mut self.alt_lines
+        };
+        lines.extend(primary.drain(..));
+
+        let cursor = on_primary.then(|| {
+            let (row, col) = self.cursor.pos();
+            (hist_len + row, col + self.cursor.end as usize)
+        });
+
+        let (mut lines, cursor) = reflow(lines, sz.cols, cursor);
+
+        // Drop empty lines below the cursor instead of pushing lines into the history
+        let keep = cursor.map_or(0, |(row, _)| row + 1);
+        while lines.len() > keep && lines.last().map_or(false, Line::is_empty) {
+            lines.pop();
+        }
+        while lines.len() < sz.rows {
+            lines.push(Line::new(sz.cols));
+        }
+
+        let hist_len = lines.len() - sz.rows;
+        let screen = lines.split_off(hist_len);
+
+        let primary = if on_primary {
+            &mut self.lines
+        } else {
+            &mut self.alt_lines
+        };
+        primary.extend(screen);
+
+        let skip = hist_len.saturating_sub(self.history_capacity);
+        self.history.extend(lines.into_iter().skip(skip));
+        if skip > 0 {
+            self.gc_links();
+        }
+
+        if let Some((row, col)) = cursor {
+            self.cursor.sz = sz;
+            self.cursor = self.cursor.exact(row.saturating_sub(hist_len), col);
+            self.cursor.end = col >= sz.cols;
+        }
+    }
+
+    /// Changes the maximum number of lines kept in the history, evicting the oldest ones
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history_capacity = capacity;
+
+        let excess = self.history.len().saturating_sub(capacity);
+        if excess > 0 {
+            self.history.drain(..excess);
+            self.gc_links();
+            self.updated = true;
+        }
+    }
+
+    /// Scroll up the buffer by 1 line.
+    /// The oldest line in the history is evicted if it's full.
     fn scroll_up(&mut self) {
         let (top, bottom) = self.scroll_region;
 
         let line = self.lines.remove(top).unwrap();
         self.history.push_back(line);
-        self.history_size = min(self.history_size + 1, Self::HISTORY_CAPACITY);
 
-        let mut line = self.history.pop_front().unwrap();
-        line.erase_all();
+        // Reuse the oldest line if the history is full
+        let line = if self.history.len() > self.history_capacity {
+            let mut line = self.history.pop_front().unwrap();
+
+            // Hyperlinks only referred by evicted lines are forgotten once in a while
+            if line.cells.iter().any(|c| c.attr.link.is_some()) {
+                self.evicted_link_lines += 1;
+                if self.evicted_link_lines >= LINK_GC_INTERVAL {
+                    self.gc_links();
+                }
+            }
+
+            line.erase_all();
+            line.resize(self.size.cols);
+            line
+        } else {
+            Line::new(self.size.cols)
+        };
         self.lines.insert(bottom, line);
     }
 
@@ -526,10 +1130,122 @@ impl State {
         }
     }
 
+    /// Returns the cursor moved to the position given by a control function,
+    /// which is relative to the scroll region in the origin mode
+    fn addressed_cursor(&self, row: usize, col: usize) -> Cursor {
+        if self.mode.origin_mode {
+            let (top, bottom) = self.scroll_region;
+            let row = min(top + row, bottom);
+            self.cursor.exact(row, col)
+        } else {
+            self.cursor.exact(row, col)
+        }
+    }
+
+    /// Returns the rows (inclusive) where CUU/CUD can move the cursor.
+    /// The cursor stops at a margin of the scroll region unless it's already beyond the margin,
+    /// which never happens in the origin mode.
+    fn vertical_limits(&self) -> (usize, usize) {
+        let (top, bottom) = self.scroll_region;
+        let row = self.cursor.row;
+        let upper = if row < top { 0 } else { top };
+        let lower = if row > bottom {
+            self.size.rows - 1
+        } else {
+            bottom
+        };
+        (upper, lower)
+    }
+
+    /// Converts a rectangular area given by DECFRA/DECERA/DECCRA (1-origin, 0 means the default)
+    /// into the 0-origin inclusive (top, left, bottom, right), or `None` if it's empty
+    fn rectangle(&self, area: [u16; 4]) -> Option<(usize, usize, usize, usize)> {
+        let [top, left, bottom, right] = area.map(|p| p as usize);
+        let (offset, max_row) = if self.mode.origin_mode {
+            self.scroll_region
+        } else {
+            (0, self.size.rows - 1)
+        };
+        let max_col = self.size.cols - 1;
+
+        let top = offset + top.max(1) - 1;
+        let left = left.max(1) - 1;
+        let bottom = match bottom {
+            0 => max_row,
+            _ => min(offset + bottom - 1, max_row),
+        };
+        let right = match right {
+            0 => max_col,
+            _ => min(right - 1, max_col),
+        };
+
+        (top <= bottom && left <= right).then_some((top, left, bottom, right))
+    }
+
     fn swap_screen_buffers(&mut self) {
         std::mem::swap(&mut self.lines, &mut self.alt_lines);
         std::mem::swap(&mut self.images, &mut self.alt_images);
     }
+
+    /// Places an image, removing the images covered by it
+    fn put_image(&mut self, image: PositionedImage) {
+        // (even if the new one is transparent, not to pile up images at the same place)
+        self.images.retain(|img| !overwrap(&image, img));
+        self.images.push(image);
+
+        log::debug!("total {} images", self.images.len());
+    }
+
+    fn clear_screen(&mut self) {
+        for line in self.lines.iter_mut() {
+            line.erase_all();
+        }
+        self.images.clear();
+    }
+
+    /// Leaves the alternate screen and resets the modes which an application may leave set
+    fn restore_sane_state(&mut self) {
+        self.switch_to_primary_screen();
+        self.mode = Mode::default();
+        self.attr = GraphicAttribute::default();
+        self.current_link = None;
+        self.scroll_region = (0, self.size.rows - 1);
+        self.cursor.style = CursorStyle::default();
+        self.cursor.blink = None;
+        self.reset_charsets();
+    }
+
+    fn reset_charsets(&mut self) {
+        self.charsets = [Charset::Ascii; 2];
+        self.shift_out = false;
+    }
+
+    /// Translates a character with the invoked character set
+    fn translate_char(&self, ch: char) -> char {
+        let charset = self.charsets[self.shift_out as usize];
+        match charset {
+            Charset::Ascii => ch,
+            Charset::DecSpecialGraphics => dec_special_graphics(ch),
+        }
+    }
+
+    /// Switches to the cleared alternate screen buffer
+    fn switch_to_alt_screen(&mut self) {
+        if self.mode.alt_screen {
+            return;
+        }
+        self.swap_screen_buffers();
+        self.clear_screen();
+        self.mode.alt_screen = true;
+    }
+
+    fn switch_to_primary_screen(&mut self) {
+        if !self.mode.alt_screen {
+            return;
+        }
+        self.swap_screen_buffers();
+        self.mode.alt_screen = false;
+    }
 }
 
 #[derive(Debug)]
@@ -539,20 +1255,46 @@ enum Command {
         cell_sz: CellSize,
     },
     SendSigterm,
+    /// Stops the engine leaving the shell running
+    #[cfg(feature = "multiplex")]
+    Detach,
 }
 
 #[derive(Debug)]
 pub struct Terminal {
     pty: OwnedFd,
+    #[cfg_attr(not(feature = "multiplex"), allow(unused))]
+    pid: Pid,
     control_req: pipe_channel::Sender<Command>,
     control_res: pipe_channel::Receiver<i32>,
     pub state: Arc<Mutex<State>>,
 }
 
 impl Terminal {
-    pub fn new(size: TerminalSize, cell_size: CellSize, cwd: &std::path::Path) -> Self {
-        let (pty, child_pid) = init_pty(cwd).unwrap();
+    /// Spawns `command` (or the shell if None) in `cwd`
+    pub fn new(
+        size: TerminalSize,
+        cell_size: CellSize,
+        cwd: &std::path::Path,
+        command: Option<&[String]>,
+    ) -> Self {
+        let (pty, child_pid) = init_pty(cwd, command).unwrap();
+        Self::start(pty, child_pid, size, cell_size)
+    }
+
+    /// Takes over the PTY of a running shell (e.g. a session detached by another process)
+    #[cfg(feature = "multiplex")]
+    pub fn attach(size: TerminalSize, cell_size: CellSize, pty: OwnedFd, pid: Pid) -> Self {
+        let terminal = Self::start(pty, pid, size, cell_size);
 
+        // The window size may be unchanged, so let the application redraw the screen anyway
+        if let Ok(pgid) = nix::unistd::tcgetpgrp(terminal.pty.as_raw_fd()) {
+            let _ = nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGWINCH);
+        }
+        terminal
+    }
+
+    fn start(pty: OwnedFd, child_pid: Pid, size: TerminalSize, cell_size: CellSize) -> Self {
         let (control_req_tx, control_req_rx) = pipe_channel::channel();
         let (control_res_tx, control_res_rx) = pipe_channel::channel();
 
@@ -569,6 +1311,7 @@ impl Terminal {
 
         Terminal {
             pty,
+            pid: child_pid,
             control_req: control_req_tx,
             control_res: control_res_rx,
             state,
@@ -593,6 +1336,21 @@ impl Terminal {
         self.control_res.recv();
     }
 
+    /// Returns the PTY and the PID of the shell without stopping the terminal
+    #[cfg(feature = "multiplex")]
+    pub fn shell(&self) -> (std::os::unix::io::RawFd, Pid) {
+        (self.pty.as_raw_fd(), self.pid)
+    }
+
+    /// Stops processing the output and returns the PTY and the PID of the shell,
+    /// which keeps running as long as the PTY is open
+    #[cfg(feature = "multiplex")]
+    pub fn detach(&mut self) -> (OwnedFd, Pid) {
+        self.control_req.send(Command::Detach);
+        self.control_res.recv();
+        (self.pty.try_clone().expect("dup"), self.pid)
+    }
+
     pub fn exit_status(&self) -> Option<i32> {
         let state = self.state.lock().unwrap();
         state.exit_status
@@ -611,6 +1369,8 @@ pub struct Cursor {
     pub col: usize,
     end: bool,
     pub style: CursorStyle,
+    /// Whether the cursor blinks (None: as configured)
+    pub blink: Option<bool>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -683,6 +1443,74 @@ impl Cursor {
     }
 }
 
+/// Copies the decoded output of the PTY to another writer (for debugging)
+struct OutputTee {
+    out: Box<dyn std::io::Write + Send>,
+    window_start: Instant,
+    written: usize,
+    dropped: usize,
+}
+
+impl OutputTee {
+    /// The maximum number of bytes copied in a second
+    const RATE_LIMIT: usize = 64 * 1024;
+
+    fn new(out: Box<dyn std::io::Write + Send>) -> Self {
+        Self {
+            out,
+            window_start: Instant::now(),
+            written: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Writes the input with escapes, so that control functions are visible
+    fn write(&mut self, input: &str) {
+        use std::io::Write as _;
+
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            if self.dropped > 0 {
+                let _ = writeln!(self.out, "[tee] {} bytes dropped", self.dropped);
+            }
+            self.window_start = Instant::now();
+            self.written = 0;
+            self.dropped = 0;
+        }
+
+        let escaped = input.escape_debug().to_string();
+        if self.written + escaped.len() > Self::RATE_LIMIT {
+            self.dropped += input.len();
+            return;
+        }
+
+        self.written += escaped.len();
+        let _ = writeln!(self.out, "{}", escaped);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PollEvent {
+    Nothing,
+    Readable,
+    Closed,
+}
+
+impl PollEvent {
+    fn classify(revents: Option<nix::poll::PollFlags>) -> Self {
+        use nix::poll::PollFlags;
+        match revents {
+            // the fd is not open
+            Some(flags) if flags.contains(PollFlags::POLLNVAL) => PollEvent::Closed,
+            // remaining data is read before handling POLLHUP
+            Some(flags) if flags.contains(PollFlags::POLLIN) => PollEvent::Readable,
+            Some(flags) if flags.intersects(PollFlags::POLLERR | PollFlags::POLLHUP) => {
+                PollEvent::Closed
+            }
+            _ => PollEvent::Nothing,
+        }
+    }
+}
+
 struct Engine {
     pid: Pid,
     pty: OwnedFd,
@@ -694,16 +1522,24 @@ struct Engine {
     tabstops: Vec<usize>,
     saved_cursor: Cursor,
     saved_attr: GraphicAttribute,
+    saved_cursor_style: (CursorStyle, Option<bool>),
+    tee: Option<OutputTee>,
+    kitty_images: kitty::Store,
+    answerback: String,
 }
 
 impl Engine {
-    fn set_term_window_size(pty_master: &OwnedFd, size: TerminalSize) -> Result<()> {
+    fn set_term_window_size(
+        pty_master: &OwnedFd,
+        size: TerminalSize,
+        cell_sz: CellSize,
+    ) -> Result<()> {
+        let (width, height) = text_area_pixels(size, cell_sz);
         let winsize = nix::pty::Winsize {
             ws_row: size.rows as u16,
             ws_col: size.cols as u16,
-            // TODO
-            ws_xpixel: 0,
-            ws_ypixel: 0,
+            ws_xpixel: width.min(u16::MAX as u32) as u16,
+            ws_ypixel: height.min(u16::MAX as u32) as u16,
         };
 
         nix::ioctl_write_ptr_bad!(tiocswinsz, nix::libc::TIOCSWINSZ, nix::pty::Winsize);
@@ -720,9 +1556,10 @@ impl Engine {
         sz: TerminalSize,
         cell_sz: CellSize,
     ) -> Self {
-        Self::set_term_window_size(&pty, sz).unwrap();
+        Self::set_term_window_size(&pty, sz, cell_sz).unwrap();
 
-        let state = Arc::new(Mutex::new(State::new(sz)));
+        let config = crate::TOYTERM_CONFIG.load();
+        let state = Arc::new(Mutex::new(State::new(sz, config.scrollback_lines)));
 
         // Initialize tabulation stops
         let mut tabstops = Vec::new();
@@ -748,6 +1585,12 @@ impl Engine {
             tabstops,
             saved_cursor,
             saved_attr: GraphicAttribute::default(),
+            saved_cursor_style: (CursorStyle::default(), None),
+            tee: config
+                .debug_tee_output
+                .then(|| OutputTee::new(Box::new(std::io::stdout()))),
+            kitty_images: kitty::Store::default(),
+            answerback: config.answerback.clone(),
         }
     }
 
@@ -758,7 +1601,7 @@ impl Engine {
     fn resize(&mut self, sz: TerminalSize, cell_sz: CellSize) {
         log::debug!("resize to {}x{} (cell)", sz.rows, sz.cols);
 
-        Self::set_term_window_size(&self.pty, sz).unwrap();
+        Self::set_term_window_size(&self.pty, sz, cell_sz).unwrap();
 
         self.cell_sz = cell_sz;
 
@@ -795,46 +1638,69 @@ impl Engine {
             use nix::sys::signal::{kill, Signal};
 
             log::trace!("polling");
-            if let Err(err) = poll(&mut fds, -1) {
-                if let Errno::EINTR | Errno::EAGAIN = err {
-                    continue;
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(Errno::EINTR | Errno::EAGAIN) => continue,
+                Err(err) => {
+                    log::error!("poll failed: {err}");
+                    let _ = kill(self.pid, Signal::SIGHUP);
+                    break;
                 }
-                log::error!("poll failed: {err}");
-                let _ = kill(self.pid, Signal::SIGHUP);
-                break;
             }
 
-            let pty_revents = fds[0].revents();
-            let ctl_revents = fds[1].revents();
+            let pty_event = PollEvent::classify(fds[0].revents());
+            let ctl_event = PollEvent::classify(fds[1].revents());
+
+            // Unexpected flags would make poll return immediately, so wait a moment
+            // not to spin while they are reported
+            if pty_event == PollEvent::Nothing && ctl_event == PollEvent::Nothing {
+                log::debug!(
+                    "unexpected poll events: {:?}, {:?}",
+                    fds[0].revents(),
+                    fds[1].revents()
+                );
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
 
-            if let Some(flags) = ctl_revents {
-                if flags.contains(PollFlags::POLLIN) {
-                    match self.control_req.recv() {
-                        Command::Resize { buff_sz, cell_sz } => {
-                            self.resize(buff_sz, cell_sz);
-                            self.control_res.send(0);
-                        }
-                        Command::SendSigterm => {
-                            let _ = kill(self.pid, Signal::SIGTERM);
-                            let _ = nix::sys::wait::waitpid(self.pid, None).unwrap();
-                            self.control_res.send(0);
-                            break;
-                        }
+            match ctl_event {
+                PollEvent::Readable => match self.control_req.recv() {
+                    Command::Resize { buff_sz, cell_sz } => {
+                        self.resize(buff_sz, cell_sz);
+                        self.control_res.send(0);
+                    }
+                    Command::SendSigterm => {
+                        let _ = kill(self.pid, Signal::SIGTERM);
+                        // (an attached shell is not a child of this process)
+                        let _ = nix::sys::wait::waitpid(self.pid, None);
+                        self.control_res.send(0);
+                        break;
+                    }
+                    #[cfg(feature = "multiplex")]
+                    Command::Detach => {
+                        log::debug!("detached: pid={}", self.pid);
+                        self.control_res.send(0);
+                        return;
                     }
-                } else if flags.contains(PollFlags::POLLERR) || flags.contains(PollFlags::POLLHUP) {
+                },
+                PollEvent::Closed => {
                     let _ = kill(self.pid, Signal::SIGHUP);
                     break;
                 }
+                PollEvent::Nothing => {}
             }
 
-            if let Some(flags) = pty_revents {
-                if flags.contains(PollFlags::POLLIN) {
+            match pty_event {
+                PollEvent::Readable => {
                     let nb = match nix::unistd::read(pty_fd, &mut buf[begin..]) {
                         Ok(0) => break,
                         Ok(nb) => nb,
+                        Err(Errno::EINTR | Errno::EAGAIN) => continue,
                         Err(err) => {
+                            // e.g. EIO after the slave side is closed
                             log::error!("PTY read: {}", err);
-                            continue;
+                            let _ = kill(self.pid, Signal::SIGHUP);
+                            break;
                         }
                     };
 
@@ -856,10 +1722,12 @@ impl Engine {
                     // (these bytes will be parsed in the next process_utf8 call)
                     buf.copy_within((end - rem_len)..end, 0);
                     begin = rem_len;
-                } else if flags.contains(PollFlags::POLLERR) || flags.contains(PollFlags::POLLHUP) {
+                }
+                PollEvent::Closed => {
                     let _ = kill(self.pid, Signal::SIGHUP);
                     break;
                 }
+                PollEvent::Nothing => {}
             }
         }
 
@@ -871,26 +1739,46 @@ impl Engine {
         };
 
         let mut state = self.state.lock().unwrap();
+        state.restore_sane_state();
         state.exit_status = Some(status);
     }
 
     fn process(&mut self, input: &str) {
         log::trace!("process: {:?}", input);
+        if let Some(tee) = self.tee.as_mut() {
+            tee.write(input);
+        }
+
         let mut state = self.state.lock().unwrap();
         state.updated = true;
 
         let term_rows = state.size.rows;
         let term_cols = state.size.cols;
 
+        // (a reloaded config takes effect from the next input)
+        let config = crate::TOYTERM_CONFIG.load();
+
         for ch in input.chars() {
             let func = match self.parser.feed(ch) {
                 Some(f) => f,
                 None => continue,
             };
 
+            // DEL is a filler ignored by default, but some legacy systems expect it to be shown
+            let func = match func {
+                control_function::Function::DEL if config.render_del_as_space => {
+                    control_function::Function::GraphicChar(' ')
+                }
+                func => func,
+            };
+
             macro_rules! ignore {
                 () => {{
-                    log::warn!("Function {:?} is not implemented", func);
+                    log::log!(
+                        ignore_level(&func),
+                        "Function {:?} is not implemented",
+                        func
+                    );
                     continue;
                 }};
             }
@@ -956,7 +1844,8 @@ impl Engine {
                     }
 
                     let (row, _) = state.cursor.pos();
-                    let up = min(pn, row);
+                    let (top, _) = state.vertical_limits();
+                    let up = min(pn, row.saturating_sub(top));
                     for _ in 0..up {
                         state.cursor = state.cursor.prev_row();
                     }
@@ -969,7 +1858,8 @@ impl Engine {
                     }
 
                     let (row, _) = state.cursor.pos();
-                    let down = min(pn, term_rows - 1 - row);
+                    let (_, bottom) = state.vertical_limits();
+                    let down = min(pn, bottom.saturating_sub(row));
                     for _ in 0..down {
                         state.cursor = state.cursor.next_row();
                     }
@@ -1002,37 +1892,23 @@ impl Engine {
                 }
 
                 HVP(pn1, pn2) | CUP(pn1, pn2) => {
-                    let mut pn1 = pn1 as usize;
-                    if pn1 > 0 {
-                        pn1 -= 1;
-                    }
-
-                    let mut pn2 = pn2 as usize;
-                    if pn2 > 0 {
-                        pn2 -= 1;
-                    }
-
-                    state.cursor = state.cursor.exact(pn1, pn2);
+                    let pn1 = (pn1 as usize).saturating_sub(1);
+                    let pn2 = (pn2 as usize).saturating_sub(1);
+                    state.cursor = state.addressed_cursor(pn1, pn2);
                 }
 
                 CHA(pn) => {
-                    let mut pn = pn as usize;
-                    if pn > 0 {
-                        pn -= 1;
-                    }
+                    let pn = (pn as usize).saturating_sub(1);
 
                     let (row, _) = state.cursor.pos();
                     state.cursor = state.cursor.exact(row, pn);
                 }
 
                 VPA(pn) => {
-                    let mut pn = pn as usize;
-                    if pn > 0 {
-                        pn -= 1;
-                    }
+                    let pn = (pn as usize).saturating_sub(1);
 
                     let (_, col) = state.cursor.pos();
-                    state.cursor = state.cursor.exact(pn, col);
+                    state.cursor = state.addressed_cursor(pn, col);
                 }
 
                 ECH(pn) => {
@@ -1083,6 +1959,13 @@ impl Engine {
 
                         // Remove sixel graphics
                         state.images.clear();
+
+                        // Forget hyperlinks which are no longer visible
+                        state.gc_links();
+                    }
+                    3 => {
+                        // clear the history (the screen is kept as xterm does)
+                        state.clear_history();
                     }
                     _ => unreachable!(),
                 },
@@ -1099,9 +1982,12 @@ impl Engine {
                         state.lines[row].erase(0..=col);
                     }
                     2 => {
-                        // clear line
+                        // clear line (the line attribute is kept)
                         let row = state.cursor.row;
-                        state.lines[row].erase_all();
+                        let line = &mut state.lines[row];
+                        let size = line.size;
+                        line.erase_all();
+                        line.size = size;
                     }
                     _ => unreachable!(),
                 },
@@ -1110,18 +1996,44 @@ impl Engine {
                     5 => {
                         // ready, no malfunction detected
                         use std::io::Write as _;
-                        FdIo(&self.pty).write_all(b"\x1b[0\x6E").unwrap();
+                        FdIo(&self.pty).write_all(b"\x1b[0n").unwrap();
                     }
                     6 => {
-                        let (row, col) = state.cursor.pos();
-
-                        // a report of the active position
+                        // CPR: a report of the active position
+                        let (row, col) = state.reported_position();
                         use std::io::Write as _;
                         FdIo(&self.pty)
-                            .write_fmt(format_args!("\x1b[{};{}\x52", row + 1, col + 1))
+                            .write_fmt(format_args!("\x1b[{row};{col}R"))
                             .unwrap();
                     }
-                    _ => unreachable!(),
+                    _ => ignore!(),
+                },
+
+                DecStatusReport(6) => {
+                    // DECXCPR: the cursor position with the page number
+                    let (row, col) = state.reported_position();
+                    use std::io::Write as _;
+                    FdIo(&self.pty)
+                        .write_fmt(format_args!("\x1b[?{row};{col};1R"))
+                        .unwrap();
+                }
+
+                RequestMode(private, pd) => {
+                    // DECRPM: 0 = not recognized, 1 = set, 2 = reset, 4 = permanently reset
+                    let ps = mode_status(&state.mode, private, pd);
+                    let private = if private == 0 { "" } else { "?" };
+                    use std::io::Write as _;
+                    FdIo(&self.pty)
+                        .write_fmt(format_args!("\x1b[{private}{pd};{ps}$y"))
+                        .unwrap();
+                }
+
+                DecStatusReport(ps) => match dec_status_report(ps) {
+                    Some(reply) => {
+                        use std::io::Write as _;
+                        FdIo(&self.pty).write_all(reply.as_bytes()).unwrap();
+                    }
+                    None => ignore!(),
                 },
 
                 ICH(pn) => {
@@ -1130,7 +2042,10 @@ impl Engine {
                         pn = 1;
                     }
 
+                    // At the end of a line (pending wrap), this works at the last column
+                    // and the pending wrap is canceled as xterm does
                     let (row, col) = state.cursor.pos();
+                    state.cursor = state.cursor.exact(row, col);
                     let line = &mut state.lines[row];
 
                     let src = col;
@@ -1147,7 +2062,9 @@ impl Engine {
                         pn = 1;
                     }
 
+                    // Cancel the pending wrap as well as ICH
                     let (row, col) = state.cursor.pos();
+                    state.cursor = state.cursor.exact(row, col);
                     let line = &mut state.lines[row];
 
                     let src = min(col + pn, term_cols);
@@ -1200,9 +2117,9 @@ impl Engine {
                     }
                 }
 
-                SGR(pss) => {
-                    let mut iter = pss.iter().copied().peekable();
-                    while let Some(ps) = iter.next() {
+                SGR(pss, subs) => {
+                    let mut iter = pss.iter().copied().zip(subs.iter().copied()).peekable();
+                    while let Some((ps, _)) = iter.next() {
                         match ps {
                             0 => state.attr = GraphicAttribute::default(),
 
@@ -1210,6 +2127,20 @@ impl Engine {
                             2 => state.attr.bold = -1,
                             22 => state.attr.bold = 0,
 
+                            3 => state.attr.italic = true,
+                            23 => state.attr.italic = false,
+
+                            // 4:0 (none), 4:1 (single), 4:2 (double), 4:3 (curly), 4:4 (dotted), 4:5 (dashed)
+                            4 => {
+                                state.attr.underline = match iter.next_if(|&(_, sub)| sub) {
+                                    Some((style @ 0..=5, _)) => style as u8,
+                                    Some(_) => state.attr.underline,
+                                    None => 1,
+                                };
+                            }
+                            21 => state.attr.underline = 2,
+                            24 => state.attr.underline = 0,
+
                             5 => state.attr.blinking = 1,
                             6 => state.attr.blinking = 2,
                             25 => state.attr.blinking = 0,
@@ -1220,6 +2151,12 @@ impl Engine {
                             8 => state.attr.concealed = true,
                             28 => state.attr.concealed = false,
 
+                            9 => state.attr.strike = true,
+                            29 => state.attr.strike = false,
+
+                            53 => state.attr.overline = true,
+                            55 => state.attr.overline = false,
+
                             x @ (30..=37 | 38 | 90..=97) => {
                                 if let Some(color) = parse_color(x - 30, &mut iter) {
                                     state.attr.fg = color;
@@ -1236,22 +2173,49 @@ impl Engine {
                             80 => state.attr.bg = Color::Special,
                             49 => state.attr.bg = GraphicAttribute::default().bg,
 
+                            58 => {
+                                if let Some(color) = parse_color(8, &mut iter) {
+                                    state.attr.underline_color = Some(color);
+                                }
+                            }
+                            59 => state.attr.underline_color = None,
+
                             _ => {}
                         }
+
+                        // ignore unknown sub-parameters
+                        while iter.next_if(|&(_, sub)| sub).is_some() {}
                     }
                 }
 
                 GraphicChar(ch) => {
+                    let ch = state.translate_char(ch);
+
                     use unicode_width::UnicodeWidthChar as _;
-                    let ch_width = if crate::TOYTERM_CONFIG.east_asian_width_ambiguous == 1 {
+                    let ch_width = if config.east_asian_width_ambiguous == 1 {
                         ch.width()
                     } else {
                         ch.width_cjk()
                     };
 
                     if let Some(width @ 1..) = ch_width {
+                        // Double-width lines have only the half of columns
+                        let right_space = |state: &State| {
+                            let (row, col) = state.cursor.pos();
+                            let cols = state.lines[row].visible_columns();
+                            min(state.cursor.right_space(), cols.saturating_sub(col))
+                        };
+
+                        if right_space(&state) < width && !state.mode.autowrap {
+                            // Without auto-wrap, the last column is overwritten
+                            let (row, _) = state.cursor.pos();
+                            let cols = state.lines[row].visible_columns();
+                            let col = cols.saturating_sub(width);
+                            state.cursor = state.cursor.exact(row, col);
+                        }
+
                         // If there is no space for new character, move cursor to the next line.
-                        if state.cursor.right_space() < width {
+                        if right_space(&state) < width {
                             let (row, col) = state.cursor.pos();
                             if !state.cursor.end {
                                 state.lines[row].erase(col..);
@@ -1266,11 +2230,22 @@ impl Engine {
                         }
 
                         let (row, col) = state.cursor.pos();
+
+                        // Shift the following characters to the right
+                        if state.mode.insert_mode {
+                            let dst = min(col + width, term_cols);
+                            let count = term_cols - dst;
+                            state.lines[row].copy_within(col..col + count, dst);
+                        }
+
                         let cell = Cell {
                             ch,
                             width: width as u16,
                             backlink: 0,
-                            attr: state.attr,
+                            attr: GraphicAttribute {
+                                link: state.current_link,
+                                ..state.attr
+                            },
                         };
                         state.lines[row].put(col, cell);
 
@@ -1281,12 +2256,20 @@ impl Engine {
                 }
 
                 SixelImage(image) => {
-                    log::debug!("image: {}x{}", image.width, image.height);
+                    // the size on the screen, which is scaled by the aspect ratio
+                    let max_width = (term_cols as u32 * self.cell_sz.w) as u64;
+                    let max_height = (term_rows as u32 * self.cell_sz.h) as u64;
+                    let width = image.display_width(max_width);
+                    let height = image.display_height(max_height);
+                    log::debug!(
+                        "image: {}x{} ({}x{})",
+                        width,
+                        height,
+                        image.width,
+                        image.height
+                    );
                     let (cursor_row, cursor_col) = state.cursor.pos();
 
-                    let cell_w = self.cell_sz.w as u64;
-                    let cell_h = self.cell_sz.h as u64;
-
                     let (row, col) = if state.mode.sixel_scrolling {
                         (cursor_row as isize, cursor_col as isize)
                     } else {
@@ -1296,37 +2279,118 @@ impl Engine {
                     let new_image = PositionedImage {
                         row,
                         col,
-                        width: image.width,
-                        height: image.height,
+                        width,
+                        height,
+                        data_width: image.width,
+                        data_height: image.height,
                         data: image.data,
+                        id: 0,
                     };
-
-                    state.images.retain(|img| !overwrap(&new_image, img));
-                    state.images.push(new_image);
-
-                    log::debug!("total {} images", state.images.len());
+                    state.put_image(new_image);
 
                     if state.mode.sixel_scrolling {
-                        let advance_h = (image.width + cell_w - 1) / cell_w;
-                        let advance_v = (image.height + cell_h - 1) / cell_h - 1;
+                        move_cursor_over_image(&mut state, self.cell_sz, width, height);
+                    }
+                }
 
-                        for _ in 0..advance_h {
-                            state.cursor = state.cursor.next_col();
+                KittyGraphics(cmd) => {
+                    // Wait for the remaining chunks
+                    let cmd = match self.kitty_images.feed(cmd) {
+                        Some(cmd) => cmd,
+                        None => continue,
+                    };
+                    let cell_sz = self.cell_sz;
+                    log::debug!(
+                        "kitty graphics: a={}, f={}, i={}",
+                        cmd.action,
+                        cmd.format,
+                        cmd.id
+                    );
+
+                    let result = match cmd.action {
+                        't' | 'T' | 'p' | 'q' => {
+                            let image = if cmd.action == 'p' {
+                                (self.kitty_images.get(cmd.id).cloned())
+                                    .ok_or_else(|| kitty::Error::new("ENOENT", "no such image"))
+                            } else {
+                                // Reading and decoding the data may take a while,
+                                // so the state is unlocked meanwhile not to block the window
+                                drop(state);
+                                let image = kitty::load(&cmd);
+                                state = self.state.lock().unwrap();
+                                image
+                            };
+
+                            image.map(|image| {
+                                if cmd.action == 'T' || cmd.action == 'p' {
+                                    let max_size = text_area_pixels(state.size, cell_sz);
+                                    let (width, height) =
+                                        kitty_image_size(&cmd, &image, cell_sz, max_size);
+                                    let (row, col) = state.cursor.pos();
+                                    let new_image = PositionedImage {
+                                        row: row as isize,
+                                        col: col as isize,
+                                        width,
+                                        height,
+                                        data_width: image.width as u64,
+                                        data_height: image.height as u64,
+                                        data: image.data.clone(),
+                                        id: cmd.id,
+                                    };
+                                    state.put_image(new_image);
+
+                                    if !cmd.fixed_cursor {
+                                        move_cursor_over_image(&mut state, cell_sz, width, height);
+                                    }
+                                }
+
+                                // Images without ids can't be referred later
+                                if cmd.id != 0 && (cmd.action == 't' || cmd.action == 'T') {
+                                    self.kitty_images.insert(cmd.id, image);
+                                }
+                            })
                         }
-                        for _ in 0..advance_v {
-                            buffer_scroll_up_if_needed(&mut state, self.cell_sz);
-                            if state.cursor.row != state.scroll_region.1 {
-                                state.cursor = state.cursor.next_row();
+
+                        'd' => {
+                            match cmd.delete {
+                                'a' | 'A' => state.images.retain(|img| img.id == 0),
+                                'i' | 'I' => state.images.retain(|img| img.id != cmd.id),
+                                d => log::debug!("kitty graphics: unsupported deletion: {d:?}"),
                             }
+                            match cmd.delete {
+                                'A' => self.kitty_images.clear(),
+                                'I' => self.kitty_images.remove(cmd.id),
+                                _ => {}
+                            }
+                            Ok(())
                         }
+
+                        _ => Err(kitty::Error::new("EINVAL", "unsupported action")),
+                    };
+
+                    if let Err(err) = &result {
+                        log::debug!("kitty graphics: {err}");
+                    }
+                    if let Some(response) = cmd.response(&result) {
+                        use std::io::Write as _;
+                        FdIo(&self.pty).write_all(response.as_bytes()).unwrap();
                     }
                 }
 
                 SelectCursorStyle(ps) => match ps {
-                    0 => state.cursor.style = CursorStyle::default(),
-                    2 => state.cursor.style = CursorStyle::Block,
-                    4 => state.cursor.style = CursorStyle::Underline,
-                    6 => state.cursor.style = CursorStyle::Bar,
+                    0 => {
+                        state.cursor.style = CursorStyle::default();
+                        state.cursor.blink = None;
+                    }
+                    1..=6 => {
+                        state.cursor.style = match ps {
+                            1 | 2 => CursorStyle::Block,
+                            3 | 4 => CursorStyle::Underline,
+                            _ => CursorStyle::Bar,
+                        };
+                        // odd numbers are blinking variants
+                        state.cursor.blink = Some(ps % 2 == 1);
+                    }
                     _ => {
                         log::warn!("unknown cursor shape: {}", ps);
                     }
@@ -1337,6 +2401,22 @@ impl Engine {
 
                     for p in ps {
                         match p {
+                            5 => {
+                                state.mode.reverse_video = true;
+                                log::debug!("Reverse Video Mode Enabled");
+                            }
+
+                            6 => {
+                                state.mode.origin_mode = true;
+                                state.cursor = state.addressed_cursor(0, 0);
+                                log::debug!("Origin Mode Enabled");
+                            }
+
+                            7 => {
+                                state.mode.autowrap = true;
+                                log::debug!("Auto-wrap Mode Enabled");
+                            }
+
                             25 => {
                                 state.mode.cursor_visible = true;
                             }
@@ -1346,29 +2426,61 @@ impl Engine {
                                 log::debug!("Sixel Scrolling Mode Enabled");
                             }
 
-                            // FIXME : I'm not sure that 1002 is equivalent to 1000 but it works
-                            1000 | 1002 => {
+                            1000 => {
                                 state.mode.mouse_track = true;
                                 log::debug!("Mouse Tracking Mode Enabled");
                             }
 
+                            1001 => {
+                                log::debug!("Highlight Mouse Tracking Mode is not supported");
+                            }
+
+                            1002 => {
+                                state.mode.mouse_track = true;
+                                state.mode.mouse_track_drag = true;
+                                log::debug!("Button-Event Mouse Tracking Mode Enabled");
+                            }
+
+                            1003 => {
+                                state.mode.mouse_track = true;
+                                state.mode.mouse_track_any = true;
+                                log::debug!("Any-Event Mouse Tracking Mode Enabled");
+                            }
+
+                            1004 => {
+                                state.mode.focus_event = true;
+                                log::debug!("Focus Event Mode Enabled");
+                            }
+
                             1006 => {
                                 state.mode.sgr_ext_mouse_track = true;
                                 log::debug!("SGR Extended Mode Mouse Tracking Enabled");
                             }
 
+                            1015 => {
+                                state.mode.urxvt_ext_mouse_track = true;
+                                log::debug!("urxvt Extended Mode Mouse Tracking Enabled");
+                            }
+
+                            1047 => {
+                                state.switch_to_alt_screen();
+                            }
+
+                            1048 => {
+                                self.saved_cursor = state.cursor;
+                                self.saved_attr = state.attr;
+                            }
+
                             1049 => {
                                 // save current cursor
                                 self.saved_cursor = state.cursor;
                                 self.saved_attr = state.attr;
 
-                                // clear the alternative buffers
-                                for line in state.alt_lines.iter_mut() {
-                                    line.erase_all();
-                                }
-                                state.alt_images.clear();
+                                // The style is saved separately so that DECSC
+                                // on the alternate screen doesn't overwrite it
+                                self.saved_cursor_style = (state.cursor.style, state.cursor.blink);
 
-                                state.swap_screen_buffers();
+                                state.switch_to_alt_screen();
                             }
 
                             2004 => {
@@ -1383,12 +2495,48 @@ impl Engine {
                     }
                 }
 
+                SM(0, ps) => {
+                    for p in ps {
+                        match p {
+                            4 => {
+                                state.mode.insert_mode = true;
+                                log::debug!("Insert Mode Enabled");
+                            }
+
+                            _ => {
+                                log::debug!("Set mode: {:?}", ps);
+                            }
+                        }
+                    }
+                }
+
+                SM(b'=', ps) if ps == [7] => {
+                    // ANSI.SYS line wrapping, same as DECAWM
+                    state.mode.autowrap = true;
+                }
+
                 SM(..) => ignore!(),
 
                 RM(b'?', ps) => {
                     log::trace!("RM - ps : {:?}", ps);
                     for p in ps {
                         match p {
+                            5 => {
+                                state.mode.reverse_video = false;
+                                log::debug!("Reverse Video Mode Disabled");
+                            }
+
+                            6 => {
+                                state.mode.origin_mode = false;
+                                state.cursor = state.addressed_cursor(0, 0);
+                                log::debug!("Origin Mode Disabled");
+                            }
+
+                            7 => {
+                                state.mode.autowrap = false;
+                                log::debug!("Auto-wrap Mode Disabled");
+                            }
+
                             25 => {
                                 state.mode.cursor_visible = false;
                             }
@@ -1398,22 +2546,51 @@ impl Engine {
                                 log::debug!("Sixel Scrolling Mode Disabled");
                             }
 
-                            // FIXME : I'm not sure that 1002 is equivalent to 1000 but it works
-                            1000 | 1002 => {
+                            // Resetting any of them disables mouse tracking
+                            1000 | 1002 | 1003 => {
                                 state.mode.mouse_track = false;
+                                state.mode.mouse_track_drag = false;
+                                state.mode.mouse_track_any = false;
                                 log::debug!("Mouse Tracking Mode Disabled");
                             }
 
+                            1004 => {
+                                state.mode.focus_event = false;
+                                log::debug!("Focus Event Mode Disabled");
+                            }
+
                             1006 => {
                                 state.mode.sgr_ext_mouse_track = false;
                                 log::debug!("SGR Extended Mode Mouse Tracking Disabled");
                             }
 
+                            1015 => {
+                                state.mode.urxvt_ext_mouse_track = false;
+                                log::debug!("urxvt Extended Mode Mouse Tracking Disabled");
+                            }
+
+                            1047 => {
+                                // the alternate screen is cleared on the way out
+                                if state.mode.alt_screen {
+                                    state.clear_screen();
+                                }
+                                state.switch_to_primary_screen();
+                            }
+
+                            1048 => {
+                                let (style, blink) = (state.cursor.style, state.cursor.blink);
+                                state.cursor = self.saved_cursor;
+                                state.cursor.style = style;
+                                state.cursor.blink = blink;
+                                state.attr = self.saved_attr;
+                            }
+
                             1049 => {
                                 // restore cursor and switch back to the primary screen buffer
                                 state.cursor = self.saved_cursor;
+                                (state.cursor.style, state.cursor.blink) = self.saved_cursor_style;
                                 state.attr = self.saved_attr;
-                                state.swap_screen_buffers();
+                                state.switch_to_primary_screen();
                             }
 
                             2004 => {
@@ -1428,6 +2605,25 @@ impl Engine {
                     }
                 }
 
+                RM(0, ps) => {
+                    for p in ps {
+                        match p {
+                            4 => {
+                                state.mode.insert_mode = false;
+                                log::debug!("Insert Mode Disabled");
+                            }
+
+                            _ => {
+                                log::debug!("Reset mode: {:?}", ps);
+                            }
+                        }
+                    }
+                }
+
+                RM(b'=', ps) if ps == [7] => {
+                    state.mode.autowrap = false;
+                }
+
                 RM(..) => ignore!(),
 
                 SaveCursor => {
@@ -1437,7 +2633,11 @@ impl Engine {
                 }
                 RestoreCursor => {
                     // restore saved cursor and graphics rendition
+                    // (the cursor style is not a part of the saved state)
+                    let (style, blink) = (state.cursor.style, state.cursor.blink);
                     state.cursor = self.saved_cursor;
+                    state.cursor.style = style;
+                    state.cursor.blink = blink;
                     state.attr = self.saved_attr;
                 }
 
@@ -1458,104 +2658,454 @@ impl Engine {
                     }
 
                     state.scroll_region = (pn1 - 1, pn2 - 1);
-                    state.cursor = state.cursor.exact(0, 0);
+                    state.cursor = state.addressed_cursor(0, 0);
                 }
 
-                ESC => {
-                    unreachable!();
-                }
+                SetModifyKeys(pp, pv) => match pp {
+                    4 => {
+                        state.mode.modify_other_keys = min(pv, 2);
+                        log::debug!("modifyOtherKeys: {}", state.mode.modify_other_keys);
+                    }
+                    _ => {
+                        log::debug!("unsupported key modifier option: {}", pp);
+                    }
+                },
 
-                NUL => ignore!(),
-                SOH => ignore!(),
-                STX => ignore!(),
-                EOT => ignore!(),
-                ENQ => ignore!(),
-                ACK => ignore!(),
-                BEL => ignore!(),
-                SO => ignore!(),
-                SI => ignore!(),
-                DLE => ignore!(),
-                DC1 => ignore!(),
-                DC2 => ignore!(),
-                DC3 => ignore!(),
-                DC4 => ignore!(),
-                NAK => ignore!(),
-                SYN => ignore!(),
-                ETB => ignore!(),
-                CAN => ignore!(),
-                EM => ignore!(),
-                SUB => ignore!(),
-                IS4 => ignore!(),
-                IS3 => ignore!(),
-                IS2 => ignore!(),
-                IS1 => ignore!(),
+                QueryModifyKeys(pp) => match pp {
+                    4 => {
+                        let pv = state.mode.modify_other_keys;
+                        use std::io::Write as _;
+                        FdIo(&self.pty)
+                            .write_fmt(format_args!("\x1b[>4;{pv}m"))
+                            .unwrap();
+                    }
+                    _ => {
+                        log::debug!("unsupported key modifier option: {}", pp);
+                    }
+                },
 
-                BPH => ignore!(),
-                NBH => ignore!(),
-                NEL => ignore!(),
-                SSA => ignore!(),
-                ESA => ignore!(),
-                HTS => ignore!(),
-                HTJ => ignore!(),
-                VTS => ignore!(),
-                PLD => ignore!(),
-                PLU => ignore!(),
-                RI => ignore!(),
-                SS2 => ignore!(),
-                SS3 => ignore!(),
-                DCS => ignore!(),
-                PU1 => ignore!(),
-                PU2 => ignore!(),
-                STS => ignore!(),
-                CCH => ignore!(),
-                MW => ignore!(),
-                SPA => ignore!(),
-                EPA => ignore!(),
-                SOS => ignore!(),
-                SCI => ignore!(),
-                ST => ignore!(),
-                OSC => ignore!(),
-                PM => ignore!(),
-                APC => ignore!(),
+                WindowOps(ps) => match ps {
+                    // report the size of the text area in pixels
+                    [14] | [14, _] => {
+                        let size = TerminalSize {
+                            rows: term_rows,
+                            cols: term_cols,
+                        };
+                        let (width, height) = text_area_pixels(size, self.cell_sz);
+                        use std::io::Write as _;
+                        FdIo(&self.pty)
+                            .write_fmt(format_args!("\x1b[4;{height};{width}t"))
+                            .unwrap();
+                    }
 
-                CNL => ignore!(),
-                CPL => ignore!(),
-                CHT => ignore!(),
-                EF => ignore!(),
-                EA => ignore!(),
-                SSE => ignore!(),
-                CPR => ignore!(),
-                SU => ignore!(),
-                SD => ignore!(),
-                NP => ignore!(),
-                PP => ignore!(),
-                CTC => ignore!(),
-                CVT => ignore!(),
-                CBT => ignore!(),
-                SRS => ignore!(),
-                PTX => ignore!(),
-                SDS => ignore!(),
-                SIMD => ignore!(),
-                HPA => ignore!(),
-                HPR => ignore!(),
-                REP => ignore!(),
-                DA => ignore!(),
-                VPR => ignore!(),
-                TBC => ignore!(),
-                MC => ignore!(),
-                HPB => ignore!(),
-                VPB => ignore!(),
-                DAQ => ignore!(),
+                    // report the size of the text area in characters
+                    [18] => {
+                        use std::io::Write as _;
+                        FdIo(&self.pty)
+                            .write_fmt(format_args!("\x1b[8;{term_rows};{term_cols}t"))
+                            .unwrap();
+                    }
 
-                SL => ignore!(),
-                SR => ignore!(),
-                GSM => ignore!(),
-                GSS => ignore!(),
-                FNT => ignore!(),
-                TSS => ignore!(),
-                JFY => ignore!(),
-                SPI => ignore!(),
-                QUAD => ignore!(),
+                    // report the window title
+                    [21] => {
+                        // control characters are dropped so that the title can't inject
+                        // sequences into the input of the application
+                        let title: String = state
+                            .title
+                            .as_deref()
+                            .unwrap_or_default()
+                            .chars()
+                            .filter(|ch| !ch.is_control())
+                            .collect();
+                        use std::io::Write as _;
+                        FdIo(&self.pty)
+                            .write_fmt(format_args!("\x1b]l{title}\x1b\\"))
+                            .unwrap();
+                    }
+
+                    // de-iconify, iconify, move, resize, raise, lower, maximize, ...
+                    // the window is left untouched by the applications
+                    [1..=11, ..] | [19..=20, ..] | [22..=24, ..] => {
+                        log::info!("ignored window operation: {:?}", ps);
+                    }
+
+                    _ => {
+                        log::debug!("unsupported window operation: {:?}", ps);
+                    }
+                },
+
+                QueryVersion => {
+                    let version = env!("CARGO_PKG_VERSION");
+                    use std::io::Write as _;
+                    FdIo(&self.pty)
+                        .write_fmt(format_args!("\x1bP>|toyterm({version})\x1b\\"))
+                        .unwrap();
+                }
+
+                RIS => {
+                    state.restore_sane_state();
+                    state.clear_screen();
+                    state.cursor = Cursor {
+                        sz: state.size,
+                        ..Cursor::default()
+                    };
+
+                    self.saved_cursor = state.cursor;
+                    self.saved_attr = GraphicAttribute::default();
+                    self.saved_cursor_style = (CursorStyle::default(), None);
+                    self.tabstops = (0..term_cols).step_by(8).collect();
+                    self.parser.reset_sixel_colors();
+                    self.kitty_images.clear();
+                    state.cursor_color = None;
+                    state.palette.clear();
+                }
+
+                HighlightMouseTracking(ps) => {
+                    // Highlight mouse tracking (?1001) is never enabled
+                    log::debug!("highlight mouse tracking is not supported: {:?}", ps);
+                }
+
+                FillRectangle(pch, area) => {
+                    let ch = char::from_u32(pch as u32)
+                        .filter(|ch| matches!(*ch as u32, 0x20..=0x7E | 0xA0..=0xFF));
+
+                    if let (Some(ch), Some((top, left, bottom, right))) =
+                        (ch, state.rectangle(area))
+                    {
+                        let mut cell = Cell::new_ascii(ch);
+                        cell.attr = state.attr;
+                        for row in top..=bottom {
+                            for col in left..=right {
+                                state.lines[row].put(col, cell);
+                            }
+                        }
+                    }
+                }
+
+                EraseRectangle(area) => {
+                    if let Some((top, left, bottom, right)) = state.rectangle(area) {
+                        for row in top..=bottom {
+                            state.lines[row].erase(left..=right);
+                        }
+                    }
+                }
+
+                CopyRectangle(src, dst_top, dst_left) => {
+                    let src = state.rectangle(src);
+                    let dst = state.rectangle([dst_top, dst_left, 0, 0]);
+                    if let (
+                        Some((top, left, bottom, right)),
+                        Some((dst_top, dst_left, max_row, max_col)),
+                    ) = (src, dst)
+                    {
+                        // The areas may overlap
+                        let copied: Vec<Vec<Cell>> = (top..=bottom)
+                            .map(|row| state.lines[row].cells[left..=right].to_vec())
+                            .collect();
+
+                        for (i, cells) in copied.iter().enumerate() {
+                            let row = dst_top + i;
+                            if row > max_row {
+                                break;
+                            }
+                            let count = min(cells.len(), max_col + 1 - dst_left);
+                            state.lines[row].write_cells(dst_left, &cells[..count]);
+                        }
+                    }
+                }
+
+                SoftReset => {
+                    // reset modes and attributes, but keep the screen contents
+                    state.attr = GraphicAttribute::default();
+                    state.scroll_region = (0, term_rows - 1);
+                    state.mode.cursor_visible = true;
+                    state.mode.insert_mode = false;
+                    state.mode.autowrap = true;
+                    state.mode.origin_mode = false;
+                    state.reset_charsets();
+
+                    self.saved_cursor = Cursor {
+                        sz: state.size,
+                        ..Cursor::default()
+                    };
+                    self.saved_attr = GraphicAttribute::default();
+                }
+
+                ScreenAlignmentTest => {
+                    // fill the screen with 'E' in single-width lines
+                    for line in state.lines.iter_mut() {
+                        line.cells.fill(Cell::new_ascii('E'));
+                        line.linewrap = false;
+                        line.size = LineSize::Normal;
+                    }
+                    state.images.clear();
+
+                    // the margins are reset too (as xterm does)
+                    state.scroll_region = (0, term_rows - 1);
+                    state.cursor = state.cursor.exact(0, 0);
+                }
+
+                DesignateCharset(slot, charset) => {
+                    log::debug!("G{} = {:?}", slot, charset);
+                    state.charsets[slot as usize] = charset;
+                }
+
+                SingleWidthLine => {
+                    let (row, _) = state.cursor.pos();
+                    state.lines[row].size = LineSize::Normal;
+                }
+
+                DoubleWidthLine | DoubleHeightLineTop | DoubleHeightLineBottom => {
+                    let size = match func {
+                        DoubleWidthLine => LineSize::DoubleWidth,
+                        DoubleHeightLineTop => LineSize::DoubleHeightTop,
+                        _ => LineSize::DoubleHeightBottom,
+                    };
+
+                    let (row, col) = state.cursor.pos();
+                    let line = &mut state.lines[row];
+                    line.size = size;
+
+                    // The right half is no longer visible
+                    let cols = line.visible_columns();
+                    line.erase(cols..);
+                    if col >= cols {
+                        state.cursor = state.cursor.exact(row, cols - 1);
+                    }
+                }
+
+                OscSetTitle(title) => {
+                    log::debug!("title changed: {:?}", title);
+                    state.title = Some(title);
+                }
+
+                OscSetWorkingDirectory(path) => {
+                    log::debug!("working directory changed: {:?}", path);
+                    state.cwd = Some(path);
+                }
+
+                OscHyperlink { id, uri } => {
+                    if uri.is_empty() {
+                        state.close_link();
+                    } else {
+                        state.open_link(id, uri);
+                    }
+                }
+
+                OscSetPalette(entries) => {
+                    for (idx, spec) in entries {
+                        match spec {
+                            ColorSpec::Rgb(rgba) => {
+                                state.palette.insert(idx, rgba);
+                            }
+                            ColorSpec::Query => {
+                                let rgba = match state.palette.get(&idx) {
+                                    Some(&rgba) => rgba,
+                                    None => crate::view::palette_rgba(Color::indexed(idx), &config),
+                                };
+                                let spec = ColorSpec::format(rgba);
+                                use std::io::Write as _;
+                                FdIo(&self.pty)
+                                    .write_fmt(format_args!("\x1b]4;{idx};{spec}\x1b\\"))
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    state.updated = true;
+                }
+                OscResetPalette(indices) => {
+                    if indices.is_empty() {
+                        state.palette.clear();
+                    }
+                    for idx in indices {
+                        state.palette.remove(&idx);
+                    }
+                    state.updated = true;
+                }
+
+                OscCursorColor(ColorSpec::Rgb(rgba)) => {
+                    state.cursor_color = Some(rgba);
+                    state.updated = true;
+                }
+                OscCursorColor(ColorSpec::Query) => {
+                    let rgba = state.cursor_color.unwrap_or(config.cursor_bg);
+                    let spec = ColorSpec::format(rgba);
+                    use std::io::Write as _;
+                    FdIo(&self.pty)
+                        .write_fmt(format_args!("\x1b]12;{spec}\x1b\\"))
+                        .unwrap();
+                }
+                OscResetCursorColor => {
+                    state.cursor_color = None;
+                    state.updated = true;
+                }
+
+                OscNotify { title, body } => {
+                    log::debug!("notification: {:?}, {:?}", title, body);
+                    if state.notifications.len() < MAX_PENDING_NOTIFICATIONS {
+                        state.notifications.push(Notification { title, body });
+                    }
+                }
+
+                OscPromptMark(mark) => match mark {
+                    PromptMark::PromptStart | PromptMark::OutputStart => {
+                        let (row, _) = state.cursor.pos();
+                        state.lines[row].mark = Some(mark);
+                    }
+                    PromptMark::CommandStart | PromptMark::CommandFinished => {
+                        log::trace!("prompt mark: {:?}", mark);
+                    }
+                },
+
+                OscInlineImage(file) => {
+                    if !file.inline {
+                        log::debug!("inline image: downloading files is not supported");
+                        continue;
+                    }
+                    let image = match file.decode() {
+                        Ok(image) => image,
+                        Err(err) => {
+                            log::warn!("inline image: {err}");
+                            continue;
+                        }
+                    };
+
+                    let cell_w = self.cell_sz.w as u64;
+                    let cell_h = self.cell_sz.h as u64;
+                    let screen = (term_cols as u64 * cell_w, term_rows as u64 * cell_h);
+                    let (width, height) = file.display_size(&image, (cell_w, cell_h), screen);
+                    log::debug!(
+                        "inline image: {}x{} ({}x{})",
+                        width,
+                        height,
+                        image.width,
+                        image.height
+                    );
+
+                    let (row, col) = state.cursor.pos();
+                    let new_image = PositionedImage {
+                        row: row as isize,
+                        col: col as isize,
+                        width,
+                        height,
+                        data_width: image.width as u64,
+                        data_height: image.height as u64,
+                        data: image.data,
+                        id: 0,
+                    };
+                    state.put_image(new_image);
+                    move_cursor_over_image(&mut state, self.cell_sz, width, height);
+                }
+
+                ESC => {
+                    unreachable!();
+                }
+
+                NUL => ignore!(),
+                SOH => ignore!(),
+                STX => ignore!(),
+                EOT => ignore!(),
+                ENQ => {
+                    if !self.answerback.is_empty() {
+                        use std::io::Write as _;
+                        FdIo(&self.pty)
+                            .write_all(self.answerback.as_bytes())
+                            .unwrap();
+                    }
+                }
+                ACK => ignore!(),
+                BEL => {
+                    state.bell = Some(Instant::now());
+                }
+                SO => {
+                    state.shift_out = true;
+                }
+                SI => {
+                    state.shift_out = false;
+                }
+                DLE => ignore!(),
+                DC1 => ignore!(),
+                DC2 => ignore!(),
+                DC3 => ignore!(),
+                DC4 => ignore!(),
+                NAK => ignore!(),
+                SYN => ignore!(),
+                ETB => ignore!(),
+                CAN => ignore!(),
+                EM => ignore!(),
+                SUB => ignore!(),
+                IS4 => ignore!(),
+                IS3 => ignore!(),
+                IS2 => ignore!(),
+                IS1 => ignore!(),
+                DEL => {}
+
+                BPH => ignore!(),
+                NBH => ignore!(),
+                NEL => ignore!(),
+                SSA => ignore!(),
+                ESA => ignore!(),
+                HTS => ignore!(),
+                HTJ => ignore!(),
+                VTS => ignore!(),
+                PLD => ignore!(),
+                PLU => ignore!(),
+                RI => ignore!(),
+                SS2 => ignore!(),
+                SS3 => ignore!(),
+                DCS => ignore!(),
+                PU1 => ignore!(),
+                PU2 => ignore!(),
+                STS => ignore!(),
+                CCH => ignore!(),
+                MW => ignore!(),
+                SPA => ignore!(),
+                EPA => ignore!(),
+                SOS => ignore!(),
+                SCI => ignore!(),
+                ST => ignore!(),
+                OSC => ignore!(),
+                PM => ignore!(),
+                APC => ignore!(),
+
+                CNL => ignore!(),
+                CPL => ignore!(),
+                CHT => ignore!(),
+                EF => ignore!(),
+                EA => ignore!(),
+                SSE => ignore!(),
+                CPR => ignore!(),
+                SU => ignore!(),
+                SD => ignore!(),
+                NP => ignore!(),
+                PP => ignore!(),
+                CTC => ignore!(),
+                CVT => ignore!(),
+                CBT => ignore!(),
+                SRS => ignore!(),
+                PTX => ignore!(),
+                SDS => ignore!(),
+                SIMD => ignore!(),
+                HPA => ignore!(),
+                HPR => ignore!(),
+                REP => ignore!(),
+                DA => ignore!(),
+                VPR => ignore!(),
+                TBC => ignore!(),
+                MC => ignore!(),
+                HPB => ignore!(),
+                VPB => ignore!(),
+                DAQ => ignore!(),
+
+                SL => ignore!(),
+                SR => ignore!(),
+                GSM => ignore!(),
+                GSS => ignore!(),
+                FNT => ignore!(),
+                TSS => ignore!(),
+                JFY => ignore!(),
+                SPI => ignore!(),
+                QUAD => ignore!(),
                 SSU => ignore!(),
                 PFS => ignore!(),
                 SHS => ignore!(),
@@ -1593,102 +3143,206 @@ impl Engine {
     }
 }
 
-fn parse_color(prefix: u16, ps: &mut impl Iterator<Item = u16>) -> Option<Color> {
-    match prefix {
-        0 => Some(Color::Black),
-        1 => Some(Color::Red),
-        2 => Some(Color::Green),
-        3 => Some(Color::Yellow),
-        4 => Some(Color::Blue),
-        5 => Some(Color::Magenta),
-        6 => Some(Color::Cyan),
-        7 => Some(Color::White),
-
-        60 => Some(Color::BrightBlack),
-        61 => Some(Color::BrightRed),
-        62 => Some(Color::BrightGreen),
-        63 => Some(Color::BrightYellow),
-        64 => Some(Color::BrightBlue),
-        65 => Some(Color::BrightMagenta),
-        66 => Some(Color::BrightCyan),
-        67 => Some(Color::BrightWhite),
-
-        8 => {
-            match ps.next() {
-                // direct color
-                Some(2) => {
-                    if let (Some(r), Some(g), Some(b)) = (ps.next(), ps.next(), ps.next()) {
-                        let (r, g, b) = (r as u32, g as u32, b as u32);
-                        Some(Color::Rgb {
-                            rgba: (r << 24) | (g << 16) | (b << 8) | 0xFF,
-                        })
-                    } else {
-                        None
-                    }
-                }
-
-                // indexed color
-                Some(5) => {
-                    if let Some(idx @ 0..=255) = ps.next() {
-                        match idx {
-                            0 => Some(Color::Black),
-                            1 => Some(Color::Red),
-                            2 => Some(Color::Green),
-                            3 => Some(Color::Yellow),
-                            4 => Some(Color::Blue),
-                            5 => Some(Color::Magenta),
-                            6 => Some(Color::Cyan),
-                            7 => Some(Color::White),
-
-                            8 => Some(Color::BrightBlack),
-                            9 => Some(Color::BrightRed),
-                            10 => Some(Color::BrightGreen),
-                            11 => Some(Color::BrightYellow),
-                            12 => Some(Color::BrightBlue),
-                            13 => Some(Color::BrightMagenta),
-                            14 => Some(Color::BrightCyan),
-                            15 => Some(Color::BrightWhite),
-
-                            // 6x6x6 colors
-                            16..=231 => {
-                                let mut x = (idx - 16) as u32;
-
-                                let b = (x % 6) * 51;
-                                x /= 6;
-                                let g = (x % 6) * 51;
-                                x /= 6;
-                                let r = (x % 6) * 51;
-
-                                Some(Color::Rgb {
-                                    rgba: (r << 24) | (g << 16) | (b << 8) | 0xFF,
-                                })
-                            }
+/// Returns the reply to DECDSR (except the cursor position report).
+/// Devices which toyterm doesn't have are reported as missing or not ready.
+fn dec_status_report(ps: u16) -> Option<&'static str> {
+    match ps {
+        // printer: no printer
+        15 => Some("\x1b[?13n"),
+        // user-defined keys: locked
+        25 => Some("\x1b[?21n"),
+        // keyboard: North American, ready, LK201
+        26 => Some("\x1b[?27;1;0;0n"),
+        // locator: no locator
+        53 | 55 => Some("\x1b[?53n"),
+        // locator type: cannot identify
+        56 => Some("\x1b[?57;0n"),
+        // data integrity: no errors
+        75 => Some("\x1b[?70n"),
+        // multiple sessions: not configured
+        85 => Some("\x1b[?83n"),
+        _ => None,
+    }
+}
 
-                            // grayscale colors
-                            232..=255 => {
-                                let x = (idx - 232) as u32;
-                                let v = x * 11;
-                                Some(Color::Rgb {
-                                    rgba: (v << 24) | (v << 16) | (v << 8) | 0xFF,
-                                })
-                            }
+/// Returns the status of a mode reported by DECRPM.
+/// `private` is `b'?'` for DEC private modes and 0 for ANSI modes.
+fn mode_status(mode: &Mode, private: u8, pd: u16) -> u16 {
+    let set = match (private, pd) {
+        (0, 4) => mode.insert_mode,
+
+        (b'?', 5) => mode.reverse_video,
+        (b'?', 6) => mode.origin_mode,
+        (b'?', 7) => mode.autowrap,
+        (b'?', 25) => mode.cursor_visible,
+        (b'?', 80) => mode.sixel_scrolling,
+        (b'?', 1000) => mode.mouse_track,
+        (b'?', 1001) => return 4,
+        (b'?', 1002) => mode.mouse_track_drag,
+        (b'?', 1003) => mode.mouse_track_any,
+        (b'?', 1004) => mode.focus_event,
+        (b'?', 1006) => mode.sgr_ext_mouse_track,
+        (b'?', 1015) => mode.urxvt_ext_mouse_track,
+        (b'?', 1047 | 1049) => mode.alt_screen,
+        (b'?', 2004) => mode.bracketed_paste,
+
+        _ => return 0,
+    };
+    if set {
+        1
+    } else {
+        2
+    }
+}
 
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        None
-                    }
-                }
+/// Returns the log level for a function which is not implemented.
+/// `CSI = Ps h/l` set ANSI.SYS screen modes, which many programs send blindly.
+fn ignore_level(func: &control_function::Function) -> log::Level {
+    use control_function::Function::*;
+    match func {
+        SM(b'=', _) | RM(b'=', _) => log::Level::Trace,
+        _ => log::Level::Warn,
+    }
+}
 
-                // unknown color format
-                _ => None,
+/// Maps the characters to the DEC Special Graphics (line drawing) set
+fn dec_special_graphics(ch: char) -> char {
+    match ch {
+        '`' => '◆',
+        'a' => '▒',
+        'b' => '␉',
+        'c' => '␌',
+        'd' => '␍',
+        'e' => '␊',
+        'f' => '°',
+        'g' => '±',
+        'h' => '␤',
+        'i' => '␋',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => ch,
+    }
+}
+
+/// Parses the color of SGR 30-38, 40-48, 58 and 90-97 (100-107), given by `prefix`
+/// (relative to 30 or 40) and the following parameters and sub-parameters (with the flags)
+fn parse_color<I>(prefix: u16, ps: &mut std::iter::Peekable<I>) -> Option<Color>
+where
+    I: Iterator<Item = (u16, bool)>,
+{
+    match prefix {
+        0..=7 => Some(Color::indexed(prefix as u8)),
+        60..=67 => Some(Color::indexed((prefix - 60 + 8) as u8)),
+
+        // the colon form (e.g. 38:2::R:G:B) gives the parameters as sub-parameters
+        8 if ps.peek().map_or(false, |&(_, sub)| sub) => {
+            let mut subs: Vec<u16> = std::iter::from_fn(|| ps.next_if(|&(_, sub)| sub))
+                .map(|(p, _)| p)
+                .collect();
+
+            // skip the color space ID (usually empty) before the components
+            if subs.first() == Some(&2) && subs.len() >= 5 {
+                subs.remove(1);
             }
+            parse_extended_color(&mut subs.into_iter())
         }
 
+        // the semicolon form (e.g. 38;2;R;G;B) has no color space ID
+        8 => parse_extended_color(&mut ps.map(|(p, _)| p)),
+
         _ => unimplemented!(),
     }
 }
 
+/// Parses the parameters following 38 (48, 58): `2;R;G;B` or `5;IDX`
+fn parse_extended_color(ps: &mut impl Iterator<Item = u16>) -> Option<Color> {
+    match ps.next() {
+        // direct color
+        Some(2) => {
+            if let (Some(r), Some(g), Some(b)) = (ps.next(), ps.next(), ps.next()) {
+                let (r, g, b) = (r as u32, g as u32, b as u32);
+                Some(Color::Rgb {
+                    rgba: (r << 24) | (g << 16) | (b << 8) | 0xFF,
+                })
+            } else {
+                None
+            }
+        }
+
+        // indexed color
+        Some(5) => match ps.next() {
+            Some(idx @ 0..=255) => Some(Color::indexed(idx as u8)),
+            _ => None,
+        },
+
+        // unknown color format
+        _ => None,
+    }
+}
+
+/// Moves the cursor to the last row of an image, on the column right after it
+fn move_cursor_over_image(state: &mut State, cell_sz: CellSize, width: u64, height: u64) {
+    let cell_w = cell_sz.w as u64;
+    let cell_h = cell_sz.h as u64;
+    let advance_h = (width + cell_w - 1) / cell_w;
+    let advance_v = ((height + cell_h - 1) / cell_h).max(1) - 1;
+
+    for _ in 0..advance_h {
+        state.cursor = state.cursor.next_col();
+    }
+    for _ in 0..advance_v {
+        buffer_scroll_up_if_needed(state, cell_sz);
+        if state.cursor.row != state.scroll_region.1 {
+            state.cursor = state.cursor.next_row();
+        }
+    }
+}
+
+/// Returns the width and height of the text area in pixels
+fn text_area_pixels(size: TerminalSize, cell_sz: CellSize) -> (u32, u32) {
+    (size.cols as u32 * cell_sz.w, size.rows as u32 * cell_sz.h)
+}
+
+/// Returns the size of a kitty image on the screen, which fits in `c` columns and `r` rows if given.
+/// The size is limited to `max_size` (the text area), since the cursor is moved over the image.
+fn kitty_image_size(
+    cmd: &kitty::Command,
+    image: &kitty::Image,
+    cell_sz: CellSize,
+    max_size: (u32, u32),
+) -> (u64, u64) {
+    let (w, h) = (image.width.max(1) as u64, image.height.max(1) as u64);
+    let cols_w = (cmd.cols as u64).saturating_mul(cell_sz.w as u64);
+    let rows_h = (cmd.rows as u64).saturating_mul(cell_sz.h as u64);
+    let (width, height) = match (cmd.cols, cmd.rows) {
+        (0, 0) => (w, h),
+        (_, 0) => (cols_w, (h.saturating_mul(cols_w) / w).max(1)),
+        (0, _) => ((w.saturating_mul(rows_h) / h).max(1), rows_h),
+        _ => (cols_w, rows_h),
+    };
+    let (max_width, max_height) = max_size;
+    (width.min(max_width as u64), height.min(max_height as u64))
+}
+
 fn buffer_scroll_up_if_needed(state: &mut State, cell_sz: CellSize) {
     if state.cursor.row == state.scroll_region.1 {
         state.scroll_up();
@@ -1706,9 +3360,9 @@ fn buffer_scroll_up_if_needed(state: &mut State, cell_sz: CellSize) {
     }
 }
 
-/// Opens PTY device and spawn a shell
+/// Opens PTY device and spawn a shell (or `command` if given)
 /// `init_pty` returns a pair (PTY master, PID of shell)
-fn init_pty(cwd: &std::path::Path) -> Result<(OwnedFd, Pid)> {
+fn init_pty(cwd: &std::path::Path, command: Option<&[String]>) -> Result<(OwnedFd, Pid)> {
     use nix::unistd::ForkResult;
 
     // Safety: single threaded here
@@ -1718,8 +3372,13 @@ fn init_pty(cwd: &std::path::Path) -> Result<(OwnedFd, Pid)> {
         // Shell side
         ForkResult::Child => {
             std::env::set_current_dir(cwd).expect("chdir");
-            exec_shell()?;
-            unreachable!();
+            let err = exec_shell(command).unwrap_err();
+
+            // The error is shown on the terminal, and the child must not return to the caller
+            // (it would go on running as a copy of toyterm)
+            use std::io::Write as _;
+            let _ = writeln!(std::io::stderr(), "toyterm: {err}");
+            unsafe { nix::libc::_exit(127) };
         }
 
         // Terminal side
@@ -1731,8 +3390,9 @@ fn init_pty(cwd: &std::path::Path) -> Result<(OwnedFd, Pid)> {
     }
 }
 
-/// Setup process states and execute shell
-fn exec_shell() -> Result<()> {
+/// Setup process states and execute shell (or `command` if given).
+/// The program is searched in $PATH if it doesn't contain a slash.
+fn exec_shell(command: Option<&[String]>) -> Result<()> {
     use std::ffi::CString;
 
     // Restore the default handler for SIGPIPE (terminate)
@@ -1740,16 +3400,14 @@ fn exec_shell() -> Result<()> {
     let sigdfl = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
     unsafe { sigaction(Signal::SIGPIPE, &sigdfl).expect("sigaction") };
 
-    let args: Vec<CString> = crate::TOYTERM_CONFIG
-        .shell
+    let config = crate::TOYTERM_CONFIG.load();
+    let args: Vec<CString> = command
+        .unwrap_or(&config.shell)
         .iter()
         .map(|arg| CString::new(arg.to_owned()).unwrap())
         .collect();
 
-    let mut vars: std::collections::HashMap<String, String> = std::env::vars().collect();
-
-    vars.insert("TERM".to_owned(), "toyterm-256color".to_owned());
-
+    let vars = shell_environment(std::env::vars(), &config);
     let envs: Vec<CString> = vars
         .into_iter()
         .map(|(key, val)| {
@@ -1758,6 +3416,1541 @@ fn exec_shell() -> Result<()> {
         })
         .collect();
 
-    nix::unistd::execve(&args[0], &args, &envs)?;
-    unreachable!();
+    let err = nix::unistd::execvpe(&args[0], &args, &envs).unwrap_err();
+    let program = args[0].to_string_lossy();
+    let kind = std::io::Error::from(err).kind();
+    Err(std::io::Error::new(
+        kind,
+        format!("{program}: {}", err.desc()),
+    ))
+}
+
+/// Returns the environment variables of the shell: the inherited ones overridden by the config
+fn shell_environment<I>(inherited: I, config: &crate::config::Config) -> HashMap<String, String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut vars: HashMap<String, String> = inherited.into_iter().collect();
+
+    vars.insert("TERM".to_owned(), config.term.clone());
+    if config.colorterm.is_empty() {
+        vars.remove("COLORTERM");
+    } else {
+        vars.insert("COLORTERM".to_owned(), config.colorterm.clone());
+    }
+
+    for var in config.env.iter().filter(|var| !var.is_empty()) {
+        match var.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                vars.insert(name.to_owned(), value.to_owned());
+            }
+            _ => log::warn!("invalid environment variable: {:?}", var),
+        }
+    }
+    vars
+}
+
+#[cfg(test)]
+mod replay;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns an engine connected to a fresh PTY, along with the slave side of it
+    pub(super) fn new_engine(rows: usize, cols: usize) -> (Engine, OwnedFd) {
+        let pty = nix::pty::openpty(None, None).unwrap();
+
+        // Receive replies as they are
+        use nix::sys::termios;
+        let mut attrs = termios::tcgetattr(pty.slave).unwrap();
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(pty.slave, termios::SetArg::TCSANOW, &attrs).unwrap();
+
+        // Safety: these fds are owned only here
+        let master = unsafe { OwnedFd::from_raw_fd(pty.master) };
+        let slave = unsafe { OwnedFd::from_raw_fd(pty.slave) };
+
+        let (_, control_req) = pipe_channel::channel();
+        let (control_res, _) = pipe_channel::channel();
+
+        let engine = Engine::new(
+            Pid::this(),
+            master,
+            control_req,
+            control_res,
+            TerminalSize { rows, cols },
+            CellSize { w: 10, h: 20 },
+        );
+        (engine, slave)
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn take_string(&self) -> String {
+            String::from_utf8(std::mem::take(&mut self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_output_tee() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        let buf = SharedBuf::default();
+        engine.tee = Some(OutputTee::new(Box::new(buf.clone())));
+
+        engine.process("ab");
+        engine.process("\x1b[1mあ\r\n");
+        assert_eq!(buf.take_string(), "ab\n\\u{1b}[1mあ\\r\\n\n");
+
+        // the characters are processed as usual
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.lines[0].get(2).unwrap().ch, 'あ');
+        drop(state);
+
+        // the output is rate-limited
+        let rest = OutputTee::RATE_LIMIT - engine.tee.as_ref().unwrap().written;
+        let long = "x".repeat(rest);
+        engine.process(&long);
+        engine.process("y");
+        assert_eq!(buf.take_string(), format!("{long}\n"));
+    }
+
+    // Reads the data written by the engine
+    fn read_reply(slave: &OwnedFd) -> String {
+        use nix::poll::{poll, PollFd, PollFlags};
+
+        let mut reply = Vec::new();
+        let mut fds = [PollFd::new(slave.as_raw_fd(), PollFlags::POLLIN)];
+        while poll(&mut fds, 100).unwrap() > 0 {
+            let mut buf = [0; 256];
+            let nb = nix::unistd::read(slave.as_raw_fd(), &mut buf).unwrap();
+            reply.extend_from_slice(&buf[..nb]);
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    /// Runs the shell command on a terminal with 10x20 px cells
+    fn spawn_terminal(rows: usize, cols: usize, command: &str) -> Terminal {
+        let command = ["sh".to_owned(), "-c".to_owned(), command.to_owned()];
+        let cwd = std::env::temp_dir();
+        let size = TerminalSize { rows, cols };
+        Terminal::new(size, CellSize { w: 10, h: 20 }, &cwd, Some(&command))
+    }
+
+    /// Waits until the condition on the state holds
+    fn wait_until(terminal: &Terminal, cond: impl Fn(&State) -> bool) {
+        for _ in 0..500 {
+            if cond(&terminal.state.lock().unwrap()) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    fn screen_text(state: &State) -> String {
+        state
+            .lines
+            .iter()
+            .flat_map(|line| line.iter().map(|c| c.ch))
+            .collect()
+    }
+
+    #[test]
+    fn test_poll_event() {
+        use nix::poll::PollFlags;
+        let classify = |flags| PollEvent::classify(Some(flags));
+
+        assert_eq!(PollEvent::classify(None), PollEvent::Nothing);
+        assert_eq!(classify(PollFlags::empty()), PollEvent::Nothing);
+        assert_eq!(classify(PollFlags::POLLPRI), PollEvent::Nothing);
+        assert_eq!(classify(PollFlags::POLLIN), PollEvent::Readable);
+        assert_eq!(
+            classify(PollFlags::POLLIN | PollFlags::POLLHUP),
+            PollEvent::Readable
+        );
+        assert_eq!(classify(PollFlags::POLLHUP), PollEvent::Closed);
+        assert_eq!(classify(PollFlags::POLLERR), PollEvent::Closed);
+        assert_eq!(classify(PollFlags::POLLNVAL), PollEvent::Closed);
+        assert_eq!(
+            classify(PollFlags::POLLIN | PollFlags::POLLNVAL),
+            PollEvent::Closed
+        );
+    }
+
+    #[test]
+    fn test_cursor_style_alt_screen() {
+        let (mut engine, _slave) = new_engine(4, 10);
+
+        engine.process("\x1b[2 q");
+        assert_eq!(
+            engine.state.lock().unwrap().cursor.style,
+            CursorStyle::Block
+        );
+
+        // full-screen app sets a bar cursor (and saves the cursor on its own)
+        engine.process("\x1b[?1049h\x1b[6 q\x1b7");
+        assert_eq!(engine.state.lock().unwrap().cursor.style, CursorStyle::Bar);
+
+        // leaving the alternate screen restores the previous style
+        engine.process("\x1b[?1049l");
+        assert_eq!(
+            engine.state.lock().unwrap().cursor.style,
+            CursorStyle::Block
+        );
+
+        // DECRC doesn't change the style
+        engine.process("\x1b[4 q\x1b8");
+        assert_eq!(
+            engine.state.lock().unwrap().cursor.style,
+            CursorStyle::Underline
+        );
+    }
+
+    #[test]
+    fn test_cursor_blink() {
+        let (mut engine, _slave) = new_engine(4, 10);
+        let style = |engine: &Engine| {
+            let cursor = engine.state.lock().unwrap().cursor;
+            (cursor.style, cursor.blink)
+        };
+
+        assert_eq!(style(&engine), (CursorStyle::Block, None));
+
+        engine.process("\x1b[1 q");
+        assert_eq!(style(&engine), (CursorStyle::Block, Some(true)));
+        engine.process("\x1b[2 q");
+        assert_eq!(style(&engine), (CursorStyle::Block, Some(false)));
+        engine.process("\x1b[3 q");
+        assert_eq!(style(&engine), (CursorStyle::Underline, Some(true)));
+        engine.process("\x1b[4 q");
+        assert_eq!(style(&engine), (CursorStyle::Underline, Some(false)));
+        engine.process("\x1b[5 q");
+        assert_eq!(style(&engine), (CursorStyle::Bar, Some(true)));
+
+        // the blinking is kept across the alternate screen
+        engine.process("\x1b[?1049h\x1b[6 q\x1b[?1049l");
+        assert_eq!(style(&engine), (CursorStyle::Bar, Some(true)));
+
+        // 0 goes back to the configured one
+        engine.process("\x1b[0 q");
+        assert_eq!(style(&engine), (CursorStyle::Block, None));
+    }
+
+    #[test]
+    fn test_soft_reset() {
+        let (mut engine, _slave) = new_engine(4, 10);
+
+        engine.process("abc\x1b[2;3r\x1b[?25l\x1b[1;31m\x1b[2;5H\x1b7");
+        engine.process("\x1b[!p");
+
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.scroll_region, (0, 3));
+            assert!(state.mode.cursor_visible);
+            assert_eq!(state.attr, GraphicAttribute::default());
+
+            // the screen is not cleared
+            let text: String = state.lines[0].iter().take(3).map(|c| c.ch).collect();
+            assert_eq!(text, "abc");
+        }
+
+        // the saved cursor is reset to the home position
+        engine.process("\x1b8");
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.cursor.pos(), (0, 0));
+    }
+
+    #[cfg(feature = "multiplex")]
+    #[test]
+    fn test_detach_and_attach() {
+        let mut terminal = spawn_terminal(3, 20, "echo ready; read line; echo got $line");
+        wait_until(&terminal, |state| screen_text(state).contains("ready"));
+
+        // the shell keeps running while its PTY is held
+        let (pty, pid) = terminal.detach();
+        drop(terminal);
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(nix::sys::signal::kill(pid, None).is_ok());
+
+        let size = TerminalSize { rows: 3, cols: 20 };
+        let mut attached = Terminal::attach(size, CellSize { w: 10, h: 20 }, pty, pid);
+        attached.pty_write(b"hello\r");
+        wait_until(&attached, |state| screen_text(state).contains("got hello"));
+        wait_until(&attached, |state| state.exit_status.is_some());
+    }
+
+    #[test]
+    fn test_exec_failure() {
+        let command = ["/nonexistent/command".to_owned()];
+        let cwd = std::env::temp_dir();
+        let size = TerminalSize { rows: 3, cols: 80 };
+        let terminal = Terminal::new(size, CellSize { w: 10, h: 20 }, &cwd, Some(&command));
+
+        // the child exits with the error shown, as a shell does
+        wait_until(&terminal, |state| state.exit_status.is_some());
+        let state = terminal.state.lock().unwrap();
+        assert_eq!(state.exit_status, Some(127));
+        assert!(screen_text(&state).contains("toyterm: /nonexistent/command: No such file"));
+    }
+
+    #[test]
+    fn test_restore_sane_state() {
+        let setup = r"printf 'abc\033[?1049h\033[?1000h\033[?25l\033[2;3r\033[1mxyz'";
+        let mut terminal = spawn_terminal(3, 5, &format!("{setup}; read line"));
+        wait_until(&terminal, |state| {
+            state.mode.alt_screen && screen_text(state).contains("xyz")
+        });
+
+        // the application exits without resetting the modes
+        terminal.pty_write(b"\r");
+        wait_until(&terminal, |state| state.exit_status.is_some());
+        {
+            let state = terminal.state.lock().unwrap();
+            assert!(!state.mode.alt_screen);
+            assert!(!state.mode.mouse_track);
+            assert!(state.mode.cursor_visible);
+            assert_eq!(state.scroll_region, (0, 2));
+            assert_eq!(state.attr, GraphicAttribute::default());
+
+            let text: String = state.lines[0].iter().take(3).map(|c| c.ch).collect();
+            assert_eq!(text, "abc");
+        }
+
+        // RIS also clears the screen
+        let (mut engine, _slave) = new_engine(3, 5);
+        engine.process("\x1b[?1049h\x1b[?1006h\x1bc");
+        let state = engine.state.lock().unwrap();
+        assert!(!state.mode.alt_screen);
+        assert!(!state.mode.sgr_ext_mouse_track);
+        assert_eq!(state.cursor.pos(), (0, 0));
+        assert!(state.lines[0].iter().all(|c| c.ch == '\n'));
+    }
+
+    #[test]
+    fn test_sixel_aspect_ratio() {
+        // cells are 10x20 pixels
+        let (mut engine, _slave) = new_engine(5, 10);
+        let last_image = |engine: &Engine| {
+            let state = engine.state.lock().unwrap();
+            let img = state.images.last().unwrap();
+            let size = (img.width, img.height, img.data_width, img.data_height);
+            (size, state.cursor.pos())
+        };
+
+        engine.process("\x1bPq\"1;1;10;12\x1b\\");
+        assert_eq!(last_image(&engine), ((10, 12, 10, 12), (0, 1)));
+
+        // the cursor advances by the size on the screen
+        engine.process("\x1b[H\x1bPq\"4;1;10;12\x1b\\");
+        assert_eq!(last_image(&engine), ((10, 48, 10, 12), (2, 1)));
+
+        // no larger than the screen (5x10 cells)
+        engine.process("\x1b[H\x1bPq\"10;20;10;12\x1b\\");
+        assert_eq!(last_image(&engine), ((100, 100, 10, 12), (4, 9)));
+    }
+
+    #[test]
+    fn test_sixel_colors_reset() {
+        let (mut engine, _slave) = new_engine(3, 10);
+        let first_pixel = |engine: &Engine| {
+            let state = engine.state.lock().unwrap();
+            state.images.last().unwrap().data[..4].to_vec()
+        };
+
+        // color registers persist across images
+        engine.process("\x1bPq#5;2;0;100;0#5~\x1b\\");
+        engine.process("\x1bPq#5~\x1b\\");
+        assert_eq!(first_pixel(&engine), [0, 255, 0, 255]);
+
+        // until RIS
+        engine.process("\x1bc\x1bPq#5~\x1b\\");
+        assert_eq!(first_pixel(&engine), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_kitty_graphics() {
+        // cells are 10x20 pixels
+        let (mut engine, slave) = new_engine(5, 10);
+        let images = |engine: &Engine| {
+            let state = engine.state.lock().unwrap();
+            let images: Vec<_> = (state.images.iter())
+                .map(|img| (img.id, img.row, img.col, img.width, img.height))
+                .collect();
+            (images, state.cursor.pos())
+        };
+
+        // a 1x1 red pixel shown in 2 columns, transmitted in 2 chunks
+        engine.process("\x1b_Ga=T,f=32,s=1,v=1,i=5,c=2,m=1;/wAA\x1b\\");
+        engine.process("\x1b_Gm=0;/w==\x1b\\");
+        assert_eq!(images(&engine), (vec![(5, 0, 0, 20, 20)], (0, 2)));
+        assert_eq!(read_reply(&slave), "\x1b_Gi=5;OK\x1b\\");
+
+        // placed again by the id, without moving the cursor
+        engine.process("\x1b[3;1H\x1b_Ga=p,i=5,C=1,q=1\x1b\\");
+        assert_eq!(images(&engine).0.len(), 2);
+        assert_eq!(images(&engine).1, (2, 0));
+        assert_eq!(read_reply(&slave), "");
+
+        // no larger than the screen (5x10 cells)
+        engine.process("\x1b[H\x1b_Ga=p,i=5,c=4000000000,r=4000000000,q=1\x1b\\");
+        assert_eq!(images(&engine).0.last(), Some(&(5, 0, 0, 100, 100)));
+        assert_eq!(images(&engine).1, (4, 9));
+
+        engine.process("\x1b_Ga=d,d=I,i=5\x1b\\");
+        assert_eq!(images(&engine).0, vec![]);
+        engine.process("\x1b_Ga=p,i=5\x1b\\");
+        assert_eq!(
+            read_reply(&slave),
+            "\x1b_Gi=5;OK\x1b\\\x1b_Gi=5;ENOENT:no such image\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_covered_images_removed() {
+        let (mut engine, _slave) = new_engine(5, 10);
+        let sixel = |p2: u8| format!("\x1b[H\x1bP0;{p2};0q#0;2;0;0;100?~\x1b\\");
+
+        engine.process(&sixel(0));
+        engine.process(&sixel(0));
+        assert_eq!(engine.state.lock().unwrap().images.len(), 1);
+
+        // a transparent image covering the others replaces them too
+        engine.process(&sixel(1));
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.images.len(), 1);
+        assert_eq!(state.images[0].data[3], 0);
+    }
+
+    #[test]
+    fn test_inline_image() {
+        // cells are 10x20 pixels
+        let (mut engine, _slave) = new_engine(5, 10);
+
+        // a 1x1 grayscale PNG image shown in 3 columns
+        let png = crate::png::tests::encode(1, 1, 8, 0, &[], &[0, 0x80]);
+        let payload = crate::utils::base64::encode(&png);
+        engine.process(&format!("\x1b]1337;File=width=3;inline=1:{payload}\x07"));
+        {
+            let state = engine.state.lock().unwrap();
+            let img = state.images.last().unwrap();
+            assert_eq!((img.width, img.height), (30, 30));
+            assert_eq!(img.data, [0x80, 0x80, 0x80, 0xFF]);
+            assert_eq!(state.cursor.pos(), (1, 3));
+        }
+
+        // not inline
+        engine.process(&format!("\x1b]1337;File=:{payload}\x07"));
+        assert_eq!(engine.state.lock().unwrap().images.len(), 1);
+    }
+
+    #[test]
+    fn test_cursor_color() {
+        let (mut engine, slave) = new_engine(2, 10);
+
+        engine.process("\x1b]12;#102030\x1b\\");
+        assert_eq!(
+            engine.state.lock().unwrap().cursor_color(),
+            Some(0x102030FF)
+        );
+
+        engine.process("\x1b]12;?\x07");
+        assert_eq!(read_reply(&slave), "\x1b]12;rgb:1010/2020/3030\x1b\\");
+
+        engine.process("\x1b]112\x07");
+        assert_eq!(engine.state.lock().unwrap().cursor_color(), None);
+
+        engine.process("\x1b]12;rgb:ff/ff/ff\x07\x1bc");
+        assert_eq!(engine.state.lock().unwrap().cursor_color(), None);
+    }
+
+    #[test]
+    fn test_palette() {
+        let (mut engine, slave) = new_engine(2, 10);
+
+        engine.process("\x1b]4;1;#102030;200;rgb:ff/ff/ff\x1b\\");
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.palette().get(&1), Some(&0x102030FF));
+            assert_eq!(state.palette().get(&200), Some(&0xFFFFFFFF));
+        }
+
+        engine.process("\x1b]4;1;?;16;?\x07");
+        assert_eq!(
+            read_reply(&slave),
+            "\x1b]4;1;rgb:1010/2020/3030\x1b\\\x1b]4;16;rgb:0000/0000/0000\x1b\\"
+        );
+
+        engine.process("\x1b]104;1\x07");
+        assert_eq!(engine.state.lock().unwrap().palette().len(), 1);
+        engine.process("\x1b]104\x07");
+        assert!(engine.state.lock().unwrap().palette().is_empty());
+    }
+
+    #[test]
+    fn test_prompt_marks() {
+        let (mut engine, _slave) = new_engine(3, 10);
+
+        let prompt = "\x1b]133;A\x07$ \x1b]133;B\x07";
+        engine.process(&format!(
+            "{prompt}ls\r\n\x1b]133;C\x07a\r\nb\r\n\x1b]133;D;0\x07"
+        ));
+        engine.process(&format!("{prompt}pwd\r\n"));
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.prompt_rows(), vec![0, 3]);
+            let marks: Vec<_> = state.range(-2, 3).map(|l| l.mark).collect();
+            let expected = [
+                Some(PromptMark::PromptStart),
+                Some(PromptMark::OutputStart),
+                None,
+                Some(PromptMark::PromptStart),
+                None,
+            ];
+            assert_eq!(marks, expected);
+        }
+
+        // the marks are kept on reflowing
+        let mut state = engine.state.lock().unwrap();
+        state.resize(TerminalSize { rows: 3, cols: 2 });
+        assert_eq!(state.prompt_rows(), vec![0, 4]);
+        state.resize(TerminalSize { rows: 3, cols: 10 });
+        assert_eq!(state.prompt_rows(), vec![0, 3]);
+        drop(state);
+
+        engine.state.lock().unwrap().clear_history();
+        assert_eq!(engine.state.lock().unwrap().prompt_rows(), vec![1]);
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (mut engine, _slave) = new_engine(2, 10);
+
+        engine.process("\x1b]9;done\x07\x1b]777;notify;make;ok\x1b\\");
+        let notifications = engine.state.lock().unwrap().take_notifications();
+        let expected = [
+            Notification {
+                title: None,
+                body: "done".to_owned(),
+            },
+            Notification {
+                title: Some("make".to_owned()),
+                body: "ok".to_owned(),
+            },
+        ];
+        assert_eq!(notifications, expected);
+        assert!(engine.state.lock().unwrap().take_notifications().is_empty());
+
+        // too many notifications are dropped
+        engine.process(&"\x1b]9;spam\x07".repeat(20));
+        let notifications = engine.state.lock().unwrap().take_notifications();
+        assert_eq!(notifications.len(), MAX_PENDING_NOTIFICATIONS);
+    }
+
+    #[test]
+    fn test_shell_environment() {
+        let inherited = || {
+            [
+                ("TERM", "xterm"),
+                ("COLORTERM", "24bit"),
+                ("HOME", "/home/a"),
+            ]
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        };
+
+        let config = crate::config::Config::default();
+        let vars = shell_environment(inherited(), &config);
+        assert_eq!(vars["TERM"], "toyterm-256color");
+        assert_eq!(vars["COLORTERM"], "truecolor");
+        assert_eq!(vars["HOME"], "/home/a");
+
+        let config = crate::config::Config {
+            term: "xterm-256color".to_owned(),
+            colorterm: String::new(),
+            env: vec!["A=1=2".to_owned(), "HOME=/tmp".to_owned(), "B".to_owned()],
+            ..Default::default()
+        };
+        let vars = shell_environment(inherited(), &config);
+        assert_eq!(vars["TERM"], "xterm-256color");
+        assert!(!vars.contains_key("COLORTERM"));
+        assert_eq!(vars["A"], "1=2");
+        assert_eq!(vars["HOME"], "/tmp");
+        assert!(!vars.contains_key("B"));
+    }
+
+    #[test]
+    fn test_italic() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("\x1b[3ma\x1b[23mb\x1b[1;3mc\x1b[0md");
+
+        let state = engine.state.lock().unwrap();
+        let italic: Vec<bool> = state.lines[0]
+            .iter()
+            .take(4)
+            .map(|c| c.attr.italic)
+            .collect();
+        assert_eq!(italic, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_underline() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("\x1b[4ma\x1b[21mb\x1b[24mc\x1b[4md\x1b[0me");
+
+        let state = engine.state.lock().unwrap();
+        let underline: Vec<u8> = state.lines[0]
+            .iter()
+            .take(5)
+            .map(|c| c.attr.underline)
+            .collect();
+        assert_eq!(underline, vec![1, 2, 0, 1, 0]);
+        drop(state);
+
+        // styles given by sub-parameters
+        engine.process("\r\x1b[4:3ma\x1b[4:0mb\x1b[4:5;1mc\x1b[4:9md\x1b[38:5:1;4:4me");
+        let state = engine.state.lock().unwrap();
+        let underline: Vec<u8> = state.lines[0]
+            .iter()
+            .take(5)
+            .map(|c| c.attr.underline)
+            .collect();
+        assert_eq!(underline, vec![3, 0, 5, 5, 4]);
+        assert_eq!(state.lines[0].get(2).unwrap().attr.bold, 1);
+        assert_eq!(state.lines[0].get(4).unwrap().attr.fg, Color::Red);
+    }
+
+    #[test]
+    fn test_strike() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("\x1b[9ma\x1b[29mb\x1b[9mc\x1b[0md");
+
+        let state = engine.state.lock().unwrap();
+        let strike: Vec<bool> = state.lines[0]
+            .iter()
+            .take(4)
+            .map(|c| c.attr.strike)
+            .collect();
+        assert_eq!(strike, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_overline() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("\x1b[53ma\x1b[55mb\x1b[53;9mc\x1b[0md");
+
+        let state = engine.state.lock().unwrap();
+        let overline: Vec<bool> = state.lines[0]
+            .iter()
+            .take(4)
+            .map(|c| c.attr.overline)
+            .collect();
+        assert_eq!(overline, vec![true, false, true, false]);
+        assert!(state.lines[0].get(2).unwrap().attr.strike);
+    }
+
+    #[test]
+    fn test_indexed_colors() {
+        let (mut engine, _slave) = new_engine(1, 10);
+        engine.process("\x1b[38;5;1ma\x1b[31mb\x1b[38;5;9mc\x1b[91md");
+        engine.process("\x1b[38;5;196me\x1b[38;5;244mf");
+
+        let state = engine.state.lock().unwrap();
+        let colors: Vec<Color> = state.lines[0].iter().take(6).map(|c| c.attr.fg).collect();
+        let expected = [
+            Color::Red,
+            Color::Red,
+            Color::BrightRed,
+            Color::BrightRed,
+            Color::Indexed(196),
+            Color::Indexed(244),
+        ];
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn test_direct_colors() {
+        let (mut engine, _slave) = new_engine(1, 10);
+        engine.process("\x1b[38;2;255;0;0ma\x1b[38:2:255:0:0mb\x1b[38:2::255:0:0mc");
+        engine.process("\x1b[38:2:1:0:255:0md\x1b[48:2::0:0:255;58:2::1:2:3me");
+
+        let state = engine.state.lock().unwrap();
+        let colors: Vec<Color> = state.lines[0].iter().take(5).map(|c| c.attr.fg).collect();
+        let rgb = |rgba| Color::Rgb { rgba };
+        let red = rgb(0xFF0000FF);
+        let green = rgb(0x00FF00FF);
+        assert_eq!(colors, [red, red, red, green, green]);
+
+        // the color space ID is skipped in 48 and 58 as well
+        let cell = state.lines[0].get(4).unwrap();
+        assert_eq!(cell.attr.bg, rgb(0x0000FFFF));
+        assert_eq!(cell.attr.underline_color, Some(rgb(0x010203FF)));
+    }
+
+    #[test]
+    fn test_underline_color() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("\x1b[4;58;5;1ma\x1b[58;2;1;2;3mb\x1b[59mc\x1b[58:5:2;31md");
+
+        let state = engine.state.lock().unwrap();
+        let colors: Vec<Option<Color>> = state.lines[0]
+            .iter()
+            .take(4)
+            .map(|c| c.attr.underline_color)
+            .collect();
+        let rgb = Color::Rgb { rgba: 0x010203FF };
+        let expected = [Some(Color::Red), Some(rgb), None, Some(Color::Green)];
+        assert_eq!(colors, expected);
+
+        // the other attributes are kept
+        let cell = state.lines[0].get(3).unwrap();
+        assert_eq!(cell.attr.underline, 1);
+        assert_eq!(cell.attr.fg, Color::Red);
+    }
+
+    #[test]
+    fn test_highlight_mouse_tracking() {
+        let (mut engine, _slave) = new_engine(3, 4);
+        engine.process("ab\r\ncd\x1b[?1001h\x1b[1;1;1;1;3T");
+
+        let state = engine.state.lock().unwrap();
+        assert!(!state.mode.mouse_track);
+
+        // the screen is not scrolled
+        let head: String = state.lines[0].iter().take(2).map(|c| c.ch).collect();
+        assert_eq!(head, "ab");
+        assert_eq!((state.cursor.row, state.cursor.col), (1, 2));
+    }
+
+    #[test]
+    fn test_double_width_line() {
+        let (mut engine, _slave) = new_engine(3, 6);
+        engine.process("abcdef\r\n\x1b#6ghijk\x1b#6");
+
+        let state = engine.state.lock().unwrap();
+        let rows: Vec<String> = state
+            .lines
+            .iter()
+            .map(|line| line.iter().map(|c| c.ch).collect())
+            .collect();
+
+        // only 3 columns are available in the double-width line
+        assert_eq!(rows, vec!["abcdef", "ghi   ", "jk\n   "]);
+        assert!(!state.lines[0].double_width());
+        assert!(state.lines[1].linewrap());
+        assert!(state.lines[1].double_width());
+        assert!(state.lines[2].double_width());
+        assert_eq!(state.cursor.pos(), (2, 2));
+        drop(state);
+
+        // the cursor is moved into the left half
+        engine.process("\x1b[1;6H\x1b#6");
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.cursor.pos(), (0, 2));
+        let head: String = state.lines[0].iter().map(|c| c.ch).collect();
+        assert_eq!(head, "abc   ");
+        drop(state);
+
+        // DECSWL and erasing the screen reset it
+        engine.process("\x1b#5\x1b[3;1H\x1b[2J");
+        let state = engine.state.lock().unwrap();
+        assert!(state.lines.iter().all(|line| !line.double_width()));
+    }
+
+    #[test]
+    fn test_double_height_line() {
+        let (mut engine, _slave) = new_engine(3, 6);
+        engine.process("\x1b#3abc\r\n\x1b#4ab");
+
+        let state = engine.state.lock().unwrap();
+        let sizes: Vec<LineSize> = state.lines.iter().map(|line| line.size()).collect();
+        assert_eq!(
+            sizes,
+            vec![
+                LineSize::DoubleHeightTop,
+                LineSize::DoubleHeightBottom,
+                LineSize::Normal
+            ]
+        );
+
+        // double-height lines are also double-width
+        let rows: Vec<String> = state
+            .lines
+            .iter()
+            .map(|line| line.iter().map(|c| c.ch).collect())
+            .collect();
+        assert_eq!(rows, vec!["abc   ", "ab\n   ", "\n\n\n\n\n\n"]);
+    }
+
+    #[test]
+    fn test_dec_special_graphics() {
+        let (mut engine, _slave) = new_engine(3, 6);
+        let row = |engine: &Engine, i: usize| -> String {
+            let state = engine.state.lock().unwrap();
+            state.lines[i].iter().map(|c| c.ch).collect()
+        };
+
+        // G0
+        engine.process("\x1b(0lqkA\x1b(Bq\r\n");
+        assert_eq!(row(&engine, 0), "┌─┐Aq\n");
+
+        // G1 is invoked by SO, and G0 by SI
+        engine.process("\x1b)0x\x0ex\x0fx\r\n");
+        assert_eq!(row(&engine, 1), "x│x\n\n\n");
+
+        // reset by DECSTR
+        engine.process("\x1b(0\x0e\x1b[!pq");
+        assert_eq!(row(&engine, 2), "q\n\n\n\n\n");
+    }
+
+    #[test]
+    fn test_hyperlink() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        engine.process("a\x1b]8;;http://x/\x1b\\bc\x1b]8;;\x1b\\d\x1b[0m");
+
+        let state = engine.state.lock().unwrap();
+        let links: Vec<Option<&str>> = state.lines[0]
+            .iter()
+            .take(4)
+            .map(|c| c.attr.link.and_then(|id| state.link(id)))
+            .collect();
+        assert_eq!(
+            links,
+            vec![None, Some("http://x/"), Some("http://x/"), None]
+        );
+    }
+
+    #[test]
+    fn test_hyperlink_nested() {
+        let (mut engine, _slave) = new_engine(2, 10);
+
+        // opening a link while another is open replaces it,
+        // and links with the same id and URI are the same link
+        engine.process("\x1b]8;id=1;http://a/\x07a");
+        engine.process("\x1b]8;;http://b/\x07b");
+        engine.process("\x1b]8;id=1;http://a/\x07c");
+        engine.process("\x1b]8;id=2;http://a/\x07d");
+        engine.process("\x1b]8;;\x07e");
+
+        let state = engine.state.lock().unwrap();
+        let ids: Vec<Option<u32>> = state.lines[0].iter().take(5).map(|c| c.attr.link).collect();
+        let uris: Vec<Option<&str>> = ids
+            .iter()
+            .map(|id| id.and_then(|id| state.link(id)))
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                Some("http://a/"),
+                Some("http://b/"),
+                Some("http://a/"),
+                Some("http://a/"),
+                None
+            ]
+        );
+        assert_eq!(ids[0], ids[2]);
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[0], ids[3]);
+    }
+
+    #[test]
+    fn test_rectangular_area() {
+        let (mut engine, _slave) = new_engine(4, 6);
+        let rows = |engine: &Engine| -> Vec<String> {
+            let state = engine.state.lock().unwrap();
+            state
+                .lines
+                .iter()
+                .map(|line| {
+                    line.iter()
+                        .map(|c| if c.ch == '\n' { '.' } else { c.ch })
+                        .collect()
+                })
+                .collect()
+        };
+
+        // DECFRA: fill rows 2-3, columns 2-4 with 'x'
+        engine.process("\x1b[1m\x1b[120;2;2;3;4$x");
+        assert_eq!(rows(&engine), ["......", ".xxx..", ".xxx..", "......"]);
+        assert_eq!(
+            engine.state.lock().unwrap().lines[1]
+                .get(1)
+                .unwrap()
+                .attr
+                .bold,
+            1
+        );
+
+        // DECERA: erase row 3, columns 3-6
+        engine.process("\x1b[3;3;3;6$z");
+        assert_eq!(rows(&engine), ["......", ".xxx..", ".x    ", "......"]);
+
+        // DECCRA: copy the rectangle (rows 2-3, columns 1-3) to (1, 4)
+        engine.process("\x1b[2;1;3;3;1;1;4;1$v");
+        assert_eq!(rows(&engine), ["....xx", ".xx.x ", ".x    ", "......"]);
+
+        // default parameters fill the whole screen
+        engine.process("\x1b[35$x");
+        assert_eq!(rows(&engine), ["######"; 4]);
+    }
+
+    #[test]
+    fn test_rectangular_area_wide() {
+        let (mut engine, _slave) = new_engine(2, 6);
+
+        // a wide character partially covered by the area is erased
+        engine.process("\u{3042}\u{3044}\u{3046}\x1b[1;2;1;3$z");
+        let state = engine.state.lock().unwrap();
+        let text: String = state.lines[0]
+            .iter()
+            .filter(|c| c.width > 0)
+            .map(|c| c.ch)
+            .collect();
+        assert_eq!(text, "    \u{3046}");
+    }
+
+    #[test]
+    fn test_screen_alignment() {
+        let (mut engine, _slave) = new_engine(3, 4);
+
+        engine.process("ab\r\n\x1b[31mcd\x1b#8");
+
+        let state = engine.state.lock().unwrap();
+        for line in state.lines.iter() {
+            assert!(line
+                .iter()
+                .all(|c| c.ch == 'E' && c.attr == GraphicAttribute::default()));
+        }
+        assert_eq!(state.cursor.pos(), (0, 0));
+        assert_eq!(state.history_size(), 0);
+        drop(state);
+
+        // double-width lines and the margins are reset
+        engine.process("\x1b[2;3r\x1b[2H\x1b#6\x1b[3H\x1b#3\x1b#8");
+        let state = engine.state.lock().unwrap();
+        assert!(state.lines.iter().all(|line| line.size == LineSize::Normal));
+        assert!(state.lines[1].iter().all(|c| c.ch == 'E'));
+        assert_eq!(state.scroll_region, (0, 2));
+        assert_eq!(state.cursor.pos(), (0, 0));
+    }
+
+    #[test]
+    fn test_insert_mode() {
+        let (mut engine, _slave) = new_engine(2, 6);
+        let row = |engine: &Engine, i: usize| -> String {
+            let state = engine.state.lock().unwrap();
+            let line = state.lines[i].iter().filter(|c| c.width > 0);
+            line.map(|c| c.ch).collect()
+        };
+
+        engine.process("abcdef\x1b[1;2H\x1b[4hXY");
+        assert_eq!(row(&engine, 0), "aXYbcd");
+
+        // wide characters shift by their width
+        engine.process("\u{3042}");
+        assert_eq!(row(&engine, 0), "aXY\u{3042}b");
+
+        engine.process("\x1b[4l\x1b[1;1HZ");
+        assert_eq!(row(&engine, 0), "ZXY\u{3042}b");
+        assert!(!engine.state.lock().unwrap().mode.insert_mode);
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let sz = TerminalSize { rows: 2, cols: 3 };
+
+        let mut state = State::new(sz, 3);
+        assert_eq!(state.history_size(), 0);
+        for i in 0..5 {
+            state.lines[0].put(0, Cell::new_ascii(char::from(b'a' + i)));
+            state.scroll_up();
+        }
+        assert_eq!(state.history_size(), 3);
+        assert_eq!(state.lines.len(), 2);
+
+        let heads: String = state.range(-3, 0).map(|l| l.cells[0].ch).collect();
+        assert_eq!(heads, "cde");
+
+        // out of the history
+        assert_eq!(state.range(-6, -4).count(), 0);
+        assert_eq!(state.range(-6, 1).count(), 4);
+
+        // shrunk at runtime
+        state.set_history_capacity(1);
+        assert_eq!(state.history_size(), 1);
+        let heads: String = state.range(-1, 0).map(|l| l.cells[0].ch).collect();
+        assert_eq!(heads, "e");
+
+        // no scrollback
+        let mut state = State::new(sz, 0);
+        state.scroll_up();
+        assert_eq!(state.history_size(), 0);
+        assert_eq!(state.lines.len(), 2);
+    }
+
+    #[cfg(feature = "multiplex")]
+    #[test]
+    fn test_put_banner() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        let row =
+            |state: &State, i: usize| -> String { state.lines[i].iter().map(|c| c.ch).collect() };
+
+        engine.process("$ exit");
+        let mut state = engine.state.lock().unwrap();
+        state.put_banner("[exited]");
+        assert_eq!(row(&state, 0), "$ exit\n\n\n\n");
+        assert_eq!(row(&state, 1), "[exited]\n\n");
+        assert!(state.lines[1].cells[0].attr.inversed);
+        assert!(!state.mode.cursor_visible);
+
+        // the screen scrolls at the bottom, and the banner is truncated
+        state.put_banner("a very long banner");
+        assert_eq!(state.history_size(), 1);
+        assert_eq!(row(&state, 0), "[exited]\n\n");
+        assert_eq!(row(&state, 1), "a very lon");
+    }
+
+    #[test]
+    fn test_clear_history_and_screen() {
+        let (mut engine, _slave) = new_engine(3, 5);
+        let blank = |state: &State| state.lines.iter().all(|l| l.iter().all(|c| c.ch == '\n'));
+
+        // ED 3 clears only the history
+        engine.process("a\r\nb\r\nc\r\nd\r\ne");
+        assert_eq!(engine.state.lock().unwrap().history_size(), 2);
+        engine.process("\x1b[3J");
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.history_size(), 0);
+            assert_eq!(state.lines[2].cells[0].ch, 'e');
+            assert_eq!(state.cursor.pos(), (2, 1));
+        }
+
+        // the combined action clears everything and homes the cursor
+        engine.process("\r\nf\r\ng");
+        let mut state = engine.state.lock().unwrap();
+        assert_eq!(state.history_size(), 2);
+        state.clear_all();
+        assert_eq!(state.history_size(), 0);
+        assert!(blank(&state));
+        assert_eq!(state.cursor.pos(), (0, 0));
+    }
+
+    #[test]
+    fn test_history_eviction() {
+        let sz = TerminalSize { rows: 2, cols: 3 };
+        let cell_sz = CellSize { w: 10, h: 10 };
+        let mut state = State::new(sz, 2);
+
+        // an image spanning the two rows
+        state.images.push(PositionedImage {
+            row: 0,
+            col: 0,
+            height: 20,
+            width: 10,
+            data_height: 20,
+            data_width: 10,
+            data: vec![0; 10 * 20 * 4],
+            id: 0,
+        });
+
+        // lines with hyperlinks are evicted from the full history
+        state.cursor = state.cursor.exact(1, 0);
+        for i in 0..2 * LINK_GC_INTERVAL {
+            state.open_link(None, format!("http://{i}/"));
+            let mut cell = Cell::new_ascii('a');
+            cell.attr.link = state.current_link;
+            state.lines[1].put(0, cell);
+            state.close_link();
+
+            buffer_scroll_up_if_needed(&mut state, cell_sz);
+
+            // the image is moved with the lines, and removed when it goes out of the screen
+            match i {
+                0 => assert_eq!(state.images[0].row, -1),
+                _ => assert!(state.images.is_empty()),
+            }
+        }
+        assert_eq!(state.history_size(), 2);
+
+        // the links referred from the lines are valid, and the evicted ones are forgotten
+        let used: Vec<u32> = state
+            .range(-2, 2)
+            .filter_map(|l| l.cells[0].attr.link)
+            .collect();
+        assert_eq!(used.len(), 3);
+        assert!(used.iter().all(|&id| state.link(id).is_some()));
+        assert!(state.links.len() <= LINK_GC_INTERVAL + used.len());
+    }
+
+    #[test]
+    fn test_resize_reflow() {
+        let (mut engine, _slave) = new_engine(3, 6);
+        let rows = |engine: &Engine| -> Vec<String> {
+            let state = engine.state.lock().unwrap();
+            let lines = state.lines.iter();
+            lines.map(|l| l.iter().map(|c| c.ch).collect()).collect()
+        };
+        let resize = |engine: &Engine, rows, cols| {
+            let mut state = engine.state.lock().unwrap();
+            state.resize(TerminalSize { rows, cols });
+        };
+
+        engine.process("abcdefgh\r\nxy");
+        assert_eq!(rows(&engine), ["abcdef", "gh\n\n\n\n", "xy\n\n\n\n"]);
+
+        // the soft-wrapped line is joined
+        resize(&engine, 3, 10);
+        assert_eq!(
+            rows(&engine),
+            ["abcdefgh\n\n", "xy\n\n\n\n\n\n\n\n", &"\n".repeat(10)]
+        );
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (1, 2));
+
+        // and wrapped again, pushing the first line into the history
+        resize(&engine, 3, 3);
+        assert_eq!(rows(&engine), ["def", "gh\n", "xy\n"]);
+        assert_eq!(engine.state.lock().unwrap().history_size(), 1);
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (2, 2));
+
+        resize(&engine, 3, 6);
+        assert_eq!(rows(&engine), ["abcdef", "gh\n\n\n\n", "xy\n\n\n\n"]);
+        assert_eq!(engine.state.lock().unwrap().history_size(), 0);
+
+        // a cursor waiting for the next character to wrap stays at the end
+        engine.process("\x1b[HABCDEFGHIJKL");
+        resize(&engine, 3, 4);
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.cursor.pos(), (1, 3));
+        assert!(state.cursor.end);
+    }
+
+    #[test]
+    fn test_resize_alt_screen_no_reflow() {
+        let (mut engine, _slave) = new_engine(2, 6);
+        let row = |lines: &VecDeque<Line>, i: usize| -> String {
+            lines[i].iter().map(|c| c.ch).collect()
+        };
+
+        engine.process("abcdefgh\x1b[?1049h\x1b[Habcdefgh");
+        engine
+            .state
+            .lock()
+            .unwrap()
+            .resize(TerminalSize { rows: 2, cols: 4 });
+
+        // the alternate screen is just truncated
+        let state = engine.state.lock().unwrap();
+        assert_eq!(row(&state.lines, 0), "abcd");
+        assert_eq!(row(&state.lines, 1), "gh\n\n");
+
+        // while the primary screen is reflowed
+        assert_eq!(row(&state.alt_lines, 0), "abcd");
+        assert_eq!(row(&state.alt_lines, 1), "efgh");
+        assert_eq!(state.history_size(), 0);
+    }
+
+    #[test]
+    fn test_bell() {
+        let (mut engine, _slave) = new_engine(2, 5);
+        assert!(engine.state.lock().unwrap().take_bell().is_none());
+
+        engine.process("\x07a");
+        let mut state = engine.state.lock().unwrap();
+        assert!(state.take_bell().is_some());
+        assert!(state.take_bell().is_none());
+        assert_eq!(state.lines[0].iter().next().unwrap().ch, 'a');
+    }
+
+    #[test]
+    fn test_insert_delete_at_end() {
+        let (mut engine, _slave) = new_engine(2, 5);
+        let row = |engine: &Engine, i: usize| -> String {
+            let state = engine.state.lock().unwrap();
+            state.lines[i].iter().map(|c| c.ch).collect()
+        };
+
+        // the cursor is at the end of the line (pending wrap)
+        engine.process("abcde\x1b[@");
+        assert_eq!(row(&engine, 0), "abcd ");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (0, 4));
+
+        // the next character is written at the last column without wrapping
+        engine.process("X");
+        assert_eq!(row(&engine, 0), "abcdX");
+        assert_eq!(row(&engine, 1), "\n\n\n\n\n");
+
+        engine.process("\x1b[P");
+        assert_eq!(row(&engine, 0), "abcd ");
+        engine.process("Y");
+        assert_eq!(row(&engine, 0), "abcdY");
+        assert_eq!(row(&engine, 1), "\n\n\n\n\n");
+    }
+
+    #[test]
+    fn test_del_ignored() {
+        let (mut engine, _slave) = new_engine(2, 10);
+        let row = |engine: &Engine| -> String {
+            let state = engine.state.lock().unwrap();
+            state.lines[0].iter().map(|c| c.ch).collect()
+        };
+
+        engine.process("a\x7Fb");
+        assert_eq!(row(&engine), "ab\n\n\n\n\n\n\n\n");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (0, 2));
+
+        // DEL in sequences doesn't break them
+        engine.process("\x1b[3\x7F1mc\x1b\x7F[md");
+        assert_eq!(row(&engine), "abcd\n\n\n\n\n\n");
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.lines[0].get(2).unwrap().attr.fg, Color::Red);
+            assert_eq!(state.lines[0].get(3).unwrap().attr.fg, Color::Foreground);
+        }
+
+        engine.process("\x1b#\x7F8");
+        let state = engine.state.lock().unwrap();
+        assert!(state.lines[1].iter().all(|c| c.ch == 'E'));
+    }
+
+    #[test]
+    fn test_equals_modes() {
+        use control_function::Function::*;
+
+        // unknown `=` modes are ignored without warnings
+        assert_eq!(ignore_level(&SM(b'=', &[5])), log::Level::Trace);
+        assert_eq!(ignore_level(&RM(b'=', &[19])), log::Level::Trace);
+        assert_eq!(ignore_level(&SM(b'?', &[9999])), log::Level::Warn);
+
+        let (mut engine, _slave) = new_engine(2, 4);
+        engine.process("\x1b[=5h\x1b[=19l");
+        assert!(engine.state.lock().unwrap().mode.autowrap);
+
+        // `=7` controls line wrapping
+        engine.process("\x1b[=7labcdef");
+        let row: String = {
+            let state = engine.state.lock().unwrap();
+            state.lines[0].iter().map(|c| c.ch).collect()
+        };
+        assert_eq!(row, "abcf");
+        engine.process("\x1b[=7h");
+        assert!(engine.state.lock().unwrap().mode.autowrap);
+    }
+
+    #[test]
+    fn test_autowrap_mode() {
+        let (mut engine, _slave) = new_engine(3, 4);
+        let row = |engine: &Engine, i: usize| -> String {
+            let state = engine.state.lock().unwrap();
+            state.lines[i].iter().map(|c| c.ch).collect()
+        };
+
+        engine.process("\x1b[?7labcdefg");
+        assert_eq!(row(&engine, 0), "abcg");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (0, 3));
+        assert!(!engine.state.lock().unwrap().lines[0].linewrap());
+
+        // wide characters are placed at the right edge as well
+        engine.process("\u{3042}");
+        assert_eq!(row(&engine, 0)[..2], *"ab");
+        assert_eq!(
+            engine.state.lock().unwrap().lines[0].get(2).unwrap().ch,
+            '\u{3042}'
+        );
+
+        // with auto-wrap, the next line is used
+        engine.process("\x1b[?7h\r\nabcde");
+        assert_eq!(row(&engine, 1), "abcd");
+        assert_eq!(&row(&engine, 2)[..1], "e");
+        assert!(engine.state.lock().unwrap().lines[1].linewrap());
+    }
+
+    #[test]
+    fn test_query_version() {
+        let (mut engine, slave) = new_engine(2, 10);
+
+        engine.process("\x1b[>q");
+        let expected = format!("\x1bP>|toyterm({})\x1b\\", env!("CARGO_PKG_VERSION"));
+        assert_eq!(read_reply(&slave), expected);
+
+        engine.process("\x1b[>0q");
+        assert_eq!(read_reply(&slave), expected);
+
+        // the cursor style is not affected
+        engine.process("\x1b[4 q");
+        assert_eq!(read_reply(&slave), "");
+        assert_eq!(
+            engine.state.lock().unwrap().cursor.style,
+            CursorStyle::Underline
+        );
+    }
+
+    #[test]
+    fn test_window_reports() {
+        // cells are 10x20 pixels
+        let (mut engine, slave) = new_engine(4, 10);
+        engine.process("\x1b[14t");
+        assert_eq!(read_reply(&slave), "\x1b[4;80;100t");
+
+        engine.resize(TerminalSize { rows: 2, cols: 3 }, CellSize { w: 8, h: 16 });
+        engine.process("\x1b[14;2t");
+        assert_eq!(read_reply(&slave), "\x1b[4;32;24t");
+
+        engine.process("\x1b[18t");
+        assert_eq!(read_reply(&slave), "\x1b[8;2;3t");
+
+        // the title is reported without control characters
+        engine.process("\x1b[21t");
+        assert_eq!(read_reply(&slave), "\x1b]l\x1b\\");
+        engine.process("\x1b]2;vim main.rs\r\n\x07");
+        engine.process("\x1b[21t");
+        assert_eq!(read_reply(&slave), "\x1b]lvim main.rs\x1b\\");
+
+        // resizing the window is not allowed
+        engine.process("\x1b[8;10;10t");
+        let state = engine.state.lock().unwrap();
+        assert_eq!(state.size, TerminalSize { rows: 2, cols: 3 });
+        drop(state);
+
+        // the PTY knows the size in pixels too
+        nix::ioctl_read_bad!(tiocgwinsz, nix::libc::TIOCGWINSZ, nix::pty::Winsize);
+        let mut winsize = nix::pty::Winsize {
+            ws_row: 0,
+            ws_col: 0,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { tiocgwinsz(slave.as_raw_fd(), &mut winsize) }.unwrap();
+        let size = (
+            winsize.ws_row,
+            winsize.ws_col,
+            winsize.ws_xpixel,
+            winsize.ws_ypixel,
+        );
+        assert_eq!(size, (2, 3, 24, 32));
+    }
+
+    #[test]
+    fn test_answerback() {
+        // nothing is sent by default
+        let (mut engine, slave) = new_engine(4, 10);
+        engine.process("\x05");
+        assert_eq!(read_reply(&slave), "");
+
+        engine.answerback = "toyterm".to_owned();
+        engine.process("\x05abc\x05");
+        assert_eq!(read_reply(&slave), "toytermtoyterm");
+    }
+
+    #[test]
+    fn test_request_mode() {
+        let (mut engine, slave) = new_engine(4, 10);
+
+        let replies = [
+            ("\x1b[?2004$p", "\x1b[?2004;2$y"),
+            ("\x1b[?2004h\x1b[?2004$p", "\x1b[?2004;1$y"),
+            ("\x1b[?7$p", "\x1b[?7;1$y"),
+            ("\x1b[?80l\x1b[?80$p", "\x1b[?80;2$y"),
+            (
+                "\x1b[?1002h\x1b[?1000$p\x1b[?1002$p",
+                "\x1b[?1000;1$y\x1b[?1002;1$y",
+            ),
+            ("\x1b[?1049h\x1b[?1049$p\x1b[?1049l", "\x1b[?1049;1$y"),
+            ("\x1b[?1001$p", "\x1b[?1001;4$y"),
+            ("\x1b[4h\x1b[4$p", "\x1b[4;1$y"),
+            // not recognized
+            ("\x1b[?9999$p", "\x1b[?9999;0$y"),
+            ("\x1b[20$p", "\x1b[20;0$y"),
+        ];
+        for (request, reply) in replies {
+            engine.process(request);
+            assert_eq!(read_reply(&slave), reply, "{request:?}");
+        }
+    }
+
+    #[test]
+    fn test_dec_status_report() {
+        let (mut engine, slave) = new_engine(4, 10);
+
+        let replies = [
+            ("\x1b[5n", "\x1b[0n"),
+            ("\x1b[?15n", "\x1b[?13n"),
+            ("\x1b[?25n", "\x1b[?21n"),
+            ("\x1b[?26n", "\x1b[?27;1;0;0n"),
+            ("\x1b[?53n", "\x1b[?53n"),
+            ("\x1b[?55n", "\x1b[?53n"),
+            ("\x1b[?56n", "\x1b[?57;0n"),
+            ("\x1b[?75n", "\x1b[?70n"),
+            ("\x1b[?85n", "\x1b[?83n"),
+        ];
+        for (request, reply) in replies {
+            engine.process(request);
+            assert_eq!(read_reply(&slave), reply, "{request:?}");
+        }
+
+        // cursor position reports
+        engine.process("\x1b[2;3H\x1b[6n\x1b[?6n");
+        assert_eq!(read_reply(&slave), "\x1b[2;3R\x1b[?2;3;1R");
+
+        // relative to the scroll region in the origin mode
+        engine.process("\x1b[2;4r\x1b[?6h\x1b[2;3H\x1b[?6n");
+        assert_eq!(read_reply(&slave), "\x1b[?2;3;1R");
+        engine.process("\x1b[6n");
+        assert_eq!(read_reply(&slave), "\x1b[2;3R");
+
+        // at the last column (pending wrap)
+        engine.process("\x1b[?6l\x1b[r\x1b[4;10Hx\x1b[6n\x1b[?6n");
+        assert_eq!(read_reply(&slave), "\x1b[4;10R\x1b[?4;10;1R");
+
+        // unknown requests are ignored
+        engine.process("\x1b[7n\x1b[?99n\x1b[>5n");
+        assert_eq!(read_reply(&slave), "");
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let (mut engine, slave) = new_engine(2, 10);
+
+        engine.process("\x1b[?4m");
+        assert_eq!(read_reply(&slave), "\x1b[>4;0m");
+
+        engine.process("\x1b[>4;2m\x1b[?4m");
+        assert_eq!(engine.state.lock().unwrap().mode.modify_other_keys, 2);
+        assert_eq!(read_reply(&slave), "\x1b[>4;2m");
+
+        // omitted value resets the option
+        engine.process("\x1b[>4m\x1b[?4m");
+        assert_eq!(read_reply(&slave), "\x1b[>4;0m");
+
+        // ordinary SGR is not affected
+        engine.process("\x1b[4m");
+        assert_eq!(engine.state.lock().unwrap().mode.modify_other_keys, 0);
+    }
+
+    #[test]
+    fn test_origin_mode() {
+        let (mut engine, slave) = new_engine(10, 10);
+        let pos = |engine: &Engine| engine.state.lock().unwrap().cursor.pos();
+
+        // set the scroll region to 3..=6 rows (1-origin)
+        engine.process("\x1b[3;6r\x1b[?6h");
+        assert_eq!(pos(&engine), (2, 0));
+
+        engine.process("\x1b[2;4H");
+        assert_eq!(pos(&engine), (3, 3));
+
+        // confined in the scroll region
+        engine.process("\x1b[9;1H");
+        assert_eq!(pos(&engine), (5, 0));
+        engine.process("\x1b[7d");
+        assert_eq!(pos(&engine), (5, 0));
+
+        // the position is reported relative to the region
+        engine.process("\x1b[2;1H\x1b[6n");
+        assert_eq!(read_reply(&slave), "\x1b[2;1R");
+
+        // reset returns to absolute addressing
+        engine.process("\x1b[?6l");
+        assert_eq!(pos(&engine), (0, 0));
+        engine.process("\x1b[9;1H");
+        assert_eq!(pos(&engine), (8, 0));
+    }
+
+    #[test]
+    fn test_vertical_movement_in_scroll_region() {
+        let (mut engine, _slave) = new_engine(10, 10);
+        let pos = |engine: &Engine| engine.state.lock().unwrap().cursor.pos();
+
+        // CUD/CUU stop at the margins in the origin mode
+        engine.process("\x1b[3;6r\x1b[?6h");
+        engine.process("\x1b[2;3H\x1b[20B");
+        assert_eq!(pos(&engine), (5, 2));
+        engine.process("\x1b[20A");
+        assert_eq!(pos(&engine), (2, 2));
+
+        // and also when the cursor is inside the region without the origin mode
+        engine.process("\x1b[?6l\x1b[4;1H\x1b[20B");
+        assert_eq!(pos(&engine), (5, 0));
+        engine.process("\x1b[20A");
+        assert_eq!(pos(&engine), (2, 0));
+
+        // the cursor beyond a margin can move to the edge of the screen
+        engine.process("\x1b[8;1H\x1b[20B");
+        assert_eq!(pos(&engine), (9, 0));
+        engine.process("\x1b[1;1H\x1b[20B");
+        assert_eq!(pos(&engine), (5, 0));
+        engine.process("\x1b[2;1H\x1b[20A");
+        assert_eq!(pos(&engine), (0, 0));
+    }
+
+    #[test]
+    fn test_save_restore_cursor() {
+        let (mut engine, _slave) = new_engine(5, 10);
+
+        // restore without save homes the cursor and resets attributes
+        engine.process("\x1b[3;4H\x1b[1;31m\x1b8");
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.cursor.pos(), (0, 0));
+            assert_eq!(state.attr, GraphicAttribute::default());
+        }
+
+        engine.process("\x1b[2;3H\x1b[32m\x1b7\x1b[5;5H\x1b[0m\x1b8");
+        {
+            let state = engine.state.lock().unwrap();
+            assert_eq!(state.cursor.pos(), (1, 2));
+            assert_eq!(state.attr.fg, Color::Green);
+        }
+
+        // can be restored repeatedly
+        engine.process("\x1b[H\x1b8");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (1, 2));
+    }
+
+    #[test]
+    fn test_alt_screen_modes() {
+        let (mut engine, _slave) = new_engine(3, 5);
+        let row = |engine: &Engine, i: usize| -> String {
+            let state = engine.state.lock().unwrap();
+            state.lines[i].iter().map(|c| c.ch).collect()
+        };
+
+        engine.process("abc");
+
+        // 1047 doesn't save the cursor
+        engine.process("\x1b[?1047h");
+        assert!(engine.state.lock().unwrap().mode.alt_screen);
+        assert_eq!(row(&engine, 0), "\n\n\n\n\n");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (0, 3));
+
+        // entering twice doesn't swap the buffers back
+        engine.process("xyz\x1b[?1047h");
+        assert_eq!(&row(&engine, 0)[..3], "\n\n\n");
+        engine.process("\x1b[H\x1b[?1047l");
+        assert!(!engine.state.lock().unwrap().mode.alt_screen);
+        assert_eq!(&row(&engine, 0)[..3], "abc");
+
+        // the alternate screen has been cleared on the way out
+        engine.process("\x1b[?1047h");
+        assert!(engine.state.lock().unwrap().lines[0]
+            .iter()
+            .all(|c| c.ch == '\n'));
+        engine.process("\x1b[?1047l");
+
+        // 1048 saves the cursor only
+        engine.process("\x1b[2;2H\x1b[?1048h\x1b[H\x1b[?1048l");
+        assert_eq!(engine.state.lock().unwrap().cursor.pos(), (1, 1));
+        assert!(!engine.state.lock().unwrap().mode.alt_screen);
+    }
 }
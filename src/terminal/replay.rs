@@ -0,0 +1,238 @@
+//! Replay tests: feeds the recorded output in `tests/replay/*.txt` to the engine, and compares the
+//! resulting screen with the snapshot saved in the same file.
+//!
+//! A fixture looks like this:
+//!
+//! ```text
+//! # comment
+//! size 3x10
+//! input \e[2J\e[Hhello
+//! input \r\n\e[1mworld
+//! --- expected
+//! |hello     |
+//! |world     |
+//! |          |
+//! cursor 1,5
+//! attr 1 0..5 bold=1
+//! ```
+//!
+//! `input` lines are concatenated (without newlines), and `\e`, `\xHH`, `\r`, `\n`, `\t` and `\\`
+//! in them are unescaped. The snapshot consists of the rows of the screen, the cursor position
+//! (row, column) and the runs of cells which have non-default graphic attributes.
+//!
+//! Running the tests with `TOYTERM_UPDATE_FIXTURES=1` rewrites the snapshots with the actual
+//! results, which should be reviewed before committing.
+
+use super::tests::new_engine;
+use super::*;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+const SEPARATOR: &str = "--- expected";
+
+struct Fixture {
+    header: String,
+    size: TerminalSize,
+    input: String,
+    expected: String,
+}
+
+fn parse_fixture(text: &str) -> std::result::Result<Fixture, String> {
+    let (header, expected) = match text.split_once(SEPARATOR) {
+        Some((header, expected)) => (header, expected.trim_start_matches('\n')),
+        None => (text, ""),
+    };
+
+    let mut size = None;
+    let mut input = String::new();
+    for line in header.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(sz) = line.strip_prefix("size ") {
+            let (rows, cols) = sz.split_once('x').ok_or("invalid size")?;
+            let rows = rows.trim().parse().map_err(|_| "invalid rows")?;
+            let cols = cols.trim().parse().map_err(|_| "invalid cols")?;
+            size = Some(TerminalSize { rows, cols });
+        } else if let Some(escaped) = line.strip_prefix("input ") {
+            input += &unescape(escaped)?;
+        } else {
+            return Err(format!("unknown line: {line:?}"));
+        }
+    }
+
+    Ok(Fixture {
+        header: header.to_owned(),
+        size: size.ok_or("size is not specified")?,
+        input,
+        expected: expected.to_owned(),
+    })
+}
+
+fn unescape(escaped: &str) -> std::result::Result<String, String> {
+    let mut out = String::new();
+    let mut chars = escaped.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('e') => out.push('\x1b'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("\\x{hex}"))?;
+                out.push(byte as char);
+            }
+            other => return Err(format!("unknown escape: {other:?}")),
+        }
+    }
+    Ok(out)
+}
+
+fn describe_color(color: Color) -> String {
+    match color {
+        Color::Rgb { rgba } => format!("#{:06x}", rgba >> 8),
+        _ => format!("{color:?}"),
+    }
+}
+
+fn describe_attr(attr: &GraphicAttribute) -> String {
+    let default = GraphicAttribute::default();
+    let mut desc = Vec::new();
+    if attr.fg != default.fg {
+        desc.push(format!("fg={}", describe_color(attr.fg)));
+    }
+    if attr.bg != default.bg {
+        desc.push(format!("bg={}", describe_color(attr.bg)));
+    }
+    if attr.bold != default.bold {
+        desc.push(format!("bold={}", attr.bold));
+    }
+    if attr.italic {
+        desc.push("italic".to_owned());
+    }
+    if attr.underline != default.underline {
+        desc.push(format!("underline={}", attr.underline));
+    }
+    if let Some(color) = attr.underline_color {
+        desc.push(format!("underline_color={}", describe_color(color)));
+    }
+    if attr.inversed {
+        desc.push("inversed".to_owned());
+    }
+    if attr.blinking != default.blinking {
+        desc.push(format!("blinking={}", attr.blinking));
+    }
+    if attr.concealed {
+        desc.push("concealed".to_owned());
+    }
+    if attr.strike {
+        desc.push("strike".to_owned());
+    }
+    if attr.overline {
+        desc.push("overline".to_owned());
+    }
+    if let Some(link) = attr.link {
+        desc.push(format!("link={link}"));
+    }
+    desc.join(" ")
+}
+
+fn snapshot(state: &State) -> String {
+    let mut out = String::new();
+    for line in state.lines.iter() {
+        out.push('|');
+        for cell in line.iter().filter(|cell| cell.width > 0) {
+            out.push(if cell.ch == '\n' { ' ' } else { cell.ch });
+        }
+        out.push_str("|\n");
+    }
+
+    let (row, col) = state.cursor.pos();
+    writeln!(out, "cursor {row},{col}").unwrap();
+
+    for (row, line) in state.lines.iter().enumerate() {
+        let mut run: Option<(usize, String)> = None;
+        let cells = line.iter().map(Some).chain(std::iter::once(None));
+        for (col, cell) in cells.enumerate() {
+            let desc = cell
+                .map(|cell| describe_attr(&cell.attr))
+                .unwrap_or_default();
+            if run.as_ref().map_or(false, |(_, prev)| *prev == desc) {
+                continue;
+            }
+            if let Some((start, prev)) = run.take() {
+                if !prev.is_empty() {
+                    writeln!(out, "attr {row} {start}..{col} {prev}").unwrap();
+                }
+            }
+            run = Some((col, desc));
+        }
+    }
+    out
+}
+
+fn replay(fixture: &Fixture) -> String {
+    let TerminalSize { rows, cols } = fixture.size;
+    let (mut engine, _slave) = new_engine(rows, cols);
+    engine.process(&fixture.input);
+    let state = engine.state.lock().unwrap();
+    snapshot(&state)
+}
+
+fn fixture_paths() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/replay");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .expect("tests/replay")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "txt"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_replay_fixtures() {
+    let update = std::env::var_os("TOYTERM_UPDATE_FIXTURES").is_some();
+
+    let paths = fixture_paths();
+    assert!(!paths.is_empty());
+
+    let mut failed = Vec::new();
+    for path in paths {
+        let text = std::fs::read_to_string(&path).unwrap();
+        let fixture = parse_fixture(&text).unwrap_or_else(|err| panic!("{path:?}: {err}"));
+
+        let actual = replay(&fixture);
+        if actual == fixture.expected {
+            continue;
+        }
+
+        if update {
+            let text = format!("{}{SEPARATOR}\n{actual}", fixture.header);
+            std::fs::write(&path, text).unwrap();
+        } else {
+            eprintln!("{}: expected:\n{}", path.display(), fixture.expected);
+            eprintln!("{}: actual:\n{}", path.display(), actual);
+            failed.push(path);
+        }
+    }
+    assert!(failed.is_empty(), "snapshots differ: {failed:?}");
+}
+
+#[test]
+fn test_unescape() {
+    assert_eq!(
+        unescape(r"a\e[1m\r\n\t\\\x07").unwrap(),
+        "a\x1b[1m\r\n\t\\\x07"
+    );
+    assert!(unescape(r"\q").is_err());
+    assert!(unescape(r"\xZZ").is_err());
+}
@@ -0,0 +1,246 @@
+// Terminfo description of toyterm
+//
+// `toyterm.info` is generated from this table (`toyterm --dump-terminfo`), and only the
+// capabilities implemented by `control_function` and `terminal` are listed.
+
+/// Capabilities sharing a description
+struct Entry {
+    description: &'static str,
+    capabilities: &'static [&'static str],
+}
+
+const fn entry(description: &'static str, capabilities: &'static [&'static str]) -> Entry {
+    Entry {
+        description,
+        capabilities,
+    }
+}
+
+const NAMES: &str = "toyterm-256color|toyterm (a toy terminal emulator)";
+
+const BOOLEANS: &[Entry] = &[
+    entry("line wrapping", &["am"]),
+    entry("safe to move while in insert mode", &["mir"]),
+    entry("safe to move while in standout mode", &["msgr"]),
+    entry("ignore a line-feed immediately after an am wrap", &["xenl"]),
+    entry("support direct RGB color sequence", &["Tc"]),
+];
+
+const NUMBERS: &[Entry] = &[
+    entry("maximum number of colors on screen", &["colors#0x1000000"]),
+    entry(
+        "maximum number of color-pairs on the screen",
+        &["pairs#65536"],
+    ),
+    entry("number of columns in a line", &["cols#80"]),
+    entry("tabs initially every # spaces", &["it#8"]),
+    entry("number of lines on screen", &["lines#24"]),
+];
+
+const STRINGS: &[Entry] = &[
+    entry("audible signal (bell)", &["bel=^G"]),
+    entry("turn on blinking", &["blink=\\E[5m"]),
+    entry("turn on bold", &["bold=\\E[1m"]),
+    entry("make cursor invisible", &["civis=\\E[?25l"]),
+    entry("clear screen and home cursor", &["clear=\\E[H\\E[J"]),
+    entry("undo civis", &["cnorm=\\E[?25h"]),
+    entry("carriage return", &["cr=\\r"]),
+    entry("change scroll region", &["csr=\\E[%i%p1%d;%p2%dr"]),
+    entry("move #1 characters to the left", &["cub=\\E[%p1%dD"]),
+    entry("move left one space", &["cub1=\\E[D"]),
+    entry("down #1 lines", &["cud=\\E[%p1%dB"]),
+    entry("down one line", &["cud1=\\E[B"]),
+    entry("move #1 characters to the right", &["cuf=\\E[%p1%dC"]),
+    entry("move right one space", &["cuf1=\\E[C"]),
+    entry("move to row #1 column #2", &["cup=\\E[%i%p1%d;%p2%dH"]),
+    entry("up #1 lines", &["cuu=\\E[%p1%dA"]),
+    entry("up one line", &["cuu1=\\E[A"]),
+    entry("delete #1 characters", &["dch=\\E[%p1%dP"]),
+    entry("delete character", &["dch1=\\E[P"]),
+    entry("turn on half-bright mode", &["dim=\\E[2m"]),
+    entry("delete #1 lines", &["dl=\\E[%p1%dM"]),
+    entry("delete line", &["dl1=\\E[M"]),
+    entry("erase #1 characters", &["ech=\\E[%p1%dX"]),
+    entry("clear to end of screen", &["ed=\\E[J"]),
+    entry("clear to end of line", &["el=\\E[K"]),
+    entry("clear to beginning of line", &["el1=\\E[1K"]),
+    entry("home cursor", &["home=\\E[H"]),
+    entry("horizontal position #1, absolute", &["hpa=\\E[%i%p1%dG"]),
+    entry("tab to next 8-space hardware tab stop", &["ht=^I"]),
+    entry("insert #1 characters", &["ich=\\E[%p1%d@"]),
+    entry("insert #1 lines", &["il=\\E[%p1%dL"]),
+    entry("insert line", &["il1=\\E[L"]),
+    entry("scroll text up", &["ind=\\n"]),
+    entry("turn on blank mode", &["invis=\\E[8m"]),
+    entry("backspace key", &["kbs=^?"]),
+    entry("back-tab key", &["kcbt=\\E[Z"]),
+    entry("left-arrow key", &["kcub1=\\E[D"]),
+    entry("down-arrow key", &["kcud1=\\E[B"]),
+    entry("right-arrow key", &["kcuf1=\\E[C"]),
+    entry("up-arrow key", &["kcuu1=\\E[A"]),
+    entry("delete-character key", &["kdch1=\\E[3~"]),
+    entry(
+        "Function keys",
+        &[
+            "kf1=\\EOP",
+            "kf2=\\EOQ",
+            "kf3=\\EOR",
+            "kf4=\\EOS",
+            "kf5=\\E[15~",
+            "kf6=\\E[17~",
+            "kf7=\\E[18~",
+            "kf8=\\E[19~",
+            "kf9=\\E[20~",
+            "kf10=\\E[21~",
+            "kf11=\\E[23~",
+            "kf12=\\E[24~",
+            "kf13=\\E[1;2P",
+            "kf14=\\E[1;2Q",
+            "kf15=\\E[1;2R",
+            "kf16=\\E[1;2S",
+        ],
+    ),
+    entry("next-page key", &["knp=\\E[6~"]),
+    entry("previous-page key", &["kpp=\\E[5~"]),
+    entry("mouse key", &["kmous=\\E[<"]),
+    entry("newline", &["nel=\\r\\n"]),
+    entry("set default pair to its original value", &["op=\\E[39;49m"]),
+    entry("restore cursor", &["rc=\\E8"]),
+    entry("turn on reverse video mode", &["rev=\\E[7m"]),
+    entry("exit italic mode", &["ritm=\\E[23m"]),
+    entry("string to end programs using cup", &["rmcup=\\E[?1049l"]),
+    entry("exit standout mode", &["rmso=\\E[27m"]),
+    entry("exit underline mode", &["rmul=\\E[24m"]),
+    entry("save cursor", &["sc=\\E7"]),
+    entry(
+        "set background color to #1",
+        &["setab=\\E[%?%p1%{8}%<%t4%p1%d%e48;2;%p1%{65536}%/%d;%p1%{256}%/%{255}%&%d;%p1%{255}%&%d%;m"],
+    ),
+    entry(
+        "set foreground color to #1",
+        &["setaf=\\E[%?%p1%{8}%<%t3%p1%d%e38;2;%p1%{65536}%/%d;%p1%{256}%/%{255}%&%d;%p1%{255}%&%d%;m"],
+    ),
+    entry("turn off all attributes", &["sgr0=\\E[m"]),
+    entry("enter italic mode", &["sitm=\\E[3m"]),
+    entry("string to start programs using cup", &["smcup=\\E[?1049h"]),
+    entry("begin standout mode", &["smso=\\E[7m"]),
+    entry("begin underline mode", &["smul=\\E[4m"]),
+    entry("vertical position #1 absolute", &["vpa=\\E[%i%p1%dd"]),
+    entry("select cursor style", &["Ss=\\E[%p1%d q"]),
+    entry("reset cursor style", &["Se=\\E[2 q"]),
+    entry("clear the scrollback (used by clear(1))", &["E3=\\E[3J"]),
+    entry("crossed-out text", &["smxx=\\E[9m", "rmxx=\\E[29m"]),
+    entry("styled underlines (curly, dotted, etc.)", &["Smulx=\\E[4:%p1%dm"]),
+    entry("colored underlines", &["Setulc=\\E[58;2;%p1%{65536}%/%d;%p1%{256}%/%{255}%&%d;%p1%{255}%&%dm"]),
+    entry("xterm mouse report request", &["XM=\\E[?1006;1000%?%p1%{1}%=%th%el%;"]),
+    entry("xterm mouse report response", &["xm=\\E[<%i%p3%d;%p1%d;%p2%d;%?%p4%tM%em%;"]),
+];
+
+/// Returns the terminfo source, which can be compiled by `tic -x`
+pub fn source() -> String {
+    let mut src = format!("{NAMES},\n");
+
+    let sections = [
+        ("Boolean capabilities", BOOLEANS),
+        ("Numeric capabilities", NUMBERS),
+        ("String capabilities", STRINGS),
+    ];
+    for (title, entries) in sections {
+        let rule = "-".repeat(title.len() + 2);
+        src += &format!("#\n#{rule}\n# {title}\n#{rule}\n#\n");
+
+        for entry in entries {
+            src += &format!("## {}\n", entry.description);
+            for cap in entry.capabilities {
+                src += &format!("    {cap},\n");
+            }
+        }
+    }
+
+    src
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_function::{Function, Parser};
+
+    /// Expands a string capability with the parameters 1, 2, ...
+    /// (only `%i`, `%pN` and `%d` are supported)
+    fn expand(cap: &str) -> Option<String> {
+        let mut out = String::new();
+        let mut increment = 0;
+        let mut stack = Vec::new();
+        let mut chars = cap.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => match chars.next()? {
+                    'E' => out.push('\x1b'),
+                    'r' => out.push('\r'),
+                    'n' => out.push('\n'),
+                    ch => out.push(ch),
+                },
+                '^' => match chars.next()? {
+                    '?' => out.push('\x7f'),
+                    ch => out.push((ch as u8 & 0x1F) as char),
+                },
+                '%' => match chars.next()? {
+                    'i' => increment = 1,
+                    'p' => stack.push(chars.next()?.to_digit(10)?),
+                    'd' => out += &(stack.pop()? + increment).to_string(),
+                    _ => return None,
+                },
+                ch => out.push(ch),
+            }
+        }
+        Some(out)
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("\\E[%i%p1%d;%p2%dH").as_deref(), Some("\x1b[2;3H"));
+        assert_eq!(expand("^I\\r\\n").as_deref(), Some("\t\r\n"));
+        assert_eq!(expand("\\E[%?%p1%tA%;"), None);
+    }
+
+    #[test]
+    fn test_installed_terminfo() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/toyterm.info");
+        let installed = std::fs::read_to_string(path).unwrap();
+        assert_eq!(
+            installed,
+            source(),
+            "run `toyterm --dump-terminfo > toyterm.info`"
+        );
+    }
+
+    #[test]
+    fn test_implemented_capabilities() {
+        let caps = STRINGS.iter().flat_map(|e| e.capabilities.iter());
+        for cap in caps {
+            let (name, value) = cap.split_once('=').unwrap();
+
+            // keys are sent by toyterm, not interpreted
+            // (and capabilities with conditions can't be expanded here)
+            if name.starts_with('k') || name == "xm" {
+                continue;
+            }
+            let Some(seq) = expand(value) else {
+                continue;
+            };
+
+            let mut parser = Parser::default();
+            let mut funcs = 0;
+            for ch in seq.chars() {
+                match parser.feed(ch) {
+                    Some(Function::Unsupported | Function::Invalid) => {
+                        panic!("{name} is not implemented: {seq:?}")
+                    }
+                    Some(_) => funcs += 1,
+                    None => {}
+                }
+            }
+            assert!(funcs > 0, "{name} is incomplete: {seq:?}");
+        }
+    }
+}
@@ -24,6 +24,12 @@ pub mod io {
 }
 
 pub mod utf8 {
+    /// The maximum length of a UTF-8 sequence
+    pub const MAX_UTF8_LEN: usize = 4;
+
+    /// Calls `callback` for each valid string and each invalid sequence in `buf`,
+    /// and returns the incomplete sequence at the end of `buf`.
+    /// The returned remainder is always shorter than `MAX_UTF8_LEN`.
     pub fn process_utf8<'b, F>(buf: &'b [u8], mut callback: F) -> &[u8]
     where
         F: FnMut(Result<&'b str, &'b [u8]>),
@@ -48,7 +54,15 @@ pub mod utf8 {
                             i += next;
                         }
                         None => {
-                            return &buf[i..];
+                            let rem = &buf[i..];
+
+                            // A remainder which can never be completed (should not happen)
+                            if rem.len() >= MAX_UTF8_LEN {
+                                callback(Err(rem));
+                                return &[];
+                            }
+
+                            return rem;
                         }
                     }
                 }
@@ -76,7 +90,7 @@ pub mod utf8 {
             {
                 let mut buf = b"abc".to_vec();
                 let mut res = String::new();
-                let rem = process_utf8_lossy(&mut buf[..], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..], |s| res.push_str(s));
                 assert_eq!(rem.len(), 0);
                 assert_eq!(&res, "abc");
             }
@@ -84,7 +98,7 @@ pub mod utf8 {
             {
                 let mut buf = b"\xE3\x81\x82\xE3\x81\x84\xE3\x81\x86".to_vec();
                 let mut res = String::new();
-                let rem = process_utf8_lossy(&mut buf[..], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..], |s| res.push_str(s));
                 assert_eq!(rem.len(), 0);
                 assert_eq!(&res, "あいう");
             }
@@ -94,7 +108,7 @@ pub mod utf8 {
                 let buf_len = buf.len();
                 let mut res = String::new();
 
-                let rem = process_utf8_lossy(&mut buf[..], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..], |s| res.push_str(s));
                 assert_eq!(rem.len(), 1);
                 assert_eq!(rem[0], b'\xE3');
 
@@ -102,7 +116,7 @@ pub mod utf8 {
                 buf.copy_within(rem_offset.., 0);
 
                 buf[1..3].copy_from_slice(b"\x81\x86");
-                let rem = process_utf8_lossy(&mut buf[..3], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..3], |s| res.push_str(s));
                 assert_eq!(rem.len(), 0);
 
                 assert_eq!(&res, "あいう");
@@ -113,7 +127,7 @@ pub mod utf8 {
                 let buf_len = buf.len();
                 let mut res = String::new();
 
-                let rem = process_utf8_lossy(&mut buf[..], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..], |s| res.push_str(s));
                 assert_eq!(rem.len(), 2);
                 assert_eq!(&rem[0..2], b"\xE3\x81");
 
@@ -121,12 +135,104 @@ pub mod utf8 {
                 buf.copy_within(rem_offset.., 0);
 
                 buf[2..3].copy_from_slice(b"\x86");
-                let rem = process_utf8_lossy(&mut buf[..3], |s| res.push_str(s));
+                let rem = process_utf8_lossy(&buf[..3], |s| res.push_str(s));
                 assert_eq!(rem.len(), 0);
 
                 assert_eq!(&res, "あいう");
             }
         }
+
+        #[test]
+        fn test_stuck_remainder() {
+            // an interrupted prefix followed by a stream of lone continuation bytes
+            let mut stream = b"a\xE3\x81b".to_vec();
+            stream.extend(std::iter::repeat(b'\x80').take(100));
+
+            let mut buf = [0_u8; 8];
+            let mut begin = 0;
+            let mut valid = String::new();
+            let mut invalid = 0;
+
+            for chunk in stream.chunks(3) {
+                let end = begin + chunk.len();
+                buf[begin..end].copy_from_slice(chunk);
+
+                let rem = process_utf8(&buf[..end], |res| match res {
+                    Ok(s) => valid.push_str(s),
+                    Err(_) => invalid += 1,
+                });
+                assert!(rem.len() < MAX_UTF8_LEN);
+
+                let rem_len = rem.len();
+                buf.copy_within((end - rem_len)..end, 0);
+                begin = rem_len;
+            }
+
+            // every broken sequence is reported, and nothing remains
+            assert_eq!(valid, "ab");
+            assert_eq!(begin, 0);
+            assert_eq!(invalid, 101);
+        }
+    }
+}
+
+pub mod base64 {
+    /// Decodes base64 data, where the padding is optional
+    pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(encoded.len() / 4 * 3);
+        let mut acc: u32 = 0;
+        let mut bits = 0;
+        for byte in encoded.bytes().filter(|&b| b != b'=') {
+            let v = match byte {
+                b'A'..=b'Z' => byte - b'A',
+                b'a'..=b'z' => byte - b'a' + 26,
+                b'0'..=b'9' => byte - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            acc = acc << 6 | v as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                out.push((acc >> bits) as u8);
+            }
+        }
+        Some(out)
+    }
+
+    /// Encodes data without the padding
+    #[cfg(test)]
+    pub fn encode(data: &[u8]) -> String {
+        const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let b = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+            for i in 0..=chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            }
+        }
+        out
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_base64() {
+            assert_eq!(decode("").unwrap(), b"");
+            assert_eq!(decode("aGVsbG8=").unwrap(), b"hello");
+            assert_eq!(decode("aGVsbG8").unwrap(), b"hello");
+            assert_eq!(decode("/+8A").unwrap(), [0xFF, 0xEF, 0x00]);
+            assert_eq!(decode("a b"), None);
+            assert_eq!(encode(b"hello"), "aGVsbG8");
+        }
     }
 }
 
@@ -1,12 +1,18 @@
 use glium::{glutin, index, texture, uniform, uniforms, Display};
 use glutin::dpi::{PhysicalPosition, PhysicalSize};
 use serde::{Deserialize, Serialize};
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use crate::cache::GlyphCache;
+use crate::config::{Config, SystemCursorBlink};
 use crate::font::{Font, FontSet, FontStyle};
-use crate::terminal::{CellSize, Color, Cursor, CursorStyle, Line, PositionedImage};
+use crate::terminal::{
+    Cell, CellSize, Color, Cursor, CursorStyle, GraphicAttribute, Line, LineSize, PositionedImage,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct Viewport {
@@ -37,8 +43,47 @@ impl Viewport {
     }
 }
 
+lazy_static::lazy_static! {
+    // The desktop settings are probed only once since it may spawn a process
+    static ref SYSTEM_CURSOR_BLINK: SystemCursorBlink = SystemCursorBlink::probe();
+}
+
+/// The time of the last user input, shared by all views to dim the whole window
+static LAST_INPUT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Restores the brightness dimmed by inactivity
+pub fn notify_user_input() {
+    *LAST_INPUT.lock().unwrap() = Some(Instant::now());
+}
+
+/// How `selection_range` is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionKind {
+    /// Cells from `left` to `right` in the reading order
+    #[default]
+    Linear,
+    /// A rectangle whose top-left and bottom-right corners are `left` and `right`
+    Block,
+}
+
+/// Returns true if the cell at (row, col) is in the rectangle selected by `SelectionKind::Block`
+pub fn in_block((left, right): (usize, usize), cols: usize, row: usize, col: usize) -> bool {
+    let (top, bottom) = (left / cols, right / cols);
+    let (first, last) = (left % cols, right % cols);
+    top <= row && row <= bottom && first <= col && col <= last
+}
+
+/// A URL detected in the screen
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlRegion {
+    // closed range of cells like `selection_range`
+    pub range: (usize, usize),
+    pub url: String,
+}
+
 pub struct TerminalView {
     fonts: FontSet,
+    synthesis: Synthesis,
     cache: GlyphCache,
     viewport: Viewport,
     cell_size: CellSize,
@@ -48,9 +93,25 @@ pub struct TerminalView {
     pub images: Vec<PositionedImage>,
     pub cursor: Option<Cursor>,
     pub selection_range: Option<(usize, usize)>,
+    pub selection_kind: SelectionKind,
+    // ranges of cells matched by the scrollback search (closed ranges like `selection_range`)
+    pub search_matches: Vec<(usize, usize)>,
+    // URLs detected in the screen, and the index of the one under the mouse pointer
+    pub urls: Vec<UrlRegion>,
+    pub hovered_url: Option<usize>,
     pub scroll_bar: Option<(u32, u32)>,
     pub bg_color: Color,
+    pub reverse_video: bool,
+    // the color of the cursor set by the application (OSC 12)
+    pub cursor_color: Option<u32>,
+    // palette entries redefined by the application (OSC 4)
+    pub palette: HashMap<u8, u32>,
     pub view_focused: bool,
+    pub hover: Option<(usize, usize)>,
+    cursor_shown: bool,
+    // when the visual bell is rung (elapsed time of `clock`)
+    flashed_at: Option<Duration>,
+    flashing: bool,
     updated: bool,
 
     display: Display,
@@ -77,9 +138,10 @@ impl TerminalView {
         font_size: u32,
         scroll_bar: Option<(u32, u32)>,
     ) -> Self {
-        let fonts = build_font_set(font_size);
+        let (fonts, synthesis) = build_font_set(font_size);
 
-        let (cell_size, cell_max_over) = calculate_cell_size(&fonts);
+        let letter_spacing = crate::TOYTERM_CONFIG.load().letter_spacing;
+        let (cell_size, cell_max_over) = calculate_cell_size(&fonts, letter_spacing);
 
         // Rasterize ASCII characters and cache them as a texture
         let cache = GlyphCache::build_ascii_visible(&display, &fonts, cell_size);
@@ -93,9 +155,11 @@ impl TerminalView {
             ..glium::DrawParameters::default()
         };
 
-        fn new_program(display: &Display, vert: &str, frag: &str) -> glium::Program {
+        check_gl_capabilities(&display);
+
+        fn new_program(display: &Display, name: &str, vert: &str, frag: &str) -> glium::Program {
             use glium::program::{Program, ProgramCreationInput};
-            Program::new(
+            let program = Program::new(
                 display,
                 ProgramCreationInput::SourceCode {
                     vertex_shader: vert,
@@ -107,24 +171,31 @@ impl TerminalView {
                     outputs_srgb: true,
                     uses_point_size: false,
                 },
-            )
-            .unwrap()
+            );
+
+            program.unwrap_or_else(|err| {
+                let gl_version = display.get_opengl_version_string();
+                panic!("{}", program_error_message(name, &err, gl_version))
+            })
         }
 
         let program_cell = new_program(
             &display,
+            "cell",
             include_str!("shaders/cell.vert"),
             include_str!("shaders/cell.frag"),
         );
 
         let program_img = new_program(
             &display,
+            "image",
             include_str!("shaders/image.vert"),
             include_str!("shaders/image.frag"),
         );
 
         TerminalView {
             fonts,
+            synthesis,
             cache,
 
             viewport,
@@ -135,9 +206,20 @@ impl TerminalView {
             images: Vec::new(),
             cursor: None,
             selection_range: None,
+            selection_kind: SelectionKind::Linear,
+            search_matches: Vec::new(),
+            urls: Vec::new(),
+            hovered_url: None,
             scroll_bar,
-            bg_color: Color::Black,
+            bg_color: Color::Background,
+            reverse_video: false,
+            cursor_color: None,
+            palette: HashMap::new(),
             view_focused: false,
+            hover: None,
+            cursor_shown: true,
+            flashed_at: None,
+            flashing: false,
             updated: false,
 
             display,
@@ -161,6 +243,11 @@ impl TerminalView {
         self.updated = true;
     }
 
+    /// Flashes the screen (visual bell)
+    pub fn flash(&mut self) {
+        self.flashed_at = Some(self.clock.elapsed());
+    }
+
     pub fn viewport(&self) -> Viewport {
         self.viewport
     }
@@ -188,7 +275,8 @@ impl TerminalView {
             self.fonts.set_fontsize(new_size);
         }
 
-        let (new_cell_size, new_cell_max_over) = calculate_cell_size(&self.fonts);
+        let letter_spacing = crate::TOYTERM_CONFIG.load().letter_spacing;
+        let (new_cell_size, new_cell_max_over) = calculate_cell_size(&self.fonts, letter_spacing);
         self.cell_size = new_cell_size;
         self.cell_max_over = new_cell_max_over;
 
@@ -197,7 +285,16 @@ impl TerminalView {
         self.updated = true;
     }
 
+    /// Returns the color of a palette entry redefined by the application (OSC 4)
+    fn redefined_color(&self, color: Color) -> Color {
+        match color.palette_index().and_then(|idx| self.palette.get(&idx)) {
+            Some(&rgba) => Color::Rgb { rgba },
+            None => color,
+        }
+    }
+
     fn rebuild_draw_queries(&mut self) {
+        let config = crate::TOYTERM_CONFIG.load();
         let viewport = self.viewport;
         let cell_size = self.cell_size;
         let timestamp = self.clock.elapsed().as_millis() as u64;
@@ -221,9 +318,9 @@ impl TerminalView {
                 &self.display,
                 glium::texture::RawImage2d {
                     data: img.data.clone().into(),
-                    width: img.width as u32,
-                    height: img.height as u32,
-                    format: glium::texture::ClientFormat::U8U8U8,
+                    width: img.data_width as u32,
+                    height: img.data_height as u32,
+                    format: glium::texture::ClientFormat::U8U8U8U8,
                 },
                 texture::MipmapsOption::NoMipmap,
             )
@@ -242,21 +339,13 @@ impl TerminalView {
 
         // clear entire screen
         {
-            let rect = GlRect {
-                x: -1.0,
-                y: 1.0,
-                w: 2.0,
-                h: 2.0,
-            };
-            let fg = Color::White;
-            let bg = self.bg_color;
-            let vs = rect_vertices(rect, fg, bg);
+            let inverted = self.reverse_video ^ self.flashing;
+            let vs = clear_screen_vertices(self.bg_color, inverted, &config);
             self.vertices_bg.extend_from_slice(&vs);
         }
 
         // scroll bar
         if let Some((sb_origin, sb_length)) = self.scroll_bar {
-            let config = &crate::TOYTERM_CONFIG;
             if config.scroll_bar_width > 0 {
                 let sb_width = config.scroll_bar_width;
 
@@ -270,7 +359,7 @@ impl TerminalView {
                 let bg = Color::Rgb {
                     rgba: config.scroll_bar_bg_color,
                 };
-                let vs = rect_vertices(rect.to_gl(viewport), fg, bg);
+                let vs = rect_vertices(rect.to_gl(viewport), fg, bg, &config);
                 self.vertices_bg.extend_from_slice(&vs);
 
                 rect.y = sb_origin as i32;
@@ -279,42 +368,80 @@ impl TerminalView {
                 let bg = Color::Rgb {
                     rgba: config.scroll_bar_fg_color,
                 };
-                let vs = rect_vertices(rect.to_gl(viewport), fg, bg);
+                let vs = rect_vertices(rect.to_gl(viewport), fg, bg, &config);
                 self.vertices_bg.extend_from_slice(&vs);
             }
         }
 
         let texture = self.cache.texture();
 
-        let mut baseline: u32 = self.cell_max_over as u32;
-        for (i, row) in self.lines.iter().enumerate() {
+        let hovered;
+        let lines = match self.hover {
+            Some((row, col)) => {
+                hovered = hover_overlay(&self.lines, row, col);
+                &hovered
+            }
+            None => &self.lines,
+        };
+
+        let cursor_fg = Color::Rgb {
+            rgba: config.cursor_fg,
+        };
+        let cursor_bg = Color::Rgb {
+            rgba: self.cursor_color.unwrap_or(config.cursor_bg),
+        };
+        for (i, row) in lines.iter().enumerate() {
+            let row_top = (i as u32 * cell_size.h) as i32;
+
+            // Cells in a double-width line are drawn twice as wide
+            let scale: u32 = if row.double_width() { 2 } else { 1 };
+            let cell_size = CellSize {
+                w: cell_size.w * scale,
+                h: cell_size.h,
+            };
+
+            // (only the left half of a double-width line fits in the window)
             let cols = row.columns();
+            let visible_cols = cols / scale as usize;
             let mut leftline: u32 = 0;
-            for (j, cell) in row.iter().enumerate() {
+            for (j, cell) in row.iter().enumerate().take(visible_cols) {
                 if cell.width == 0 {
                     continue;
                 }
 
                 let cell_width_px = cell_size.w * cell.width as u32;
 
-                let style = if cell.attr.bold == -1 {
-                    FontStyle::Faint
-                } else if cell.attr.bold == 0 {
-                    FontStyle::Regular
-                } else {
-                    FontStyle::Bold
-                };
+                // The glyph is slanted if no italic font is given
+                let slanted = cell.attr.italic && self.synthesis.italic;
+                let italic_font = cell.attr.italic && !self.synthesis.italic;
+                let (style, bold_offsets) =
+                    glyph_style(cell.attr.bold, self.synthesis.bold, italic_font);
+
+                let center = i * cols + j + (cell.width / 2) as usize;
+                let in_range = |(left, right): (usize, usize)| left <= center && center <= right;
+                let on_hovered_url = self
+                    .hovered_url
+                    .and_then(|k| self.urls.get(k))
+                    .map_or(false, |url| in_range(url.range));
 
                 let (fg, bg) = {
                     let mut fg = cell.attr.fg;
                     let mut bg = cell.attr.bg;
 
+                    if cell.attr.bold > 0 && config.bold_is_bright {
+                        fg = bright_color(fg);
+                    }
+
+                    fg = self.redefined_color(fg);
+                    bg = self.redefined_color(bg);
+
                     if cell.attr.inversed {
                         std::mem::swap(&mut fg, &mut bg);
                     }
 
                     let on_cursor = if let Some(cursor) = self.cursor {
                         self.view_focused
+                            && self.cursor_shown
                             && cursor.style == CursorStyle::Block
                             && i == cursor.row
                             && j == cursor.col
@@ -322,27 +449,48 @@ impl TerminalView {
                         false
                     };
 
-                    let is_selected = match self.selection_range {
-                        Some((left, right)) => {
-                            let offset = i * cols + j;
-                            let center = offset + (cell.width / 2) as usize;
-                            left <= center && center <= right
-                        }
-                        None => false,
+                    // (wide characters are selected if their center is in the rectangle)
+                    let is_selected = match self.selection_kind {
+                        SelectionKind::Linear => self.selection_range.map_or(false, in_range),
+                        SelectionKind::Block => self.selection_range.map_or(false, |range| {
+                            let col = j + (cell.width / 2) as usize;
+                            in_block(range, row.columns(), i, col)
+                        }),
                     };
+                    let is_matched = self.search_matches.iter().copied().any(in_range);
 
-                    if on_cursor ^ is_selected {
+                    if on_cursor && !is_selected {
+                        fg = cursor_fg;
+                        bg = cursor_bg;
+                    } else if is_selected && !on_cursor {
                         bg = Color::Selection;
+                    } else if is_matched {
+                        fg = Color::Black;
+                        bg = Color::Yellow;
+                    }
+
+                    // DECSCNM (inverted while the visual bell is rung)
+                    if self.reverse_video ^ self.flashing {
+                        std::mem::swap(&mut fg, &mut bg);
                     }
 
                     if cell.attr.concealed {
                         fg = bg;
                     }
 
+                    // Faint text is blended toward the background
+                    // (except the gaming color, which is resolved in the shader)
+                    if cell.attr.bold < 0 && fg != Color::Special && bg != Color::Special {
+                        let alpha = config.faint_alpha;
+                        let rgba =
+                            blend_rgba(palette_rgba(fg, &config), palette_rgba(bg, &config), alpha);
+                        fg = Color::Rgb { rgba };
+                    }
+
                     (fg, bg)
                 };
 
-                let blinking = cell.attr.blinking;
+                let blinking = cell_blinking(cell.attr, config.disable_blink);
 
                 // Background
                 {
@@ -353,7 +501,20 @@ impl TerminalView {
                         h: cell_size.h,
                     };
 
-                    let vs = rect_vertices(rect.to_gl(viewport), fg, bg);
+                    let vs = rect_vertices(rect.to_gl(viewport), fg, bg, &config);
+                    self.vertices_bg.extend_from_slice(&vs);
+                }
+
+                // Underline for hyperlinks (detected URLs are underlined on hover)
+                if cell.attr.link.is_some() || on_hovered_url {
+                    let rect = PixelRect {
+                        x: (j as u32 * cell_size.w) as i32,
+                        y: ((i + 1) as u32 * cell_size.h) as i32 - 1,
+                        w: cell_width_px,
+                        h: 1,
+                    };
+
+                    let vs = rect_vertices(rect.to_gl(viewport), fg, fg, &config);
                     self.vertices_bg.extend_from_slice(&vs);
                 }
 
@@ -365,18 +526,51 @@ impl TerminalView {
                         if !region.is_empty() {
                             let bearing_x = (metrics.horiBearingX >> 6) as u32;
                             let bearing_y = (metrics.horiBearingY >> 6) as u32;
+                            let advance_x = (metrics.horiAdvance >> 6) as u32;
+                            let left = glyph_left(
+                                leftline,
+                                cell_width_px,
+                                bearing_x as i32 * scale as i32,
+                                advance_x * scale,
+                                &config,
+                            );
 
-                            let rect = PixelRect {
-                                x: leftline as i32 + bearing_x as i32,
-                                y: baseline as i32 - bearing_y as i32,
-                                w: region.w,
-                                h: region.h,
-                            };
-                            let gl_rect = rect.to_gl(viewport);
                             let uv_rect = region.to_uv(texture.width(), texture.height());
 
-                            let vs = glyph_vertices(gl_rect, uv_rect, fg, bg, blinking);
-                            self.vertices_fg.extend_from_slice(&vs);
+                            for dx in bold_offsets {
+                                let (y, h) = glyph_vertical(
+                                    row_top,
+                                    self.cell_max_over,
+                                    bearing_y as i32,
+                                    region.h,
+                                    cell_size.h,
+                                    row.size(),
+                                );
+                                let rect = PixelRect {
+                                    x: left + dx,
+                                    y,
+                                    w: region.w * scale,
+                                    h,
+                                };
+                                let (rect, uv_rect) = match clip_to_row(
+                                    rect,
+                                    uv_rect,
+                                    row_top,
+                                    cell_size.h,
+                                    row.size(),
+                                ) {
+                                    Some(clipped) => clipped,
+                                    None => continue,
+                                };
+                                let gl_rect = rect.to_gl(viewport);
+
+                                let mut vs =
+                                    glyph_vertices(gl_rect, uv_rect, fg, bg, blinking, &config);
+                                if slanted {
+                                    slant_glyph(&mut vs, italic_shift(rect.h, viewport));
+                                }
+                                self.vertices_fg.extend_from_slice(&vs);
+                            }
                         }
                     }
                     Ok(None) => {
@@ -388,14 +582,15 @@ impl TerminalView {
                                 log::info!("draw separetely");
                                 let bearing_x = (metrics.horiBearingX >> 6) as u32;
                                 let bearing_y = (metrics.horiBearingY >> 6) as u32;
+                                let advance_x = (metrics.horiAdvance >> 6) as u32;
+                                let left = glyph_left(
+                                    leftline,
+                                    cell_width_px,
+                                    bearing_x as i32 * scale as i32,
+                                    advance_x * scale,
+                                    &config,
+                                );
 
-                                let rect = PixelRect {
-                                    x: leftline as i32 + bearing_x as i32,
-                                    y: baseline as i32 - bearing_y as i32,
-                                    w: glyph_image.width,
-                                    h: glyph_image.height,
-                                };
-                                let gl_rect = rect.to_gl(viewport);
                                 let uv_rect = UvRect {
                                     x: 0.0,
                                     y: 0.0,
@@ -403,7 +598,40 @@ impl TerminalView {
                                     h: 1.0,
                                 };
 
-                                let vs = glyph_vertices(gl_rect, uv_rect, fg, bg, blinking);
+                                let mut vs = Vec::new();
+                                for dx in bold_offsets {
+                                    let (y, h) = glyph_vertical(
+                                        row_top,
+                                        self.cell_max_over,
+                                        bearing_y as i32,
+                                        glyph_image.height,
+                                        cell_size.h,
+                                        row.size(),
+                                    );
+                                    let rect = PixelRect {
+                                        x: left + dx,
+                                        y,
+                                        w: glyph_image.width * scale,
+                                        h,
+                                    };
+                                    let (rect, uv_rect) = match clip_to_row(
+                                        rect,
+                                        uv_rect,
+                                        row_top,
+                                        cell_size.h,
+                                        row.size(),
+                                    ) {
+                                        Some(clipped) => clipped,
+                                        None => continue,
+                                    };
+                                    let gl_rect = rect.to_gl(viewport);
+                                    let mut glyph =
+                                        glyph_vertices(gl_rect, uv_rect, fg, bg, blinking, &config);
+                                    if slanted {
+                                        slant_glyph(&mut glyph, italic_shift(rect.h, viewport));
+                                    }
+                                    vs.extend(glyph);
+                                }
 
                                 let vertex_buffer =
                                     glium::VertexBuffer::new(&self.display, &vs).unwrap();
@@ -426,34 +654,89 @@ impl TerminalView {
                     }
                 }
 
+                // Underline, strikethrough and overline (drawn over the glyph)
+                // In double-height lines, each of them is drawn only in one of the halves.
+                let underline_color = match cell.attr.underline_color {
+                    Some(color) if !cell.attr.concealed => self.redefined_color(color),
+                    _ => fg,
+                };
+                let underline = match row.size() {
+                    LineSize::DoubleHeightTop => 0,
+                    _ => cell.attr.underline,
+                };
+                for rect in underline_rects(i, j, cell_width_px, cell_size, underline) {
+                    let vs = rect_vertices(
+                        rect.to_gl(viewport),
+                        underline_color,
+                        underline_color,
+                        &config,
+                    );
+                    self.vertices_fg.extend_from_slice(&vs);
+                }
+                if cell.attr.strike && row.size() != LineSize::DoubleHeightTop {
+                    let mut rect = strike_line(i, j, cell_width_px, cell_size);
+                    if row.size() == LineSize::DoubleHeightBottom {
+                        // the center of the double-height line
+                        rect.y -= (cell_size.h / 2) as i32;
+                    }
+                    let vs = rect_vertices(rect.to_gl(viewport), fg, fg, &config);
+                    self.vertices_fg.extend_from_slice(&vs);
+                }
+                if cell.attr.overline && row.size() != LineSize::DoubleHeightBottom {
+                    let rect = overline(i, j, cell_width_px, cell_size);
+                    let vs = rect_vertices(rect.to_gl(viewport), fg, fg, &config);
+                    self.vertices_fg.extend_from_slice(&vs);
+                }
+
                 leftline += cell_width_px;
             }
-            baseline += cell_size.h;
+
+            let enabled = config.soft_wrap_indicator;
+            if let Some(rect) =
+                soft_wrap_marker(i, visible_cols, row.linewrap(), cell_size, enabled)
+            {
+                let color = Color::BrightBlack;
+                let vs = rect_vertices(rect.to_gl(viewport), color, color, &config);
+                self.vertices_bg.extend_from_slice(&vs);
+            }
         }
 
         if let Some(cursor) = self.cursor {
             if self.view_focused
+                && self.cursor_shown
                 && matches!(cursor.style, CursorStyle::Underline | CursorStyle::Bar)
             {
+                let double_width = lines.get(cursor.row).map_or(false, |l| l.double_width());
+                let cell_w = if double_width {
+                    cell_size.w * 2
+                } else {
+                    cell_size.w
+                };
+
                 let rect = if cursor.style == CursorStyle::Underline {
                     PixelRect {
-                        x: cursor.col as i32 * cell_size.w as i32,
+                        x: cursor.col as i32 * cell_w as i32,
                         y: (cursor.row + 1) as i32 * cell_size.h as i32 - 4,
-                        w: cell_size.w,
+                        w: cell_w,
                         h: 4,
                     }
                 } else {
                     PixelRect {
-                        x: cursor.col as i32 * cell_size.w as i32,
+                        x: cursor.col as i32 * cell_w as i32,
                         y: cursor.row as i32 * cell_size.h as i32,
                         w: 4,
                         h: cell_size.h,
                     }
                 };
 
+                // (inverted like the block cursor)
                 let fg = Color::Black;
-                let bg = Color::Selection;
-                let vs = rect_vertices(rect.to_gl(viewport), fg, bg);
+                let bg = if self.reverse_video ^ self.flashing {
+                    cursor_fg
+                } else {
+                    cursor_bg
+                };
+                let vs = rect_vertices(rect.to_gl(viewport), fg, bg, &config);
                 self.vertices_fg.extend_from_slice(&vs);
             }
         }
@@ -474,11 +757,37 @@ impl TerminalView {
     }
 
     pub fn draw(&mut self, surface: &mut glium::Frame) {
+        let config = crate::TOYTERM_CONFIG.load();
+        let last_input = *LAST_INPUT.lock().unwrap();
+        let idle = last_input.map_or(self.clock.elapsed(), |t| t.elapsed());
+
+        // The blinking phase starts over on every input
+        let system = config.cursor_blink_system.then(|| *SYSTEM_CURSOR_BLINK);
+        let (blink_default, blink_ms) = config.cursor_blink_settings(system);
+        let blink = self.cursor.and_then(|c| c.blink).unwrap_or(blink_default);
+        let cursor_shown = !blink || cursor_blink_phase(idle, blink_ms);
+        if cursor_shown != self.cursor_shown {
+            self.cursor_shown = cursor_shown;
+            self.updated = true;
+        }
+
+        let flashing = is_flashing(self.flashed_at, self.clock.elapsed());
+        if flashing != self.flashing {
+            self.flashing = flashing;
+            self.updated = true;
+        }
+
         if self.updated {
             self.rebuild_draw_queries();
         }
 
-        let elapsed = self.clock.elapsed().as_millis() as f32;
+        let elapsed = shader_timestamp(self.clock.elapsed());
+        let gaming_color = gaming_rgba(self.clock.elapsed(), config.gaming_color_period_ms);
+
+        let brightness = {
+            let dim_after = Duration::from_secs(config.idle_dim_seconds as u64);
+            idle_dim_factor(idle, dim_after, config.idle_dim_brightness)
+        };
 
         const TRIANGLES: index::NoIndices = index::NoIndices(index::PrimitiveType::TrianglesList);
 
@@ -494,7 +803,12 @@ impl TerminalView {
                 .sampled()
                 .magnify_filter(uniforms::MagnifySamplerFilter::Linear)
                 .minify_filter(uniforms::MinifySamplerFilter::Linear);
-            let uniforms = uniform! { tex: sampler, timestamp: elapsed };
+            let uniforms = uniform! {
+                tex: sampler,
+                timestamp: elapsed,
+                brightness: brightness,
+                gaming_color: gaming_color,
+            };
 
             surface
                 .draw(
@@ -508,12 +822,13 @@ impl TerminalView {
         }
 
         for query in iter_img {
+            // Non-square pixels are scaled without blurring
             let sampler = query
                 .texture
                 .sampled()
-                .magnify_filter(uniforms::MagnifySamplerFilter::Linear)
+                .magnify_filter(uniforms::MagnifySamplerFilter::Nearest)
                 .minify_filter(uniforms::MinifySamplerFilter::Linear);
-            let uniforms = uniform! { tex: sampler };
+            let uniforms = uniform! { tex: sampler, brightness: brightness };
 
             surface
                 .draw(
@@ -528,17 +843,31 @@ impl TerminalView {
     }
 }
 
-fn build_font_set(font_size: u32) -> FontSet {
-    let config = &crate::TOYTERM_CONFIG;
+/// Styles which are not given by the user's fonts and rendered by modifying other glyphs
+#[derive(Debug, Clone, Copy)]
+struct Synthesis {
+    bold: bool,
+    italic: bool,
+}
+
+fn build_font_set(font_size: u32) -> (FontSet, Synthesis) {
+    let config = crate::TOYTERM_CONFIG.load();
 
     let mut fonts = FontSet::new(font_size);
 
+    let mut loaded = std::collections::HashSet::new();
+
     use std::iter::repeat;
     let regular_iter = repeat(FontStyle::Regular).zip(config.fonts_regular.iter());
     let bold_iter = repeat(FontStyle::Bold).zip(config.fonts_bold.iter());
     let faint_iter = repeat(FontStyle::Faint).zip(config.fonts_faint.iter());
+    let italic_iter = repeat(FontStyle::Italic).zip(config.fonts_italic.iter());
 
-    for (style, path) in regular_iter.chain(bold_iter).chain(faint_iter) {
+    let iter = regular_iter
+        .chain(bold_iter)
+        .chain(faint_iter)
+        .chain(italic_iter);
+    for (style, path) in iter {
         // FIXME
         if path.as_os_str().is_empty() {
             continue;
@@ -552,6 +881,7 @@ fn build_font_set(font_size: u32) -> FontSet {
                 let face_idx = 0;
                 let font = Font::new(&data, face_idx);
                 fonts.add(style, font);
+                loaded.insert(style);
             }
 
             Err(e) => {
@@ -562,7 +892,8 @@ fn build_font_set(font_size: u32) -> FontSet {
 
     // Add embedded fonts
     {
-        let regular_font = Font::new(include_bytes!("fonts/Mplus1Code-Regular.ttf"), 0);
+        let regular_data: &[u8] = include_bytes!("fonts/Mplus1Code-Regular.ttf");
+        let regular_font = Font::new(regular_data, 0);
         fonts.add(FontStyle::Regular, regular_font);
 
         let bold_font = Font::new(include_bytes!("fonts/Mplus1Code-SemiBold.ttf"), 0);
@@ -570,12 +901,54 @@ fn build_font_set(font_size: u32) -> FontSet {
 
         let faint_font = Font::new(include_bytes!("fonts/Mplus1Code-Thin.ttf"), 0);
         fonts.add(FontStyle::Faint, faint_font);
+
+        // There is no embedded italic font, glyphs missing in the italic fonts are drawn upright
+        let italic_fallback = Font::new(regular_data, 0);
+        fonts.add(FontStyle::Italic, italic_fallback);
+    }
+
+    let synthesis = Synthesis {
+        // Without this, the embedded bold font would be used for the user's regular font
+        bold: config.synthetic_bold
+            && loaded.contains(&FontStyle::Regular)
+            && !loaded.contains(&FontStyle::Bold),
+        italic: !loaded.contains(&FontStyle::Italic),
+    };
+
+    (fonts, synthesis)
+}
+
+/// A hint shown when the OpenGL implementation doesn't satisfy the requirements
+pub const GL_HINT: &str = "toyterm requires OpenGL 3.1 (GLSL 1.40) or later. \
+    On VMs or remote X sessions, the software renderer of Mesa may work: \
+    try running with LIBGL_ALWAYS_SOFTWARE=1";
+
+/// Panics with an explanation if the shaders can't be compiled on the display
+fn check_gl_capabilities(display: &Display) {
+    use glium::{Api, Version};
+
+    let glsl = Version(Api::Gl, 1, 40);
+    if !display.is_glsl_version_supported(&glsl) {
+        let gl_version = display.get_opengl_version_string();
+        panic!("GLSL 1.40 is not supported (OpenGL {gl_version}). {GL_HINT}");
     }
+}
 
-    fonts
+/// Explains why the shader program can't be created
+fn program_error_message(
+    name: &str,
+    err: &glium::ProgramCreationError,
+    gl_version: &str,
+) -> String {
+    // the error includes the log of the compiler or the linker
+    let detail = err.to_string();
+    format!(
+        "Failed to create the {name} shader program on OpenGL {gl_version}. {GL_HINT}\n{}",
+        detail.trim_end()
+    )
 }
 
-fn calculate_cell_size(fonts: &FontSet) -> (CellSize, i32) {
+fn calculate_cell_size(fonts: &FontSet, letter_spacing: u32) -> (CellSize, i32) {
     let mut max_advance_x: i32 = 0;
     let mut max_over: i32 = 0;
     let mut max_under: i32 = 0;
@@ -596,7 +969,7 @@ fn calculate_cell_size(fonts: &FontSet) -> (CellSize, i32) {
         }
     }
 
-    let cell_w = max_advance_x as u32;
+    let cell_w = max_advance_x as u32 + letter_spacing;
     let cell_h = (max_over + max_under) as u32;
 
     log::debug!("cell size: {}x{} (px)", cell_w, cell_h);
@@ -610,12 +983,44 @@ fn calculate_cell_size(fonts: &FontSet) -> (CellSize, i32) {
     )
 }
 
-fn color_to_rgba(color: Color) -> u32 {
-    let config = &crate::TOYTERM_CONFIG;
+/// Returns `fg * alpha + bg * (1 - alpha)` for each RGB component, keeping the alpha of `fg`
+fn blend_rgba(fg: u32, bg: u32, alpha: f32) -> u32 {
+    let alpha = alpha.clamp(0.0, 1.0);
+    let component = |shift: u32| -> u32 {
+        let f = ((fg >> shift) & 0xFF) as f32;
+        let b = ((bg >> shift) & 0xFF) as f32;
+        let c = (f * alpha + b * (1.0 - alpha)).round() as u32;
+        c.min(0xFF) << shift
+    };
+    component(24) | component(16) | component(8) | (fg & 0xFF)
+}
+
+/// Cells in the "gaming" color (SGR 70/80) are given this color, and the shader replaces it
+/// with the one at the time (`gaming_rgba`). Colors with alpha 0 are reserved for this purpose.
+const GAMING_PLACEHOLDER: u32 = 0xFFFFFF00;
+
+/// Returns the "gaming" color at the time: its hue goes around the color wheel in `period_ms`
+fn gaming_rgba(elapsed: Duration, period_ms: u32) -> u32 {
+    let hue = if period_ms == 0 {
+        0.0
+    } else {
+        (elapsed.as_millis() % period_ms as u128) as f32 / period_ms as f32
+    };
+
+    // HSV to RGB with the saturation and the value of 1
+    let component = |offset: f32| -> u32 {
+        let x = ((hue + offset).fract() * 6.0 - 3.0).abs() - 1.0;
+        (x.clamp(0.0, 1.0) * 255.0).round() as u32
+    };
+    let (r, g, b) = (component(0.0), component(2.0 / 3.0), component(1.0 / 3.0));
+    (r << 24) | (g << 16) | (b << 8) | 0xFF
+}
 
+pub fn palette_rgba(color: Color, config: &crate::config::Config) -> u32 {
     match color {
         Color::Rgb { rgba } => rgba,
-        Color::Special => 0xFFFFFF00,
+        Color::Indexed(idx) => Color::indexed_rgba(idx),
+        Color::Special => GAMING_PLACEHOLDER,
 
         Color::Black => config.color_black,
         Color::Red => config.color_red,
@@ -641,6 +1046,297 @@ fn color_to_rgba(color: Color) -> u32 {
     }
 }
 
+/// Returns the y coordinate and the height of a glyph drawn in the row starting at `row_top`.
+/// In double-height lines, the glyph is scaled 2x vertically across the two rows.
+fn glyph_vertical(
+    row_top: i32,
+    max_over: i32,
+    bearing_y: i32,
+    h: u32,
+    cell_h: u32,
+    size: LineSize,
+) -> (i32, u32) {
+    match size {
+        LineSize::DoubleHeightTop => (row_top + 2 * (max_over - bearing_y), h * 2),
+        LineSize::DoubleHeightBottom => {
+            (row_top - cell_h as i32 + 2 * (max_over - bearing_y), h * 2)
+        }
+        _ => (row_top + max_over - bearing_y, h),
+    }
+}
+
+/// Clips a glyph of a double-height line to the half drawn in the row,
+/// adjusting the texture coordinates. Returns `None` if nothing is left.
+fn clip_to_row(
+    rect: PixelRect,
+    uv_rect: UvRect,
+    row_top: i32,
+    cell_h: u32,
+    size: LineSize,
+) -> Option<(PixelRect, UvRect)> {
+    if !matches!(
+        size,
+        LineSize::DoubleHeightTop | LineSize::DoubleHeightBottom
+    ) {
+        return Some((rect, uv_rect));
+    }
+
+    let top = max(rect.y, row_top);
+    let bottom = min(rect.y + rect.h as i32, row_top + cell_h as i32);
+    if top >= bottom {
+        return None;
+    }
+
+    let uv_per_px = uv_rect.h / rect.h as f32;
+    let clipped = PixelRect {
+        y: top,
+        h: (bottom - top) as u32,
+        ..rect
+    };
+    let uv_clipped = UvRect {
+        y: uv_rect.y + (top - rect.y) as f32 * uv_per_px,
+        h: (bottom - top) as f32 * uv_per_px,
+        ..uv_rect
+    };
+    Some((clipped, uv_clipped))
+}
+
+/// Returns the x coordinate of a glyph drawn in the cell(s) starting at `leftline`
+fn glyph_left(
+    leftline: u32,
+    cell_width: u32,
+    bearing_x: i32,
+    advance_x: u32,
+    config: &crate::config::Config,
+) -> i32 {
+    let mut left = leftline as i32 + bearing_x + config.horizontal_glyph_offset;
+    if config.center_glyphs {
+        // the remaining space is divided equally on both sides
+        left += (cell_width as i32 - advance_x as i32) / 2;
+    }
+    left
+}
+
+/// Returns the bright variant of the 8 base colors, and the others as they are
+fn bright_color(color: Color) -> Color {
+    match color {
+        Color::Black => Color::BrightBlack,
+        Color::Red => Color::BrightRed,
+        Color::Green => Color::BrightGreen,
+        Color::Yellow => Color::BrightYellow,
+        Color::Blue => Color::BrightBlue,
+        Color::Magenta => Color::BrightMagenta,
+        Color::Cyan => Color::BrightCyan,
+        Color::White => Color::BrightWhite,
+        _ => color,
+    }
+}
+
+/// Returns the font style and the horizontal offsets (in pixels) where a glyph is drawn.
+/// A synthetic bold glyph is the regular one drawn twice with a 1px offset.
+/// There is no bold face of the italic fonts, so bold italic glyphs are always synthesized.
+fn glyph_style(bold: i8, synthetic_bold: bool, italic_font: bool) -> (FontStyle, &'static [i32]) {
+    match bold {
+        1.. if italic_font => (FontStyle::Italic, &[0, 1]),
+        _ if italic_font => (FontStyle::Italic, &[0]),
+        -1 => (FontStyle::Faint, &[0]),
+        0 => (FontStyle::Regular, &[0]),
+        _ if synthetic_bold => (FontStyle::Regular, &[0, 1]),
+        _ => (FontStyle::Bold, &[0]),
+    }
+}
+
+/// Returns a copy of the lines where the cell at (row, col) is highlighted
+/// and its details are written at the bottom right corner (for debugging)
+fn hover_overlay(lines: &[Line], row: usize, col: usize) -> Vec<Line> {
+    let mut lines = lines.to_vec();
+
+    let mut cells: Vec<Cell> = match lines.get(row) {
+        Some(line) if col < line.columns() => line.iter().collect(),
+        _ => return lines,
+    };
+
+    // the head of a wide character
+    let head = (0..=col).rev().find(|&j| cells[j].width > 0).unwrap_or(col);
+    let cell = cells[head];
+    cells[head].attr.inversed ^= true;
+    lines[row] = cells.into_iter().collect();
+
+    let info = format!(
+        "({}, {}) {:?} U+{:04X} width={} fg={:?} bg={:?} bold={}",
+        row, col, cell.ch, cell.ch as u32, cell.width, cell.attr.fg, cell.attr.bg, cell.attr.bold,
+    );
+
+    if let Some(last) = lines.last_mut() {
+        let mut cells: Vec<Cell> = last.iter().collect();
+        let start = cells.len().saturating_sub(info.chars().count());
+        for (dst, ch) in cells[start..].iter_mut().zip(info.chars()) {
+            let mut overlay = Cell::new_ascii(if ch.is_ascii() { ch } else { '?' });
+            overlay.attr.inversed = true;
+            *dst = overlay;
+        }
+        *last = cells.into_iter().collect();
+    }
+
+    lines
+}
+
+/// Returns the horizontal shift (in the GL coordinates) of the top edge of a slanted glyph
+fn italic_shift(glyph_height: u32, viewport: Viewport) -> f32 {
+    const SLANT: f32 = 0.2;
+    (glyph_height as f32 * SLANT / viewport.w as f32) * 2.0
+}
+
+/// Shears the glyph vertices so that the top edge is shifted to the right by `shift`
+fn slant_glyph(vs: &mut [CellVertex; 6], shift: f32) {
+    let top = vs.iter().map(|v| v.position[1]).fold(f32::MIN, f32::max);
+    for v in vs.iter_mut() {
+        if v.position[1] == top {
+            v.position[0] += shift / 2.0;
+        } else {
+            v.position[0] -= shift / 2.0;
+        }
+    }
+}
+
+/// Returns the brightness of the window after `idle` of inactivity.
+/// It fades to `dimmed` after `dim_after` (zero disables dimming).
+fn idle_dim_factor(idle: Duration, dim_after: Duration, dimmed: f32) -> f32 {
+    const FADE: Duration = Duration::from_secs(1);
+
+    if dim_after.is_zero() || idle <= dim_after {
+        return 1.0;
+    }
+
+    let dimmed = dimmed.clamp(0.0, 1.0);
+    let t = ((idle - dim_after).as_secs_f32() / FADE.as_secs_f32()).min(1.0);
+    1.0 - (1.0 - dimmed) * t
+}
+
+/// Returns true while the screen is flashed by the visual bell rung at `flashed_at`
+fn is_flashing(flashed_at: Option<Duration>, now: Duration) -> bool {
+    const DURATION: Duration = Duration::from_millis(100);
+    flashed_at.map_or(false, |t| now.saturating_sub(t) < DURATION)
+}
+
+/// Returns true if a blinking cursor is in its visible phase
+fn cursor_blink_phase(since: Duration, blink_ms: u32) -> bool {
+    blink_ms == 0 || (since.as_millis() / blink_ms as u128) % 2 == 0
+}
+
+/// Returns the `timestamp` uniform in milliseconds.
+/// It wraps around every hour so that animations don't stutter due to the precision of f32.
+/// (The period is a multiple of all the periods of animations in the shader.)
+fn shader_timestamp(elapsed: Duration) -> f32 {
+    const PERIOD_MS: u128 = 60 * 60 * 1000;
+    (elapsed.as_millis() % PERIOD_MS) as f32
+}
+
+/// Returns the blinking rate passed to the shader (0 means steady)
+fn cell_blinking(attr: GraphicAttribute, disable_blink: bool) -> u8 {
+    if disable_blink {
+        0
+    } else {
+        attr.blinking
+    }
+}
+
+/// Returns the rectangles of the underline inside the cell
+/// (1: single, 2: double, 3: curly, 4: dotted, 5: dashed)
+fn underline_rects(
+    row: usize,
+    col: usize,
+    width: u32,
+    cell_size: CellSize,
+    underline: u8,
+) -> Vec<PixelRect> {
+    let h = max(cell_size.h / 16, 1);
+    let bottom = (row + 1) as u32 * cell_size.h;
+    let line = |above: u32| PixelRect {
+        x: (col as u32 * cell_size.w) as i32,
+        y: bottom.saturating_sub(above * h) as i32,
+        w: width,
+        h,
+    };
+
+    // pieces of the line at the offsets in [0, width)
+    let pieces = |step: u32, len: u32, dy: &dyn Fn(u32) -> i32| -> Vec<PixelRect> {
+        let base = line(2);
+        (0..width)
+            .step_by(step as usize)
+            .map(|x| PixelRect {
+                x: base.x + x as i32,
+                y: base.y + dy(x),
+                w: min(len, width - x),
+                h,
+            })
+            .collect()
+    };
+
+    match underline {
+        0 => Vec::new(),
+        1 => vec![line(2)],
+        2 => vec![line(2), line(4)],
+        3 => {
+            // a sine wave with the period of the cell width
+            let amplitude = h as f32;
+            let period = cell_size.w as f32;
+            let wave = |x: u32| {
+                let phase = 2.0 * std::f32::consts::PI * x as f32 / period;
+                (-amplitude * phase.sin()).round() as i32
+            };
+            pieces(h, h, &wave)
+        }
+        4 => pieces(2 * h, h, &|_| 0),
+        _ => pieces(5 * h, 3 * h, &|_| 0),
+    }
+}
+
+/// Returns the rectangle of the strikethrough line at the vertical center of the cell
+fn strike_line(row: usize, col: usize, width: u32, cell_size: CellSize) -> PixelRect {
+    let h = max(cell_size.h / 16, 1);
+    PixelRect {
+        x: (col as u32 * cell_size.w) as i32,
+        y: (row as u32 * cell_size.h + (cell_size.h - h) / 2) as i32,
+        w: width,
+        h,
+    }
+}
+
+/// Returns the rectangle of the overline at the top of the cell
+fn overline(row: usize, col: usize, width: u32, cell_size: CellSize) -> PixelRect {
+    let h = max(cell_size.h / 16, 1);
+    PixelRect {
+        x: (col as u32 * cell_size.w) as i32,
+        y: (row as u32 * cell_size.h) as i32,
+        w: width,
+        h,
+    }
+}
+
+/// Returns the rectangle of the marker for a soft-wrapped line
+fn soft_wrap_marker(
+    row: usize,
+    cols: usize,
+    linewrap: bool,
+    cell_size: CellSize,
+    enabled: bool,
+) -> Option<PixelRect> {
+    if !enabled || !linewrap || cols == 0 {
+        return None;
+    }
+
+    // a small square at the lower right corner of the last column
+    let w = max(cell_size.w / 4, 1);
+    let h = max(cell_size.h / 4, 1);
+    Some(PixelRect {
+        x: (cols as u32 * cell_size.w - w) as i32,
+        y: ((row + 1) as u32 * cell_size.h - h) as i32,
+        w,
+        h,
+    })
+}
+
 #[derive(Clone, Copy)]
 pub struct PixelRect {
     pub x: i32,
@@ -706,6 +1402,7 @@ fn glyph_vertices(
     fg_color: Color,
     bg_color: Color,
     blinking: u8,
+    config: &Config,
 ) -> [CellVertex; 6] {
     // top-left, bottom-left, bottom-right, top-right
     let gl_ps = [
@@ -724,7 +1421,10 @@ fn glyph_vertices(
     let v = |idx| CellVertex {
         position: gl_ps[idx],
         tex_coords: uv_ps[idx],
-        color: [color_to_rgba(bg_color), color_to_rgba(fg_color)],
+        color: [
+            palette_rgba(bg_color, config),
+            palette_rgba(fg_color, config),
+        ],
         is_bg: 0,
         blinking: blinking as u32,
     };
@@ -741,8 +1441,29 @@ fn glyph_vertices(
     [/* A */ v(0), v(1), v(2), /* B */ v(2), v(3), v(0)]
 }
 
+/// Generate vertices filling the entire viewport with the background color
+fn clear_screen_vertices(bg_color: Color, inverted: bool, config: &Config) -> [CellVertex; 6] {
+    let rect = GlRect {
+        x: -1.0,
+        y: 1.0,
+        w: 2.0,
+        h: 2.0,
+    };
+    let bg = if inverted {
+        Color::Foreground
+    } else {
+        bg_color
+    };
+    rect_vertices(rect, Color::White, bg, config)
+}
+
 /// Generate vertices for a rectangle
-fn rect_vertices(gl_rect: GlRect, fg_color: Color, bg_color: Color) -> [CellVertex; 6] {
+fn rect_vertices(
+    gl_rect: GlRect,
+    fg_color: Color,
+    bg_color: Color,
+    config: &Config,
+) -> [CellVertex; 6] {
     let GlRect { x, y, w, h } = gl_rect;
 
     // top-left, bottom-left, bottom-right, top-right
@@ -751,7 +1472,10 @@ fn rect_vertices(gl_rect: GlRect, fg_color: Color, bg_color: Color) -> [CellVert
     let v = |idx| CellVertex {
         position: gl_ps[idx],
         tex_coords: [0.0, 0.0],
-        color: [color_to_rgba(bg_color), color_to_rgba(fg_color)],
+        color: [
+            palette_rgba(bg_color, config),
+            palette_rgba(fg_color, config),
+        ],
         is_bg: 1,
         blinking: 0,
     };
@@ -781,3 +1505,412 @@ fn image_vertices(gl_rect: GlRect) -> [ImageVertex; 6] {
 
     [v(0), v(1), v(2), v(2), v(3), v(0)]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::Cell;
+
+    #[test]
+    fn test_shader_timestamp() {
+        assert_eq!(shader_timestamp(Duration::from_millis(1234)), 1234.0);
+
+        // still precise after a long time
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(shader_timestamp(day + Duration::from_millis(1)), 1.0);
+        assert_eq!(
+            shader_timestamp(day - Duration::from_millis(1)),
+            3_599_999.0
+        );
+    }
+
+    #[test]
+    fn test_is_flashing() {
+        let ms = Duration::from_millis;
+        assert!(!is_flashing(None, ms(1000)));
+        assert!(is_flashing(Some(ms(1000)), ms(1000)));
+        assert!(is_flashing(Some(ms(1000)), ms(1099)));
+        assert!(!is_flashing(Some(ms(1000)), ms(1100)));
+    }
+
+    #[test]
+    fn test_cursor_blink_phase() {
+        let ms = Duration::from_millis;
+        assert!(cursor_blink_phase(ms(0), 500));
+        assert!(cursor_blink_phase(ms(499), 500));
+        assert!(!cursor_blink_phase(ms(500), 500));
+        assert!(!cursor_blink_phase(ms(999), 500));
+        assert!(cursor_blink_phase(ms(1000), 500));
+
+        // 0 means steady
+        assert!(cursor_blink_phase(ms(700), 0));
+    }
+
+    #[test]
+    fn test_disable_blink() {
+        let mut cell = Cell::new_ascii('a');
+        cell.attr.blinking = 1;
+
+        let gl_rect = GlRect {
+            x: 0.0,
+            y: 0.0,
+            w: 1.0,
+            h: 1.0,
+        };
+        let uv_rect = UvRect {
+            x: 0.0,
+            y: 0.0,
+            w: 1.0,
+            h: 1.0,
+        };
+        let (fg, bg) = (cell.attr.fg, cell.attr.bg);
+        let config = Config::default();
+
+        let blinking = cell_blinking(cell.attr, false);
+        let vs = glyph_vertices(gl_rect, uv_rect, fg, bg, blinking, &config);
+        assert!(vs.iter().all(|v| v.blinking == 1));
+
+        let blinking = cell_blinking(cell.attr, true);
+        let vs = glyph_vertices(gl_rect, uv_rect, fg, bg, blinking, &config);
+        assert!(vs.iter().all(|v| v.blinking == 0));
+    }
+
+    #[test]
+    fn test_soft_wrap_marker() {
+        let cell_size = CellSize { w: 8, h: 16 };
+
+        // only wrapped lines have the marker
+        assert!(soft_wrap_marker(0, 80, false, cell_size, true).is_none());
+        let rect = soft_wrap_marker(1, 80, true, cell_size, true).unwrap();
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (638, 28, 2, 4));
+
+        // disabled by the config
+        assert!(soft_wrap_marker(1, 80, true, cell_size, false).is_none());
+    }
+
+    #[test]
+    fn test_synthetic_bold() {
+        assert_eq!(glyph_style(1, false, false), (FontStyle::Bold, &[0][..]));
+        assert_eq!(glyph_style(0, true, false), (FontStyle::Regular, &[0][..]));
+        assert_eq!(glyph_style(-1, true, false), (FontStyle::Faint, &[0][..]));
+
+        // the regular glyph is drawn twice, shifted by 1px
+        let (style, offsets) = glyph_style(1, true, false);
+        assert_eq!(style, FontStyle::Regular);
+        assert_eq!(offsets, &[0, 1]);
+
+        // bold italic text keeps its weight with the italic fonts
+        assert_eq!(glyph_style(0, false, true), (FontStyle::Italic, &[0][..]));
+        assert_eq!(
+            glyph_style(1, false, true),
+            (FontStyle::Italic, &[0, 1][..])
+        );
+    }
+
+    #[test]
+    fn test_hover_overlay() {
+        let line = |s: &str| -> Line { s.chars().map(Cell::new_ascii).collect() };
+        let text = |line: &Line| -> String { line.iter().map(|c| c.ch).collect() };
+
+        let lines = vec![line("abc"), line("def"), line(&" ".repeat(40))];
+        let overlaid = hover_overlay(&lines, 1, 2);
+
+        // only the hovered cell is highlighted
+        assert!(overlaid[1].get(2).unwrap().attr.inversed);
+        assert!(!overlaid[1].get(1).unwrap().attr.inversed);
+        assert_eq!(text(&overlaid[0]), "abc");
+
+        // the details are shown at the bottom right corner
+        let last = text(&overlaid[2]);
+        assert!(last.trim_start().starts_with("(1, 2) 'f' U+0066"));
+
+        // out of the screen
+        let overlaid = hover_overlay(&lines, 5, 0);
+        assert_eq!(text(&overlaid[2]), " ".repeat(40));
+    }
+
+    #[test]
+    fn test_synthetic_italic() {
+        let gl_rect = GlRect {
+            x: 0.0,
+            y: 0.0,
+            w: 0.5,
+            h: 1.0,
+        };
+        let uv_rect = UvRect {
+            x: 0.0,
+            y: 0.0,
+            w: 1.0,
+            h: 1.0,
+        };
+        let config = Config::default();
+        let upright = glyph_vertices(gl_rect, uv_rect, Color::White, Color::Black, 0, &config);
+
+        let mut slanted = upright;
+        slant_glyph(&mut slanted, 0.2);
+
+        for (u, s) in upright.iter().zip(slanted.iter()) {
+            let dx = s.position[0] - u.position[0];
+            if u.position[1] == gl_rect.y {
+                assert!((dx - 0.1).abs() < 1e-6); // top edge
+            } else {
+                assert!((dx + 0.1).abs() < 1e-6); // bottom edge
+            }
+            assert_eq!(s.position[1], u.position[1]);
+            assert_eq!(s.tex_coords, u.tex_coords);
+        }
+
+        let viewport = Viewport {
+            x: 0,
+            y: 0,
+            w: 100,
+            h: 100,
+        };
+        assert!((italic_shift(50, viewport) - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_underline_rects() {
+        let cell_size = CellSize { w: 8, h: 32 };
+
+        assert!(underline_rects(0, 0, 8, cell_size, 0).is_empty());
+
+        let rects = underline_rects(1, 2, 16, cell_size, 1);
+        let rects: Vec<_> = rects.iter().map(|r| (r.x, r.y, r.w, r.h)).collect();
+        assert_eq!(rects, vec![(16, 60, 16, 2)]);
+
+        // dotted and dashed lines are divided into pieces
+        assert_eq!(underline_rects(0, 0, 8, cell_size, 4).len(), 2);
+        assert_eq!(underline_rects(0, 0, 16, cell_size, 5).len(), 2);
+
+        // the curly line goes up and down
+        let ys: Vec<i32> = underline_rects(0, 0, 8, cell_size, 3)
+            .iter()
+            .map(|r| r.y)
+            .collect();
+        assert_eq!(ys, vec![28, 26, 28, 30]);
+
+        // lines are kept inside the cell
+        for style in 1..=5 {
+            for rect in underline_rects(1, 2, 16, cell_size, style) {
+                assert!(32 <= rect.y && rect.y as u32 + rect.h <= 64);
+                assert!(16 <= rect.x && rect.x as u32 + rect.w <= 32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strike_line() {
+        let cell_size = CellSize { w: 8, h: 32 };
+
+        let rect = strike_line(0, 0, 8, cell_size);
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (0, 15, 8, 2));
+
+        // a wide character
+        let rect = strike_line(2, 3, 16, cell_size);
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (24, 79, 16, 2));
+    }
+
+    #[test]
+    fn test_overline() {
+        let cell_size = CellSize { w: 8, h: 32 };
+
+        let rect = overline(0, 0, 8, cell_size);
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (0, 0, 8, 2));
+
+        // a wide character
+        let rect = overline(2, 3, 16, cell_size);
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (24, 64, 16, 2));
+    }
+
+    #[test]
+    fn test_program_error_message() {
+        use glium::program::ShaderType;
+        use glium::ProgramCreationError;
+
+        let err = ProgramCreationError::CompilationError(
+            "0:1(10): error: GLSL 1.40 is not supported\n".to_owned(),
+            ShaderType::Fragment,
+        );
+        let msg = program_error_message("cell", &err, "2.1 Mesa 20.0");
+        assert!(
+            msg.starts_with("Failed to create the cell shader program on OpenGL 2.1 Mesa 20.0.")
+        );
+        assert!(msg.contains("LIBGL_ALWAYS_SOFTWARE=1"));
+        assert!(msg.ends_with(
+            "Compilation error in fragment shader: 0:1(10): error: GLSL 1.40 is not supported"
+        ));
+
+        let err = ProgramCreationError::CompilationNotSupported;
+        let msg = program_error_message("image", &err, "3.0");
+        assert!(msg.starts_with("Failed to create the image shader program on OpenGL 3.0."));
+        assert!(msg.ends_with(&err.to_string()));
+    }
+
+    #[test]
+    fn test_double_height_glyph() {
+        // a glyph of 10px height whose top is 4px below the top of the cell
+        let (cell_h, max_over, bearing_y) = (20, 16, 12);
+        let uv = UvRect {
+            x: 0.0,
+            y: 0.5,
+            w: 0.1,
+            h: 0.2,
+        };
+
+        let (y, h) = glyph_vertical(40, max_over, bearing_y, 10, cell_h, LineSize::Normal);
+        assert_eq!((y, h), (44, 10));
+        let rect = PixelRect { x: 0, y, w: 8, h };
+        let (clipped, _) = clip_to_row(rect, uv, 40, cell_h, LineSize::Normal).unwrap();
+        assert_eq!((clipped.y, clipped.h), (44, 10));
+
+        // the top half: rows 2 and 3 form a double-height line
+        let size = LineSize::DoubleHeightTop;
+        let (y, h) = glyph_vertical(40, max_over, bearing_y, 10, cell_h, size);
+        assert_eq!((y, h), (48, 20));
+        let rect = PixelRect { x: 0, y, w: 8, h };
+        let (clipped, uv_clipped) = clip_to_row(rect, uv, 40, cell_h, size).unwrap();
+        assert_eq!((clipped.y, clipped.h), (48, 12));
+        assert!((uv_clipped.y - 0.5).abs() < 1e-6);
+        assert!((uv_clipped.h - 0.12).abs() < 1e-6);
+
+        // the bottom half shows the rest of the same glyph
+        let size = LineSize::DoubleHeightBottom;
+        let (y, h) = glyph_vertical(60, max_over, bearing_y, 10, cell_h, size);
+        assert_eq!((y, h), (48, 20));
+        let rect = PixelRect { x: 0, y, w: 8, h };
+        let (clipped, uv_clipped) = clip_to_row(rect, uv, 60, cell_h, size).unwrap();
+        assert_eq!((clipped.y, clipped.h), (60, 8));
+        assert!((uv_clipped.y - 0.62).abs() < 1e-6);
+        assert!((uv_clipped.h - 0.08).abs() < 1e-6);
+
+        // glyphs entirely in the other half are not drawn
+        let rect = PixelRect {
+            x: 0,
+            y: 40,
+            w: 8,
+            h: 10,
+        };
+        assert!(clip_to_row(rect, uv, 60, cell_h, size).is_none());
+    }
+
+    #[test]
+    fn test_glyph_left() {
+        let mut config = crate::config::Config::default();
+        assert_eq!(glyph_left(16, 8, 1, 6, &config), 17);
+
+        // a narrow glyph is centered
+        config.center_glyphs = true;
+        assert_eq!(glyph_left(16, 8, 1, 6, &config), 18);
+        assert_eq!(glyph_left(16, 8, 1, 8, &config), 17);
+        // in a wide cell
+        assert_eq!(glyph_left(16, 16, 0, 6, &config), 21);
+
+        config.horizontal_glyph_offset = -2;
+        assert_eq!(glyph_left(16, 8, 1, 6, &config), 16);
+    }
+
+    #[test]
+    fn test_blend_rgba() {
+        assert_eq!(blend_rgba(0xFFFFFFFF, 0x000000FF, 0.6), 0x999999FF);
+        assert_eq!(blend_rgba(0x000000FF, 0xFFFFFFFF, 0.6), 0x666666FF);
+        assert_eq!(blend_rgba(0x204060FF, 0x000000FF, 0.5), 0x102030FF);
+
+        assert_eq!(blend_rgba(0x123456FF, 0xABCDEF00, 1.0), 0x123456FF);
+        assert_eq!(blend_rgba(0x12345680, 0xABCDEFFF, 0.0), 0xABCDEF80);
+    }
+
+    #[test]
+    fn test_bright_color() {
+        assert_eq!(bright_color(Color::Black), Color::BrightBlack);
+        assert_eq!(bright_color(Color::White), Color::BrightWhite);
+
+        // the others are not affected
+        assert_eq!(bright_color(Color::BrightRed), Color::BrightRed);
+        let rgb = Color::Rgb { rgba: 0x112233FF };
+        assert_eq!(bright_color(rgb), rgb);
+        assert_eq!(bright_color(Color::Foreground), Color::Foreground);
+    }
+
+    #[test]
+    fn test_idle_dim_factor() {
+        let secs = Duration::from_secs_f32;
+        let dim_after = Duration::from_secs(60);
+
+        assert_eq!(idle_dim_factor(secs(0.0), dim_after, 0.4), 1.0);
+        assert_eq!(idle_dim_factor(secs(60.0), dim_after, 0.4), 1.0);
+
+        // fades out in a second
+        assert!((idle_dim_factor(secs(60.5), dim_after, 0.4) - 0.7).abs() < 1e-6);
+        assert!((idle_dim_factor(secs(61.0), dim_after, 0.4) - 0.4).abs() < 1e-6);
+        assert!((idle_dim_factor(secs(3600.0), dim_after, 0.4) - 0.4).abs() < 1e-6);
+
+        // disabled
+        assert_eq!(idle_dim_factor(secs(3600.0), Duration::ZERO, 0.4), 1.0);
+    }
+
+    #[test]
+    fn test_window_background() {
+        let config = crate::config::Config {
+            color_background: 0x202020FF,
+            ..Default::default()
+        };
+
+        // the window is cleared with the background color, not with ANSI black
+        let bg = palette_rgba(Color::Background, &config);
+        assert_eq!(bg, 0x202020FF);
+        assert_ne!(bg, palette_rgba(Color::Black, &config));
+
+        // the whole viewport is filled with it, or with the foreground color in reverse video
+        let vs = clear_screen_vertices(Color::Background, false, &config);
+        assert!(vs.iter().all(|v| v.color[0] == 0x202020FF));
+        let xs = vs.iter().map(|v| v.position[0]);
+        let ys = vs.iter().map(|v| v.position[1]);
+        assert_eq!(xs.clone().fold(f32::MAX, f32::min), -1.0);
+        assert_eq!(xs.fold(f32::MIN, f32::max), 1.0);
+        assert_eq!(ys.clone().fold(f32::MAX, f32::min), -1.0);
+        assert_eq!(ys.fold(f32::MIN, f32::max), 1.0);
+
+        let vs = clear_screen_vertices(Color::Background, true, &config);
+        assert!(vs.iter().all(|v| v.color[0] == config.color_foreground));
+    }
+
+    #[test]
+    fn test_indexed_palette() {
+        let config = crate::config::Config {
+            color_red: 0xCC3333FF,
+            ..Default::default()
+        };
+
+        // the base 16 colors follow the configured palette, and the others are computed
+        assert_eq!(palette_rgba(Color::indexed(1), &config), 0xCC3333FF);
+        assert_eq!(palette_rgba(Color::indexed(196), &config), 0xFF0000FF);
+        assert_eq!(palette_rgba(Color::indexed(16), &config), 0x000000FF);
+        assert_eq!(palette_rgba(Color::indexed(231), &config), 0xFFFFFFFF);
+        assert_eq!(palette_rgba(Color::indexed(232), &config), 0x000000FF);
+        assert_eq!(palette_rgba(Color::indexed(255), &config), 0xFDFDFDFF);
+    }
+
+    #[test]
+    fn test_gaming_rgba() {
+        let ms = Duration::from_millis;
+        let config = crate::config::Config::default();
+        assert_eq!(palette_rgba(Color::Special, &config), GAMING_PLACEHOLDER);
+
+        // red -> yellow -> green -> cyan -> blue -> magenta -> red
+        assert_eq!(gaming_rgba(ms(0), 3000), 0xFF0000FF);
+        assert_eq!(gaming_rgba(ms(500), 3000), 0xFFFF00FF);
+        assert_eq!(gaming_rgba(ms(1000), 3000), 0x00FF00FF);
+        assert_eq!(gaming_rgba(ms(1500), 3000), 0x00FFFFFF);
+        assert_eq!(gaming_rgba(ms(2000), 3000), 0x0000FFFF);
+        assert_eq!(gaming_rgba(ms(2500), 3000), 0xFF00FFFF);
+        assert_eq!(gaming_rgba(ms(3000), 3000), 0xFF0000FF);
+        assert_eq!(gaming_rgba(ms(250), 3000), 0xFF8000FF);
+
+        // the resolved color is always opaque, and never the placeholder
+        assert_eq!(gaming_rgba(ms(1234), 3000) & 0xFF, 0xFF);
+
+        // stopped
+        assert_eq!(gaming_rgba(ms(1000), 0), 0xFF0000FF);
+    }
+}
@@ -1,16 +1,58 @@
 use glium::{glutin, Display};
 use glutin::{
-    dpi::PhysicalPosition,
+    dpi::{PhysicalPosition, PhysicalSize},
     event::{ElementState, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent},
     event_loop::ControlFlow,
 };
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use crate::terminal::{Mode, Terminal, TerminalSize};
-use crate::view::{TerminalView, Viewport};
+pub use crate::config::StartupMode;
+use crate::config::{Action, Bell, Config, Key};
+use crate::terminal::{CellSize, Line, Mode, Notification, Terminal, TerminalSize};
+pub use crate::view::GL_HINT;
+use crate::view::{SelectionKind, TerminalView, UrlRegion, Viewport};
 
 type Event = glutin::event::Event<'static, ()>;
 type CursorPosition = PhysicalPosition<f64>;
 
+/// Input given programmatically as if it's typed by the user (for automation and tests)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InjectedInput {
+    /// Characters typed on the keyboard
+    Text(String),
+    /// A key press with modifiers, including the ones bound to commands (e.g. Ctrl+Shift+V).
+    /// Keys which produce characters should be given as `Text`.
+    Key(ModifiersState, VirtualKeyCode),
+    /// Bytes written to the PTY as they are
+    Bytes(Vec<u8>),
+}
+
+impl InjectedInput {
+    /// Writes the input to the terminal. Returns false if it has to be handled by the window.
+    fn write_to(&self, terminal: &mut Terminal) -> bool {
+        match self.to_bytes() {
+            Some(bytes) => {
+                terminal.pty_write(&bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the bytes sent to the PTY, or `None` if the input is handled by the window
+    fn to_bytes(&self) -> Option<std::borrow::Cow<'_, [u8]>> {
+        use std::borrow::Cow;
+        match self {
+            InjectedInput::Text(text) => Some(Cow::Borrowed(text.as_bytes())),
+            InjectedInput::Key(modifiers, keycode) => {
+                key_sequence(*modifiers, *keycode).map(Cow::Borrowed)
+            }
+            InjectedInput::Bytes(bytes) => Some(Cow::Borrowed(bytes)),
+        }
+    }
+}
+
 pub struct TerminalWindow {
     display: Display,
     terminal: Terminal,
@@ -18,11 +60,34 @@ pub struct TerminalWindow {
 
     view: TerminalView,
     mode: Mode,
+    title: Option<String>,
     history_head: isize,
     last_history_head: isize,
     focused: bool,
+    scroll_lock: bool,
+    last_beep: Option<std::time::Instant>,
+    last_notification: Option<std::time::Instant>,
+    search: Option<Search>,
     modifiers: ModifiersState,
     mouse: MouseState,
+    keybindings: HashMap<Key, Action>,
+    // true after a key bound to an action is pressed, whose character is not sent
+    bound_key_pressed: bool,
+    // the configuration applied to this window, and its generation
+    config: Arc<Config>,
+    config_generation: u64,
+}
+
+/// State of the scrollback search
+struct Search {
+    query: String,
+    // true while the query is being typed
+    editing: bool,
+    // matches in the history and the screen (rows are counted from the oldest line)
+    matches: Vec<crate::search::Match>,
+    current: Option<usize>,
+    // the matches have to be highlighted again
+    dirty: bool,
 }
 
 struct MouseState {
@@ -31,13 +96,24 @@ struct MouseState {
     cursor_pos: CursorPosition,
     pressed_pos: Option<CursorPosition>,
     released_pos: Option<CursorPosition>,
+    // the button is released after selecting text, which should be copied to PRIMARY
+    selection_finished: bool,
+    // Alt is held when the selection is started
+    block_selection: bool,
     click_count: usize,
     last_clicked: std::time::Instant,
+    held_button: Option<u8>,
+    reported_cell: Option<(u32, u32)>,
 }
 
 impl TerminalWindow {
+    /// Opens a terminal running `command` (or the shell if None) in `cwd`
     #[allow(unused)]
-    pub fn new(display: Display, cwd: Option<&std::path::Path>) -> Self {
+    pub fn new(
+        display: Display,
+        cwd: Option<&std::path::Path>,
+        command: Option<&[String]>,
+    ) -> Self {
         let size = display.gl_window().window().inner_size();
         let full = Viewport {
             x: 0,
@@ -45,15 +121,104 @@ impl TerminalWindow {
             w: size.width,
             h: size.height,
         };
-        Self::with_viewport(display, full, cwd)
+        // The size doesn't matter when the window is maximized or fullscreen
+        let windowed = {
+            let window = display.gl_window();
+            let window = window.window();
+            window.fullscreen().is_none() && !window.is_maximized()
+        };
+
+        let mut window = Self::with_viewport(display, full, cwd, command);
+
+        if let Some(size) = initial_terminal_size().filter(|_| windowed) {
+            window.request_terminal_size(size);
+        }
+
+        window
+    }
+
+    /// Opens a terminal taking over the PTY of a running shell (see `session`)
+    #[cfg(feature = "multiplex")]
+    pub fn attached(display: Display, pty: std::os::unix::io::OwnedFd, pid: i32) -> Self {
+        let size = display.gl_window().window().inner_size();
+        let full = Viewport {
+            x: 0,
+            y: 0,
+            w: size.width,
+            h: size.height,
+        };
+        let pid = nix::unistd::Pid::from_raw(pid);
+        Self::with_terminal(display, full, |size, cell_size| {
+            Terminal::attach(size, cell_size, pty, pid)
+        })
+    }
+
+    /// Returns the PTY and the PID of the shell, which keeps being served until `detach`
+    #[cfg(feature = "multiplex")]
+    pub fn shell(&self) -> (std::os::unix::io::RawFd, i32) {
+        let (pty, pid) = self.terminal.shell();
+        (pty, pid.as_raw())
+    }
+
+    /// Stops the terminal leaving the shell running, and returns its PTY and PID.
+    /// The window should be closed after this.
+    #[cfg(feature = "multiplex")]
+    pub fn detach(&mut self) -> (std::os::unix::io::OwnedFd, i32) {
+        let (pty, pid) = self.terminal.detach();
+        (pty, pid.as_raw())
+    }
+
+    /// Resizes the window so that the terminal has the given number of rows and columns
+    fn request_terminal_size(&mut self, size: TerminalSize) {
+        let cell_size = self.view.cell_size();
+        let scroll_bar_width = reserved_scroll_bar_width(&crate::TOYTERM_CONFIG.load());
+        let w = size.cols as u32 * cell_size.w + scroll_bar_width;
+        let h = size.rows as u32 * cell_size.h;
+        log::debug!("initial size: {}x{} (cell)", size.rows, size.cols);
+
+        let window = self.display.gl_window();
+        window.window().set_inner_size(PhysicalSize::new(w, h));
+    }
+
+    /// Saves the current size to be used by the next launch (if `remember_size` is enabled)
+    pub fn save_terminal_size(&self) {
+        if !crate::TOYTERM_CONFIG.load().remember_size {
+            return;
+        }
+
+        let size = self.terminal.state.lock().unwrap().size();
+        if let Some(path) = saved_size_path() {
+            let save = || -> std::io::Result<()> {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                std::fs::write(&path, format!("{} {}\n", size.rows, size.cols))
+            };
+            if let Err(err) = save() {
+                log::warn!("Failed to save the size to {:?}: {}", path.display(), err);
+            }
+        }
     }
 
     pub fn with_viewport(
         display: Display,
         viewport: Viewport,
         cwd: Option<&std::path::Path>,
+        command: Option<&[String]>,
     ) -> Self {
-        let font_size = crate::TOYTERM_CONFIG.font_size;
+        Self::with_terminal(display, viewport, |size, cell_size| {
+            let parent_cwd = std::env::current_dir().expect("cwd");
+            let child_cwd = cwd.unwrap_or(&parent_cwd);
+            Terminal::new(size, cell_size, child_cwd, command)
+        })
+    }
+
+    /// Opens a window with the terminal of the given size made by `terminal`
+    fn with_terminal<F>(display: Display, viewport: Viewport, terminal: F) -> Self
+    where
+        F: FnOnce(TerminalSize, CellSize) -> Terminal,
+    {
+        let font_size = crate::TOYTERM_CONFIG.load().font_size;
         let view = TerminalView::with_viewport(
             display.clone(),
             viewport,
@@ -63,14 +228,9 @@ impl TerminalWindow {
 
         let terminal = {
             let cell_size = view.cell_size();
-            let scroll_bar_width = crate::TOYTERM_CONFIG.scroll_bar_width;
-            let size = TerminalSize {
-                rows: (viewport.h / cell_size.h) as usize,
-                cols: ((viewport.w - scroll_bar_width) / cell_size.w) as usize,
-            };
-            let parent_cwd = std::env::current_dir().expect("cwd");
-            let child_cwd = cwd.unwrap_or(&parent_cwd);
-            Terminal::new(size, cell_size, child_cwd)
+            let scroll_bar_width = reserved_scroll_bar_width(&crate::TOYTERM_CONFIG.load());
+            let size = terminal_size(viewport, cell_size, scroll_bar_width);
+            terminal(size, cell_size)
         };
 
         // Use I-beam mouse cursor
@@ -86,9 +246,14 @@ impl TerminalWindow {
 
             view,
             mode: Mode::default(),
+            title: None,
             history_head: 0,
             last_history_head: 0,
             focused: true,
+            scroll_lock: false,
+            last_beep: None,
+            last_notification: None,
+            search: None,
             modifiers: ModifiersState::empty(),
             mouse: MouseState {
                 wheel_delta_x: 0.0,
@@ -96,9 +261,17 @@ impl TerminalWindow {
                 cursor_pos: CursorPosition::default(),
                 pressed_pos: None,
                 released_pos: None,
+                selection_finished: false,
+                block_selection: false,
                 click_count: 0,
                 last_clicked: std::time::Instant::now() - std::time::Duration::from_secs(10),
+                held_button: None,
+                reported_cell: None,
             },
+            keybindings: crate::TOYTERM_CONFIG.load().keybindings.table(),
+            bound_key_pressed: false,
+            config: crate::TOYTERM_CONFIG.load(),
+            config_generation: crate::TOYTERM_CONFIG.generation(),
         }
     }
 
@@ -112,13 +285,10 @@ impl TerminalWindow {
         self.terminal = {
             let viewport = self.view.viewport();
             let cell_size = self.view.cell_size();
-            let scroll_bar_width = crate::TOYTERM_CONFIG.scroll_bar_width;
-            let size = TerminalSize {
-                rows: (viewport.h / cell_size.h) as usize,
-                cols: ((viewport.w - scroll_bar_width) / cell_size.w) as usize,
-            };
+            let scroll_bar_width = reserved_scroll_bar_width(&crate::TOYTERM_CONFIG.load());
+            let size = terminal_size(viewport, cell_size, scroll_bar_width);
             let cwd = std::env::current_dir().expect("cwd");
-            Terminal::new(size, cell_size, &cwd)
+            Terminal::new(size, cell_size, &cwd, None)
         };
 
         // Invalidate rendering cache
@@ -131,6 +301,17 @@ impl TerminalWindow {
         self.terminal.send_sigterm();
     }
 
+    /// Shows a banner with the exit status below the last output of the exited process
+    #[cfg(feature = "multiplex")]
+    pub fn show_exit_banner(&mut self) {
+        let mut state = self.terminal.state.lock().unwrap();
+        let banner = match state.exit_status {
+            Some(0) | None => "[exited] press any key to close".to_owned(),
+            Some(status) => format!("[exited with status {status}] press any key to close"),
+        };
+        state.put_banner(&banner);
+    }
+
     // Change cursor icon according to the current mouse_track mode
     pub fn refresh_cursor_icon(&mut self) {
         let icon = if self.mode.mouse_track {
@@ -141,32 +322,143 @@ impl TerminalWindow {
         self.display.gl_window().window().set_cursor_icon(icon);
     }
 
+    // Set the window title reported by the terminal (if any)
+    fn refresh_title(&mut self) {
+        if let Some(title) = self.title.as_ref() {
+            self.display.gl_window().window().set_title(title);
+        }
+    }
+
+    fn ring_bell(&mut self) {
+        match crate::TOYTERM_CONFIG.load().bell {
+            Bell::None => {}
+            Bell::Visual => self.view.flash(),
+            Bell::Audible => self.beep(),
+            Bell::Both => {
+                self.view.flash();
+                self.beep();
+            }
+        }
+    }
+
+    /// Shows a desktop notification and requests the attention, unless the window is focused
+    fn notify(&mut self, notification: Notification) {
+        let config = crate::TOYTERM_CONFIG.load();
+        if !config.notifications || self.focused {
+            log::debug!("notification suppressed: {:?}", notification);
+            return;
+        }
+
+        let now = std::time::Instant::now();
+        if !should_notify(self.last_notification, now) {
+            log::debug!("notification dropped (too frequent): {:?}", notification);
+            return;
+        }
+        self.last_notification = Some(now);
+
+        let title = notification
+            .title
+            .or_else(|| self.title.clone())
+            .unwrap_or_else(|| "toyterm".to_owned());
+        let mut command =
+            match notification_command(&config.notification_command, &title, &notification.body) {
+                Some(command) => command,
+                None => {
+                    log::error!("notification_command is empty");
+                    return;
+                }
+            };
+        match command.spawn() {
+            Ok(mut child) => {
+                // reap the child in background
+                std::thread::spawn(move || child.wait());
+            }
+            Err(err) => {
+                log::error!("Failed to launch {:?}: {}", command.get_program(), err);
+            }
+        }
+
+        self.request_attention(true);
+    }
+
+    /// Sets or clears the urgency hint of the window
+    fn request_attention(&self, urgent: bool) {
+        use glutin::window::UserAttentionType;
+        let attention = urgent.then_some(UserAttentionType::Informational);
+        self.display
+            .gl_window()
+            .window()
+            .request_user_attention(attention);
+    }
+
+    fn beep(&mut self) {
+        let now = std::time::Instant::now();
+        if should_beep(self.last_beep, now) {
+            self.last_beep = Some(now);
+
+            #[cfg(feature = "audio")]
+            if play_sound(&crate::TOYTERM_CONFIG.load().bell_sound) {
+                return;
+            }
+
+            system_beep(self.display.gl_window().window());
+        }
+    }
+
     // Returns true if the PTY is closed, false otherwise
     fn check_update(&mut self) -> bool {
         let cell_size = self.view.cell_size();
 
+        let exited: bool;
         let contents_updated: bool;
         let mouse_track_mode_changed: bool;
+        let title_changed: bool;
+        let bell: bool;
+        let notifications: Vec<Notification>;
         let terminal_size: TerminalSize;
         {
             // hold the lock while copying states
             let mut state = self.terminal.state.lock().unwrap();
 
-            if state.exit_status.is_some() {
-                return true;
-            }
+            // The last output is still shown (a dead pane in the multiplexer keeps it)
+            exited = state.exit_status.is_some();
 
             mouse_track_mode_changed = self.mode.mouse_track != state.mode().mouse_track;
             self.mode = state.mode();
 
-            contents_updated = state.updated || self.last_history_head != self.history_head;
+            title_changed = self.title.as_deref() != state.title();
+            if title_changed {
+                self.title = state.title().map(str::to_owned);
+            }
+
+            bell = state.take_bell().is_some();
+            notifications = state.take_notifications();
+
+            // The matches are searched again as the contents change
+            if let Some(search) = self.search.as_mut() {
+                if state.updated && !self.scroll_lock {
+                    search_matches(search, &state);
+                }
+            }
+            let search_dirty = self.search.as_ref().map_or(false, |s| s.dirty);
+
+            // The history may have been cleared by the application (ED 3)
+            let hist_rows = state.history_size() as isize;
+            self.history_head = self.history_head.max(-hist_rows);
+
+            // While the scroll lock is active, the screen is frozen
+            contents_updated = !self.scroll_lock
+                && (state.updated || self.last_history_head != self.history_head || search_dirty);
             self.last_history_head = self.history_head;
 
             terminal_size = state.size();
 
             if contents_updated {
                 // update scroll bar
-                let scroll_bar_position = {
+                let auto_hide = crate::TOYTERM_CONFIG.load().scroll_bar_auto_hide;
+                let scroll_bar_position = if auto_hide && self.history_head >= 0 {
+                    None
+                } else {
                     let hist_rows = state.history_size();
                     let rows = state.size().rows;
                     let viewport_height = self.viewport().h;
@@ -223,22 +515,70 @@ impl TerminalWindow {
                     None
                 };
 
+                let search_matches = match self.search.as_mut() {
+                    Some(search) => {
+                        search.dirty = false;
+                        let top = (state.history_size() as isize + self.history_head) as usize;
+                        visible_matches(&search.matches, &lines, top)
+                    }
+                    None => Vec::new(),
+                };
+
+                let urls = detect_urls(&lines);
+
                 self.view.update_contents(|view| {
                     view.lines = lines;
                     view.images = images;
+                    view.search_matches = search_matches;
+                    view.urls = urls;
                     view.cursor = cursor;
                     view.scroll_bar = scroll_bar_position;
                     view.view_focused = self.focused;
+                    view.reverse_video = state.mode().reverse_video;
+                    view.cursor_color = state.cursor_color();
+                    view.palette = state.palette().clone();
                 });
             }
 
-            state.updated = false;
+            if !self.scroll_lock {
+                state.updated = false;
+            }
+        }
+
+        let hovered_url = hover_cell(self.mouse.cursor_pos, cell_size, terminal_size)
+            .and_then(|(row, col)| url_at(&self.view.urls, &self.view.lines, row, col));
+        if self.view.hovered_url != hovered_url {
+            self.view
+                .update_contents(|view| view.hovered_url = hovered_url);
+        }
+
+        if crate::TOYTERM_CONFIG.load().debug_hover {
+            let hover = hover_cell(self.mouse.cursor_pos, cell_size, terminal_size)
+                .map(|(row, col)| (row, line_col(&self.view.lines, row, col)));
+            if self.view.hover != hover {
+                self.view.update_contents(|view| view.hover = hover);
+            }
         }
 
         if mouse_track_mode_changed {
             self.refresh_cursor_icon();
         }
 
+        if title_changed && self.focused {
+            self.refresh_title();
+        }
+
+        if bell {
+            self.ring_bell();
+            if crate::TOYTERM_CONFIG.load().bell_urgent && !self.focused {
+                self.request_attention(true);
+            }
+        }
+
+        for notification in notifications {
+            self.notify(notification);
+        }
+
         // Update text selection
         if let Some(CursorPosition { x: sx, y: sy }) = self.mouse.pressed_pos {
             let CursorPosition { x: ex, y: ey } =
@@ -254,9 +594,13 @@ impl TerminalWindow {
             let ey = ey.clamp(0.0, y_max - 0.1);
 
             let mut s_row = (sy / cell_size.h as f64).floor() as usize;
-            let mut s_col = (sx / cell_size.w as f64).round() as usize;
             let mut e_row = (ey / cell_size.h as f64).floor() as usize;
-            let mut e_col = (ex / cell_size.w as f64).round() as usize;
+            let mut s_col = (sx / cell_width(lines, s_row, cell_size)).round() as usize;
+            let mut e_col = (ex / cell_width(lines, e_row, cell_size)).round() as usize;
+
+            // Alt + drag: rectangular selection
+            let block = (self.mouse.block_selection && self.mouse.click_count == 1)
+                .then(|| block_range((s_row, s_col), (e_row, e_col), terminal_size.cols));
 
             if (e_row, e_col) < (s_row, s_col) {
                 std::mem::swap(&mut s_row, &mut e_row);
@@ -277,30 +621,33 @@ impl TerminalWindow {
                     fn delimiter(ch: char) -> bool {
                         ch.is_ascii_punctuation() || ch.is_ascii_whitespace()
                     }
-                    fn on_different_word(a: char, b: char) -> bool {
-                        delimiter(a) || delimiter(b)
-                    }
+                    let cols = terminal_size.cols;
+                    (s_col, e_col) = expand_to_word(
+                        (&lines[s_row], s_col),
+                        (&lines[e_row], e_col),
+                        cols,
+                        delimiter,
+                    );
+                }
 
-                    while 0 < s_col && s_col < terminal_size.cols {
-                        let prev = lines[s_row].get(s_col - 1).unwrap().ch;
-                        let curr = lines[s_row].get(s_col).unwrap().ch;
-                        if on_different_word(prev, curr) {
-                            break;
-                        }
-                        s_col -= 1;
-                    }
-                    while e_col < terminal_size.cols - 1 {
-                        let prev = lines[e_row].get(e_col).unwrap().ch;
-                        let curr = lines[e_row].get(e_col + 1).unwrap().ch;
-                        if on_different_word(prev, curr) {
-                            break;
-                        }
-                        e_col += 1;
+                // triple click: WORD (whitespace-delimited) selection
+                3 => {
+                    fn delimiter(ch: char) -> bool {
+                        ch.is_ascii_whitespace()
                     }
+                    let cols = terminal_size.cols;
+                    (s_col, e_col) = expand_to_word(
+                        (&lines[s_row], s_col),
+                        (&lines[e_row], e_col),
+                        cols,
+                        delimiter,
+                    );
                 }
 
-                // triple click (or more): line selection
+                // quadruple click (or more): logical line selection
                 _ => {
+                    let wraps: Vec<bool> = lines.iter().map(Line::linewrap).collect();
+                    (s_row, e_row) = logical_line_rows(&wraps, s_row, e_row);
                     s_col = 0;
                     e_col = terminal_size.cols - 1;
                 }
@@ -308,20 +655,29 @@ impl TerminalWindow {
 
             let l = s_row * terminal_size.cols + s_col;
             let r = e_row * terminal_size.cols + e_col;
-            let new_selection_range = if l <= r { Some((l, r)) } else { None };
+            let (kind, new_selection_range) = match block {
+                Some(range) => (SelectionKind::Block, range),
+                None => (SelectionKind::Linear, (l <= r).then_some((l, r))),
+            };
 
-            if self.view.selection_range != new_selection_range {
+            if self.view.selection_range != new_selection_range || self.view.selection_kind != kind
+            {
                 self.view.update_contents(|view| {
                     view.selection_range = new_selection_range;
+                    view.selection_kind = kind;
                 });
             }
+
+            if std::mem::take(&mut self.mouse.selection_finished) && new_selection_range.is_some() {
+                self.copy_primary();
+            }
         } else if self.view.selection_range.is_some() {
             self.view.update_contents(|view| {
                 view.selection_range = None;
             });
         }
 
-        false
+        exited
     }
 
     pub fn draw(&mut self, surface: &mut glium::Frame) {
@@ -343,22 +699,42 @@ impl TerminalWindow {
         self.resize_buffer();
     }
 
+    /// Applies the changes of the reloaded configuration which can be applied live
+    fn apply_config(&mut self) {
+        let generation = crate::TOYTERM_CONFIG.generation();
+        if generation == self.config_generation {
+            return;
+        }
+        self.config_generation = generation;
+
+        let config = crate::TOYTERM_CONFIG.load();
+        let old = std::mem::replace(&mut self.config, config.clone());
+
+        self.keybindings = config.keybindings.table();
+
+        let mut state = self.terminal.state.lock().unwrap();
+        state.set_history_capacity(config.scrollback_lines);
+        drop(state);
+
+        // The size changed by the keybindings is kept relative to the configured one
+        let size_diff = config.font_size as i32 - old.font_size as i32;
+        if size_diff != 0 || config.letter_spacing != old.letter_spacing {
+            self.view.increase_font_size(size_diff);
+        }
+        self.resize_buffer();
+
+        // Colors are looked up again on rebuilding the contents
+        self.view.update_contents(|_| {});
+    }
+
     fn resize_buffer(&mut self) {
         self.mouse.pressed_pos = None;
         self.mouse.released_pos = None;
 
         let viewport = self.view.viewport();
-
-        let scroll_bar_width = crate::TOYTERM_CONFIG.scroll_bar_width;
-        let width = viewport.w.saturating_sub(scroll_bar_width);
-
         let cell_size = self.view.cell_size();
-        let rows = (viewport.h / cell_size.h) as usize;
-        let cols = (width / cell_size.w) as usize;
-        let buff_size = TerminalSize {
-            rows: rows.max(1),
-            cols: cols.max(1),
-        };
+        let scroll_bar_width = reserved_scroll_bar_width(&crate::TOYTERM_CONFIG.load());
+        let buff_size = terminal_size(viewport, cell_size, scroll_bar_width);
         self.terminal.request_resize(buff_size, cell_size);
     }
 
@@ -372,10 +748,22 @@ impl TerminalWindow {
 
         if gain {
             self.refresh_cursor_icon();
+            self.refresh_title();
+            self.request_attention(false);
+        }
+
+        // Reports are suppressed while the history is scrolled back
+        if self.mode.focus_event && self.history_head >= 0 {
+            let report: &[u8] = if gain { b"\x1b[I" } else { b"\x1b[O" };
+            self.terminal.pty_write(report);
         }
     }
 
     pub fn on_event(&mut self, event: &Event, control_flow: &mut ControlFlow) {
+        if is_user_input(event) {
+            crate::view::notify_user_input();
+        }
+
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => {
@@ -396,16 +784,30 @@ impl TerminalWindow {
                 }
 
                 &WindowEvent::ReceivedCharacter(ch) => {
-                    // Handle these characters on WindowEvent::KeyboardInput event
-                    if ch == '-'
-                        || ch == '='
-                        || ch == '\x7F'
-                        || ch == '\x03'
-                        || ch == '\x08'
-                        || ch == '\x0C'
-                        || ch == '\x16'
-                        || ch == '\x1B'
-                    {
+                    if self.search_input(ch) {
+                        return;
+                    }
+
+                    if handled_on_key_press(ch, self.modifiers) {
+                        return;
+                    }
+
+                    // The key has been handled as an action (e.g. Ctrl+Shift+M)
+                    if std::mem::take(&mut self.bound_key_pressed) {
+                        return;
+                    }
+
+                    let passthrough = crate::TOYTERM_CONFIG.load().flow_control_passthrough;
+                    if let Some(lock) = local_flow_control(ch, passthrough) {
+                        log::debug!("scroll lock: {}", lock);
+                        self.scroll_lock = lock;
+                        return;
+                    }
+
+                    let level = self.mode.modify_other_keys;
+                    if let Some(seq) = modify_other_keys(level, self.modifiers, ch) {
+                        log::debug!("input: {:?}", seq);
+                        self.terminal.pty_write(seq.as_bytes());
                         return;
                     }
 
@@ -431,6 +833,24 @@ impl TerminalWindow {
                     let x = position.x - viewport.x as f64;
                     let y = position.y - viewport.y as f64;
                     self.mouse.cursor_pos = CursorPosition { x, y };
+
+                    let is_inner =
+                        0.0 <= x && x < viewport.w as f64 && 0.0 <= y && y < viewport.h as f64;
+                    if self.mode.mouse_track && is_inner {
+                        // Motion is reported only when the pointer moves to another cell
+                        let (col, row) = self.mouse_cell();
+                        if self.mouse.reported_cell != Some((col, row)) {
+                            self.mouse.reported_cell = Some((col, row));
+
+                            let held = self.mouse.held_button;
+                            let (drag, any) =
+                                (self.mode.mouse_track_drag, self.mode.mouse_track_any);
+                            if let Some(button) = motion_button(held, drag, any) {
+                                let button = button + self.mouse_modifiers();
+                                self.mouse_report(button, col, row, &ElementState::Pressed);
+                            }
+                        }
+                    }
                 }
 
                 WindowEvent::MouseInput { state, button, .. } => {
@@ -447,36 +867,44 @@ impl TerminalWindow {
                         return;
                     }
 
+                    // Ctrl+Click opens a hyperlink
+                    if self.modifiers.ctrl()
+                        && *button == MouseButton::Left
+                        && *state == ElementState::Pressed
+                        && self.open_hyperlink()
+                    {
+                        return;
+                    }
+
                     if self.mode.mouse_track {
+                        let pressed = match button {
+                            MouseButton::Left => 0,
+                            MouseButton::Middle => 1,
+                            MouseButton::Right => 2,
+                            MouseButton::Other(button_id) => {
+                                // FIXME : Support multi button mouse?
+                                log::warn!("unknown mouse button : {}", button_id);
+                                0
+                            }
+                        };
+
+                        self.mouse.held_button = match state {
+                            ElementState::Pressed => Some(pressed),
+                            ElementState::Released => None,
+                        };
+
                         let button = match state {
                             ElementState::Released if !self.mode.sgr_ext_mouse_track => 3,
-                            _ => match button {
-                                MouseButton::Left => 0,
-                                MouseButton::Middle => 1,
-                                MouseButton::Right => 2,
-                                MouseButton::Other(button_id) => {
-                                    // FIXME : Support multi button mouse?
-                                    log::warn!("unknown mouse button : {}", button_id);
-                                    0
-                                }
-                            },
+                            _ => pressed,
                         };
 
-                        #[rustfmt::skip]
-                        let mods =
-                            if self.modifiers.shift() { 0b00000100 } else { 0 }
-                        |   if self.modifiers.alt()   { 0b00001000 } else { 0 }
-                        |   if self.modifiers.ctrl()  { 0b00010000 } else { 0 };
-
-                        let CursorPosition { x, y } = self.mouse.cursor_pos;
-                        let cell_size = self.view.cell_size();
-                        let col = x.round() as u32 / cell_size.w + 1;
-                        let row = y.round() as u32 / cell_size.h + 1;
+                        let mods = self.mouse_modifiers();
+                        let (col, row) = self.mouse_cell();
 
-                        if self.mode.sgr_ext_mouse_track {
-                            self.sgr_ext_mouse_report(button + mods, col, row, state);
-                        } else {
-                            self.normal_mouse_report(button + mods, col, row);
+                        self.mouse_report(button + mods, col, row, state);
+                    } else if *button == MouseButton::Middle {
+                        if *state == ElementState::Pressed {
+                            self.paste_primary();
                         }
                     } else {
                         match state {
@@ -493,9 +921,12 @@ impl TerminalWindow {
 
                                 self.mouse.pressed_pos = Some(self.mouse.cursor_pos);
                                 self.mouse.released_pos = None;
+                                self.mouse.selection_finished = false;
+                                self.mouse.block_selection = self.modifiers.alt();
                             }
                             ElementState::Released => {
                                 self.mouse.released_pos = Some(self.mouse.cursor_pos);
+                                self.mouse.selection_finished = true;
                             }
                         }
                     }
@@ -549,6 +980,9 @@ impl TerminalWindow {
             },
 
             Event::MainEventsCleared => {
+                crate::TOYTERM_CONFIG.reload_if_modified();
+                self.apply_config();
+
                 if self.check_update() {
                     *control_flow = ControlFlow::Exit;
                     return;
@@ -566,171 +1000,171 @@ impl TerminalWindow {
         }
     }
 
+    /// Processes the input as if it's typed on this window
+    pub fn inject_input(&mut self, input: &InjectedInput) {
+        log::debug!("injected: {:?}", input);
+        if input.write_to(&mut self.terminal) {
+            return;
+        }
+        if let &InjectedInput::Key(modifiers, keycode) = input {
+            let saved = std::mem::replace(&mut self.modifiers, modifiers);
+            self.on_key_press(keycode);
+            self.modifiers = saved;
+        }
+    }
+
     fn on_key_press(&mut self, keycode: VirtualKeyCode) {
-        use ModifiersState as Mod;
-        const EMPTY: u32 = Mod::empty().bits();
-        const CTRL: u32 = Mod::CTRL.bits();
-        const CTRL_SHIFT: u32 = Mod::CTRL.bits() | Mod::SHIFT.bits();
+        self.bound_key_pressed = false;
+
+        if self.search_key_press(keycode) {
+            return;
+        }
 
         // normally text selection is cleared when user types something,
         // but there are some exceptions. history_head is cleared too.
         let mut clear = true;
 
-        match (self.modifiers.bits(), keycode) {
-            (EMPTY, VirtualKeyCode::Escape) => {
-                self.history_head = 0;
-                self.mouse.pressed_pos = None;
-                self.mouse.released_pos = None;
-                self.terminal.pty_write(b"\x1B");
-            }
-
-            (CTRL, VirtualKeyCode::Minus) => {
-                // font size -
-                self.increase_font_size(-1);
-            }
-            (CTRL, VirtualKeyCode::Equals) => {
-                // font size +
-                self.increase_font_size(1);
+        let action = self.keybindings.get(&Key::Code(self.modifiers, keycode));
+        match action
+            .copied()
+            .and_then(|action| self.perform_action(action))
+        {
+            Some(keep_selection) => {
+                self.bound_key_pressed = true;
+                clear = !keep_selection;
             }
 
-            // Backspace
-            (EMPTY, VirtualKeyCode::Back) => {
-                // Note: send DEL instead of BS
-                self.terminal.pty_write(b"\x7f");
-            }
+            None => {
+                let level = self.mode.modify_other_keys;
+                if let Some(seq) = modify_other_keys_for_key(level, self.modifiers, keycode) {
+                    log::debug!("input: {:?}", seq);
+                    self.terminal.pty_write(seq.as_bytes());
+                    // the character of the key is not sent
+                    self.bound_key_pressed = true;
+                } else if let Some(seq) = key_sequence(self.modifiers, keycode) {
+                    self.terminal.pty_write(seq);
+                } else {
+                    log::trace!("key pressed: ({:?}) {:?}", self.modifiers, keycode);
 
-            (EMPTY, VirtualKeyCode::Delete) => {
-                self.terminal.pty_write(b"\x1b[3~");
+                    use VirtualKeyCode::*;
+                    if let LControl | RControl | LShift | RShift = keycode {
+                        clear = false;
+                    }
+                }
             }
+        }
 
-            (EMPTY, VirtualKeyCode::Up) => {
-                self.terminal.pty_write(b"\x1b[\x41");
-            }
-            (EMPTY, VirtualKeyCode::Down) => {
-                self.terminal.pty_write(b"\x1b[\x42");
-            }
-            (EMPTY, VirtualKeyCode::Right) => {
-                self.terminal.pty_write(b"\x1b[\x43");
-            }
-            (EMPTY, VirtualKeyCode::Left) => {
-                self.terminal.pty_write(b"\x1b[\x44");
-            }
+        if clear {
+            self.view.update_contents(|view| {
+                view.selection_range = None;
+            });
 
-            (EMPTY, VirtualKeyCode::PageUp) => {
-                self.terminal.pty_write(b"\x1b[5~");
-            }
-            (EMPTY, VirtualKeyCode::PageDown) => {
-                self.terminal.pty_write(b"\x1b[6~");
-            }
+            self.history_head = 0;
+            self.mouse.pressed_pos = None;
+            self.mouse.released_pos = None;
+        }
+    }
 
-            (EMPTY, VirtualKeyCode::Minus) => {
-                self.terminal.pty_write(b"-");
-            }
-            (EMPTY, VirtualKeyCode::Equals) => {
-                self.terminal.pty_write(b"=");
+    /// Performs the action bound to a key, and returns whether the selection is kept.
+    /// Returns None for the actions of the multiplexer.
+    fn perform_action(&mut self, action: Action) -> Option<bool> {
+        let keep_selection = match action {
+            Action::FontDec => {
+                self.increase_font_size(-1);
+                false
             }
-
-            (CTRL, VirtualKeyCode::C) => {
-                self.terminal.pty_write(b"\x03");
+            Action::FontInc => {
+                self.increase_font_size(1);
+                false
             }
 
-            (CTRL_SHIFT, VirtualKeyCode::C) => {
-                clear = false;
+            Action::Copy => {
                 self.copy_clipboard();
+                true
             }
-
-            (CTRL, VirtualKeyCode::V) => {
-                self.terminal.pty_write(b"\x16");
+            Action::Paste => {
+                self.paste_clipboard();
+                false
             }
 
-            (CTRL_SHIFT, VirtualKeyCode::V) => {
-                self.paste_clipboard();
+            Action::MatchCopy => {
+                self.match_and_copy(false);
+                true
+            }
+            Action::MatchCopyAll => {
+                self.match_and_copy(true);
+                true
             }
 
-            (CTRL, VirtualKeyCode::L) => {
-                self.terminal.pty_write(b"\x0c");
+            Action::Search => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    editing: true,
+                    matches: Vec::new(),
+                    current: None,
+                    dirty: true,
+                });
+                self.refresh_search_title();
+                true
             }
 
-            (CTRL_SHIFT, VirtualKeyCode::L) => {
+            Action::ClearHistory => {
                 self.history_head = 0;
                 let mut state = self.terminal.state.lock().unwrap();
-                state.clear_history();
-            }
-
-            (EMPTY, VirtualKeyCode::F1) => {
-                self.terminal.pty_write(b"\x1BOP");
-            }
-            (EMPTY, VirtualKeyCode::F2) => {
-                self.terminal.pty_write(b"\x1BOQ");
-            }
-            (EMPTY, VirtualKeyCode::F3) => {
-                self.terminal.pty_write(b"\x1BOR");
-            }
-            (EMPTY, VirtualKeyCode::F4) => {
-                self.terminal.pty_write(b"\x1BOS");
-            }
-            (EMPTY, VirtualKeyCode::F5) => {
-                self.terminal.pty_write(b"\x1B[15~");
-            }
-            (EMPTY, VirtualKeyCode::F6) => {
-                self.terminal.pty_write(b"\x1B[17~");
-            }
-            (EMPTY, VirtualKeyCode::F7) => {
-                self.terminal.pty_write(b"\x1B[18~");
-            }
-            (EMPTY, VirtualKeyCode::F8) => {
-                self.terminal.pty_write(b"\x1B[19~");
-            }
-            (EMPTY, VirtualKeyCode::F9) => {
-                self.terminal.pty_write(b"\x1B[20~");
-            }
-            (EMPTY, VirtualKeyCode::F10) => {
-                self.terminal.pty_write(b"\x1B[21~");
-            }
-            (EMPTY, VirtualKeyCode::F11) => {
-                self.terminal.pty_write(b"\x1B[23~");
-            }
-            (EMPTY, VirtualKeyCode::F12) => {
-                self.terminal.pty_write(b"\x1B[24~");
-            }
-            (EMPTY, VirtualKeyCode::F13) => {
-                self.terminal.pty_write(b"\x1B[1;2P");
-            }
-            (EMPTY, VirtualKeyCode::F14) => {
-                self.terminal.pty_write(b"\x1B[1;2Q");
-            }
-            (EMPTY, VirtualKeyCode::F15) => {
-                self.terminal.pty_write(b"\x1B[1;2R");
-            }
-            (EMPTY, VirtualKeyCode::F16) => {
-                self.terminal.pty_write(b"\x1B[1;2S");
+                if crate::TOYTERM_CONFIG.load().clear_history_with_screen {
+                    state.clear_all();
+                } else {
+                    state.clear_history();
+                }
+                false
             }
 
-            (_, keycode) => {
-                log::trace!("key pressed: ({:?}) {:?}", self.modifiers, keycode);
-
-                use VirtualKeyCode::*;
-                if let LControl | RControl | LShift | RShift = keycode {
-                    clear = false;
+            Action::PrevPrompt | Action::NextPrompt => {
+                let state = self.terminal.state.lock().unwrap();
+                let hist = state.history_size() as isize;
+                let forward = action == Action::NextPrompt;
+                if let Some(row) =
+                    prompt_jump(&state.prompt_rows(), hist + self.history_head, forward)
+                {
+                    self.history_head = (row as isize - hist).clamp(-hist, 0);
+                } else if forward {
+                    self.history_head = 0;
                 }
+                true
             }
-        }
 
-        if clear {
-            self.view.update_contents(|view| {
-                view.selection_range = None;
-            });
+            Action::ToggleFullscreen => {
+                toggle_fullscreen(self.display.gl_window().window());
+                false
+            }
 
-            self.history_head = 0;
-            self.mouse.pressed_pos = None;
-            self.mouse.released_pos = None;
-        }
+            _ => return None,
+        };
+        Some(keep_selection)
     }
 
     fn copy_clipboard(&mut self) {
-        let mut text = String::new();
+        let text = self.selected_text();
+        log::info!("copy: {:?}", text);
+        let _ = self.clipboard.set_text(text);
+    }
 
-        let selection_range = self.view.selection_range;
+    // Copies the selected text to the PRIMARY selection (no-op if it's not supported)
+    fn copy_primary(&mut self) {
+        let text = self.selected_text();
+        log::debug!("copy to PRIMARY: {:?}", text);
+        if let Err(err) = set_primary_text(&mut self.clipboard, text) {
+            log::debug!("Failed to set PRIMARY: {}", err);
+        }
+    }
+
+    fn selected_text(&self) -> String {
+        let selection_range = self.view.selection_range;
+        if let (SelectionKind::Block, Some(range)) = (self.view.selection_kind, selection_range) {
+            return block_text(&self.view.lines, range);
+        }
+
+        let mut text = String::new();
 
         'row: for (i, row) in self.view.lines.iter().enumerate() {
             let cols = row.columns();
@@ -771,6 +1205,216 @@ impl TerminalWindow {
                 }
             }
         }
+        text
+    }
+
+    // Returns true if a hyperlink or a URL is found under the mouse cursor
+    fn open_hyperlink(&mut self) -> bool {
+        let (row, col) = cell_at(self.mouse.cursor_pos, self.view.cell_size());
+        let col = line_col(&self.view.lines, row, col);
+
+        let link = match self.view.lines.get(row).and_then(|line| line.get(col)) {
+            Some(cell) => cell.attr.link,
+            None => None,
+        };
+        let uri = match link {
+            Some(id) => {
+                let state = self.terminal.state.lock().unwrap();
+                state.link(id).map(str::to_owned)
+            }
+            None => None,
+        };
+
+        // URLs detected automatically
+        let uri = uri.or_else(|| {
+            let (row, col) = cell_at(self.mouse.cursor_pos, self.view.cell_size());
+            let k = url_at(&self.view.urls, &self.view.lines, row, col)?;
+            Some(self.view.urls[k].url.clone())
+        });
+
+        let uri = match uri {
+            Some(uri) => uri,
+            None => return false,
+        };
+
+        log::info!("open: {:?}", uri);
+        let mut command = match opener_command(&crate::TOYTERM_CONFIG.load().url_opener, &uri) {
+            Some(command) => command,
+            None => {
+                log::error!("url_opener is empty");
+                return true;
+            }
+        };
+        match command.spawn() {
+            Ok(mut child) => {
+                // reap the child in background
+                std::thread::spawn(move || child.wait());
+            }
+            Err(err) => {
+                log::error!("Failed to launch {:?}: {}", command.get_program(), err);
+            }
+        }
+        true
+    }
+
+    /// Handles a key press during the search. Returns true if the key is consumed.
+    fn search_key_press(&mut self, keycode: VirtualKeyCode) -> bool {
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return false,
+        };
+
+        match keycode {
+            VirtualKeyCode::Escape => {
+                self.close_search();
+                true
+            }
+
+            // characters are handled on WindowEvent::ReceivedCharacter
+            _ if !search.editing => {
+                use VirtualKeyCode::*;
+                if let N | Return | LShift | RShift | LControl | RControl | LAlt | RAlt = keycode {
+                    return true;
+                }
+                self.close_search();
+                false
+            }
+
+            VirtualKeyCode::Back => {
+                search.query.pop();
+                self.update_search();
+                true
+            }
+
+            // The other keys are not sent to the PTY while typing the query
+            _ => true,
+        }
+    }
+
+    /// Handles a character typed during the search. Returns true if it's consumed.
+    fn search_input(&mut self, ch: char) -> bool {
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return false,
+        };
+
+        if search.editing {
+            if ch == '\r' {
+                search.editing = false;
+                self.refresh_search_title();
+            } else if !ch.is_control() {
+                search.query.push(ch);
+                self.update_search();
+            }
+            return true;
+        }
+
+        // n: older match, N: newer match
+        let step = match ch {
+            'n' => -1,
+            'N' => 1,
+            _ => return false,
+        };
+        if let Some(current) = search.current {
+            let len = search.matches.len() as isize;
+            search.current = Some((current as isize + step).rem_euclid(len) as usize);
+            self.jump_to_search_match();
+        }
+        true
+    }
+
+    /// Searches the query again and jumps to the newest match
+    fn update_search(&mut self) {
+        if let Some(search) = self.search.as_mut() {
+            let state = self.terminal.state.lock().unwrap();
+            search_matches(search, &state);
+            search.current = search.matches.len().checked_sub(1);
+        }
+        self.jump_to_search_match();
+    }
+
+    /// Scrolls the history so that the current match is visible
+    fn jump_to_search_match(&mut self) {
+        if let Some(search) = self.search.as_mut() {
+            if let Some(m) = search.current.map(|i| &search.matches[i]) {
+                let state = self.terminal.state.lock().unwrap();
+                let hist = state.history_size() as isize;
+                let rows = state.size().rows as isize;
+
+                let row = m.first.0 as isize - hist;
+                if row < self.history_head || self.history_head + rows <= row {
+                    self.history_head = (row - rows / 2).clamp(-hist, 0);
+                }
+            }
+            search.dirty = true;
+        }
+        self.refresh_search_title();
+    }
+
+    fn close_search(&mut self) {
+        self.search = None;
+        self.view
+            .update_contents(|view| view.search_matches.clear());
+        if self.title.is_none() {
+            self.display.gl_window().window().set_title("toyterm");
+        }
+        self.refresh_title();
+    }
+
+    // Shows the query and the number of matches in the title while searching
+    fn refresh_search_title(&mut self) {
+        if let Some(search) = self.search.as_ref() {
+            let position = search.current.map_or(0, |i| i + 1);
+            let cursor = if search.editing { "_" } else { "" };
+            let title = format!(
+                "Search: {}{} [{}/{}]",
+                search.query,
+                cursor,
+                position,
+                search.matches.len()
+            );
+            self.display.gl_window().window().set_title(&title);
+        }
+    }
+
+    // Copies matches of `match_copy_pattern` in the screen to the clipboard
+    fn match_and_copy(&mut self, all: bool) {
+        use crate::search::{find_matches, Dump};
+
+        let pattern = &crate::TOYTERM_CONFIG.load().match_copy_pattern;
+        let re = match regex::Regex::new(pattern) {
+            Ok(re) => re,
+            Err(err) => {
+                log::error!("invalid match_copy_pattern: {}", err);
+                return;
+            }
+        };
+
+        let dump = Dump::new(&self.view.lines);
+        let matches = find_matches(&dump, &re);
+        let first = match matches.first() {
+            Some(m) => m,
+            None => {
+                log::info!("no match: {:?}", pattern);
+                return;
+            }
+        };
+
+        // Select the first match to show what is copied
+        let cols = self.terminal.state.lock().unwrap().size().cols;
+        let left = first.first.0 * cols + first.first.1;
+        let right = first.last.0 * cols + first.last.1;
+        self.view.update_contents(|view| {
+            view.selection_range = Some((left, right));
+            view.selection_kind = SelectionKind::Linear;
+        });
+
+        let text = if all {
+            let texts: Vec<&str> = matches.iter().map(|m| m.text.as_str()).collect();
+            texts.join("\n")
+        } else {
+            first.text.clone()
+        };
 
         log::info!("copy: {:?}", text);
         let _ = self.clipboard.set_text(text);
@@ -778,22 +1422,64 @@ impl TerminalWindow {
 
     fn paste_clipboard(&mut self) {
         match self.clipboard.get_text() {
-            Ok(text) => {
-                log::debug!("paste: {:?}", text);
-                if self.mode.bracketed_paste {
-                    self.terminal.pty_write(b"\x1b[200~");
-                    self.terminal.pty_write(text.as_bytes());
-                    self.terminal.pty_write(b"\x1b[201~");
-                } else {
-                    self.terminal.pty_write(text.as_bytes());
-                }
-            }
+            Ok(text) => self.paste(&text),
             Err(_) => {
                 log::error!("Failed to paste something from clipboard");
             }
         }
     }
 
+    // Pastes the PRIMARY selection (no-op if it's not supported)
+    fn paste_primary(&mut self) {
+        match get_primary_text(&mut self.clipboard) {
+            Ok(text) => self.paste(&text),
+            Err(err) => {
+                log::debug!("Failed to get PRIMARY: {}", err);
+            }
+        }
+    }
+
+    fn paste(&mut self, text: &str) {
+        log::debug!("paste: {:?}", text);
+        if self.mode.bracketed_paste {
+            self.terminal.pty_write(b"\x1b[200~");
+            self.terminal.pty_write(text.as_bytes());
+            self.terminal.pty_write(b"\x1b[201~");
+        } else {
+            self.terminal.pty_write(text.as_bytes());
+        }
+    }
+
+    fn mouse_modifiers(&self) -> u8 {
+        #[rustfmt::skip]
+        let mods =
+            if self.modifiers.shift() { 0b00000100 } else { 0 }
+        |   if self.modifiers.alt()   { 0b00001000 } else { 0 }
+        |   if self.modifiers.ctrl()  { 0b00010000 } else { 0 };
+        mods
+    }
+
+    /// Returns the 1-origin (column, row) under the mouse cursor
+    fn mouse_cell(&self) -> (u32, u32) {
+        let CursorPosition { x, y } = self.mouse.cursor_pos;
+        let cell_size = self.view.cell_size();
+        let col = x.round() as u32 / cell_size.w + 1;
+        let row = y.round() as u32 / cell_size.h + 1;
+        (col, row)
+    }
+
+    /// Sends a mouse report in the encoding selected by the mode
+    fn mouse_report(&mut self, button: u8, col: u32, row: u32, state: &ElementState) {
+        if self.mode.sgr_ext_mouse_track {
+            self.sgr_ext_mouse_report(button, col, row, state);
+        } else if self.mode.urxvt_ext_mouse_track {
+            let msg = urxvt_ext_mouse_report(button, col, row);
+            self.terminal.pty_write(msg.as_bytes());
+        } else {
+            self.normal_mouse_report(button, col, row);
+        }
+    }
+
     fn normal_mouse_report(&mut self, button: u8, col: u32, row: u32) {
         let col = if 0 < col && col < 224 { col + 32 } else { 0 } as u8;
         let row = if 0 < row && row < 224 { row + 32 } else { 0 } as u8;
@@ -814,6 +1500,698 @@ impl TerminalWindow {
     }
 }
 
+/// Returns the new state of the scroll lock if the character is consumed locally,
+/// or `None` if it should be sent to the PTY.
+fn local_flow_control(ch: char, passthrough: bool) -> Option<bool> {
+    if passthrough {
+        return None;
+    }
+
+    match ch {
+        '\x13' => Some(true),  // XOFF (Ctrl+S)
+        '\x11' => Some(false), // XON (Ctrl+Q)
+        _ => None,
+    }
+}
+
+/// Unlike the normal report, the coordinates are not limited to 223
+fn urxvt_ext_mouse_report(button: u8, col: u32, row: u32) -> String {
+    let button = 32 + button as u32;
+    format!("\x1b[{button};{col};{row}M")
+}
+
+/// Returns the button code of a motion report, or `None` if the motion shouldn't be reported.
+/// `held` is the button being pressed.
+fn motion_button(held: Option<u8>, drag: bool, any: bool) -> Option<u8> {
+    const MOTION: u8 = 32;
+    const NO_BUTTON: u8 = 3;
+
+    match held {
+        Some(button) if drag || any => Some(MOTION + button),
+        None if any => Some(MOTION + NO_BUTTON),
+        _ => None,
+    }
+}
+
+/// Returns the row of the prompt to be shown at the top of the view,
+/// which is the previous or next one from the current top row
+fn prompt_jump(prompt_rows: &[usize], top: isize, forward: bool) -> Option<usize> {
+    if forward {
+        prompt_rows.iter().copied().find(|&row| row as isize > top)
+    } else {
+        prompt_rows
+            .iter()
+            .copied()
+            .rev()
+            .find(|&row| (row as isize) < top)
+    }
+}
+
+/// Returns true if the character is sent on WindowEvent::KeyboardInput event instead.
+/// Keys which are not bound to any action (e.g. Ctrl+Shift+Enter) are sent as characters.
+fn handled_on_key_press(ch: char, modifiers: ModifiersState) -> bool {
+    match ch {
+        '-' | '=' | '\x7F' | '\x03' | '\x08' | '\x0C' | '\x16' | '\x1B' => true,
+
+        // Shift+Tab is handled as back-tab, but Tab with other modifiers
+        // (e.g. Ctrl+Shift+Tab) is sent as it is
+        '\t' => key_sequence(modifiers, VirtualKeyCode::Tab).is_some(),
+
+        _ => false,
+    }
+}
+
+/// Returns the sequence to be sent for the key which doesn't have a character
+fn key_sequence(modifiers: ModifiersState, keycode: VirtualKeyCode) -> Option<&'static [u8]> {
+    const EMPTY: u32 = ModifiersState::empty().bits();
+    const CTRL: u32 = ModifiersState::CTRL.bits();
+    const SHIFT: u32 = ModifiersState::SHIFT.bits();
+
+    use VirtualKeyCode::*;
+    match (modifiers.bits(), keycode) {
+        (EMPTY, Escape) => Some(b"\x1B"),
+
+        // Note: send DEL instead of BS
+        (EMPTY, Back) => Some(b"\x7f"),
+        (EMPTY, Delete) => Some(b"\x1b[3~"),
+
+        (EMPTY, Up) => Some(b"\x1b[\x41"),
+        (EMPTY, Down) => Some(b"\x1b[\x42"),
+        (EMPTY, Right) => Some(b"\x1b[\x43"),
+        (EMPTY, Left) => Some(b"\x1b[\x44"),
+
+        (EMPTY, PageUp) => Some(b"\x1b[5~"),
+        (EMPTY, PageDown) => Some(b"\x1b[6~"),
+
+        (EMPTY, Minus) => Some(b"-"),
+        (EMPTY, Equals) => Some(b"="),
+
+        (CTRL, C) => Some(b"\x03"),
+        (CTRL, V) => Some(b"\x16"),
+        (CTRL, L) => Some(b"\x0c"),
+
+        (EMPTY, F1) => Some(b"\x1BOP"),
+        (EMPTY, F2) => Some(b"\x1BOQ"),
+        (EMPTY, F3) => Some(b"\x1BOR"),
+        (EMPTY, F4) => Some(b"\x1BOS"),
+        (EMPTY, F5) => Some(b"\x1B[15~"),
+        (EMPTY, F6) => Some(b"\x1B[17~"),
+        (EMPTY, F7) => Some(b"\x1B[18~"),
+        (EMPTY, F8) => Some(b"\x1B[19~"),
+        (EMPTY, F9) => Some(b"\x1B[20~"),
+        (EMPTY, F10) => Some(b"\x1B[21~"),
+        // F11 toggles fullscreen
+        (EMPTY, F12) => Some(b"\x1B[24~"),
+        (EMPTY, F13) => Some(b"\x1B[1;2P"),
+        (EMPTY, F14) => Some(b"\x1B[1;2Q"),
+        (EMPTY, F15) => Some(b"\x1B[1;2R"),
+        (EMPTY, F16) => Some(b"\x1B[1;2S"),
+
+        // Back-tab (CBT)
+        (SHIFT, Tab) => Some(b"\x1b[Z"),
+        _ => None,
+    }
+}
+
+/// Encodes a modified key as `CSI 27 ; modifiers ; code ~` if required by
+/// the modifyOtherKeys level
+fn modify_other_keys(level: u16, modifiers: ModifiersState, ch: char) -> Option<String> {
+    if level == 0 || !(modifiers.ctrl() || modifiers.alt()) {
+        return None;
+    }
+
+    // Recover the key from the control character given by Ctrl
+    let (code, transformed) = match ch {
+        '\x00' => (' ', true),
+        '\x01'..='\x1A' => ((ch as u8 + 0x60) as char, true),
+        _ if ch.is_control() => return None,
+        _ => (ch, false),
+    };
+
+    // Level 1 leaves keys which have well-known encodings as they are
+    if level == 1 && (transformed || !modifiers.ctrl()) {
+        return None;
+    }
+
+    Some(modified_key_sequence(modifiers, code as u32))
+}
+
+/// Same as `modify_other_keys` for the keys which produce control characters, which can't be
+/// told from the character (e.g. Ctrl+Enter gives CR as Ctrl+M does)
+fn modify_other_keys_for_key(
+    level: u16,
+    modifiers: ModifiersState,
+    keycode: VirtualKeyCode,
+) -> Option<String> {
+    use VirtualKeyCode::*;
+    let code = match keycode {
+        Return => 0x0D,
+        Tab => 0x09,
+        Back => 0x08,
+        Escape => 0x1B,
+        _ => return None,
+    };
+
+    // These keys have well-known encodings, which are left as they are by level 1
+    if level < 2 || !(modifiers.ctrl() || modifiers.alt()) {
+        return None;
+    }
+
+    Some(modified_key_sequence(modifiers, code))
+}
+
+fn modified_key_sequence(modifiers: ModifiersState, code: u32) -> String {
+    #[rustfmt::skip]
+    let mods = 1
+        + if modifiers.shift() { 1 } else { 0 }
+        + if modifiers.alt()   { 2 } else { 0 }
+        + if modifiers.ctrl()  { 4 } else { 0 };
+
+    format!("\x1b[27;{};{}~", mods, code)
+}
+
+/// Returns (row, col) of the cell at the position relative to the viewport
+fn cell_at(pos: CursorPosition, cell_size: CellSize) -> (usize, usize) {
+    let row = (pos.y.max(0.0) / cell_size.h as f64).floor() as usize;
+    let col = (pos.x.max(0.0) / cell_size.w as f64).floor() as usize;
+    (row, col)
+}
+
+/// Expands the selection [s, e] to the boundaries of words.
+/// Characters for which `delimiter` returns true are words of their own.
+fn expand_to_word(
+    (s_line, mut s_col): (&Line, usize),
+    (e_line, mut e_col): (&Line, usize),
+    cols: usize,
+    delimiter: fn(char) -> bool,
+) -> (usize, usize) {
+    let on_different_word = |a: char, b: char| delimiter(a) || delimiter(b);
+
+    while 0 < s_col && s_col < cols {
+        let prev = s_line.get(s_col - 1).unwrap().ch;
+        let curr = s_line.get(s_col).unwrap().ch;
+        if on_different_word(prev, curr) {
+            break;
+        }
+        s_col -= 1;
+    }
+    while e_col < cols - 1 {
+        let prev = e_line.get(e_col).unwrap().ch;
+        let curr = e_line.get(e_col + 1).unwrap().ch;
+        if on_different_word(prev, curr) {
+            break;
+        }
+        e_col += 1;
+    }
+    (s_col, e_col)
+}
+
+/// Expands the rows [s_row, e_row] to the whole logical lines,
+/// following the soft-wraps given by `wraps` (the linewrap flag of each row)
+fn logical_line_rows(wraps: &[bool], mut s_row: usize, mut e_row: usize) -> (usize, usize) {
+    while s_row > 0 && wraps[s_row - 1] {
+        s_row -= 1;
+    }
+    while e_row + 1 < wraps.len() && wraps[e_row] {
+        e_row += 1;
+    }
+    (s_row, e_row)
+}
+
+/// Returns the width in pixels of cells in the row
+fn cell_width(lines: &[Line], row: usize, cell_size: CellSize) -> f64 {
+    let w = cell_size.w as f64;
+    match lines.get(row) {
+        Some(line) if line.double_width() => w * 2.0,
+        _ => w,
+    }
+}
+
+/// Returns false if the last beep is too recent, so that a flood of BEL doesn't stutter
+fn should_beep(last: Option<std::time::Instant>, now: std::time::Instant) -> bool {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
+    last.map_or(true, |last| now.saturating_duration_since(last) >= INTERVAL)
+}
+
+/// Returns false if the last notification is too recent,
+/// so that a flood of OSC 9/777 doesn't keep launching the notification command
+fn should_notify(last: Option<std::time::Instant>, now: std::time::Instant) -> bool {
+    const INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+    last.map_or(true, |last| now.saturating_duration_since(last) >= INTERVAL)
+}
+
+/// Returns the commands which can play the sound file, in the order of preference
+#[cfg(feature = "audio")]
+fn sound_players(path: &std::path::Path) -> Vec<std::process::Command> {
+    use std::process::Command;
+
+    let mut players = Vec::new();
+
+    let mut paplay = Command::new("paplay");
+    paplay.arg(path);
+    players.push(paplay);
+
+    // aplay supports only (uncompressed) WAV files
+    let wav = path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("wav"));
+    if wav {
+        let mut aplay = Command::new("aplay");
+        aplay.arg("-q").arg(path);
+        players.push(aplay);
+    }
+
+    players
+}
+
+/// Plays the sound file in the background. Returns false if no player is available.
+#[cfg(feature = "audio")]
+fn play_sound(path: &std::path::Path) -> bool {
+    use std::process::Stdio;
+
+    if !path.is_file() {
+        return false;
+    }
+
+    for mut player in sound_players(path) {
+        let spawned = player
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = spawned {
+            // reap the process when it finishes
+            std::thread::spawn(move || child.wait());
+            return true;
+        }
+    }
+
+    log::warn!("No sound player is available for {:?}", path.display());
+    false
+}
+
+/// Rings the bell of the X server, or writes BEL to stderr if it's not available (e.g. on Wayland)
+fn system_beep(window: &glutin::window::Window) {
+    use glutin::platform::unix::WindowExtUnix as _;
+
+    if let Some(display) = window.xlib_display() {
+        match x11_dl::xlib::Xlib::open() {
+            Ok(xlib) => {
+                // Safety: the display is alive as long as the window
+                unsafe {
+                    (xlib.XBell)(display.cast(), 0);
+                    (xlib.XFlush)(display.cast());
+                }
+                return;
+            }
+            Err(err) => log::warn!("Failed to load Xlib: {}", err),
+        }
+    }
+
+    use std::io::{IsTerminal as _, Write as _};
+    let mut stderr = std::io::stderr();
+    if stderr.is_terminal() {
+        let _ = stderr.write_all(b"\x07");
+    }
+}
+
+/// Returns the startup mode given by the arguments, or the one in the config
+pub fn startup_mode<I: IntoIterator<Item = String>>(args: I) -> StartupMode {
+    parse_startup_mode(args).unwrap_or(crate::TOYTERM_CONFIG.load().startup_mode)
+}
+
+/// Parses `--maximized` and `--fullscreen` (the last one wins)
+fn parse_startup_mode<I: IntoIterator<Item = String>>(args: I) -> Option<StartupMode> {
+    args.into_iter()
+        .take_while(|arg| arg != "-e")
+        .filter_map(|arg| match arg.as_str() {
+            "--maximized" => Some(StartupMode::Maximized),
+            "--fullscreen" => Some(StartupMode::Fullscreen),
+            _ => None,
+        })
+        .last()
+}
+
+/// What the first terminal runs, given by the command line
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Launch {
+    /// `-e <command> [args...]` runs the command instead of the shell
+    pub command: Option<Vec<String>>,
+    /// `--working-directory <dir>`
+    pub working_directory: Option<std::path::PathBuf>,
+    /// `--attach` or `--attach=NAME` takes over a detached session
+    /// (`Some(None)` means the most recently detached one)
+    pub attach: Option<Option<String>>,
+}
+
+/// Parses the command line (all arguments after `-e` are the command)
+pub fn launch_options<I: IntoIterator<Item = String>>(args: I) -> Launch {
+    let mut launch = Launch::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" => {
+                let command: Vec<String> = args.by_ref().collect();
+                if command.is_empty() {
+                    log::warn!("-e requires a command");
+                } else {
+                    launch.command = Some(command);
+                }
+            }
+            "--working-directory" => match args.next() {
+                Some(dir) => launch.working_directory = Some(dir.into()),
+                None => log::warn!("--working-directory requires a directory"),
+            },
+            "--attach" => launch.attach = Some(None),
+            _ => {
+                if let Some(name) = arg.strip_prefix("--attach=") {
+                    launch.attach = Some(Some(name.to_owned()));
+                }
+            }
+        }
+    }
+    launch
+}
+
+/// Configures the window to be built according to the startup mode
+pub fn with_startup_mode(
+    builder: glutin::window::WindowBuilder,
+    mode: StartupMode,
+) -> glutin::window::WindowBuilder {
+    use glutin::window::Fullscreen;
+    match mode {
+        StartupMode::Windowed => builder,
+        StartupMode::Maximized => builder.with_maximized(true),
+        StartupMode::Fullscreen => builder.with_fullscreen(Some(Fullscreen::Borderless(None))),
+    }
+}
+
+fn toggle_fullscreen(window: &glutin::window::Window) {
+    use glutin::window::Fullscreen;
+    if window.fullscreen().is_some() {
+        window.set_fullscreen(None);
+    } else {
+        window.set_fullscreen(Some(Fullscreen::Borderless(None)));
+    }
+}
+
+/// Returns the regex matching the query literally and case-insensitively
+fn search_regex(query: &str) -> Option<regex::Regex> {
+    if query.is_empty() {
+        return None;
+    }
+    regex::RegexBuilder::new(&regex::escape(query))
+        .case_insensitive(true)
+        .build()
+        .ok()
+}
+
+/// Finds the query in the history and the screen
+fn search_matches(search: &mut Search, state: &crate::terminal::State) {
+    use crate::search::{find_matches, Dump};
+
+    search.dirty = true;
+    search.matches = match search_regex(&search.query) {
+        Some(re) => {
+            let hist = state.history_size() as isize;
+            let rows = state.size().rows as isize;
+            let dump = Dump::new(state.range(-hist, rows));
+            find_matches(&dump, &re)
+        }
+        None => Vec::new(),
+    };
+
+    let len = search.matches.len();
+    search.current = search.current.filter(|_| len > 0).map(|i| i.min(len - 1));
+}
+
+/// Converts the matches into ranges of cells on the screen, whose top row is `top` in the matches
+fn visible_matches(
+    matches: &[crate::search::Match],
+    lines: &[Line],
+    top: usize,
+) -> Vec<(usize, usize)> {
+    let rows = lines.len();
+    let cols = match lines.first() {
+        Some(line) => line.columns(),
+        None => return Vec::new(),
+    };
+
+    matches
+        .iter()
+        .filter(|m| top <= m.last.0 && m.first.0 < top + rows)
+        .map(|m| {
+            let first = if m.first.0 < top {
+                0
+            } else {
+                (m.first.0 - top) * cols + m.first.1
+            };
+            let last = if m.last.0 >= top + rows {
+                rows * cols - 1
+            } else {
+                // the last character may be a wide one
+                let (row, col) = (m.last.0 - top, m.last.1);
+                let width = lines[row].get(col).map_or(1, |c| c.width as usize);
+                row * cols + col + width.max(1) - 1
+            };
+            (first, last)
+        })
+        .collect()
+}
+
+/// Returns the width taken by the scroll bar (it takes nothing if it's auto-hidden)
+fn reserved_scroll_bar_width(config: &crate::config::Config) -> u32 {
+    if config.scroll_bar_auto_hide {
+        0
+    } else {
+        config.scroll_bar_width
+    }
+}
+
+/// Returns the size of the terminal which fits in the viewport
+fn terminal_size(viewport: Viewport, cell_size: CellSize, scroll_bar_width: u32) -> TerminalSize {
+    let width = viewport.w.saturating_sub(scroll_bar_width);
+    let rows = (viewport.h / cell_size.h) as usize;
+    let cols = (width / cell_size.w) as usize;
+    TerminalSize {
+        rows: rows.max(1),
+        cols: cols.max(1),
+    }
+}
+
+/// Converts the column on the screen to the one in the line (the half on double-width lines)
+fn line_col(lines: &[Line], row: usize, col: usize) -> usize {
+    match lines.get(row) {
+        Some(line) if line.double_width() => col / 2,
+        _ => col,
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod primary {
+    use arboard::{Clipboard, ClipboardExtLinux as _, Error, LinuxClipboardKind};
+
+    pub fn set_primary_text(clipboard: &mut Clipboard, text: String) -> Result<(), Error> {
+        clipboard.set_text_with_clipboard(text, LinuxClipboardKind::Primary)
+    }
+
+    pub fn get_primary_text(clipboard: &mut Clipboard) -> Result<String, Error> {
+        clipboard.get_text_with_clipboard(LinuxClipboardKind::Primary)
+    }
+}
+
+// Platforms without the primary selection
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+)))]
+mod primary {
+    use arboard::{Clipboard, Error};
+
+    pub fn set_primary_text(_: &mut Clipboard, _: String) -> Result<(), Error> {
+        Err(Error::ClipboardNotSupported)
+    }
+
+    pub fn get_primary_text(_: &mut Clipboard) -> Result<String, Error> {
+        Err(Error::ClipboardNotSupported)
+    }
+}
+
+use primary::{get_primary_text, set_primary_text};
+
+/// Returns the closed range of the rectangle whose corners are the cell boundaries `s` and `e`
+/// (in (row, col)), or `None` if it has no columns
+fn block_range(s: (usize, usize), e: (usize, usize), cols: usize) -> Option<(usize, usize)> {
+    let (top, bottom) = (s.0.min(e.0), s.0.max(e.0));
+    let (left, right) = (s.1.min(e.1), s.1.max(e.1));
+    if left < right {
+        Some((top * cols + left, bottom * cols + right - 1))
+    } else {
+        None
+    }
+}
+
+/// Returns the text in the rectangle selected by `SelectionKind::Block`.
+/// Each row is terminated by a newline except the last one, and trailing spaces are removed.
+fn block_text(lines: &[Line], range: (usize, usize)) -> String {
+    let rows: Vec<String> = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let cols = line.columns();
+            if !(range.0 / cols <= i && i <= range.1 / cols) {
+                return None;
+            }
+
+            let mut text = String::new();
+            for (j, cell) in line.iter().enumerate() {
+                if cell.width == 0 {
+                    continue;
+                }
+                if cell.ch == '\n' {
+                    break;
+                }
+                // a wide character is selected if its center is in the rectangle
+                let center = j + (cell.width / 2) as usize;
+                if crate::view::in_block(range, cols, i, center) {
+                    text.push(cell.ch);
+                }
+            }
+            Some(text.trim_end_matches(' ').to_owned())
+        })
+        .collect();
+    rows.join("\n")
+}
+
+/// Returns the URLs in the lines as clickable regions
+fn detect_urls(lines: &[Line]) -> Vec<UrlRegion> {
+    use crate::search::{find_urls, Dump};
+
+    let matches = find_urls(&Dump::new(lines));
+    let ranges = visible_matches(&matches, lines, 0);
+    matches
+        .into_iter()
+        .zip(ranges)
+        .map(|(m, range)| UrlRegion { range, url: m.text })
+        .collect()
+}
+
+/// Returns the index of the URL which contains the cell at (row, col)
+fn url_at(urls: &[UrlRegion], lines: &[Line], row: usize, col: usize) -> Option<usize> {
+    let cols = lines.first()?.columns();
+    let k = row * cols + line_col(lines, row, col);
+    urls.iter()
+        .position(|url| url.range.0 <= k && k <= url.range.1)
+}
+
+/// Returns the command opening the URI (it's appended to `opener` as the last argument)
+fn opener_command(opener: &[String], uri: &str) -> Option<std::process::Command> {
+    let (program, args) = opener.split_first()?;
+    let mut command = std::process::Command::new(program);
+    command.args(args).arg(uri);
+    Some(command)
+}
+
+/// Returns the command showing a notification (the title and the body are appended to `command`
+/// after `--`, so that they are not taken as options even if they start with `-`)
+fn notification_command(
+    command: &[String],
+    title: &str,
+    body: &str,
+) -> Option<std::process::Command> {
+    let (program, args) = command.split_first()?;
+    let mut command = std::process::Command::new(program);
+    command.args(args).arg("--").arg(title).arg(body);
+    Some(command)
+}
+
+/// Returns the initial size given by `$LINES`/`$COLUMNS`, or the one saved by the last launch
+fn initial_terminal_size() -> Option<TerminalSize> {
+    let config = crate::TOYTERM_CONFIG.load();
+
+    let env = if config.size_from_env {
+        let lines = std::env::var("LINES").ok();
+        let columns = std::env::var("COLUMNS").ok();
+        parse_size(lines.as_deref(), columns.as_deref())
+    } else {
+        None
+    };
+
+    let saved = if config.remember_size {
+        let path = saved_size_path()?;
+        let contents = std::fs::read_to_string(path).ok();
+        contents.and_then(|contents| {
+            let mut iter = contents.split_whitespace();
+            parse_size(iter.next(), iter.next())
+        })
+    } else {
+        None
+    };
+
+    resolve_initial_size(env, saved)
+}
+
+/// The environment variables take precedence over the saved size
+fn resolve_initial_size(
+    env: Option<TerminalSize>,
+    saved: Option<TerminalSize>,
+) -> Option<TerminalSize> {
+    env.or(saved)
+}
+
+fn parse_size(rows: Option<&str>, cols: Option<&str>) -> Option<TerminalSize> {
+    let rows: usize = rows?.trim().parse().ok()?;
+    let cols: usize = cols?.trim().parse().ok()?;
+    (rows > 0 && cols > 0).then_some(TerminalSize { rows, cols })
+}
+
+/// "$XDG_STATE_HOME/toyterm/size" (or "$HOME/.local/state/toyterm/size")
+fn saved_size_path() -> Option<std::path::PathBuf> {
+    let mut path = std::env::var_os("XDG_STATE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            let home = std::env::var_os("HOME")?;
+            let mut p = std::path::PathBuf::from(home);
+            p.push(".local");
+            p.push("state");
+            Some(p)
+        })?;
+
+    path.push("toyterm");
+    path.push("size");
+    Some(path)
+}
+
+pub(crate) fn is_user_input(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::WindowEvent {
+            event: WindowEvent::ReceivedCharacter(_)
+                | WindowEvent::KeyboardInput { .. }
+                | WindowEvent::MouseInput { .. }
+                | WindowEvent::MouseWheel { .. }
+                | WindowEvent::CursorMoved { .. },
+            ..
+        }
+    )
+}
+
+/// Returns (row, col) of the cell under the mouse pointer, or `None` if it's out of the screen
+fn hover_cell(
+    pos: CursorPosition,
+    cell_size: CellSize,
+    size: TerminalSize,
+) -> Option<(usize, usize)> {
+    if pos.x < 0.0 || pos.y < 0.0 {
+        return None;
+    }
+    let (row, col) = cell_at(pos, cell_size);
+    (row < size.rows && col < size.cols).then_some((row, col))
+}
+
 #[cfg(feature = "multiplex")]
 impl TerminalWindow {
     pub fn get_foreground_process_name(&self) -> String {
@@ -832,6 +2210,14 @@ impl TerminalWindow {
     }
 
     pub fn get_foreground_process_cwd(&self) -> std::path::PathBuf {
+        // Prefer the directory reported by the shell (OSC 7)
+        {
+            let state = self.terminal.state.lock().unwrap();
+            if let Some(cwd) = state.cwd() {
+                return cwd.to_owned();
+            }
+        }
+
         let pgid = self.terminal.get_pgid();
         match std::fs::read_link(format!("/proc/{pgid}/cwd")) {
             Ok(cwd) => cwd,
@@ -845,3 +2231,623 @@ impl TerminalWindow {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        use crate::search::{find_matches, Dump};
+        use crate::terminal::Cell;
+
+        let line = |s: &str| -> Line {
+            let mut cells = Vec::new();
+            for ch in s.chars() {
+                if ch == '\u{3042}' {
+                    // a wide character takes 2 cells
+                    let mut cell = Cell::new_ascii(ch);
+                    cell.width = 2;
+                    cells.push(cell);
+                    let mut rest = Cell::new_ascii(' ');
+                    rest.width = 0;
+                    cells.push(rest);
+                } else {
+                    cells.push(Cell::new_ascii(ch));
+                }
+            }
+            cells.into_iter().collect()
+        };
+        let lines = [
+            line("Error: x   "),
+            line("no error   "),
+            line("\u{3042}ERROR\u{3042}  "),
+        ];
+
+        let dump = Dump::new(lines.iter());
+        let re = search_regex("error").unwrap();
+        let matches = find_matches(&dump, &re);
+        assert_eq!(matches.len(), 3);
+
+        // case-insensitive, and the column of a wide character is of its first cell
+        assert_eq!((matches[1].first, matches[1].last), ((1, 3), (1, 7)));
+        assert_eq!((matches[2].first, matches[2].last), ((2, 2), (2, 6)));
+
+        // only rows [1, 3) are on the screen
+        let cols = lines[0].columns();
+        let ranges = visible_matches(&matches, &lines[1..], 1);
+        assert_eq!(ranges, [(3, 7), (cols + 2, cols + 6)]);
+
+        // the wide character at the end is covered entirely
+        let re = search_regex("r\u{3042}").unwrap();
+        let matches = find_matches(&dump, &re);
+        let ranges = visible_matches(&matches, &lines, 0);
+        assert_eq!(ranges, [(2 * cols + 6, 2 * cols + 8)]);
+
+        assert!(search_regex("").is_none());
+    }
+
+    #[test]
+    fn test_block_selection() {
+        use crate::terminal::Cell;
+
+        let line = |s: &str| -> Line {
+            let mut cells = Vec::new();
+            for ch in s.chars() {
+                let mut cell = Cell::new_ascii(ch);
+                if ch == '\u{3042}' {
+                    // a wide character takes 2 cells
+                    cell.width = 2;
+                    cells.push(cell);
+                    let mut rest = Cell::new_ascii(' ');
+                    rest.width = 0;
+                    cells.push(rest);
+                } else {
+                    cells.push(cell);
+                }
+            }
+            cells.resize(10, Cell::new_ascii('\n'));
+            cells.into_iter().collect()
+        };
+        let lines = [
+            line("PID  TTY"),
+            line("1    tty1"),
+            line("23   \u{3042}\u{3042}"),
+            line("x"),
+        ];
+        let cols = 10;
+
+        // dragged from the boundary (0, 5) to (2, 7), in any direction
+        let range = block_range((0, 5), (2, 7), cols).unwrap();
+        assert_eq!(range, (5, 2 * cols + 6));
+        assert_eq!(block_range((2, 7), (0, 5), cols), Some(range));
+        assert_eq!(block_range((2, 5), (0, 7), cols), Some(range));
+        assert_eq!(block_range((0, 5), (2, 5), cols), None);
+        assert_eq!(block_range((0, 0), (2, 0), cols), None);
+
+        assert!(crate::view::in_block(range, cols, 1, 5));
+        assert!(crate::view::in_block(range, cols, 2, 6));
+        assert!(!crate::view::in_block(range, cols, 1, 7));
+        assert!(!crate::view::in_block(range, cols, 3, 5));
+
+        // the first wide character is in the rectangle, but the second one is not
+        assert_eq!(block_text(&lines, range), "TT\ntt\n\u{3042}");
+
+        // rows shorter than the rectangle
+        let range = block_range((1, 0), (3, 2), cols).unwrap();
+        assert_eq!(block_text(&lines, range), "1\n23\nx");
+    }
+
+    #[test]
+    fn test_urls() {
+        use crate::terminal::Cell;
+
+        let line = |s: &str| -> Line { s.chars().map(Cell::new_ascii).collect() };
+        let mut wrapped = line("go https://");
+        wrapped.set_linewrap(true);
+        let lines = [wrapped, line("a.org/ now "), line("x http://b ")];
+        let cols = lines[0].columns();
+
+        let urls = detect_urls(&lines);
+        assert_eq!(
+            urls,
+            [
+                UrlRegion {
+                    range: (3, cols + 5),
+                    url: "https://a.org/".to_owned(),
+                },
+                UrlRegion {
+                    range: (2 * cols + 2, 2 * cols + 9),
+                    url: "http://b".to_owned(),
+                },
+            ]
+        );
+
+        assert_eq!(url_at(&urls, &lines, 0, 2), None);
+        assert_eq!(url_at(&urls, &lines, 0, 3), Some(0));
+        assert_eq!(url_at(&urls, &lines, 1, 5), Some(0));
+        assert_eq!(url_at(&urls, &lines, 1, 6), None);
+        assert_eq!(url_at(&urls, &lines, 2, 9), Some(1));
+
+        let opener = ["firefox".to_owned(), "--new-tab".to_owned()];
+        let command = opener_command(&opener, "http://b").unwrap();
+        assert_eq!(command.get_program(), "firefox");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["--new-tab", "http://b"]);
+        assert!(opener_command(&[], "http://b").is_none());
+    }
+
+    #[test]
+    fn test_notification_command() {
+        let notifier = ["notify-send".to_owned(), "-u".to_owned(), "low".to_owned()];
+        let command = notification_command(&notifier, "make", "done").unwrap();
+        assert_eq!(command.get_program(), "notify-send");
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-u", "low", "--", "make", "done"]);
+        assert!(notification_command(&[], "make", "done").is_none());
+
+        // the title and the body given by applications are never options
+        let command = notification_command(&notifier, "-u critical", "--icon=x").unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-u", "low", "--", "-u critical", "--icon=x"]);
+    }
+
+    #[test]
+    fn test_selection_units() {
+        use crate::terminal::Cell;
+        let line: Line = "ls /usr/bin-x".chars().map(Cell::new_ascii).collect();
+        let cols = line.columns();
+
+        // word: punctuation-aware
+        fn word(ch: char) -> bool {
+            ch.is_ascii_punctuation() || ch.is_ascii_whitespace()
+        }
+        assert_eq!(expand_to_word((&line, 5), (&line, 5), cols, word), (4, 6));
+
+        // WORD: whitespace-delimited
+        fn big_word(ch: char) -> bool {
+            ch.is_ascii_whitespace()
+        }
+        assert_eq!(
+            expand_to_word((&line, 5), (&line, 5), cols, big_word),
+            (3, 12)
+        );
+        assert_eq!(
+            expand_to_word((&line, 0), (&line, 0), cols, big_word),
+            (0, 1)
+        );
+
+        // line: follows soft-wraps
+        let wraps = [false, true, true, false, false];
+        assert_eq!(logical_line_rows(&wraps, 2, 2), (1, 3));
+        assert_eq!(logical_line_rows(&wraps, 0, 0), (0, 0));
+        assert_eq!(logical_line_rows(&wraps, 4, 4), (4, 4));
+    }
+
+    #[test]
+    fn test_startup_mode() {
+        let args = |args: &[&str]| args.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+
+        assert_eq!(parse_startup_mode(args(&["toyterm"])), None);
+        assert_eq!(
+            parse_startup_mode(args(&["toyterm", "--maximized"])),
+            Some(StartupMode::Maximized)
+        );
+        assert_eq!(
+            parse_startup_mode(args(&["toyterm", "--maximized", "--fullscreen"])),
+            Some(StartupMode::Fullscreen)
+        );
+
+        // the arguments of the command are not parsed
+        assert_eq!(
+            parse_startup_mode(args(&["toyterm", "-e", "vi", "--maximized"])),
+            None
+        );
+
+        use glutin::window::{Fullscreen, WindowBuilder};
+        let builder = with_startup_mode(WindowBuilder::new(), StartupMode::Fullscreen);
+        assert_eq!(
+            builder.window.fullscreen,
+            Some(Fullscreen::Borderless(None))
+        );
+        assert!(!builder.window.maximized);
+
+        let builder = with_startup_mode(WindowBuilder::new(), StartupMode::Maximized);
+        assert_eq!(builder.window.fullscreen, None);
+        assert!(builder.window.maximized);
+
+        let builder = with_startup_mode(WindowBuilder::new(), StartupMode::Windowed);
+        assert_eq!(builder.window.fullscreen, None);
+        assert!(!builder.window.maximized);
+    }
+
+    #[test]
+    fn test_launch_options() {
+        let args = |args: &[&str]| args.iter().map(|&s| s.to_owned()).collect::<Vec<_>>();
+
+        assert_eq!(launch_options(args(&["toyterm"])), Launch::default());
+
+        let launch = launch_options(args(&["toyterm", "--working-directory", "/tmp"]));
+        assert_eq!(launch.working_directory, Some("/tmp".into()));
+        assert_eq!(launch.command, None);
+
+        let launch = launch_options(args(&["toyterm", "-e", "vi", "--working-directory", "a"]));
+        assert_eq!(launch.working_directory, None);
+        assert_eq!(
+            launch.command,
+            Some(args(&["vi", "--working-directory", "a"]))
+        );
+
+        assert_eq!(launch_options(args(&["toyterm", "-e"])).command, None);
+
+        let attach = |argv: &[&str]| launch_options(args(argv)).attach;
+        assert_eq!(attach(&["toyterm", "--attach"]), Some(None));
+        assert_eq!(
+            attach(&["toyterm", "--attach=1234"]),
+            Some(Some("1234".into()))
+        );
+        assert_eq!(attach(&["toyterm", "-e", "--attach"]), None);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_sound_players() {
+        let programs = |path: &str| -> Vec<String> {
+            let players = sound_players(std::path::Path::new(path));
+            let name = |p: &std::process::Command| p.get_program().to_string_lossy().into_owned();
+            players.iter().map(name).collect()
+        };
+        assert_eq!(programs("/tmp/bell.oga"), ["paplay"]);
+        assert_eq!(programs("/tmp/bell.WAV"), ["paplay", "aplay"]);
+    }
+
+    #[test]
+    fn test_should_beep() {
+        let now = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+
+        assert!(should_beep(None, now));
+        assert!(!should_beep(Some(now), now));
+        assert!(!should_beep(Some(now), now + ms(199)));
+        assert!(should_beep(Some(now), now + ms(200)));
+    }
+
+    #[test]
+    fn test_should_notify() {
+        let now = std::time::Instant::now();
+        let ms = std::time::Duration::from_millis;
+
+        assert!(should_notify(None, now));
+        assert!(!should_notify(Some(now), now));
+        assert!(!should_notify(Some(now), now + ms(1999)));
+        assert!(should_notify(Some(now), now + ms(2000)));
+    }
+
+    #[test]
+    fn test_terminal_size_auto_hide() {
+        let viewport = Viewport {
+            x: 0,
+            y: 0,
+            w: 800,
+            h: 600,
+        };
+        let cell_size = CellSize { w: 10, h: 20 };
+
+        let mut config = crate::config::Config {
+            scroll_bar_width: 20,
+            ..Default::default()
+        };
+        let shown = terminal_size(viewport, cell_size, reserved_scroll_bar_width(&config));
+        assert_eq!((shown.rows, shown.cols), (30, 78));
+
+        // the columns reclaim the space of the scroll bar
+        config.scroll_bar_auto_hide = true;
+        let hidden = terminal_size(viewport, cell_size, reserved_scroll_bar_width(&config));
+        assert_eq!((hidden.rows, hidden.cols), (30, 80));
+    }
+
+    #[test]
+    fn test_flow_control() {
+        // passthrough: XOFF/XON reach the PTY
+        assert_eq!(local_flow_control('\x13', true), None);
+        assert_eq!(local_flow_control('\x11', true), None);
+
+        // intercepted: used for the local scroll lock
+        assert_eq!(local_flow_control('\x13', false), Some(true));
+        assert_eq!(local_flow_control('\x11', false), Some(false));
+
+        // other characters are never consumed
+        assert_eq!(local_flow_control('a', false), None);
+        assert_eq!(local_flow_control('\x03', false), None);
+    }
+
+    #[test]
+    fn test_flow_control_passthrough() {
+        // the program disables IXON, so XOFF/XON are delivered to it as data
+        let command = [
+            "sh".to_owned(),
+            "-c".to_owned(),
+            "stty raw -echo; echo ready; od -An -tx1 -N2".to_owned(),
+        ];
+        let size = TerminalSize { rows: 4, cols: 20 };
+        let cell_size = CellSize { w: 10, h: 20 };
+        let cwd = std::env::temp_dir();
+        let mut terminal = Terminal::new(size, cell_size, &cwd, Some(&command));
+
+        let screen = |terminal: &Terminal| -> String {
+            let state = terminal.state.lock().unwrap();
+            let lines = state.range(0, size.rows as isize);
+            lines.flat_map(|line| line.iter().map(|c| c.ch)).collect()
+        };
+        let wait_for = |terminal: &Terminal, text: &str| {
+            for _ in 0..500 {
+                if screen(terminal).contains(text) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("{text:?} is not shown: {:?}", screen(terminal));
+        };
+
+        wait_for(&terminal, "ready");
+        for ch in ['\x13', '\x11'] {
+            assert_eq!(local_flow_control(ch, true), None);
+            terminal.pty_write(&[ch as u8]);
+        }
+        wait_for(&terminal, " 13 11");
+    }
+
+    #[test]
+    fn test_urxvt_ext_mouse_report() {
+        assert_eq!(urxvt_ext_mouse_report(0, 1, 1), "\x1b[32;1;1M");
+        assert_eq!(urxvt_ext_mouse_report(3, 300, 250), "\x1b[35;300;250M");
+    }
+
+    #[test]
+    fn test_motion_button() {
+        // normal tracking (1000) doesn't report motion
+        assert_eq!(motion_button(None, false, false), None);
+        assert_eq!(motion_button(Some(0), false, false), None);
+
+        // button-event tracking (1002) reports motion only while a button is held
+        assert_eq!(motion_button(None, true, false), None);
+        assert_eq!(motion_button(Some(0), true, false), Some(32));
+        assert_eq!(motion_button(Some(2), true, false), Some(34));
+
+        // any-event tracking (1003)
+        assert_eq!(motion_button(None, false, true), Some(35));
+        assert_eq!(motion_button(Some(1), false, true), Some(33));
+    }
+
+    #[test]
+    fn test_injected_input() {
+        let bytes = |input: InjectedInput| input.to_bytes().map(|b| b.into_owned());
+        let key = |modifiers, keycode| bytes(InjectedInput::Key(modifiers, keycode));
+
+        assert_eq!(
+            bytes(InjectedInput::Text("ls \u{3042}\r".to_owned())),
+            Some("ls \u{3042}\r".as_bytes().to_vec())
+        );
+        assert_eq!(
+            bytes(InjectedInput::Bytes(vec![0x1b, 0xff])),
+            Some(vec![0x1b, 0xff])
+        );
+        let empty = ModifiersState::empty();
+        assert_eq!(key(empty, VirtualKeyCode::Up), Some(b"\x1b[A".to_vec()));
+        assert_eq!(key(empty, VirtualKeyCode::F5), Some(b"\x1b[15~".to_vec()));
+        assert_eq!(
+            key(ModifiersState::CTRL, VirtualKeyCode::C),
+            Some(b"\x03".to_vec())
+        );
+
+        // commands are handled by the window
+        let ctrl_shift = ModifiersState::CTRL | ModifiersState::SHIFT;
+        assert_eq!(key(ctrl_shift, VirtualKeyCode::V), None);
+        assert_eq!(key(empty, VirtualKeyCode::F11), None);
+
+        // the bytes arrive at the program unchanged
+        let command = [
+            "sh".to_owned(),
+            "-c".to_owned(),
+            "stty raw -echo; echo ready; od -An -tx1 -N12".to_owned(),
+        ];
+        let size = TerminalSize { rows: 4, cols: 60 };
+        let cell_size = CellSize { w: 10, h: 20 };
+        let cwd = std::env::temp_dir();
+        let mut terminal = Terminal::new(size, cell_size, &cwd, Some(&command));
+
+        let screen = |terminal: &Terminal| -> String {
+            let state = terminal.state.lock().unwrap();
+            let lines = state.range(0, size.rows as isize);
+            lines.flat_map(|line| line.iter().map(|c| c.ch)).collect()
+        };
+        let wait_for = |terminal: &Terminal, text: &str| {
+            for _ in 0..500 {
+                if screen(terminal).contains(text) {
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("{text:?} is not shown: {:?}", screen(terminal));
+        };
+
+        wait_for(&terminal, "ready");
+        for input in [
+            InjectedInput::Text("echo \u{3042}".to_owned()),
+            InjectedInput::Key(empty, VirtualKeyCode::Left),
+            InjectedInput::Bytes(b"\r".to_vec()),
+        ] {
+            assert!(input.write_to(&mut terminal));
+        }
+        assert!(!InjectedInput::Key(ctrl_shift, VirtualKeyCode::V).write_to(&mut terminal));
+        wait_for(&terminal, " 65 63 68 6f 20 e3 81 82 1b 5b 44 0d");
+    }
+
+    #[test]
+    fn test_prompt_jump() {
+        let rows = [3, 10, 20];
+        assert_eq!(prompt_jump(&rows, 15, false), Some(10));
+        assert_eq!(prompt_jump(&rows, 10, false), Some(3));
+        assert_eq!(prompt_jump(&rows, 3, false), None);
+        assert_eq!(prompt_jump(&rows, 10, true), Some(20));
+        assert_eq!(prompt_jump(&rows, 0, true), Some(3));
+        assert_eq!(prompt_jump(&rows, 20, true), None);
+        assert_eq!(prompt_jump(&[], 5, false), None);
+    }
+
+    #[test]
+    fn test_back_tab() {
+        let shift = ModifiersState::SHIFT;
+        let expected: &[u8] = b"\x1b[Z";
+        assert_eq!(key_sequence(shift, VirtualKeyCode::Tab), Some(expected));
+
+        // plain Tab is sent as HT via ReceivedCharacter
+        assert_eq!(
+            key_sequence(ModifiersState::empty(), VirtualKeyCode::Tab),
+            None
+        );
+        // Tab with other modifiers is sent as HT too (not dropped)
+        assert_eq!(
+            key_sequence(shift | ModifiersState::CTRL, VirtualKeyCode::Tab),
+            None
+        );
+        assert_eq!(
+            key_sequence(shift | ModifiersState::ALT, VirtualKeyCode::Tab),
+            None
+        );
+    }
+
+    #[test]
+    fn test_handled_on_key_press() {
+        let ctrl_shift = ModifiersState::CTRL | ModifiersState::SHIFT;
+        assert!(handled_on_key_press('\t', ModifiersState::SHIFT));
+        assert!(handled_on_key_press('\x03', ModifiersState::CTRL));
+        assert!(!handled_on_key_press('\t', ctrl_shift));
+        assert!(!handled_on_key_press('a', ModifiersState::empty()));
+
+        // Ctrl+Shift+Enter is not bound by default, so it's sent as Enter
+        let table = crate::config::Keybindings::default().table();
+        let key = Key::Code(ctrl_shift, VirtualKeyCode::Return);
+        assert_eq!(table.get(&key), None);
+        assert!(!handled_on_key_press('\r', ctrl_shift));
+        assert_eq!(modify_other_keys(0, ctrl_shift, '\r'), None);
+        assert_eq!(
+            modify_other_keys_for_key(0, ctrl_shift, VirtualKeyCode::Return),
+            None
+        );
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let ctrl = ModifiersState::CTRL;
+        let alt = ModifiersState::ALT;
+
+        // disabled
+        assert_eq!(modify_other_keys(0, ctrl, '\x01'), None);
+
+        // level 1: only ambiguous keys are encoded
+        assert_eq!(modify_other_keys(1, ctrl, '\x01'), None);
+        assert_eq!(modify_other_keys(1, alt, 'a'), None);
+        assert_eq!(
+            modify_other_keys(1, ctrl, '1'),
+            Some("\x1b[27;5;49~".to_owned())
+        );
+
+        // level 2: all modified keys are encoded
+        assert_eq!(
+            modify_other_keys(2, ctrl, '\x01'),
+            Some("\x1b[27;5;97~".to_owned())
+        );
+        assert_eq!(
+            modify_other_keys(2, alt, 'a'),
+            Some("\x1b[27;3;97~".to_owned())
+        );
+        assert_eq!(
+            modify_other_keys(2, ctrl | ModifiersState::SHIFT, '1'),
+            Some("\x1b[27;6;49~".to_owned())
+        );
+
+        // keys without modifiers are sent as they are
+        assert_eq!(modify_other_keys(2, ModifiersState::empty(), 'a'), None);
+        assert_eq!(modify_other_keys(2, ModifiersState::SHIFT, 'A'), None);
+    }
+
+    #[test]
+    fn test_modify_other_keys_for_key() {
+        use VirtualKeyCode::*;
+        let ctrl = ModifiersState::CTRL;
+        let seq = modify_other_keys_for_key;
+
+        // level 2: the codes are given by the keys, not by the control characters
+        assert_eq!(seq(2, ctrl, Return), Some("\x1b[27;5;13~".to_owned()));
+        assert_eq!(seq(2, ctrl, Tab), Some("\x1b[27;5;9~".to_owned()));
+        assert_eq!(seq(2, ctrl, Back), Some("\x1b[27;5;8~".to_owned()));
+        assert_eq!(
+            seq(2, ModifiersState::ALT, Escape),
+            Some("\x1b[27;3;27~".to_owned())
+        );
+        assert_eq!(
+            seq(2, ctrl | ModifiersState::SHIFT, Tab),
+            Some("\x1b[27;6;9~".to_owned())
+        );
+
+        // Ctrl+M is still encoded by its character
+        assert_eq!(seq(2, ctrl, M), None);
+        assert_eq!(
+            modify_other_keys(2, ctrl, '\r'),
+            Some("\x1b[27;5;109~".to_owned())
+        );
+
+        // level 1 and Shift+Tab (back-tab) are left as they are
+        assert_eq!(seq(1, ctrl, Return), None);
+        assert_eq!(seq(0, ctrl, Return), None);
+        assert_eq!(seq(2, ModifiersState::SHIFT, Tab), None);
+    }
+
+    #[test]
+    fn test_cell_at() {
+        let cell_size = CellSize { w: 10, h: 20 };
+        let pos = |x, y| CursorPosition { x, y };
+
+        assert_eq!(cell_at(pos(0.0, 0.0), cell_size), (0, 0));
+        assert_eq!(cell_at(pos(9.9, 19.9), cell_size), (0, 0));
+        assert_eq!(cell_at(pos(10.0, 20.0), cell_size), (1, 1));
+        assert_eq!(cell_at(pos(35.0, 65.0), cell_size), (3, 3));
+        assert_eq!(cell_at(pos(-5.0, -5.0), cell_size), (0, 0));
+    }
+
+    #[test]
+    fn test_initial_size() {
+        let size = |rows, cols| Some(TerminalSize { rows, cols });
+
+        assert_eq!(parse_size(Some("24"), Some("80")), size(24, 80));
+        assert_eq!(parse_size(Some(" 30\n"), Some("100")), size(30, 100));
+        assert_eq!(parse_size(Some("24"), None), None);
+        assert_eq!(parse_size(Some("0"), Some("80")), None);
+        assert_eq!(parse_size(Some("x"), Some("80")), None);
+
+        // $LINES/$COLUMNS > saved size > window size
+        assert_eq!(
+            resolve_initial_size(size(24, 80), size(30, 90)),
+            size(24, 80)
+        );
+        assert_eq!(resolve_initial_size(None, size(30, 90)), size(30, 90));
+        assert_eq!(resolve_initial_size(None, None), None);
+    }
+
+    #[test]
+    fn test_hover_cell() {
+        let cell_size = CellSize { w: 10, h: 20 };
+        let size = TerminalSize { rows: 3, cols: 4 };
+        let pos = |x, y| CursorPosition { x, y };
+
+        assert_eq!(hover_cell(pos(0.0, 0.0), cell_size, size), Some((0, 0)));
+        assert_eq!(hover_cell(pos(39.9, 59.9), cell_size, size), Some((2, 3)));
+        assert_eq!(hover_cell(pos(25.0, 45.0), cell_size, size), Some((2, 2)));
+
+        // out of the screen
+        assert_eq!(hover_cell(pos(40.0, 0.0), cell_size, size), None);
+        assert_eq!(hover_cell(pos(0.0, 60.0), cell_size, size), None);
+        assert_eq!(hover_cell(pos(-1.0, 0.0), cell_size, size), None);
+    }
+}